@@ -0,0 +1,22 @@
+//! Embeds the message catalog of the CLI given in `YEELIGHT_MESSAGES` at build time (see
+//! `src/bin/cli/messages.rs`). Does nothing unless the `cli` feature is enabled.
+
+use std::path::Path;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if std::env::var_os("CARGO_FEATURE_CLI").is_none() {
+        return;
+    }
+    println!("cargo:rerun-if-env-changed=YEELIGHT_MESSAGES");
+    let out = Path::new(&std::env::var("OUT_DIR").unwrap()).join("messages.yaml");
+    let catalog = match std::env::var("YEELIGHT_MESSAGES") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Could not read the messages from {}: {}", path, e))
+        }
+        Err(_) => String::new(),
+    };
+    std::fs::write(out, catalog).unwrap();
+}
@@ -12,6 +12,8 @@ rustPlatform.buildRustPackage
   cargoLock.lockFile = ./Cargo.lock;
   src = ./.;
 
+  buildFeatures = [ "cli" ];
+
   meta = with lib; {
     description = toml.package.description;
     homepage = toml.package.repository;
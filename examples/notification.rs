@@ -1,5 +1,4 @@
 use yeelight::Bulb;
-use yeelight::Notification;
 
 use tokio::sync::mpsc;
 
@@ -14,8 +13,11 @@ async fn main() {
 
     bulb.set_notify(sender).await;
 
-    while let Some(Notification(i)) = recv.recv().await {
-        for (k, v) in i.iter() {
+    while let Some(notification) = recv.recv().await {
+        let Some(props) = notification.props() else {
+            continue;
+        };
+        for (k, v) in props.iter() {
             println!("{} {}", k, v);
         }
     }
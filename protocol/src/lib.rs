@@ -0,0 +1,156 @@
+//! Low level message building and parsing.
+//!
+//! This crate contains the wire types used to talk to the bulbs, re-exported by `yeelight` as
+//! `yeelight::protocol`. It does not depend on any transport, nor on `std` (only `alloc`), so
+//! it can be reused to build messages for other kinds of connections (serial gateways, test
+//! harnesses, embedded gateways with their own TCP stack...).
+//!
+//! # Example
+//! ```
+//! # use yeelight_protocol::{JsonResponse, Request};
+//! let request = Request::new(1, "toggle", Vec::new());
+//! assert_eq!(request.to_string(), "{\"id\":1,\"method\":\"toggle\",\"params\":[]}\r\n");
+//!
+//! let response = JsonResponse::parse("{\"id\":1,\"result\":[\"ok\"]}").unwrap();
+//! assert_eq!(response.id(), Some(1));
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Response from the bulb, with the values as strings (see [to_strings]).
+pub type Response = Vec<String>;
+
+/// Response from the bulb, with the values as received.
+///
+/// Bulbs answer most requests with strings, but some methods and firmwares use numbers or
+/// objects (such as `cron_get`).
+pub type RawResponse = Vec<serde_json::Value>;
+
+/// Values of a [RawResponse] as strings: strings are kept as they are, `null` becomes an
+/// empty string and other values are encoded as JSON.
+///
+/// ```
+/// # use yeelight_protocol::to_strings;
+/// let values = serde_json::from_str::<Vec<serde_json::Value>>(r#"["on", 5, {"delay":15}]"#);
+/// assert_eq!(to_strings(&values.unwrap()), ["on", "5", "{\"delay\":15}"]);
+/// ```
+pub fn to_strings(values: &[serde_json::Value]) -> Response {
+    values
+        .iter()
+        .map(|value| match value {
+            serde_json::Value::String(value) => value.clone(),
+            serde_json::Value::Null => String::new(),
+            value => value.to_string(),
+        })
+        .collect()
+}
+
+/// Event Notification
+///
+/// Property changes are sent with the `props` method (see [Notification::props]), but other
+/// methods may be used by newer firmwares.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Notification {
+    pub method: String,
+    pub params: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Notification {
+    /// Changed properties, `None` if this is not a `props` notification.
+    pub fn props(&self) -> Option<&serde_json::Map<String, serde_json::Value>> {
+        (self.method == "props").then_some(&self.params)
+    }
+}
+
+/// Request sent to the bulb.
+///
+/// It is serialized with `serde_json` (`{"id":1,"method":"toggle","params":[]}`), so the
+/// parameters are always escaped. The [`Display`](fmt::Display) implementation produces the
+/// line to be written to the bulb, including the `\r\n` terminator.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Request {
+    pub id: u64,
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<serde_json::Value>,
+}
+
+impl Request {
+    pub fn new(id: u64, method: &str, params: Vec<serde_json::Value>) -> Self {
+        Self {
+            id,
+            method: method.to_string(),
+            params,
+        }
+    }
+
+    /// Parse a request line (without the line terminator), as received by a bulb.
+    ///
+    /// ```
+    /// # use yeelight_protocol::Request;
+    /// let line = "{\"id\":3,\"method\":\"set_bright\",\"params\":[30, \"smooth\", 500]}";
+    /// let request = Request::parse(line).unwrap();
+    /// let params = vec![30.into(), "smooth".into(), 500.into()];
+    /// assert_eq!(request, Request::new(3, "set_bright", params));
+    /// ```
+    pub fn parse(line: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}\r\n", line)
+    }
+}
+
+/// Message received from the bulb.
+///
+/// It can either be the result of a request, an error response or a notification.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum JsonResponse {
+    Result {
+        id: u64,
+        result: RawResponse,
+    },
+    Error {
+        id: u64,
+        error: ErrDetails,
+    },
+    Notification {
+        method: String,
+        params: serde_json::Map<String, serde_json::Value>,
+    },
+}
+
+impl JsonResponse {
+    /// Parse a single line received from the bulb (without the line terminator).
+    pub fn parse(line: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+
+    /// Id of the request this message responds to (`None` for notifications).
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            Self::Result { id, .. } | Self::Error { id, .. } => Some(*id),
+            Self::Notification { .. } => None,
+        }
+    }
+}
+
+/// Details of an error response from the bulb.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrDetails {
+    pub code: i32,
+    pub message: String,
+}
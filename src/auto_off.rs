@@ -0,0 +1,144 @@
+//! Turn off bulbs that have been left on and unchanged for a while.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{BulbGroup, Properties, Property};
+
+/// Absence timer settings, see [`BulbGroup::auto_off`].
+///
+/// Serializes the durations in seconds:
+///
+/// ```yaml
+/// after: 1800
+/// fade: 30
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutoOff {
+    /// Time the bulb has to be on without changes before turning it off.
+    #[serde(with = "seconds")]
+    pub after: Duration,
+    /// Duration of the fade out (see [Bulb::fade_off](crate::Bulb::fade_off)).
+    #[serde(with = "seconds", default)]
+    pub fade: Duration,
+}
+
+pub(crate) mod seconds {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_secs)
+    }
+}
+
+impl BulbGroup {
+    /// Fade off the members that have been on and unchanged for the time set in `timers`.
+    ///
+    /// Changes are detected with the notifications of the bulbs (see
+    /// [BulbGroup::notifications]), so any change (including the ones made from the Yeelight
+    /// app or by this library) restarts the timer. Members without an entry in `timers` are
+    /// left alone.
+    ///
+    /// This only returns when the notification channels of all the members are closed.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test(mut group: yeelight::BulbGroup) {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// let timers = [(
+    ///     "hall".to_string(),
+    ///     AutoOff {
+    ///         after: Duration::from_secs(30 * 60),
+    ///         fade: Duration::from_secs(10),
+    ///     },
+    /// )];
+    /// group.auto_off(&timers.into_iter().collect()).await;
+    /// # }
+    /// ```
+    pub async fn auto_off(&mut self, timers: &BTreeMap<String, AutoOff>) {
+        let mut notifications = self.notifications().await;
+
+        // Deadline of the members that are on
+        let mut deadlines = BTreeMap::new();
+        let power = Properties(vec![Property::Power]);
+        for (name, bulb) in self.iter_mut() {
+            let Some(timer) = timers.get(name) else {
+                continue;
+            };
+            match bulb.get_prop(&power).await {
+                Ok(Some(values)) if values.first().is_some_and(|v| v == "on") => {
+                    deadlines.insert(name.to_string(), Instant::now() + timer.after);
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("Could not get the power of {}: {}", name, e),
+            }
+        }
+
+        loop {
+            let next = deadlines.values().min().copied();
+            let expired = async move {
+                match next {
+                    Some(next) => tokio::time::sleep_until(next).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                notification = notifications.recv() => {
+                    let Some((name, notification)) = notification else {
+                        return;
+                    };
+                    let (Some(timer), Some(props)) = (timers.get(&name), notification.props())
+                    else {
+                        continue;
+                    };
+                    let deadline = Instant::now() + timer.after;
+                    match props.get("power").and_then(|v| v.as_str()) {
+                        Some("off") => {
+                            deadlines.remove(&name);
+                        }
+                        Some(_) => {
+                            deadlines.insert(name, deadline);
+                        }
+                        // Other changes restart the timer only if the bulb is on
+                        None => {
+                            if let Some(d) = deadlines.get_mut(&name) {
+                                *d = deadline;
+                            }
+                        }
+                    }
+                }
+                _ = expired => {
+                    let now = Instant::now();
+                    let due: Vec<_> = deadlines
+                        .iter()
+                        .filter(|(_, deadline)| **deadline <= now)
+                        .map(|(name, _)| name.clone())
+                        .collect();
+
+                    for name in due {
+                        deadlines.remove(&name);
+                        let (Some(bulb), Some(timer)) = (self.get_mut(&name), timers.get(&name))
+                        else {
+                            continue;
+                        };
+                        log::info!("Turning off {} after {:?} unchanged", name, timer.after);
+                        if let Err(e) = bulb.fade_off(timer.fade).await {
+                            log::error!("Could not turn off {}: {}", name, e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
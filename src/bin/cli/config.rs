@@ -0,0 +1,49 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// Settings read from the configuration file of the CLI.
+///
+/// The file is read from `--config`, or `$XDG_CONFIG_HOME/yeelight/config.yaml`
+/// (`~/.config/yeelight/config.yaml`) if it exists.
+///
+/// # Example
+/// ```yaml
+/// default_scene: !Ct { ct: 2700, bright: 80 }
+/// pipelines:
+///   evening:
+///     steps:
+///       - !Fade { to: !Ct { ct: 2700, bright: 40 }, duration: 600 }
+///       - !Preset Candle
+///       - !OffAt "23:30"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// Scene applied by `toggle --restore-color` when turning the light on.
+    pub default_scene: Option<yeelight::Scene>,
+    /// Pipelines run by `pipeline run`.
+    #[serde(default)]
+    pub pipelines: BTreeMap<String, yeelight::pipeline::Pipeline>,
+}
+
+impl Config {
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        Ok(serde_yaml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(dir.join("yeelight").join("config.yaml"))
+}
@@ -0,0 +1,169 @@
+use serde::Serialize;
+use structopt::clap::arg_enum;
+use yeelight::discover::{BulbInfo, DiscoveredBulb};
+use yeelight::Property;
+
+use crate::messages::msg;
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    pub enum Format {
+        Table,
+        Json,
+        Csv,
+    }
+}
+
+/// Bulb found in the network, with the state read from it (or from the discovery response if
+/// it could not be reached).
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    #[serde(flatten)]
+    info: BulbInfo,
+    reachable: bool,
+}
+
+/// Columns of the CSV output (the ones of the table are translated, see [crate::messages]).
+const COLUMNS: [&str; 10] = [
+    "ID",
+    "ADDRESS",
+    "NAME",
+    "MODEL",
+    "FIRMWARE",
+    "POWER",
+    "BRIGHT",
+    "COLOR",
+    "METHODS",
+    "REACHABLE",
+];
+
+/// Read the information of all the bulbs, sorted by model and firmware so outdated bulbs
+/// stand out.
+pub async fn collect(bulbs: &[DiscoveredBulb]) -> Vec<Entry> {
+    let adopted = yeelight::discover::adopt_all(bulbs).await;
+    let mut entries: Vec<Entry> = bulbs
+        .iter()
+        .zip(adopted)
+        .map(|(dbulb, adopted)| match adopted {
+            Ok(info) => Entry {
+                info,
+                reachable: true,
+            },
+            Err(_) => Entry {
+                info: dbulb.info(),
+                reachable: false,
+            },
+        })
+        .collect();
+    entries.sort_by_key(|entry| {
+        let info = &entry.info;
+        (
+            info.model.clone(),
+            info.fw_ver.clone(),
+            info.address.clone(),
+        )
+    });
+    entries
+}
+
+pub fn render(entries: &[Entry], format: Format) -> String {
+    match format {
+        Format::Json => serde_json::to_string_pretty(entries).expect("Bulb info serializes"),
+        Format::Csv => {
+            let header = COLUMNS.map(str::to_lowercase).join(",");
+            let rows = entries.iter().map(|entry| {
+                let mut row = entry.row();
+                // The full list instead of the count
+                row[8] = entry.info.support.join(" ");
+                row.map(|field| csv_field(&field)).join(",")
+            });
+            std::iter::once(header)
+                .chain(rows)
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        Format::Table => {
+            let header = [
+                msg!(ColumnId),
+                msg!(ColumnAddress),
+                msg!(ColumnName),
+                msg!(ColumnModel),
+                msg!(ColumnFirmware),
+                msg!(ColumnPower),
+                msg!(ColumnBright),
+                msg!(ColumnColor),
+                msg!(ColumnMethods),
+                msg!(ColumnReachable),
+            ];
+            let rows: Vec<[String; 10]> = std::iter::once(header)
+                .chain(entries.iter().map(Entry::row))
+                .collect();
+            let widths: Vec<usize> = (0..COLUMNS.len())
+                .map(|i| {
+                    rows.iter()
+                        .map(|row| row[i].chars().count().max(1))
+                        .max()
+                        .unwrap_or_default()
+                })
+                .collect();
+            rows.iter()
+                .map(|row| {
+                    let cells = row.iter().zip(&widths);
+                    let line: Vec<String> = cells
+                        .map(|(cell, width)| match cell.as_str() {
+                            "" => format!("{:width$}", "-", width = width),
+                            cell => format!("{:width$}", cell, width = width),
+                        })
+                        .collect();
+                    line.join("  ").trim_end().to_string()
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    }
+}
+
+impl Entry {
+    fn row(&self) -> [String; 10] {
+        let info = &self.info;
+        let state = |property| info.state.get(property).unwrap_or("-").to_string();
+        let reachable = match self.reachable {
+            true => "yes",
+            false => "no",
+        };
+        [
+            format!("0x{:016x}", info.id),
+            info.address.clone(),
+            info.name.clone(),
+            info.model.clone(),
+            info.fw_ver.clone(),
+            state(Property::Power),
+            state(Property::Bright),
+            self.color(),
+            info.support.len().to_string(),
+            reachable.to_string(),
+        ]
+    }
+
+    /// Color of the main light in the current color mode.
+    fn color(&self) -> String {
+        let state = &self.info.state;
+        let value = |property| state.get(property).unwrap_or_default();
+        match state.get(Property::ColorMode) {
+            Some("1") => match value(Property::Rgb).parse::<u32>() {
+                Ok(rgb) => format!("#{:06x}", rgb),
+                Err(_) => "-".to_string(),
+            },
+            Some("2") => format!("{}K", value(Property::Ct)),
+            Some("3") => format!("hsv({},{})", value(Property::Hue), value(Property::Sat)),
+            _ => "-".to_string(),
+        }
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
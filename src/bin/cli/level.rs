@@ -0,0 +1,52 @@
+use std::str::FromStr;
+
+use crate::messages::msg;
+
+/// Value given on the command line, absolute (`50`) or relative to the current one: `+10` and
+/// `-20` add or subtract units, `+10%` changes it by a percentage of the current value.
+///
+/// A `K` suffix is accepted for color temperatures (`+300K`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Absolute(u32),
+    Relative(i64),
+    Percent(i64),
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || msg!(InvalidLevel, s);
+
+        let value = s.trim().trim_end_matches(['K', 'k']);
+        let (value, percent) = match value.strip_suffix('%') {
+            Some(value) => (value, true),
+            None => (value, false),
+        };
+        let relative = value.starts_with(['+', '-']);
+        match (relative, percent) {
+            (false, false) => value.parse().map(Level::Absolute).map_err(|_| invalid()),
+            (true, false) => value.parse().map(Level::Relative).map_err(|_| invalid()),
+            (true, true) => value.parse().map(Level::Percent).map_err(|_| invalid()),
+            (false, true) => Err(invalid()),
+        }
+    }
+}
+
+impl Level {
+    pub fn is_absolute(&self) -> bool {
+        matches!(self, Level::Absolute(_))
+    }
+
+    /// Value to set given the `current` one. Relative changes are clamped to `min..=max`.
+    pub fn target(self, current: u32, (min, max): (u32, u32)) -> u32 {
+        let current = current as i64;
+        let target = match self {
+            Level::Absolute(value) => return value,
+            Level::Relative(change) => current + change,
+            Level::Percent(change) => current + current * change / 100,
+        };
+        target.clamp(min as i64, max as i64) as u32
+    }
+}
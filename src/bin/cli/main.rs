@@ -1,14 +1,24 @@
-mod presets;
+mod config;
+mod inventory;
+mod level;
+mod messages;
 
-use std::{collections::HashSet, net::IpAddr, time::Duration};
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use itertools::join;
 use structopt::{
-    clap::{AppSettings, ArgGroup},
+    clap::{arg_enum, AppSettings, ArgGroup},
     StructOpt,
 };
 
+use messages::msg;
 use tokio::sync::mpsc;
+use yeelight::presets::Preset;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -27,10 +37,36 @@ struct Options {
     port: u16,
     #[structopt(short, long, default_value = "5000", env = "YEELIGHT_TIMEOUT")]
     timeout: u64,
+    #[structopt(long, env = "YEELIGHT_CONFIG", help = "Configuration file")]
+    config: Option<PathBuf>,
+    #[cfg(unix)]
+    #[structopt(
+        long,
+        env = "YEELIGHT_BROKER",
+        help = "Socket of the connection broker"
+    )]
+    broker: Option<PathBuf>,
+    #[cfg(unix)]
+    #[structopt(long, help = "Send the commands even if the broker deduplicates them")]
+    force: bool,
+    #[structopt(long, env = "YEELIGHT_LIGHT", possible_values = &Light::variants(), case_insensitive = true)]
+    #[structopt(
+        help = "Light of the device to act on (also selected with .bg or .both after the address)"
+    )]
+    light: Option<Light>,
     #[structopt(subcommand)]
     subcommand: Command,
 }
 
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Light {
+        Main,
+        Bg,
+        Both,
+    }
+}
+
 #[derive(Debug, StructOpt, Clone)]
 enum Command {
     #[structopt(about = "Get properties")]
@@ -48,6 +84,14 @@ enum Command {
         dev: bool,
         #[structopt(long, group = "light", help = "Perform action on background light")]
         bg: bool,
+        #[structopt(
+            long,
+            conflicts_with = "dev",
+            help = "Apply the default scene of the configuration file when turning on"
+        )]
+        restore_color: bool,
+        #[structopt(skip)]
+        default_scene: Option<yeelight::Scene>,
     },
     #[structopt(about = "Turn on light")]
     On {
@@ -117,7 +161,7 @@ enum Command {
         bg: bool,
     },
     #[structopt(about = "Adjust properties (Bright/CT/Color) with percentage (-100~100)")]
-    #[structopt(setting = AppSettings::AllowNegativeNumbers)]
+    #[structopt(setting = AppSettings::AllowLeadingHyphen)]
     AdjustPercent {
         #[structopt(possible_values = &yeelight::Prop::variants(), case_insensitive = true)]
         property: yeelight::Prop,
@@ -133,18 +177,187 @@ enum Command {
     MusicStop,
     #[structopt(about = "Presets")]
     Preset {
-        #[structopt(possible_values = &presets::Preset::variants(), case_insensitive = true)]
-        preset: presets::Preset,
+        #[structopt(possible_values = &Preset::variants(), case_insensitive = true)]
+        preset: Preset,
+    },
+    #[structopt(about = "Software effects (hue rotation, breathing and looping presets)")]
+    Effect(EffectCommand),
+    #[structopt(about = "Pipelines of the configuration file (scenes, presets and fades)")]
+    Pipeline(PipelineCommand),
+    #[structopt(about = "Print the state of the light as JSON (to use with restore)")]
+    Snapshot,
+    #[structopt(about = "Restore the state printed by snapshot, read from the standard input")]
+    Restore,
+    #[structopt(about = "Show the properties that changed between two files printed by snapshot")]
+    Diff {
+        before: PathBuf,
+        after: PathBuf,
+        #[structopt(long, help = "Output in JSON format")]
+        json: bool,
     },
     #[structopt(about = "Listen to notifications from lamp")]
     Listen,
+    #[structopt(about = "Measure the frame rates that can be sent in music mode")]
+    Bench {
+        #[structopt(help = "Address of this host (for the music mode connection)")]
+        host: String,
+        #[structopt(long, use_delimiter = true, default_value = "5,10,20,30,60")]
+        fps: Vec<u32>,
+        #[structopt(long, default_value = "60", help = "Frames sent at each frame rate")]
+        frames: u32,
+        #[structopt(long, default_value = "0")]
+        #[structopt(help = "Requests sent to measure the request throughput first")]
+        requests: u32,
+        #[structopt(long, default_value = "0")]
+        #[structopt(help = "Reuse this many response slots for the requests (0 to disable)")]
+        response_slots: usize,
+    },
     #[structopt(about = "Search for lamps in the network")]
     Discover {
         #[structopt(long, default_value = "5000")]
         duration: u64,
     },
+    #[structopt(about = "Report the model, firmware and state of all the lamps found")]
+    Inventory {
+        #[structopt(
+            long,
+            default_value = "5000",
+            help = "Discovery time (in milliseconds)"
+        )]
+        duration: u64,
+        #[structopt(long, help = "Probe this subnet instead of using discovery")]
+        subnet: Option<String>,
+        #[structopt(long, default_value = "table", possible_values = &inventory::Format::variants(), case_insensitive = true)]
+        format: inventory::Format,
+    },
+    #[structopt(about = "Search for lamps in a subnet without multicast (e.g. 192.168.2.0/24)")]
+    Scan {
+        subnet: String,
+        #[structopt(long, default_value = "500ms", parse(try_from_str = parse_duration))]
+        #[structopt(help = "Time given to each address to answer (e.g. 300ms or 1s)")]
+        timeout: Duration,
+        #[structopt(long, default_value = "64", help = "Addresses probed at the same time")]
+        parallelism: usize,
+    },
+    #[cfg(unix)]
+    #[structopt(about = "Keep the bulb connections open and share them with other invocations")]
+    Broker {
+        #[structopt(long, help = "Do not send commands that would not change the light")]
+        dedup: bool,
+    },
+}
+
+impl Command {
+    /// Flag selecting the background light, for the commands that have a version for it.
+    fn bg_flag(&mut self) -> Option<&mut bool> {
+        match self {
+            Command::Toggle { dev: false, bg, .. }
+            | Command::On { bg, .. }
+            | Command::Off { bg, .. }
+            | Command::Flow { bg, .. }
+            | Command::FlowStop { bg }
+            | Command::Adjust { bg, .. }
+            | Command::AdjustPercent { bg, .. } => Some(bg),
+            Command::Set { property, .. } => match property {
+                Prop::Power { bg, .. }
+                | Prop::Ct { bg, .. }
+                | Prop::Rgb { bg, .. }
+                | Prop::Hsv { bg, .. }
+                | Prop::Bright { bg, .. }
+                | Prop::Scene { bg, .. }
+                | Prop::Default { bg } => Some(bg),
+                Prop::Wrgb { .. } | Prop::Name { .. } => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Split the light selected with a suffix (`desk.bg`, `192.168.1.5.both`) from the address.
+fn split_light(address: &str) -> (&str, Option<Light>) {
+    if let Some((rest, suffix)) = address.rsplit_once('.') {
+        if let Ok(light) = suffix.parse() {
+            return (rest, Some(light));
+        }
+    }
+    (address, None)
 }
 
+#[derive(Debug, StructOpt, Clone)]
+enum EffectCommand {
+    #[structopt(about = "List the available effects")]
+    List,
+    #[structopt(about = "Run an effect in the foreground until interrupted (Ctrl+C)")]
+    Start(EffectOptions),
+    #[structopt(about = "Stop the color flow left running by an effect")]
+    Stop,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+enum PipelineCommand {
+    #[structopt(about = "List the pipelines of the configuration file")]
+    List,
+    #[structopt(about = "Run a pipeline in the foreground until it ends")]
+    Run {
+        name: String,
+        #[structopt(skip)]
+        pipeline: Option<yeelight::pipeline::Pipeline>,
+    },
+}
+
+#[derive(Debug, StructOpt, Clone)]
+struct EffectOptions {
+    #[structopt(help = "Effect to run (see `effect list`)")]
+    effect: String,
+    #[structopt(
+        long,
+        default_value = "10000",
+        help = "Period of the effect in milliseconds"
+    )]
+    period: u64,
+    #[structopt(long, default_value = "100", help = "Brightness (maximum for breathe)")]
+    bright: u8,
+    #[structopt(long, default_value = "10", help = "Minimum brightness (breathe)")]
+    min_bright: u8,
+    #[structopt(long, default_value = "100", help = "Saturation (hue-rotate)")]
+    sat: u8,
+    #[structopt(long, default_value = "2700", help = "Color temperature (breathe)")]
+    ct: u16,
+    #[structopt(long, help = "RGB color, instead of the color temperature (breathe)")]
+    rgb: Option<u32>,
+    #[structopt(long, default_value = "0")]
+    #[structopt(help = "Stop after this many seconds (0 runs until interrupted)")]
+    duration: u64,
+    #[structopt(long, possible_values = &OnStop::variants(), case_insensitive = true)]
+    #[structopt(
+        default_value = "Recover",
+        help = "What to do with the light when stopped"
+    )]
+    on_stop: OnStop,
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    enum OnStop {
+        Recover,
+        Stay,
+        Off,
+    }
+}
+
+impl From<OnStop> for yeelight::effects::OnStop {
+    fn from(on_stop: OnStop) -> Self {
+        match on_stop {
+            OnStop::Recover => Self::Recover,
+            OnStop::Stay => Self::Stay,
+            OnStop::Off => Self::Off,
+        }
+    }
+}
+
+/// Effects of the effects engine, the flow presets can also be used as effects.
+const EFFECTS: [&str; 2] = ["hue-rotate", "breathe"];
+
 #[derive(Debug, StructOpt, Clone)]
 enum Prop {
     Power {
@@ -156,8 +369,10 @@ enum Prop {
         #[structopt(long, help = "Perform action on background light")]
         bg: bool,
     },
+    #[structopt(about = "Set the color temperature (absolute, +300K, -300K or +10%)", setting = AppSettings::AllowLeadingHyphen)]
     Ct {
-        color_temperature: u16,
+        #[structopt(allow_hyphen_values = true)]
+        color_temperature: level::Level,
         #[structopt(long, help = "Perform action on background light")]
         bg: bool,
     },
@@ -166,6 +381,12 @@ enum Prop {
         #[structopt(long, help = "Perform action on background light")]
         bg: bool,
     },
+    #[structopt(
+        about = "Set the white level and color (0xWWRRGGBB) of lights with a white channel"
+    )]
+    Wrgb {
+        wrgb_value: u32,
+    },
     Hsv {
         hue: u16,
         #[structopt(default_value = "100")]
@@ -173,14 +394,17 @@ enum Prop {
         #[structopt(long, help = "Perform action on background light")]
         bg: bool,
     },
+    #[structopt(about = "Set the brightness (absolute, +10, -20 or +10%)", setting = AppSettings::AllowLeadingHyphen)]
     Bright {
-        brightness: u8,
+        #[structopt(allow_hyphen_values = true)]
+        brightness: level::Level,
         #[structopt(long, help = "Perform action on background light")]
         bg: bool,
     },
     Name {
         name: String,
     },
+    #[structopt(about = "Set scene (for cf, val1 is the flow count)")]
     Scene {
         #[structopt(possible_values = &yeelight::Class::variants(), case_insensitive = true)]
         class: yeelight::Class,
@@ -189,6 +413,11 @@ enum Prop {
         val2: u64,
         #[structopt(default_value = "100")]
         val3: u64,
+        #[structopt(long, help = "Flow expression (cf class only)")]
+        flow: Option<yeelight::FlowExpresion>,
+        #[structopt(possible_values = &yeelight::CfAction::variants(), case_insensitive = true)]
+        #[structopt(long, default_value = "Recover", help = "Flow action (cf class only)")]
+        action: yeelight::CfAction,
         #[structopt(long, help = "Perform action on background light")]
         bg: bool,
     },
@@ -208,6 +437,30 @@ macro_rules! sel_bg {
     );
 }
 
+#[cfg(unix)]
+fn broker_socket(opt: &Options) -> PathBuf {
+    opt.broker
+        .clone()
+        .unwrap_or_else(yeelight::broker::default_socket)
+}
+
+/// Share the connection of the broker if it is running.
+#[cfg(unix)]
+async fn connect_broker(opt: &Options) -> Option<yeelight::Bulb> {
+    let socket = broker_socket(opt);
+    let connected = match opt.force {
+        true => yeelight::broker::connect_forced(socket, &opt.address, opt.port).await,
+        false => yeelight::broker::connect(socket, &opt.address, opt.port).await,
+    };
+    connected.ok()
+}
+
+/// The broker is only available on Unix.
+#[cfg(not(unix))]
+async fn connect_broker(_opt: &Options) -> Option<yeelight::Bulb> {
+    None
+}
+
 fn display_dbulb_info(dbulb: &yeelight::discover::DiscoveredBulb) {
     let dash = "-".to_owned();
     let name = dbulb.properties.get("name").unwrap_or(&dash);
@@ -219,9 +472,99 @@ fn display_dbulb_info(dbulb: &yeelight::discover::DiscoveredBulb) {
     eprintln!("{}\t{}", &location, &name);
 }
 
+/// Print the changes between the snapshots of a bulb, or of all the bulbs of a group (as
+/// printed by `snapshot` for `all`). Returns whether they are equal.
+fn diff_snapshots(
+    before: &Path,
+    after: &Path,
+    json: bool,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    type Snapshots = BTreeMap<String, yeelight::StateSnapshot>;
+
+    let read = |path: &Path| -> Result<Snapshots, Box<dyn std::error::Error>> {
+        let content = std::fs::read_to_string(path)?;
+        match serde_json::from_str::<yeelight::StateSnapshot>(&content) {
+            // A single bulb, without name
+            Ok(snapshot) => Ok(BTreeMap::from([(String::new(), snapshot)])),
+            Err(_) => Ok(serde_json::from_str(&content)?),
+        }
+    };
+    let (before, after) = (read(before)?, read(after)?);
+
+    let empty = yeelight::StateSnapshot::default();
+    let names: HashSet<&String> = before.keys().chain(after.keys()).collect();
+    let changes: BTreeMap<&String, Vec<yeelight::PropertyChange>> = names
+        .into_iter()
+        .map(|name| {
+            let before = before.get(name).unwrap_or(&empty);
+            (name, before.diff(after.get(name).unwrap_or(&empty)))
+        })
+        .filter(|(_, changes)| !changes.is_empty())
+        .collect();
+
+    let single = before.keys().chain(after.keys()).all(String::is_empty);
+    if json && single {
+        let changes = changes.values().next().cloned().unwrap_or_default();
+        println!("{}", serde_json::to_string_pretty(&changes)?);
+    } else if json {
+        println!("{}", serde_json::to_string_pretty(&changes)?);
+    } else {
+        for (name, change) in changes
+            .iter()
+            .flat_map(|(n, c)| c.iter().map(move |c| (n, c)))
+        {
+            match single {
+                true => println!("{}", change),
+                false => println!("{}\t{}", name, change),
+            }
+        }
+    }
+    Ok(changes.is_empty())
+}
+
 #[tokio::main]
 async fn main() {
-    let opt = Options::from_args();
+    let mut opt = Options::from_args();
+
+    if let Command::Toggle {
+        restore_color: true,
+        default_scene,
+        ..
+    } = &mut opt.subcommand
+    {
+        let config = config::Config::load(opt.config.as_deref()).unwrap_or_else(|e| {
+            eprintln!("{}", msg!(ConfigUnreadable, e));
+            std::process::exit(1);
+        });
+        *default_scene = config.default_scene;
+        if default_scene.is_none() {
+            eprintln!("{}", msg!(NoDefaultScene));
+            std::process::exit(1);
+        }
+    }
+
+    if let Command::Pipeline(command) = &mut opt.subcommand {
+        let mut config = config::Config::load(opt.config.as_deref()).unwrap_or_else(|e| {
+            eprintln!("{}", msg!(ConfigUnreadable, e));
+            std::process::exit(1);
+        });
+        match command {
+            PipelineCommand::List => {
+                config
+                    .pipelines
+                    .keys()
+                    .for_each(|name| println!("{}", name));
+                return;
+            }
+            PipelineCommand::Run { name, pipeline } => {
+                *pipeline = config.pipelines.remove(name.as_str());
+                if pipeline.is_none() {
+                    eprintln!("{}", msg!(UnknownPipeline, name));
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
 
     // If discovery is used, we do not try to connect to any bulb
     if let Command::Discover { duration } = opt.subcommand {
@@ -234,9 +577,111 @@ async fn main() {
         return;
     }
 
+    if let Command::Effect(EffectCommand::List) = &opt.subcommand {
+        EFFECTS.iter().for_each(|effect| println!("{}", effect));
+        Preset::variants()
+            .iter()
+            .filter(|preset| preset.parse::<Preset>().unwrap().flow().is_some())
+            .for_each(|preset| println!("{}", preset.to_lowercase()));
+        return;
+    }
+
+    if let Command::Scan {
+        subnet,
+        timeout,
+        parallelism,
+    } = &opt.subcommand
+    {
+        match yeelight::discover::probe_subnet_with(subnet, *parallelism, *timeout).await {
+            Ok(dbulbs) => dbulbs.iter().for_each(display_dbulb_info),
+            Err(e) => {
+                eprintln!("{}", msg!(InvalidSubnet, subnet, e));
+                std::process::exit(1);
+            }
+        }
+
+        return;
+    }
+
+    if let Command::Diff {
+        before,
+        after,
+        json,
+    } = &opt.subcommand
+    {
+        match diff_snapshots(before, after, *json) {
+            Ok(true) => return,
+            // Like diff(1), exit with 1 if there are differences
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{}", msg!(CompareFailed, e));
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if let Command::Inventory {
+        duration,
+        subnet,
+        format,
+    } = &opt.subcommand
+    {
+        let found = match subnet {
+            Some(subnet) => yeelight::discover::probe_subnet(subnet, 64).await,
+            None => yeelight::discover::find_bulbs_timeout(Duration::from_millis(*duration)).await,
+        };
+        let dbulbs = found.unwrap_or_else(|e| {
+            eprintln!("{}", msg!(SearchFailed, e));
+            std::process::exit(1);
+        });
+        let entries = inventory::collect(&dbulbs).await;
+        println!("{}", inventory::render(&entries, *format));
+
+        return;
+    }
+
+    #[cfg(unix)]
+    if let Command::Broker { dedup } = &opt.subcommand {
+        let socket = broker_socket(&opt);
+        eprintln!("{}", msg!(BrokerListening, socket.display()));
+        if let Err(e) = yeelight::broker::Broker::new()
+            .options(yeelight::ConnectOptions::from_env())
+            .dedup(*dedup)
+            .serve(&socket)
+            .await
+        {
+            eprintln!("{}", msg!(BrokerError, e));
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let (address, suffix) = split_light(&opt.address);
+    let selected = suffix.or(opt.light);
+    opt.address = address.to_string();
+    let light = match (selected, opt.subcommand.bg_flag().map(|bg| *bg)) {
+        (Some(light), Some(true)) if light != Light::Bg => {
+            structopt::clap::Error::with_description(
+                &msg!(BgConflict),
+                structopt::clap::ErrorKind::ArgumentConflict,
+            )
+            .exit();
+        }
+        (None, Some(true)) => Light::Bg,
+        (Some(Light::Main) | None, _) => Light::Main,
+        (Some(light), Some(_)) => light,
+        (Some(_), None) => {
+            structopt::clap::Error::with_description(
+                &msg!(MainLightOnly),
+                structopt::clap::ErrorKind::InvalidValue,
+            )
+            .exit();
+        }
+    };
+
     // If the address is ALL or all, we run the command for all the bulbs we find
     if opt.address.to_lowercase() == "all" {
-        eprintln!("Discovering bulbs...");
+        eprintln!("{}", msg!(Discovering));
         let (tx, mut rx) = mpsc::channel(5);
         tokio::spawn(discover_unique_with_timeout(tx, opt.timeout));
 
@@ -254,11 +699,49 @@ async fn main() {
             println!("{{");
         }
 
-        let mut first = true;
+        let mut dbulbs = Vec::new();
         while let Some(dbulb) = rx.recv().await {
             display_dbulb_info(&dbulb);
-            let bulb = dbulb.connect().await.unwrap();
-            let response = run_command(opt.subcommand.clone(), bulb).await.unwrap();
+            dbulbs.push(dbulb);
+        }
+        let options = yeelight::ConnectOptions::from_env();
+        let bulbs = yeelight::discover::connect_all_with(&dbulbs, &options).await;
+
+        // Effects and snapshots run on all the bulbs at the same time, as a group
+        if let Command::Effect(EffectCommand::Start(_)) | Command::Snapshot | Command::Restore =
+            opt.subcommand
+        {
+            let mut group = yeelight::BulbGroup::new();
+            for (dbulb, bulb) in dbulbs.iter().zip(bulbs) {
+                let address = dbulb.info().address;
+                match bulb {
+                    Ok(bulb) => group.add(address, bulb),
+                    Err(e) => eprintln!("{}\t{}", address, e),
+                }
+            }
+            if let Err(e) = run_group_command(opt.subcommand, group).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+
+        let mut first = true;
+        let mut failures = Vec::new();
+        for (dbulb, bulb) in dbulbs.iter().zip(bulbs) {
+            let response = match bulb {
+                Ok(bulb) => run_on_lights(opt.subcommand.clone(), bulb, light)
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            let response = match response {
+                Ok(response) => response,
+                Err(e) => {
+                    failures.push((dbulb, e));
+                    continue;
+                }
+            };
 
             let mut has_name = true;
             let name = dbulb.properties.get("name").unwrap_or_else(|| {
@@ -294,13 +777,25 @@ async fn main() {
             println!("}}");
         }
 
+        if !failures.is_empty() {
+            eprintln!("{}", msg!(FailedOn, failures.len()));
+            for (dbulb, e) in failures {
+                let location = dbulb
+                    .properties
+                    .get("Location")
+                    .map_or("-", |l| l.trim_start_matches("yeelight://"));
+                eprintln!("{}\t{}", location, e);
+            }
+            std::process::exit(1);
+        }
+
         return;
     }
 
     // At this point, if the address is NULL, the user did not specify the address so we error
     if opt.address == "NULL" {
         structopt::clap::Error::with_description(
-            "No address specified (use --help for more info)",
+            &msg!(NoAddress),
             structopt::clap::ErrorKind::MissingRequiredArgument,
         )
         .exit();
@@ -309,7 +804,11 @@ async fn main() {
     // If the address is valid, try to connect to it
     let bulb = if opt.address.parse::<IpAddr>().is_ok() {
         tokio::time::timeout(Duration::from_secs(opt.timeout), async {
-            yeelight::Bulb::connect(&opt.address, opt.port)
+            if let Some(bulb) = connect_broker(&opt).await {
+                return bulb;
+            }
+            let options = yeelight::ConnectOptions::from_env();
+            yeelight::Bulb::connect_with(&opt.address, opt.port, &options)
                 .await
                 .unwrap()
         })
@@ -317,7 +816,7 @@ async fn main() {
         .unwrap()
     } else {
         // otherwise, search for bulbs matching the name
-        println!("Discovering bulbs...");
+        println!("{}", msg!(Discovering));
         let (tx, mut rx) = mpsc::channel(5);
         tokio::spawn(discover_unique_with_timeout(tx, opt.timeout));
         (async {
@@ -325,7 +824,8 @@ async fn main() {
                 display_dbulb_info(&dbulb);
                 let name = dbulb.properties.get("name").unwrap();
                 if name == &opt.address {
-                    return Some(dbulb.connect().await.unwrap());
+                    let options = yeelight::ConnectOptions::from_env();
+                    return Some(dbulb.connect_with(&options).await.unwrap());
                 }
             }
             None
@@ -333,14 +833,14 @@ async fn main() {
         .await
         .unwrap_or_else(|| {
             structopt::clap::Error::with_description(
-                "Bulb not found",
+                &msg!(BulbNotFound),
                 structopt::clap::ErrorKind::InvalidValue,
             )
             .exit();
         })
     };
 
-    let response = run_command(opt.subcommand, bulb).await.unwrap();
+    let response = run_on_lights(opt.subcommand, bulb, light).await.unwrap();
 
     if let Some(result) = response {
         result.iter().for_each(|x| {
@@ -351,13 +851,53 @@ async fn main() {
     }
 }
 
+/// Run the command on the selected lights of the device, the main light first.
+async fn run_on_lights(
+    command: Command,
+    bulb: yeelight::Bulb,
+    light: Light,
+) -> Result<Option<Vec<String>>, yeelight::BulbError> {
+    let lights: &[bool] = match light {
+        Light::Main => &[false],
+        Light::Bg => &[true],
+        Light::Both => &[false, true],
+    };
+    let mut output: Option<Vec<String>> = None;
+    for &bg in lights {
+        let mut command = command.clone();
+        if let Some(flag) = command.bg_flag() {
+            *flag = bg;
+        }
+        if let Some(result) = run_command(command, bulb.clone()).await? {
+            output.get_or_insert_with(Vec::new).extend(result);
+        }
+    }
+    Ok(output)
+}
+
 async fn run_command(
     command: Command,
     bulb: yeelight::Bulb,
 ) -> Result<Option<Vec<String>>, yeelight::BulbError> {
     let mut bulb = bulb;
     match command {
-        Command::Toggle { bg, dev } => match (bg, dev) {
+        Command::Toggle {
+            bg,
+            default_scene: Some(scene),
+            ..
+        } => {
+            let power = match bg {
+                true => yeelight::Property::BgPower,
+                false => yeelight::Property::Power,
+            };
+            let power = bulb.get_prop(&yeelight::Properties(vec![power])).await?;
+            match power.as_ref().and_then(|p| p.first()).map(String::as_str) {
+                // Setting the scene also turns the light on
+                Some("off") => sel_bg!(bulb.set_scene(scene) || bg_set_scene if bg),
+                _ => sel_bg!(bulb.toggle() || bg_toggle if bg),
+            }
+        }
+        Command::Toggle { bg, dev, .. } => match (bg, dev) {
             (true, _) => bulb.bg_toggle().await,
             (_, true) => bulb.dev_toggle().await,
             _ => bulb.toggle().await,
@@ -417,16 +957,34 @@ async fn run_command(
                 color_temperature,
                 bg,
             } => {
-                sel_bg!(bulb.set_ct_abx(color_temperature, effect, Duration::from_millis(duration)) || bg_set_ct_abx if bg)
+                let property = match bg {
+                    true => yeelight::Property::BgCt,
+                    false => yeelight::Property::Ct,
+                };
+                let range = bulb.limits().ct_range.unwrap_or((1700, 6500));
+                let range = (range.0 as u32, range.1 as u32);
+                let ct = target(&mut bulb, property, color_temperature, range).await?;
+                let ct = narrow(ct as u64, "color temperature");
+                sel_bg!(bulb.set_ct_abx(ct, effect, Duration::from_millis(duration)) || bg_set_ct_abx if bg)
             }
             Prop::Rgb { rgb_value, bg } => {
                 sel_bg!(bulb.set_rgb(rgb_value, effect, Duration::from_millis(duration)) || bg_set_rgb if bg)
             }
+            Prop::Wrgb { wrgb_value } => {
+                bulb.set_wrgb(wrgb_value, effect, Duration::from_millis(duration))
+                    .await
+            }
             Prop::Hsv { hue, sat, bg } => {
                 sel_bg!(bulb.set_hsv(hue, sat, effect, Duration::from_millis(duration)) || bg_set_hsv if bg)
             }
             Prop::Bright { brightness, bg } => {
-                sel_bg!(bulb.set_bright(brightness, effect, Duration::from_millis(duration)) || bg_set_bright if bg)
+                let property = match bg {
+                    true => yeelight::Property::BgBright,
+                    false => yeelight::Property::Bright,
+                };
+                let bright = target(&mut bulb, property, brightness, (1, 100)).await?;
+                let bright = narrow(bright as u64, "brightness");
+                sel_bg!(bulb.set_bright(bright, effect, Duration::from_millis(duration)) || bg_set_bright if bg)
             }
             Prop::Name { name } => bulb.set_name(&name).await,
             Prop::Scene {
@@ -434,8 +992,13 @@ async fn run_command(
                 val1,
                 val2,
                 val3,
+                flow,
+                action,
                 bg,
-            } => sel_bg!(bulb.set_scene(class, val1, val2, val3) || bg_set_scene if bg),
+            } => {
+                let scene = build_scene(class, val1, val2, val3, flow, action);
+                sel_bg!(bulb.set_scene(scene) || bg_set_scene if bg)
+            }
             Prop::Default { bg } => sel_bg!(bulb.set_default() || bg_set_default if bg),
         },
         Command::Timer { minutes } => bulb.cron_add(yeelight::CronType::Off, minutes).await,
@@ -473,21 +1036,244 @@ async fn run_command(
             bulb.set_music(yeelight::MusicAction::On, &host, port).await
         }
         Command::MusicStop => bulb.set_music(yeelight::MusicAction::Off, "", 0).await,
-        Command::Preset { preset } => presets::apply(bulb, preset).await,
+        Command::Preset { preset } => preset.apply(&mut bulb).await,
+        Command::Effect(EffectCommand::Start(options)) => {
+            run_effect(bulb, options).await?;
+            Ok(None)
+        }
+        Command::Effect(EffectCommand::Stop) => bulb.stop_cf().await,
+        Command::Pipeline(PipelineCommand::Run {
+            pipeline: Some(pipeline),
+            ..
+        }) => {
+            pipeline.run(&mut bulb).await?;
+            Ok(None)
+        }
+        Command::Snapshot => {
+            let snapshot = bulb.snapshot().await?;
+            let json = serde_json::to_string_pretty(&snapshot).expect("Snapshots serialize");
+            Ok(Some(vec![json]))
+        }
+        Command::Restore => {
+            let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+            let input = std::io::read_to_string(std::io::stdin())?;
+            let snapshot: yeelight::StateSnapshot =
+                serde_json::from_str(&input).map_err(|e| invalid(e.to_string()))?;
+            bulb.restore(&snapshot).await?;
+            Ok(None)
+        }
+        Command::Effect(EffectCommand::List) => unreachable!(), // Special command run in main
+        // Listed in main, and the pipeline to run is read from the configuration file there
+        Command::Pipeline(PipelineCommand::List | PipelineCommand::Run { pipeline: None, .. }) => {
+            unreachable!()
+        }
         Command::Listen => {
             let (sender, mut recv) = mpsc::channel(10);
 
             bulb.set_notify(sender).await;
 
-            while let Some(yeelight::Notification(i)) = recv.recv().await {
-                for (k, v) in i.iter() {
+            while let Some(notification) = recv.recv().await {
+                if notification.props().is_none() {
+                    println!("[{}]", notification.method);
+                }
+                for (k, v) in notification.params.iter() {
                     println!("{} {}", k, v);
                 }
             }
             Ok(None)
         }
+        Command::Bench {
+            host,
+            fps,
+            frames,
+            requests,
+            response_slots,
+        } => {
+            let mut output = Vec::new();
+            if requests > 0 {
+                bulb = bulb.response_slots(response_slots);
+                let report = yeelight::effects::benchmark_requests(&mut bulb, requests).await?;
+                output.extend(report.to_string().lines().map(String::from));
+            }
+            // Uncapped, to measure the frame rates beyond the one of the model
+            let mut music = bulb
+                .start_music(&host)
+                .await
+                .map_err(|e| yeelight::BulbError::Io(std::io::Error::other(e.to_string())))?
+                .max_frame_rate(None);
+            let options = yeelight::effects::BenchmarkOptions { fps, frames };
+            let report = yeelight::effects::benchmark(&mut music, &options).await?;
+            output.extend(report.to_string().lines().map(String::from));
+            Ok(Some(output))
+        }
         Command::Discover { duration: _ } => unreachable!(), // Special command run in main
+        Command::Diff { .. } => unreachable!(),              // Special command run in main
+        Command::Inventory { .. } => unreachable!(),         // Special command run in main
+        Command::Scan { .. } => unreachable!(),              // Special command run in main
+        #[cfg(unix)]
+        Command::Broker { .. } => unreachable!(), // Special command run in main
+    }
+}
+
+/// Run the commands that act on all the bulbs at once, with the bulbs named by address.
+async fn run_group_command(
+    command: Command,
+    mut group: yeelight::BulbGroup,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Effect(EffectCommand::Start(options)) => run_effect(group, options).await?,
+        Command::Snapshot => {
+            let mut snapshots = BTreeMap::new();
+            for (address, snapshot) in group.snapshot().await {
+                match snapshot {
+                    Ok(snapshot) => {
+                        snapshots.insert(address, snapshot);
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            println!("{}", serde_json::to_string_pretty(&snapshots)?);
+        }
+        Command::Restore => {
+            let snapshots: BTreeMap<String, yeelight::StateSnapshot> =
+                serde_json::from_str(&std::io::read_to_string(std::io::stdin())?)?;
+            let mode = yeelight::ReconcileMode::Apply;
+            for (_, result) in group.reconcile(&snapshots, mode).await {
+                if let Err(e) = result {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+        _ => unreachable!("Not a group command"),
+    }
+    Ok(())
+}
+
+/// Run an effect on a bulb or group until it is interrupted, fails or `duration` elapses.
+async fn run_effect<T: yeelight::effects::EffectTarget>(
+    target: T,
+    options: EffectOptions,
+) -> Result<(), yeelight::BulbError> {
+    use yeelight::effects::{breathe, hue_rotate, play_flow, Easing};
+
+    let period = Duration::from_millis(options.period);
+    let on_stop = options.on_stop.into();
+    let mut handle = match options.effect.to_lowercase().as_str() {
+        "hue-rotate" => hue_rotate(target, period, options.sat, options.bright, on_stop),
+        "breathe" => {
+            let color = match options.rgb {
+                Some(rgb) => yeelight::ColorSpec::Rgb(rgb),
+                None => yeelight::ColorSpec::Ct(options.ct),
+            };
+            let (min, max) = (options.min_bright, options.bright);
+            breathe(target, color, period, min, max, Easing::Sine, on_stop)
+        }
+        name => match name.parse().ok().and_then(Preset::flow) {
+            Some(flow) => play_flow(target, flow, on_stop),
+            None => structopt::clap::Error::with_description(
+                &msg!(UnknownEffect, name),
+                structopt::clap::ErrorKind::InvalidValue,
+            )
+            .exit(),
+        },
+    };
+
+    eprintln!("{}", msg!(RunningEffect, options.effect));
+    let timeout = async {
+        match options.duration {
+            0 => std::future::pending().await,
+            seconds => tokio::time::sleep(Duration::from_secs(seconds)).await,
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = timeout => {}
+        _ = handle.finished() => {}
     }
+    let (_, result) = handle.stop().await?;
+    result
+}
+
+fn build_scene(
+    class: yeelight::Class,
+    val1: u64,
+    val2: u64,
+    val3: u64,
+    flow: Option<yeelight::FlowExpresion>,
+    action: yeelight::CfAction,
+) -> yeelight::Scene {
+    use yeelight::{Class, Scene};
+    match class {
+        Class::Color => Scene::Color {
+            rgb: narrow(val1, "rgb"),
+            bright: narrow(val2, "brightness"),
+        },
+        Class::Hsv => Scene::Hsv {
+            hue: narrow(val1, "hue"),
+            sat: narrow(val2, "saturation"),
+            bright: narrow(val3, "brightness"),
+        },
+        Class::Ct => Scene::Ct {
+            ct: narrow(val1, "color temperature"),
+            bright: narrow(val2, "brightness"),
+        },
+        Class::Cf => Scene::Cf {
+            count: narrow(val1, "count"),
+            action,
+            flow: flow.unwrap_or_else(|| {
+                structopt::clap::Error::with_description(
+                    &msg!(MissingFlow),
+                    structopt::clap::ErrorKind::MissingRequiredArgument,
+                )
+                .exit()
+            }),
+        },
+        Class::AutoDelayOff => Scene::AutoDelayOff {
+            bright: narrow(val1, "brightness"),
+            minutes: val2,
+        },
+    }
+}
+
+/// Parse a duration such as `300ms` or `2s` (milliseconds if there is no unit).
+fn parse_duration(value: &str) -> Result<Duration, std::num::ParseIntError> {
+    Ok(match value.strip_suffix("ms") {
+        Some(ms) => Duration::from_millis(ms.parse()?),
+        None => match value.strip_suffix('s') {
+            Some(s) => Duration::from_secs(s.parse()?),
+            None => Duration::from_millis(value.parse()?),
+        },
+    })
+}
+
+/// Value to set for `property`, reading the current one if `level` is relative.
+async fn target(
+    bulb: &mut yeelight::Bulb,
+    property: yeelight::Property,
+    level: level::Level,
+    range: (u32, u32),
+) -> Result<u32, yeelight::BulbError> {
+    if level.is_absolute() {
+        return Ok(level.target(0, range));
+    }
+    let values = bulb.get_prop(&yeelight::Properties(vec![property])).await?;
+    let current = values
+        .and_then(|values| values.first()?.parse().ok())
+        .ok_or_else(|| {
+            let message = format!("Could not read the current value of {}", property);
+            std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+        })?;
+    Ok(level.target(current, range))
+}
+
+fn narrow<T: TryFrom<u64>>(value: u64, name: &str) -> T {
+    T::try_from(value).unwrap_or_else(|_| {
+        structopt::clap::Error::with_description(
+            &msg!(OutOfRange, value, name),
+            structopt::clap::ErrorKind::InvalidValue,
+        )
+        .exit()
+    })
 }
 
 async fn discover_unique_with_timeout(
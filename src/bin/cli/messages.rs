@@ -0,0 +1,121 @@
+//! Catalog of the messages shown to users, so they can be translated.
+//!
+//! The catalog is a YAML file mapping the names of the messages to their text, with `{}` for
+//! each value in order:
+//!
+//! ```yaml
+//! ConfigUnreadable: "No se pudo leer el archivo de configuración: {}"
+//! Discovering: "Buscando bombillas..."
+//! ```
+//!
+//! It is read from `$YEELIGHT_MESSAGES` at run time. Packagers shipping a translated build can
+//! set `YEELIGHT_MESSAGES` when building instead, and the catalog is embedded in the binary
+//! (see `build.rs`). Missing messages are shown in English. Machine readable output (`--json`,
+//! `--format json` and `--format csv`) is never translated.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+macro_rules! messages {
+    ($($name:ident => $text:literal),* $(,)?) => {
+        #[derive(Debug, Clone, Copy)]
+        pub enum Message {
+            $($name),*
+        }
+
+        impl Message {
+            fn name(self) -> &'static str {
+                match self {
+                    $(Message::$name => stringify!($name)),*
+                }
+            }
+
+            fn english(self) -> &'static str {
+                match self {
+                    $(Message::$name => $text),*
+                }
+            }
+        }
+    };
+}
+
+messages!(
+    ConfigUnreadable => "Could not read the configuration file: {}",
+    NoDefaultScene => "No default_scene in the configuration file",
+    UnknownPipeline => "No pipeline {} in the configuration file",
+    InvalidSubnet => "Invalid subnet {}: {}",
+    CompareFailed => "Could not compare the snapshots: {}",
+    SearchFailed => "Could not search for bulbs: {}",
+    BrokerListening => "Broker listening on {}",
+    BrokerError => "Broker error: {}",
+    Discovering => "Discovering bulbs...",
+    FailedOn => "Failed on {} bulb(s):",
+    BgConflict => "--bg conflicts with the selected light",
+    MainLightOnly => "This command can only act on the main light",
+    NoAddress => "No address specified (use --help for more info)",
+    BulbNotFound => "Bulb not found",
+    UnknownEffect => "Unknown effect {} (see `effect list`)",
+    MissingFlow => "The cf scene requires a flow expression (--flow)",
+    OutOfRange => "Value {} out of range for {}",
+    InvalidLevel => "Invalid value {} (use 50, +10, -20 or +10%)",
+    RunningEffect => "Running {} (Ctrl+C to stop)",
+    ColumnId => "ID",
+    ColumnAddress => "ADDRESS",
+    ColumnName => "NAME",
+    ColumnModel => "MODEL",
+    ColumnFirmware => "FIRMWARE",
+    ColumnPower => "POWER",
+    ColumnBright => "BRIGHT",
+    ColumnColor => "COLOR",
+    ColumnMethods => "METHODS",
+    ColumnReachable => "REACHABLE",
+);
+
+/// Text of `message` in the catalog, with the `{}` replaced by `values`.
+pub fn text(message: Message, values: &[&dyn Display]) -> String {
+    let template = catalog()
+        .get(message.name())
+        .map_or(message.english(), String::as_str);
+    let mut values = values.iter();
+    let mut parts = template.split("{}");
+    let mut text = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        if let Some(value) = values.next() {
+            text.push_str(&value.to_string());
+        }
+        text.push_str(part);
+    }
+    text
+}
+
+/// Text of a [Message] by name, with the values of its `{}`.
+macro_rules! msg {
+    ($name:ident $(, $value:expr)* $(,)?) => {
+        $crate::messages::text($crate::messages::Message::$name, &[$(&$value),*])
+    };
+}
+pub(crate) use msg;
+
+/// Catalog embedded at build time (empty if none was given).
+const EMBEDDED: &str = include_str!(concat!(env!("OUT_DIR"), "/messages.yaml"));
+
+fn catalog() -> &'static HashMap<String, String> {
+    static CATALOG: OnceLock<HashMap<String, String>> = OnceLock::new();
+    CATALOG.get_or_init(|| {
+        let (source, catalog) = match std::env::var("YEELIGHT_MESSAGES") {
+            Ok(path) => {
+                let catalog = std::fs::read_to_string(&path).map_err(|e| e.to_string());
+                (path, catalog)
+            }
+            Err(_) if EMBEDDED.trim().is_empty() => return HashMap::new(),
+            Err(_) => ("the embedded catalog".to_string(), Ok(EMBEDDED.to_string())),
+        };
+        let catalog =
+            catalog.and_then(|content| serde_yaml::from_str(&content).map_err(|e| e.to_string()));
+        catalog.unwrap_or_else(|e| {
+            eprintln!("Could not read the messages from {}: {}", source, e);
+            HashMap::new()
+        })
+    })
+}
@@ -0,0 +1,145 @@
+use std::net::{IpAddr, SocketAddr};
+
+use structopt::{clap::AppSettings, StructOpt};
+use tokio::net::TcpListener;
+use yeelight::simulator::Simulator;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "yeelight-simulator",
+    about = "A simulated Yeelight bulb for local development."
+)]
+#[structopt(global_setting = AppSettings::ColoredHelp)]
+struct Options {
+    #[structopt(
+        short,
+        long,
+        default_value = "127.0.0.1",
+        help = "Address to listen on (and announce in discovery)"
+    )]
+    address: IpAddr,
+    #[structopt(short, long, default_value = "55443")]
+    port: u16,
+    #[structopt(short, long, default_value = "color", help = "Model to simulate")]
+    model: String,
+    #[structopt(long, help = "Do not answer discovery requests")]
+    no_discovery: bool,
+}
+
+#[tokio::main]
+async fn main() {
+    let opt = Options::from_args();
+    let addr = SocketAddr::new(opt.address, opt.port);
+
+    let listener = TcpListener::bind(addr).await.unwrap_or_else(|e| {
+        eprintln!("Could not listen on {}: {}", addr, e);
+        std::process::exit(1);
+    });
+    let simulator = Simulator::new(&opt.model);
+    eprintln!("Simulating a '{}' bulb on {}", opt.model, addr);
+
+    if !opt.no_discovery {
+        let simulator = simulator.clone();
+        tokio::spawn(async move {
+            if let Err(e) = simulator.serve_discovery(addr).await {
+                eprintln!("Discovery disabled: {}", e);
+            }
+        });
+    }
+
+    let mut changes = simulator.subscribe();
+    let display = simulator.clone();
+    tokio::spawn(async move {
+        render(&display.state());
+        loop {
+            match changes.recv().await {
+                Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
+                    render(&display.state())
+                }
+                Err(_) => return,
+            }
+        }
+    });
+
+    if let Err(e) = simulator.serve(listener).await {
+        eprintln!("{}", e);
+    }
+}
+
+/// Print the state of the lights with a color swatch.
+fn render(state: &std::collections::BTreeMap<String, String>) {
+    let mut line = String::new();
+    for prefix in ["", "bg_"] {
+        let get = |key: &str| {
+            state
+                .get(&format!("{}{}", prefix, key))
+                .map(String::as_str)
+                .unwrap_or_default()
+        };
+        let power = match state.get(&format!("{}power", prefix)) {
+            Some(power) => power,
+            None => continue,
+        };
+        let mode = if prefix.is_empty() {
+            get("color_mode")
+        } else {
+            get("lmode")
+        };
+        let number = |key: &str| get(key).parse::<u32>().unwrap_or_default();
+
+        let (rgb, description) = match mode {
+            "1" => (number("rgb"), format!("rgb #{:06x}", number("rgb"))),
+            "3" => (
+                hsv_to_rgb(number("hue"), number("sat")),
+                format!("hue {} sat {}", number("hue"), number("sat")),
+            ),
+            _ => (ct_to_rgb(number("ct")), format!("ct {}K", number("ct"))),
+        };
+        let swatch = if power == "on" {
+            format!(
+                "\x1b[48;2;{};{};{}m    \x1b[0m",
+                rgb >> 16,
+                (rgb >> 8) & 0xff,
+                rgb & 0xff
+            )
+        } else {
+            "    ".to_string()
+        };
+        let flowing = if get("flowing") == "1" { " (flow)" } else { "" };
+
+        line.push_str(&format!(
+            "{}{} {:3} {:3}% {}{}  ",
+            if prefix.is_empty() { "main " } else { "bg " },
+            swatch,
+            power,
+            get("bright"),
+            description,
+            flowing
+        ));
+    }
+    println!("{}", line.trim_end());
+}
+
+fn hsv_to_rgb(hue: u32, sat: u32) -> u32 {
+    let (hue, sat) = ((hue % 360) as f64, sat.min(100) as f64 / 100.0);
+    let x = sat * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (sat, x, 0.0),
+        1 => (x, sat, 0.0),
+        2 => (0.0, sat, x),
+        3 => (0.0, x, sat),
+        4 => (x, 0.0, sat),
+        _ => (sat, 0.0, x),
+    };
+    let byte = |v: f64| ((v + 1.0 - sat) * 255.0).round() as u32;
+    (byte(r) << 16) | (byte(g) << 8) | byte(b)
+}
+
+/// Rough color of a black body at the given temperature.
+fn ct_to_rgb(ct: u32) -> u32 {
+    let t = ct.clamp(1700, 6500) as f64;
+    let blue = (t - 1700.0) / (6500.0 - 1700.0);
+    let green = 0.6 + 0.4 * blue;
+    let byte = |v: f64| (v * 255.0).round() as u32;
+    (255 << 16) | (byte(green) << 8) | byte(blue)
+}
@@ -0,0 +1,345 @@
+//! Share the connections to the bulbs between processes through a Unix domain socket.
+//!
+//! Short-lived programs (such as the CLI) opening a new connection for every command use up
+//! the connection and command quotas of the bulbs. A [Broker] keeps one connection per bulb
+//! open and forwards the requests of its clients through it, rewriting the request ids so
+//! clients do not interfere with each other. Notifications are sent to every client of the
+//! bulb.
+//!
+//! With [Broker::dedup], commands that would not change the state requested by the previous
+//! commands (for instance, several clients turning on the same light) are answered by the
+//! broker without being sent, saving the command quota of the bulb. Clients connected with
+//! [connect_forced] always have their commands sent.
+//!
+//! # Example
+//! ```
+//! # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+//! use yeelight::broker::{self, Broker};
+//!
+//! // In the daemon
+//! tokio::spawn(Broker::new().serve(broker::default_socket()));
+//!
+//! // In the clients, falling back to a direct connection
+//! let mut bulb = match broker::connect(broker::default_socket(), "192.168.1.204", 0).await {
+//!     Ok(bulb) => bulb,
+//!     Err(_) => yeelight::Bulb::connect("192.168.1.204", 0).await?,
+//! };
+//! bulb.toggle().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+use crate::{shutdown, Bulb, ConnectOptions, Journal};
+
+/// Socket used when no other is given: `$XDG_RUNTIME_DIR/yeelight.sock`, or
+/// `yeelight-$USER.sock` in the temporary directory.
+pub fn default_socket() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("yeelight.sock"),
+        None => {
+            let user = std::env::var("USER").unwrap_or_default();
+            std::env::temp_dir().join(format!("yeelight-{}.sock", user))
+        }
+    }
+}
+
+/// Connect to a bulb through the broker listening on `socket`.
+///
+/// Fails if no broker is running (use a direct connection in that case) or if the broker
+/// can not connect to the bulb. If `port` is 0, the default value (55443) is used.
+pub async fn connect(socket: impl AsRef<Path>, addr: &str, port: u16) -> io::Result<Bulb> {
+    handshake(socket.as_ref(), addr, port, false).await
+}
+
+/// Like [connect], but the broker sends all the commands of this client to the bulb, even if
+/// they are deduplicated for the other clients (see [Broker::dedup]).
+pub async fn connect_forced(socket: impl AsRef<Path>, addr: &str, port: u16) -> io::Result<Bulb> {
+    handshake(socket.as_ref(), addr, port, true).await
+}
+
+async fn handshake(socket: &Path, addr: &str, port: u16, force: bool) -> io::Result<Bulb> {
+    let port = if port == 0 { 55443 } else { port };
+    let mut stream = UnixStream::connect(socket).await?;
+
+    let handshake = json!({ "connect": format!("{}:{}", addr, port), "force": force });
+    stream
+        .write_all(format!("{}\r\n", handshake).as_bytes())
+        .await?;
+    let reply: Value = serde_json::from_str(&read_line(&mut stream).await?)?;
+    if let Some(error) = reply.get("error").and_then(Value::as_str) {
+        return Err(io::Error::other(error.to_string()));
+    }
+
+    Ok(Bulb::attach_io(stream))
+}
+
+/// Read a line byte by byte, so nothing after it is consumed.
+async fn read_line(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<String> {
+    let mut line = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            b'\n' => return Ok(String::from_utf8_lossy(&line).trim().to_string()),
+            byte => line.push(byte),
+        }
+    }
+}
+
+/// Owner of the shared bulb connections, see the [module documentation](self).
+#[derive(Debug, Clone, Default)]
+pub struct Broker {
+    options: ConnectOptions,
+    bulbs: Arc<tokio::sync::Mutex<HashMap<String, Arc<Upstream>>>>,
+    next_id: Arc<AtomicU64>,
+    /// Desired state of the bulbs, by address, if the commands are deduplicated.
+    journal: Option<Journal>,
+}
+
+/// Connection to a bulb shared by the clients.
+#[derive(Debug)]
+struct Upstream {
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    /// Client and original id of the requests waiting for a response, by forwarded id.
+    pending: Mutex<HashMap<u64, (Client, Value)>>,
+    clients: Mutex<Vec<Client>>,
+    closed: tokio::sync::watch::Receiver<bool>,
+    addr: String,
+    journal: Option<Journal>,
+}
+
+type Client = mpsc::UnboundedSender<String>;
+
+impl Broker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect to the bulbs with the given options (such as a proxy).
+    pub fn options(mut self, options: ConnectOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Answer the commands that would not change the state requested by the previous ones
+    /// (sent by any client) without sending them to the bulb.
+    ///
+    /// The requested state is forgotten when the bulb notifies a different one (it was
+    /// changed by other means), a command fails or the connection is lost.
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.journal = dedup.then(Journal::new);
+        self
+    }
+
+    /// Listen on `socket` and serve the clients.
+    ///
+    /// A socket file left by a broker that is no longer running is replaced. Fails with
+    /// [AddrInUse](io::ErrorKind::AddrInUse) if another broker is listening on it.
+    pub async fn serve(self, socket: impl AsRef<Path>) -> io::Result<()> {
+        let socket = socket.as_ref();
+        if UnixStream::connect(socket).await.is_ok() {
+            let message = format!("A broker is already listening on {}", socket.display());
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, message));
+        }
+        let _ = std::fs::remove_file(socket);
+        self.serve_listener(UnixListener::bind(socket)?).await
+    }
+
+    /// Serve the clients accepted by `listener`.
+    pub async fn serve_listener(self, listener: UnixListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let broker = self.clone();
+            spawn(async move {
+                if let Err(e) = broker.client(stream).await {
+                    log::debug!("Broker client error: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn client(self, stream: UnixStream) -> io::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+
+        let handshake: Value = match lines.next_line().await? {
+            Some(line) => serde_json::from_str(&line)?,
+            None => return Ok(()),
+        };
+        let addr = handshake
+            .get("connect")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let force = handshake.get("force").and_then(Value::as_bool) == Some(true);
+        let upstream = match self.upstream(addr).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                let reply = json!({ "error": e.to_string() });
+                return write.write_all(format!("{}\r\n", reply).as_bytes()).await;
+            }
+        };
+        write.write_all(b"{\"ok\":true}\r\n").await?;
+
+        let (client, mut responses) = mpsc::unbounded_channel::<String>();
+        lock(&upstream.clients).push(client.clone());
+        spawn(async move {
+            while let Some(line) = responses.recv().await {
+                if write.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            let Ok(mut request) = serde_json::from_str::<Value>(&line) else {
+                continue;
+            };
+            let original = request.get("id").cloned().unwrap_or(Value::Null);
+            if let Some(journal) = &upstream.journal {
+                let (method, params) = command(&request);
+                if !force && journal.is_current(&upstream.addr, &method, &params) {
+                    log::debug!("Broker skipped duplicate {} to {}", method, upstream.addr);
+                    let response = json!({ "id": original, "result": ["ok"] });
+                    let _ = client.send(format!("{}\r\n", response));
+                    continue;
+                }
+                journal.record(&upstream.addr, &method, &params);
+            }
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+            request["id"] = id.into();
+
+            lock(&upstream.pending).insert(id, (client.clone(), original.clone()));
+            let sent = upstream
+                .writer
+                .lock()
+                .await
+                .write_all(format!("{}\r\n", request).as_bytes())
+                .await;
+            if let Err(e) = sent {
+                lock(&upstream.pending).remove(&id);
+                let _ = client.send(error_response(&original, &e.to_string()));
+            }
+        }
+
+        // Stops the writer once the pending responses are sent
+        lock(&upstream.clients).retain(|c| !c.same_channel(&client));
+        Ok(())
+    }
+
+    /// Connection to the bulb at `addr`, opening it if there is none or it was closed.
+    async fn upstream(&self, addr: &str) -> io::Result<Arc<Upstream>> {
+        let mut bulbs = self.bulbs.lock().await;
+        if let Some(upstream) = bulbs.get(addr).filter(|u| !*u.closed.borrow()) {
+            return Ok(upstream.clone());
+        }
+
+        let (host, port) = match addr.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(io::Error::other)?),
+            None => (addr, 55443),
+        };
+        let stream = self.options.connect(host, port).await?;
+        let (read, writer) = stream.into_split();
+        let (closed_sender, closed) = tokio::sync::watch::channel(false);
+        let upstream = Arc::new(Upstream {
+            writer: tokio::sync::Mutex::new(writer),
+            pending: Mutex::default(),
+            clients: Mutex::default(),
+            closed,
+            addr: addr.to_string(),
+            journal: self.journal.clone(),
+        });
+
+        let relay = upstream.clone();
+        spawn(async move {
+            let mut lines = BufReader::new(read).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                relay.dispatch(&line);
+            }
+            closed_sender.send_replace(true);
+            if let Some(journal) = &relay.journal {
+                journal.clear(&relay.addr);
+            }
+            for (_, (client, id)) in lock(&relay.pending).drain() {
+                let _ = client.send(error_response(&id, "Connection to the bulb lost"));
+            }
+        });
+
+        bulbs.insert(addr.to_string(), upstream.clone());
+        Ok(upstream)
+    }
+}
+
+/// Spawn a task of the broker, tracked as a connection by the current
+/// [Coordinator](shutdown::Coordinator) and stopped when the connections have to close.
+fn spawn<F>(future: F)
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    shutdown::spawn_connection(async move {
+        tokio::select! {
+            _ = future => {}
+            _ = shutdown::closing() => {}
+        }
+    });
+}
+
+impl Upstream {
+    /// Send a message of the bulb to the client that made the request, or to all the clients
+    /// if it is a notification.
+    fn dispatch(&self, line: &str) {
+        let Ok(mut message) = serde_json::from_str::<Value>(line) else {
+            return;
+        };
+        match message.get("id").and_then(Value::as_u64) {
+            Some(id) => {
+                if let (Some(journal), Some(_)) = (&self.journal, message.get("error")) {
+                    journal.clear(&self.addr);
+                }
+                if let Some((client, original)) = lock(&self.pending).remove(&id) {
+                    message["id"] = original;
+                    let _ = client.send(format!("{}\r\n", message));
+                }
+            }
+            None => {
+                let props = message.get("params").and_then(Value::as_object);
+                if let (Some(journal), Some(props)) = (&self.journal, props) {
+                    journal.observe(&self.addr, props);
+                }
+                lock(&self.clients)
+                    .retain(|client| client.send(format!("{}\r\n", line.trim())).is_ok());
+            }
+        }
+    }
+}
+
+/// Method and params of a request.
+fn command(request: &Value) -> (String, Vec<Value>) {
+    let method = request
+        .get("method")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let params = match request.get("params") {
+        Some(Value::Array(params)) => params.clone(),
+        _ => Vec::new(),
+    };
+    (method.to_string(), params)
+}
+
+fn error_response(id: &Value, message: &str) -> String {
+    let response = json!({ "id": id, "error": { "code": -1, "message": message } });
+    format!("{}\r\n", response)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
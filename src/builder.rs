@@ -0,0 +1,404 @@
+//! Builders for the methods with many parameters.
+
+use std::io;
+use std::time::Duration;
+
+use crate::{
+    Bulb, BulbError, CfAction, ConnectError, ConnectOptions, Effect, FlowExpresion, Mode, Power,
+    Proxy, Response,
+};
+
+/// Transition of a change: sudden by default.
+#[derive(Debug, Clone, Copy)]
+struct Transition {
+    effect: Effect,
+    duration: Duration,
+}
+
+impl Default for Transition {
+    fn default() -> Self {
+        Self {
+            effect: Effect::Sudden,
+            duration: Duration::ZERO,
+        }
+    }
+}
+
+/// Builder of [`Bulb::set_power`], see [`Bulb::power`].
+#[must_use = "the command is only sent with send()"]
+pub struct PowerCommand<'a> {
+    bulb: &'a mut Bulb,
+    power: Power,
+    transition: Transition,
+    mode: Mode,
+    bg: bool,
+}
+
+impl PowerCommand<'_> {
+    /// Turn the light on (the default).
+    pub fn on(mut self) -> Self {
+        self.power = Power::On;
+        self
+    }
+
+    /// Turn the light off.
+    pub fn off(mut self) -> Self {
+        self.power = Power::Off;
+        self
+    }
+
+    /// Change gradually over `duration`.
+    pub fn smooth(mut self, duration: Duration) -> Self {
+        self.transition = Transition {
+            effect: Effect::Smooth,
+            duration,
+        };
+        self
+    }
+
+    /// Change immediately (the default).
+    pub fn sudden(mut self) -> Self {
+        self.transition = Transition::default();
+        self
+    }
+
+    /// Mode to turn on in ([Mode::Normal] by default, which keeps the current mode).
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Send the command to the background light.
+    pub fn bg(mut self) -> Self {
+        self.bg = true;
+        self
+    }
+
+    pub async fn send(self) -> Result<Option<Response>, BulbError> {
+        let Transition { effect, duration } = self.transition;
+        match self.bg {
+            true => {
+                self.bulb
+                    .bg_set_power(self.power, effect, duration, self.mode)
+                    .await
+            }
+            false => {
+                self.bulb
+                    .set_power(self.power, effect, duration, self.mode)
+                    .await
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Change {
+    Bright(u8),
+    Ct(u16),
+    Rgb(u32),
+    Hsv(u16, u8),
+}
+
+/// Builder of [`Bulb::set_bright`], [`Bulb::set_ct_abx`], [`Bulb::set_rgb`] and
+/// [`Bulb::set_hsv`], see [`Bulb::bright`].
+#[must_use = "the command is only sent with send()"]
+pub struct ChangeCommand<'a> {
+    bulb: &'a mut Bulb,
+    change: Change,
+    transition: Transition,
+    bg: bool,
+}
+
+impl ChangeCommand<'_> {
+    /// Change gradually over `duration`.
+    pub fn smooth(mut self, duration: Duration) -> Self {
+        self.transition = Transition {
+            effect: Effect::Smooth,
+            duration,
+        };
+        self
+    }
+
+    /// Change immediately (the default).
+    pub fn sudden(mut self) -> Self {
+        self.transition = Transition::default();
+        self
+    }
+
+    /// Send the command to the background light.
+    pub fn bg(mut self) -> Self {
+        self.bg = true;
+        self
+    }
+
+    pub async fn send(self) -> Result<Option<Response>, BulbError> {
+        let Transition { effect, duration } = self.transition;
+        let bulb = self.bulb;
+        match (self.change, self.bg) {
+            (Change::Bright(bright), false) => bulb.set_bright(bright, effect, duration).await,
+            (Change::Bright(bright), true) => bulb.bg_set_bright(bright, effect, duration).await,
+            (Change::Ct(ct), false) => bulb.set_ct_abx(ct, effect, duration).await,
+            (Change::Ct(ct), true) => bulb.bg_set_ct_abx(ct, effect, duration).await,
+            (Change::Rgb(rgb), false) => bulb.set_rgb(rgb, effect, duration).await,
+            (Change::Rgb(rgb), true) => bulb.bg_set_rgb(rgb, effect, duration).await,
+            (Change::Hsv(hue, sat), false) => bulb.set_hsv(hue, sat, effect, duration).await,
+            (Change::Hsv(hue, sat), true) => bulb.bg_set_hsv(hue, sat, effect, duration).await,
+        }
+    }
+}
+
+/// Builder of [`Bulb::start_cf`], see [`Bulb::flow`].
+#[must_use = "the command is only sent with send()"]
+pub struct FlowCommand<'a> {
+    bulb: &'a mut Bulb,
+    flow: FlowExpresion,
+    count: u8,
+    action: CfAction,
+    bg: bool,
+}
+
+impl FlowCommand<'_> {
+    /// Number of state changes before the flow stops (`0`, the default, loops forever).
+    pub fn count(mut self, count: u8) -> Self {
+        self.count = count;
+        self
+    }
+
+    /// What to do when the flow stops ([CfAction::Recover] by default).
+    pub fn action(mut self, action: CfAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Send the command to the background light.
+    pub fn bg(mut self) -> Self {
+        self.bg = true;
+        self
+    }
+
+    pub async fn send(self) -> Result<Option<Response>, BulbError> {
+        match self.bg {
+            true => {
+                self.bulb
+                    .bg_start_cf(self.count, self.action, self.flow)
+                    .await
+            }
+            false => self.bulb.start_cf(self.count, self.action, self.flow).await,
+        }
+    }
+}
+
+/// Builder of a [Bulb] connection, see [`Bulb::builder`].
+#[derive(Debug, Clone)]
+#[must_use = "the bulb is only connected with connect()"]
+pub struct BulbBuilder {
+    addr: Option<String>,
+    port: u16,
+    options: ConnectOptions,
+    response_timeout: Option<Duration>,
+    no_response: bool,
+    notify_buffer: usize,
+}
+
+impl BulbBuilder {
+    /// Address (IP or host name) of the bulb, required.
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addr = Some(addr.into());
+        self
+    }
+
+    /// Port of the bulb (55443 by default).
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// See [ConnectOptions::connect_timeout].
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.options = self.options.connect_timeout(timeout);
+        self
+    }
+
+    /// See [Bulb::set_timeout].
+    pub fn response_timeout(mut self, timeout: Duration) -> Self {
+        self.response_timeout = Some(timeout);
+        self
+    }
+
+    /// See [Bulb::no_response].
+    pub fn no_response(mut self) -> Self {
+        self.no_response = true;
+        self
+    }
+
+    /// See [SocketOptions::nodelay](crate::SocketOptions::nodelay).
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.options.socket = self.options.socket.nodelay(nodelay);
+        self
+    }
+
+    /// Capacity of the channels created by [Bulb::get_notify] (10 by default).
+    pub fn notify_buffer(mut self, capacity: usize) -> Self {
+        self.notify_buffer = capacity.max(1);
+        self
+    }
+
+    /// See [ConnectOptions::proxy].
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.options = self.options.proxy(proxy);
+        self
+    }
+
+    /// See [ConnectOptions::reconnect].
+    pub fn reconnect(mut self, reconnect: bool) -> Self {
+        self.options = self.options.reconnect(reconnect);
+        self
+    }
+
+    /// Use these [ConnectOptions] (replacing the ones set with the other methods), for the
+    /// options without a method of their own.
+    pub fn options(mut self, options: ConnectOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Connect to the bulb (see [Bulb::connect_with]).
+    ///
+    /// Fails with [ConnectError::Resolve] if no [address](BulbBuilder::addr) was set.
+    pub async fn connect(self) -> Result<Bulb, ConnectError> {
+        let Some(addr) = &self.addr else {
+            let error = io::Error::new(io::ErrorKind::InvalidInput, "No address set");
+            return Err(ConnectError::Resolve(error));
+        };
+        let mut bulb = Bulb::connect_with(addr, self.port, &self.options).await?;
+        bulb.set_timeout(self.response_timeout);
+        bulb.notify_buffer = self.notify_buffer;
+        Ok(match self.no_response {
+            true => bulb.no_response(),
+            false => bulb,
+        })
+    }
+}
+
+impl Bulb {
+    /// Configure a connection to a bulb.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::Bulb;
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::builder()
+    ///     .addr("192.168.1.204")
+    ///     .connect_timeout(Duration::from_secs(2))
+    ///     .response_timeout(Duration::from_secs(1))
+    ///     .tcp_nodelay(true)
+    ///     .notify_buffer(100)
+    ///     .connect()
+    ///     .await
+    ///     .unwrap();
+    /// let notifications = bulb.get_notify().await;
+    /// # }
+    /// ```
+    pub fn builder() -> BulbBuilder {
+        BulbBuilder {
+            addr: None,
+            port: 0,
+            options: ConnectOptions::default(),
+            response_timeout: None,
+            no_response: false,
+            notify_buffer: 10,
+        }
+    }
+
+    /// Build a [Bulb::set_power] command, naming only the parameters that are not the default.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// bulb.power()
+    ///     .on()
+    ///     .smooth(Duration::from_millis(500))
+    ///     .mode(Mode::Ct)
+    ///     .send()
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub fn power(&mut self) -> PowerCommand<'_> {
+        PowerCommand {
+            bulb: self,
+            power: Power::On,
+            transition: Transition::default(),
+            mode: Mode::Normal,
+            bg: false,
+        }
+    }
+
+    /// Build a [Bulb::set_bright] command.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// bulb.bright(30).smooth(Duration::from_secs(1)).bg().send().await.unwrap();
+    /// # }
+    /// ```
+    pub fn bright(&mut self, bright: u8) -> ChangeCommand<'_> {
+        self.change(Change::Bright(bright))
+    }
+
+    /// Build a [Bulb::set_ct_abx] command, see [Bulb::bright].
+    pub fn ct(&mut self, ct: u16) -> ChangeCommand<'_> {
+        self.change(Change::Ct(ct))
+    }
+
+    /// Build a [Bulb::set_rgb] command, see [Bulb::bright].
+    pub fn rgb(&mut self, rgb: u32) -> ChangeCommand<'_> {
+        self.change(Change::Rgb(rgb))
+    }
+
+    /// Build a [Bulb::set_hsv] command, see [Bulb::bright].
+    pub fn hsv(&mut self, hue: u16, sat: u8) -> ChangeCommand<'_> {
+        self.change(Change::Hsv(hue, sat))
+    }
+
+    /// Build a [Bulb::start_cf] command.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// let flow = FlowExpresion(vec![
+    ///     FlowTuple::rgb(Duration::from_secs(1), 0xff_00_00, 100),
+    ///     FlowTuple::rgb(Duration::from_secs(1), 0x00_00_ff, 100),
+    /// ]);
+    /// bulb.flow(flow).count(10).action(CfAction::Stay).send().await.unwrap();
+    /// # }
+    /// ```
+    pub fn flow(&mut self, flow: FlowExpresion) -> FlowCommand<'_> {
+        FlowCommand {
+            bulb: self,
+            flow,
+            count: 0,
+            action: CfAction::Recover,
+            bg: false,
+        }
+    }
+
+    fn change(&mut self, change: Change) -> ChangeCommand<'_> {
+        ChangeCommand {
+            bulb: self,
+            change,
+            transition: Transition::default(),
+            bg: false,
+        }
+    }
+}
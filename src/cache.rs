@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+use crate::state::{property_name, StateSnapshot};
+use crate::Property;
+
+/// Names used by some firmwares in the notifications instead of the property names, with the
+/// property they are stored as.
+///
+/// Ceiling lights with a background light notify `main_power` (the main light only, while
+/// `power` may follow both lights) and `bg_color_mode` (`bg_lmode` in `get_prop`).
+const DEFAULT_ALIASES: [(&str, Property); 2] = [
+    ("main_power", Property::Power),
+    ("bg_color_mode", Property::BgColorMode),
+];
+
+/// How long the cached values of the properties can answer [Bulb::get_prop] without asking
+/// the bulb, see [Bulb::freshness].
+///
+/// The default policy never answers from the cache.
+///
+/// # Example
+/// ```
+/// # use yeelight::*;
+/// use std::time::Duration;
+///
+/// // Trust the values for a minute, but always read the flow state
+/// let policy = Freshness::max_age(Duration::from_secs(60)).property(Property::Flowing, Duration::ZERO);
+/// ```
+///
+/// [Bulb::get_prop]: crate::Bulb::get_prop
+/// [Bulb::freshness]: crate::Bulb::freshness
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Freshness {
+    /// Max age of the properties without their own.
+    max_age: Duration,
+    /// Max age of specific properties, by name.
+    properties: HashMap<String, Duration>,
+}
+
+impl Freshness {
+    /// Answer from the values received (in notifications or responses) in the last `max_age`.
+    pub fn max_age(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            properties: HashMap::new(),
+        }
+    }
+
+    /// Use a different max age for `property` (`Duration::ZERO` to always ask the bulb).
+    pub fn property(mut self, property: Property, max_age: Duration) -> Self {
+        self.properties.insert(property_name(property), max_age);
+        self
+    }
+
+    fn max_age_of(&self, name: &str) -> Duration {
+        self.properties.get(name).copied().unwrap_or(self.max_age)
+    }
+}
+
+/// Last known values of the bulb properties, updated from notifications and `get_prop`
+/// responses.
+#[derive(Debug)]
+pub struct StateCache {
+    state: watch::Sender<StateSnapshot>,
+    last_notification: Mutex<Option<Instant>>,
+    /// Property each notified name is stored as, see [DEFAULT_ALIASES].
+    aliases: Mutex<HashMap<String, String>>,
+    /// Time each property was last received.
+    received: Mutex<HashMap<String, Instant>>,
+    freshness: Mutex<Freshness>,
+}
+
+pub type SharedCache = Arc<StateCache>;
+
+impl StateCache {
+    pub fn new() -> SharedCache {
+        Arc::new(Self {
+            state: watch::Sender::new(StateSnapshot::default()),
+            last_notification: Mutex::new(None),
+            aliases: Mutex::new(
+                DEFAULT_ALIASES
+                    .iter()
+                    .map(|(name, property)| (name.to_string(), property_name(*property)))
+                    .collect(),
+            ),
+            received: Mutex::default(),
+            freshness: Mutex::default(),
+        })
+    }
+
+    pub fn watch(&self) -> watch::Receiver<StateSnapshot> {
+        self.state.subscribe()
+    }
+
+    pub fn state(&self) -> StateSnapshot {
+        self.state.borrow().clone()
+    }
+
+    /// Time of the last notification received.
+    pub fn last_notification(&self) -> Option<Instant> {
+        *self
+            .last_notification
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Store the values notified as `name` as the property `property`.
+    pub fn alias(&self, name: &str, property: Property) {
+        self.lock_aliases()
+            .insert(name.to_string(), property_name(property));
+    }
+
+    /// Update with the params of a `props` notification.
+    pub fn notification(&self, params: &serde_json::Map<String, serde_json::Value>) {
+        *self
+            .last_notification
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+
+        let aliases = self.lock_aliases().clone();
+        let values = params.iter().map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            match aliases.get(key) {
+                Some(property) => (property.clone(), value, true),
+                None => (key.clone(), value, false),
+            }
+        });
+        // Aliases are more specific (main_power over power), so they are stored last
+        let (aliased, direct): (Vec<_>, Vec<_>) = values.partition(|(_, _, aliased)| *aliased);
+        self.update(
+            direct
+                .into_iter()
+                .chain(aliased)
+                .map(|(key, value, _)| (key, value)),
+        );
+    }
+
+    fn lock_aliases(&self) -> std::sync::MutexGuard<'_, HashMap<String, String>> {
+        self.aliases.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Update with the result of a `get_prop` request.
+    pub fn properties(&self, properties: &[Property], values: &[String]) {
+        let names: Vec<String> = properties.iter().map(|p| property_name(*p)).collect();
+        self.properties_by_name(&names, values);
+    }
+
+    /// Update with the result of a `get_prop` request, with the properties given by name.
+    pub fn properties_by_name(&self, names: &[String], values: &[String]) {
+        self.update(
+            names
+                .iter()
+                .zip(values)
+                .filter(|(_, value)| !value.is_empty())
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+    }
+
+    pub fn set_freshness(&self, freshness: Freshness) {
+        *self.freshness.lock().unwrap_or_else(|e| e.into_inner()) = freshness;
+    }
+
+    /// Values of the properties received recently enough according to the [Freshness]
+    /// policy, `None` for the ones that have to be read.
+    pub fn fresh(&self, properties: &[Property]) -> Vec<Option<String>> {
+        let freshness = self.freshness.lock().unwrap_or_else(|e| e.into_inner());
+        let received = self.lock_received();
+        let state = self.state.borrow();
+        properties
+            .iter()
+            .map(|property| {
+                let name = property_name(*property);
+                let age = received.get(&name)?.elapsed();
+                (age < freshness.max_age_of(&name))
+                    .then(|| state.0.get(&name).cloned())
+                    .flatten()
+            })
+            .collect()
+    }
+
+    /// Consider all the values stale (they may have changed while disconnected).
+    pub fn expire(&self) {
+        self.lock_received().clear();
+    }
+
+    /// Consider stale the values of the properties that `method` may change, until they are
+    /// received again.
+    pub fn sent(&self, method: &str) {
+        let mut received = self.lock_received();
+        match method {
+            "get_prop" | "cron_get" | "set_default" | "bg_set_default" => {}
+            "set_name" => drop(received.remove("name")),
+            "cron_add" | "cron_del" => drop(received.remove("delayoff")),
+            "set_music" => drop(received.remove("music_on")),
+            "dev_toggle" => received.clear(),
+            method if method.starts_with("bg_") => {
+                received.retain(|name, _| !name.starts_with("bg_"))
+            }
+            _ => received.retain(|name, _| name.starts_with("bg_")),
+        }
+    }
+
+    fn lock_received(&self) -> std::sync::MutexGuard<'_, HashMap<String, Instant>> {
+        self.received.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update(&self, values: impl Iterator<Item = (String, String)>) {
+        let now = Instant::now();
+        let mut received = self.lock_received();
+        self.state.send_if_modified(|state| {
+            let mut modified = false;
+            for (key, value) in values {
+                received.insert(key.clone(), now);
+                if state.0.get(&key) != Some(&value) {
+                    state.0.insert(key, value);
+                    modified = true;
+                }
+            }
+            modified
+        });
+    }
+}
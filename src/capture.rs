@@ -0,0 +1,121 @@
+//! Capture the commands sent by a [`Bulb`] to test application logic without a real bulb.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::protocol::Request;
+use crate::{shutdown, Bulb, Response};
+
+/// Handle to inspect the requests sent by a bulb created with [`Bulb::with_capture`].
+#[derive(Debug, Clone, Default)]
+pub struct Capture(Arc<Mutex<Inner>>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    requests: Vec<Request>,
+    responses: HashMap<String, Response>,
+}
+
+impl Capture {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Requests received so far.
+    pub fn requests(&self) -> Vec<Request> {
+        self.lock().requests.clone()
+    }
+
+    /// Requests received since the last call (or since the bulb was created).
+    pub fn take(&self) -> Vec<Request> {
+        std::mem::take(&mut self.lock().requests)
+    }
+
+    /// Methods of the requests received so far.
+    pub fn methods(&self) -> Vec<String> {
+        self.lock()
+            .requests
+            .iter()
+            .map(|r| r.method.clone())
+            .collect()
+    }
+
+    /// Answer the requests with the given method with `response`.
+    ///
+    /// By default all the requests are answered with `["ok"]`, except `get_prop` which gets
+    /// an empty string for each property.
+    pub fn respond(&self, method: &str, response: Response) {
+        self.lock().responses.insert(method.to_string(), response);
+    }
+
+    fn handle(&self, line: &str) -> Option<String> {
+        let request = match Request::parse(line) {
+            Ok(request) => request,
+            Err(e) => {
+                log::error!("Captured invalid request {}: {}", line, e);
+                return None;
+            }
+        };
+
+        let mut inner = self.lock();
+        let result = match inner.responses.get(&request.method) {
+            Some(response) => response.clone(),
+            None if request.method == "get_prop" => {
+                vec![String::new(); request.params.len()]
+            }
+            None => vec!["ok".to_string()],
+        };
+        let response = serde_json::json!({"id": request.id, "result": result});
+        inner.requests.push(request);
+        Some(format!("{}\r\n", response))
+    }
+}
+
+impl Bulb {
+    /// Create a bulb that records the requests instead of sending them over the network.
+    ///
+    /// Requests are answered as configured with [Capture::respond]. A request is recorded
+    /// before it is answered, so it can be inspected as soon as the method returns (in
+    /// [no_response](Bulb::no_response) mode there is no such guarantee).
+    ///
+    /// Must be called from a tokio runtime.
+    ///
+    /// # Example
+    /// ```
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// # use yeelight::*;
+    /// # use yeelight::protocol::Request;
+    /// # use std::time::Duration;
+    /// let (mut bulb, capture) = Bulb::with_capture();
+    ///
+    /// // Code under test
+    /// bulb.set_bright(30, Effect::Smooth, Duration::from_millis(500)).await.unwrap();
+    ///
+    /// let params = vec![30.into(), "smooth".into(), 500.into()];
+    /// assert_eq!(capture.take(), [Request::new(1, "set_bright", params)]);
+    /// # }
+    /// ```
+    pub fn with_capture() -> (Self, Capture) {
+        let (io, remote) = tokio::io::duplex(4096);
+        let capture = Capture::default();
+
+        let handler = capture.clone();
+        shutdown::spawn_connection(async move {
+            let (read, mut write) = tokio::io::split(remote);
+            let mut lines = BufReader::new(read).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let Some(response) = handler.handle(&line) else {
+                    continue;
+                };
+                if write.write_all(response.as_bytes()).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        (Self::attach_io(io), capture)
+    }
+}
@@ -0,0 +1,225 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::helpers::{hsv_to_rgb, rgb_to_hsv};
+use crate::{Bulb, BulbError, Effect, Properties, Property, Response};
+
+/// Color accepted by [`Bulb::set_color`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColorSpec {
+    /// RGB color (`0x00_00_00` to `0xff_ff_ff`).
+    Rgb(u32),
+    /// Hue (`0` to `359`) and saturation (`0` to `100`).
+    Hsv(u16, u8),
+    /// Color temperature in K.
+    Ct(u16),
+}
+
+/// What to do when a color is requested on a bulb that only supports color temperature.
+///
+/// The bulb [`Model`](crate::model::Model) must be known for the emulation to be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorPolicy {
+    /// Send the command as is (the bulb will answer with an error).
+    #[default]
+    Strict,
+    /// Set the closest color temperature and brightness instead.
+    EmulateWithCt,
+}
+
+impl Bulb {
+    /// Set the color of the light using the method matching the color specification
+    /// ([Bulb::set_rgb], [Bulb::set_hsv] or [Bulb::set_ct_abx]).
+    ///
+    /// If the bulb model has no color support and the [ColorPolicy] is
+    /// [ColorPolicy::EmulateWithCt], RGB and HSV colors are converted to the closest color
+    /// temperature and brightness.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap()
+    ///     .with_model("ct_bulb")
+    ///     .color_policy(ColorPolicy::EmulateWithCt);
+    /// // Sets a warm color temperature
+    /// bulb.set_color(ColorSpec::Rgb(0xff_a0_40), Effect::Smooth, Duration::from_millis(500))
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub async fn set_color(
+        &mut self,
+        color: ColorSpec,
+        effect: Effect,
+        duration: Duration,
+    ) -> Result<Option<Response>, BulbError> {
+        let emulate = self.color_policy == ColorPolicy::EmulateWithCt
+            && self.model.is_some_and(|model| !model.color);
+
+        let rgb = match color {
+            ColorSpec::Ct(ct) => return self.set_ct_abx(ct, effect, duration).await,
+            ColorSpec::Rgb(rgb) if !emulate => return self.set_rgb(rgb, effect, duration).await,
+            ColorSpec::Hsv(hue, sat) if !emulate => {
+                return self.set_hsv(hue, sat, effect, duration).await
+            }
+            ColorSpec::Rgb(rgb) => rgb,
+            ColorSpec::Hsv(hue, sat) => hsv_to_rgb(hue.into(), sat.into()),
+        };
+
+        let range = self.model.and_then(|model| model.ct_range);
+        let (ct, bright) = rgb_to_ct(rgb, range);
+
+        if range.is_some() {
+            self.set_ct_abx(ct, effect, duration).await?;
+        }
+        self.set_bright(bright, effect, duration).await
+    }
+}
+
+impl Bulb {
+    /// Rotate the hue of the light by `degrees` (negative values go backwards), keeping the
+    /// saturation.
+    ///
+    /// Unlike [AdjustAction::Circle](crate::AdjustAction::Circle), which jumps between preset
+    /// colors, the new hue is computed on the host and set with [Bulb::set_hsv], so repeated
+    /// steps cycle through the colors deterministically (useful for keybindings).
+    ///
+    /// The current color is taken from the [cached state](Bulb::cached_state), or read with
+    /// [Bulb::get_prop] if it is not known. The cache is updated with the new color, so steps
+    /// sent in a quick succession do not need to wait for the notifications of the bulb. RGB
+    /// colors are converted to hue and saturation.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// bulb.step_hue(30, Effect::Smooth, Duration::from_millis(300)).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn step_hue(
+        &mut self,
+        degrees: i16,
+        effect: Effect,
+        duration: Duration,
+    ) -> Result<Option<Response>, BulbError> {
+        self.step_hue_of(false, degrees, effect, duration).await
+    }
+
+    /// Rotate the hue of the **background** light by `degrees`.
+    ///
+    /// **See:** [Bulb::step_hue]
+    pub async fn bg_step_hue(
+        &mut self,
+        degrees: i16,
+        effect: Effect,
+        duration: Duration,
+    ) -> Result<Option<Response>, BulbError> {
+        self.step_hue_of(true, degrees, effect, duration).await
+    }
+
+    async fn step_hue_of(
+        &mut self,
+        bg: bool,
+        degrees: i16,
+        effect: Effect,
+        duration: Duration,
+    ) -> Result<Option<Response>, BulbError> {
+        let properties = match bg {
+            true => [
+                Property::BgColorMode,
+                Property::BgRgb,
+                Property::BgHue,
+                Property::BgSat,
+            ],
+            false => [
+                Property::ColorMode,
+                Property::Rgb,
+                Property::Hue,
+                Property::Sat,
+            ],
+        };
+        let (hue, sat) = match self.current_hsv(&properties).await? {
+            Some(hsv) => hsv,
+            None => {
+                let message = "The current color of the light is not known";
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message).into());
+            }
+        };
+
+        let hue = (hue as i32 + degrees as i32).rem_euclid(360) as u16;
+        let response = match bg {
+            true => self.bg_set_hsv(hue, sat, effect, duration).await?,
+            false => self.set_hsv(hue, sat, effect, duration).await?,
+        };
+        self.cache.properties(
+            &[properties[0], properties[2], properties[3]],
+            &["3".to_string(), hue.to_string(), sat.to_string()],
+        );
+        Ok(response)
+    }
+
+    /// Hue and saturation of the light from the cache, or read from the bulb.
+    ///
+    /// `properties` are the color mode, rgb, hue and saturation properties of the light.
+    async fn current_hsv(
+        &mut self,
+        properties: &[Property; 4],
+    ) -> Result<Option<(u16, u8)>, BulbError> {
+        let state = self.cached_state();
+        let mut values: Vec<String> = properties
+            .iter()
+            .map_while(|p| state.get(*p).map(str::to_string))
+            .collect();
+        if values.len() < properties.len() {
+            match self.get_prop(&Properties(properties.to_vec())).await? {
+                Some(response) => values = response,
+                None => return Ok(None),
+            }
+        }
+
+        let number = |i: usize| values.get(i).and_then(|v| v.parse::<u32>().ok());
+        Ok(match values.first().map(String::as_str) {
+            Some("1") => number(1).map(rgb_to_hsv),
+            _ => number(2)
+                .zip(number(3))
+                .map(|(hue, sat)| ((hue % 360) as u16, sat.min(100) as u8)),
+        })
+    }
+}
+
+/// Closest color temperature (clamped to `range`) and brightness for an RGB color.
+pub(crate) fn rgb_to_ct(rgb: u32, range: Option<(u16, u16)>) -> (u16, u8) {
+    let channel = |shift: u32| ((rgb >> shift) & 0xff) as f64 / 255.0;
+    let (r, g, b) = (channel(16), channel(8), channel(0));
+
+    let bright = (r.max(g).max(b) * 100.0).round().clamp(1.0, 100.0) as u8;
+
+    // sRGB -> linear -> CIE XYZ -> xy chromaticity -> CCT (McCamy's approximation)
+    let linear = |c: f64| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let (r, g, b) = (linear(r), linear(g), linear(b));
+    let x = 0.4124 * r + 0.3576 * g + 0.1805 * b;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = 0.0193 * r + 0.1192 * g + 0.9505 * b;
+
+    let (min, max) = range.unwrap_or((1700, 6500));
+    let sum = x + y + z;
+    if sum == 0.0 {
+        return (min, bright);
+    }
+
+    let n = (x / sum - 0.3320) / (0.1858 - y / sum);
+    let cct = 449.0 * n.powi(3) + 3525.0 * n.powi(2) + 6823.3 * n + 5520.33;
+
+    (cct.clamp(min as f64, max as f64).round() as u16, bright)
+}
@@ -0,0 +1,288 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AdjustAction, Bulb, BulbError, CfAction, CronType, Effect, FlowExpresion, Method, Mode,
+    MusicAction, Power, Prop, Properties, Response, Scene, ToParams,
+};
+
+// Generate the Command enum with the methods of the same name
+macro_rules! commands {
+    ($(
+        $(#[$comment:meta])*
+        $variant:ident $({ $( $(#[$attr:meta])* $field:ident : $t:ty ),* $(,)? })?
+    ),* $(,)?) => {
+        /// Message to a bulb, as a value that can be stored, queued, serialized and sent later
+        /// with [Bulb::send_command].
+        ///
+        /// There is a variant for each [Method], with the parameters of the method of [Bulb] of
+        /// the same name. Durations are serialized in milliseconds.
+        ///
+        /// # Example
+        /// ```
+        /// # async fn test() {
+        /// # use yeelight::*;
+        /// use std::time::Duration;
+        ///
+        /// let commands: Vec<Command> = serde_json::from_str(
+        ///     r#"[{"SetBright": {"brightness": 30, "effect": "Smooth", "duration": 500}}, "Toggle"]"#,
+        /// )
+        /// .unwrap();
+        /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+        /// for command in &commands {
+        ///     bulb.send_command(command).await.unwrap();
+        /// }
+        /// # }
+        /// ```
+        #[derive(Debug, Clone, Serialize, Deserialize)]
+        pub enum Command {
+            $(
+                $(#[$comment])*
+                $variant $({ $( $(#[$attr])* $field: $t ),* })?
+            ),*
+        }
+
+        impl Command {
+            /// Method sent by the command.
+            pub fn method(&self) -> Method {
+                match self {
+                    $(Command::$variant { .. } => Method::$variant),*
+                }
+            }
+
+            /// Parameters of the command as sent.
+            pub fn params(&self) -> Vec<serde_json::Value> {
+                match self {
+                    $(
+                        Command::$variant $({ $($field),* })? => {
+                            let params: Vec<Vec<serde_json::Value>> =
+                                vec![$($($field.to_params()),*)?];
+                            params.concat()
+                        }
+                    ),*
+                }
+            }
+        }
+    };
+}
+
+commands!(
+    /// See [Bulb::get_prop].
+    GetProp { properties: Properties },
+    /// See [Bulb::set_ct_abx].
+    SetCtAbx {
+        ct_value: u16,
+        effect: Effect,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::set_rgb].
+    SetRgb {
+        rgb_value: u32,
+        effect: Effect,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::set_wrgb].
+    SetWrgb {
+        wrgb_value: u32,
+        effect: Effect,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::set_hsv].
+    SetHsv {
+        hue: u16,
+        sat: u8,
+        effect: Effect,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::set_bright].
+    SetBright {
+        brightness: u8,
+        effect: Effect,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::set_power].
+    SetPower {
+        power: Power,
+        effect: Effect,
+        #[serde(with = "millis")]
+        duration: Duration,
+        mode: Mode,
+    },
+    /// See [Bulb::toggle].
+    Toggle,
+    /// See [Bulb::set_default].
+    SetDefault,
+    /// See [Bulb::start_cf].
+    StartCf {
+        count: u8,
+        action: CfAction,
+        flow_expression: FlowExpresion,
+    },
+    /// See [Bulb::stop_cf].
+    StopCf,
+    /// See [Bulb::set_scene].
+    SetScene { scene: Scene },
+    /// See [Bulb::cron_add].
+    CronAdd { cron_type: CronType, value: u64 },
+    /// See [Bulb::cron_get].
+    CronGet { cron_type: CronType },
+    /// See [Bulb::cron_del].
+    CronDel { cron_type: CronType },
+    /// See [Bulb::set_adjust].
+    SetAdjust { action: AdjustAction, prop: Prop },
+    /// See [Bulb::adjust_bright].
+    AdjustBright {
+        percentage: i8,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::adjust_ct].
+    AdjustCt {
+        percentage: i8,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::adjust_color].
+    AdjustColor {
+        percentage: i8,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::set_music].
+    SetMusic {
+        action: MusicAction,
+        host: String,
+        port: u16,
+    },
+    /// See [Bulb::set_name].
+    SetName { name: String },
+    /// See [Bulb::bg_set_rgb].
+    BgSetRgb {
+        rgb_value: u32,
+        effect: Effect,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::bg_set_hsv].
+    BgSetHsv {
+        hue: u16,
+        sat: u8,
+        effect: Effect,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::bg_set_ct_abx].
+    BgSetCtAbx {
+        ct_value: u16,
+        effect: Effect,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::bg_start_cf].
+    BgStartCf {
+        count: u8,
+        action: CfAction,
+        flow_expression: FlowExpresion,
+    },
+    /// See [Bulb::bg_stop_cf].
+    BgStopCf,
+    /// See [Bulb::bg_set_scene].
+    BgSetScene { scene: Scene },
+    /// See [Bulb::bg_set_default].
+    BgSetDefault,
+    /// See [Bulb::bg_set_power].
+    BgSetPower {
+        power: Power,
+        effect: Effect,
+        #[serde(with = "millis")]
+        duration: Duration,
+        mode: Mode,
+    },
+    /// See [Bulb::bg_set_bright].
+    BgSetBright {
+        brightness: u8,
+        effect: Effect,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::bg_set_adjust].
+    BgSetAdjust { action: AdjustAction, prop: Prop },
+    /// See [Bulb::bg_adjust_bright].
+    BgAdjustBright {
+        percentage: i8,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::bg_adjust_ct].
+    BgAdjustCt {
+        percentage: i8,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::bg_adjust_color].
+    BgAdjustColor {
+        percentage: i8,
+        #[serde(with = "millis")]
+        duration: Duration,
+    },
+    /// See [Bulb::bg_toggle].
+    BgToggle,
+    /// See [Bulb::dev_toggle].
+    DevToggle,
+);
+
+mod millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+impl Bulb {
+    /// Send the given [Command], as with the method of the same name.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// use std::time::Duration;
+    ///
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// let command = Command::SetRgb {
+    ///     rgb_value: 0xff_80_00,
+    ///     effect: Effect::Smooth,
+    ///     duration: Duration::from_millis(300),
+    /// };
+    /// bulb.send_command(&command).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn send_command(&mut self, command: &Command) -> Result<Option<Response>, BulbError> {
+        match command {
+            // Answered from (and stored in) the cached state
+            Command::GetProp { properties } => self.get_prop(properties).await,
+            Command::CronGet { cron_type } => self.cron_get(*cron_type).await,
+            command => {
+                let method = command.method().as_str();
+                self.check_support(method)?;
+                let params = command.params();
+                if let Some(result) = self.send_music(method, &params).await {
+                    return result;
+                }
+                self.writer.send(method, &params).await
+            }
+        }
+    }
+}
@@ -1,18 +1,40 @@
-use crate::Bulb;
+use crate::model::{self, Model};
+use crate::room::split_address;
+use crate::shutdown;
+use crate::{Bulb, ConnectError, ConnectOptions, Properties, Property, StateSnapshot};
 
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::io;
 use std::iter::FromIterator;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
 use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::mpsc;
-use tokio::task::spawn;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
 
 const MULTICAST_ADDR: &str = "239.255.255.250:1982";
 const LOCAL_ADDR: &str = "0.0.0.0:0";
 
+/// State properties included in the discovery response.
+const STATE_PROPERTIES: [&str; 7] = ["power", "bright", "color_mode", "ct", "rgb", "hue", "sat"];
+
+/// Port of the control protocol of the bulbs.
+const CONTROL_PORT: u16 = 55443;
+/// Default time given to each host to accept the connection and to answer the `get_prop`
+/// request.
+pub const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Smallest prefix accepted by [probe_subnet] (65536 addresses).
+const MIN_PREFIX: u8 = 16;
+/// Port where the bulbs answer discovery requests.
+const DISCOVERY_PORT: u16 = 1982;
+/// Discovery requests sent by [query_state] before giving up (UDP messages can be lost).
+const QUERY_ATTEMPTS: usize = 3;
+
 #[derive(Debug)]
 pub struct DiscoveredBulb {
     pub uid: u64,
@@ -21,25 +43,145 @@ pub struct DiscoveredBulb {
 }
 
 impl DiscoveredBulb {
-    pub async fn connect(&self) -> Result<Bulb, Box<dyn Error>> {
-        let addr = self.properties.get("Location").unwrap();
-        let addr = addr.trim_start_matches("yeelight://");
+    /// Connect to the address in the discovery response, see [Bulb::connect].
+    ///
+    /// Fails with [ConnectError::Resolve] if the response has no valid `Location`.
+    pub async fn connect(&self) -> Result<Bulb, ConnectError> {
+        self.connect_with(&ConnectOptions::default()).await
+    }
+
+    /// Same as [DiscoveredBulb::connect] with the given [ConnectOptions], see
+    /// [Bulb::connect_with].
+    pub async fn connect_with(&self, options: &ConnectOptions) -> Result<Bulb, ConnectError> {
+        let location = self.properties.get("Location").ok_or_else(|| {
+            let error = io::Error::new(
+                io::ErrorKind::NotFound,
+                "Discovery response without Location",
+            );
+            ConnectError::Resolve(error)
+        })?;
+        let (host, port) = split_address(location.trim_start_matches("yeelight://"))?;
+
+        let mut bulb = Bulb::connect_with(host, port, options).await?;
+        if let Some(model) = self.properties.get("model") {
+            bulb = bulb.with_model(model);
+        }
+        if let Some(support) = self.properties.get("support") {
+            bulb = bulb.with_support(support.split_whitespace());
+        }
+        Ok(bulb)
+    }
+
+    /// Information of the bulb from the discovery response, without connecting to it.
+    ///
+    /// The state only has the properties of the main light included in the response, use
+    /// [DiscoveredBulb::adopt] to read the full state.
+    pub fn info(&self) -> BulbInfo {
+        let property = |key: &str| self.properties.get(key).cloned().unwrap_or_default();
+        BulbInfo {
+            id: self.uid,
+            address: property("Location")
+                .trim_start_matches("yeelight://")
+                .to_string(),
+            model: property("model"),
+            fw_ver: property("fw_ver"),
+            name: property("name"),
+            support: property("support")
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            state: StateSnapshot(
+                STATE_PROPERTIES
+                    .iter()
+                    .filter_map(|key| Some((key.to_string(), self.properties.get(*key)?)))
+                    .filter(|(_, value)| !value.is_empty())
+                    .map(|(key, value)| (key, value.clone()))
+                    .collect(),
+            ),
+        }
+    }
 
-        let stream = TcpStream::connect(addr).await?;
+    /// Connect to the bulb and read its [info](DiscoveredBulb::info) with the full state
+    /// (including the background light).
+    pub async fn adopt(&self) -> Result<BulbInfo, Box<dyn Error>> {
+        let mut info = self.info();
+        info.state = self.connect().await?.snapshot().await?;
+        Ok(info)
+    }
+}
 
-        Ok(Bulb::attach_tokio(stream))
+/// Information of a bulb that can be stored to validate commands without a network round
+/// trip.
+///
+/// # Example
+/// ```
+/// # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::time::Duration;
+/// use yeelight::discover::{adopt_all, find_bulbs_timeout};
+///
+/// let bulbs = find_bulbs_timeout(Duration::from_secs(1)).await?;
+/// for info in adopt_all(&bulbs).await.into_iter().flatten() {
+///     println!("{} ({}) supports set_rgb: {}", info.name, info.model, info.supports("set_rgb"));
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BulbInfo {
+    pub id: u64,
+    /// Address of the bulb (`ip:port`).
+    pub address: String,
+    pub model: String,
+    pub fw_ver: String,
+    pub name: String,
+    /// Methods supported by the bulb.
+    pub support: Vec<String>,
+    /// State of the bulb when it was discovered or adopted.
+    pub state: StateSnapshot,
+}
+
+impl BulbInfo {
+    /// Whether the bulb supports the given method (such as `set_rgb`).
+    pub fn supports(&self, method: &str) -> bool {
+        self.support.iter().any(|m| m == method)
+    }
+
+    /// Capabilities of the model of the bulb, if it is known.
+    pub fn model(&self) -> Option<&'static Model> {
+        model::lookup(&self.model)
     }
 }
 
+/// [Connect](DiscoveredBulb::connect) to all the bulbs concurrently.
+///
+/// The results are in the same order as `bulbs`, so an unreachable bulb does not prevent
+/// using the others.
+pub async fn connect_all(bulbs: &[DiscoveredBulb]) -> Vec<Result<Bulb, ConnectError>> {
+    connect_all_with(bulbs, &ConnectOptions::default()).await
+}
+
+/// Same as [connect_all] with the given [ConnectOptions].
+pub async fn connect_all_with(
+    bulbs: &[DiscoveredBulb],
+    options: &ConnectOptions,
+) -> Vec<Result<Bulb, ConnectError>> {
+    join_all(bulbs.iter().map(|bulb| bulb.connect_with(options))).await
+}
+
+/// [Adopt](DiscoveredBulb::adopt) all the bulbs concurrently.
+///
+/// The results are in the same order as `bulbs`.
+pub async fn adopt_all(bulbs: &[DiscoveredBulb]) -> Vec<Result<BulbInfo, Box<dyn Error>>> {
+    join_all(bulbs.iter().map(DiscoveredBulb::adopt)).await
+}
+
 impl PartialEq for DiscoveredBulb {
     fn eq(&self, other: &Self) -> bool {
         self.uid == other.uid
     }
 }
 
-impl Eq for DiscoveredBulb {
-    fn assert_receiver_is_total_eq(&self) {}
-}
+impl Eq for DiscoveredBulb {}
 
 impl std::hash::Hash for DiscoveredBulb {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
@@ -81,34 +223,101 @@ fn parse(buf: &[u8], len: usize) -> Option<DiscoveryResponse> {
     None
 }
 
-async fn relay(recv: Arc<UdpSocket>, send: mpsc::Sender<DiscoveredBulb>) -> ! {
+/// Forward the discovery responses received on `recv` until the receiver of `send` is
+/// dropped.
+pub(crate) async fn relay(recv: Arc<UdpSocket>, send: mpsc::Sender<DiscoveredBulb>) {
     let mut buf = [0; 2048];
     loop {
-        if let Ok((len, addr)) = recv.recv_from(&mut buf).await {
+        let received = tokio::select! {
+            received = recv.recv_from(&mut buf) => received,
+            // Stop even if no more responses arrive
+            _ = send.closed() => return,
+        };
+        if let Ok((len, addr)) = received {
             if let Some(DiscoveryResponse(id, info)) = parse(&buf, len) {
-                send.send(DiscoveredBulb {
+                let dbulb = DiscoveredBulb {
                     uid: id,
                     response_address: addr,
                     properties: info,
-                })
-                .await
-                .unwrap_or_default();
+                };
+                if send.send(dbulb).await.is_err() {
+                    return;
+                }
             }
         }
     }
 }
 
+/// Running discovery, started with [Discovery::start].
+///
+/// The socket and the task receiving the responses are released when the discovery is
+/// stopped or dropped, so long running programs can restart it without leaking them.
+///
+/// # Example
+/// ```
+/// # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+/// # use yeelight::discover::Discovery;
+/// # use std::time::Duration;
+/// let mut discovery = Discovery::start().await?;
+/// let search = async {
+///     while let Some(dbulb) = discovery.recv().await {
+///         println!("Found {:?}", dbulb.properties.get("name"));
+///     }
+/// };
+/// let _ = tokio::time::timeout(Duration::from_secs(5), search).await;
+/// discovery.stop().await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Discovery {
+    bulbs: mpsc::Receiver<DiscoveredBulb>,
+    task: JoinHandle<()>,
+}
+
+impl Discovery {
+    /// Send a discovery message and start receiving the responses.
+    pub async fn start() -> Result<Self, std::io::Error> {
+        let (bulbs, task) = start_relay().await?;
+        Ok(Self { bulbs, task })
+    }
+
+    /// Next bulb that answered (bulbs may answer more than once). `None` once stopped.
+    pub async fn recv(&mut self) -> Option<DiscoveredBulb> {
+        self.bulbs.recv().await
+    }
+
+    /// Stop receiving responses and wait until the socket is closed.
+    pub async fn stop(mut self) {
+        self.bulbs.close();
+        self.task.abort();
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Send a discovery message and receive the responses in a channel.
+///
+/// Discovery stops when the receiver is dropped, see [Discovery] to stop it explicitly.
 pub async fn find_bulbs() -> Result<mpsc::Receiver<DiscoveredBulb>, std::io::Error> {
+    Ok(start_relay().await?.0)
+}
+
+async fn start_relay() -> Result<(mpsc::Receiver<DiscoveredBulb>, JoinHandle<()>), std::io::Error> {
     let sock = create_socket().await?;
     let soc_send = Arc::new(sock);
     let soc_recv = soc_send.clone();
 
     send_payload(soc_send).await?;
     let (send, recv) = mpsc::channel(10);
+    let task = shutdown::spawn_watcher(relay(soc_recv, send));
 
-    spawn(relay(soc_recv, send));
-
-    Ok(recv)
+    Ok((recv, task))
 }
 
 pub async fn find_bulbs_timeout(
@@ -131,16 +340,191 @@ pub async fn find_bulbs_timeout(
     Ok(Vec::from_iter(found))
 }
 
+/// Find bulbs in a subnet without multicast, probing each address of `cidr` (such as
+/// `192.168.10.0/24`).
+///
+/// Useful when the bulbs are in another network (VLAN) that multicast does not reach. A TCP
+/// connection to the control port is attempted on each address, with at most `parallelism`
+/// probes at a time, and hosts that accept it are identified as bulbs by answering a
+/// `get_prop` request.
+///
+/// Bulbs only report their state and name this way, so the `properties` of the results only
+/// contain `Location`, `name` and the state of the main light, and `uid` is derived from the
+/// IP address (the id of the bulb is only sent in discovery responses).
+///
+/// Each host is given [PROBE_TIMEOUT] to answer, see [probe_subnet_with] to change it.
+///
+/// # Example
+/// ```
+/// # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+/// use yeelight::discover::probe_subnet;
+///
+/// for bulb in probe_subnet("192.168.10.0/24", 32).await? {
+///     println!("{}", bulb.info().address);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub async fn probe_subnet(
+    cidr: &str,
+    parallelism: usize,
+) -> Result<Vec<DiscoveredBulb>, Box<dyn Error>> {
+    probe_subnet_with(cidr, parallelism, PROBE_TIMEOUT).await
+}
+
+/// [probe_subnet] giving each host `timeout` to accept the connection and to answer.
+pub async fn probe_subnet_with(
+    cidr: &str,
+    parallelism: usize,
+    timeout: Duration,
+) -> Result<Vec<DiscoveredBulb>, Box<dyn Error>> {
+    let hosts = parse_cidr(cidr)?;
+    let permits = Arc::new(Semaphore::new(parallelism.max(1)));
+
+    let probes = hosts.map(|ip| {
+        let permits = permits.clone();
+        shutdown::spawn(async move {
+            let _permit = permits.acquire_owned().await.ok()?;
+            probe(ip, timeout).await
+        })
+    });
+    let found = join_all(probes).await;
+
+    Ok(found.into_iter().filter_map(|r| r.ok().flatten()).collect())
+}
+
+/// Addresses of the hosts of an IPv4 subnet in CIDR notation (without the network and
+/// broadcast addresses).
+fn parse_cidr(cidr: &str) -> Result<impl Iterator<Item = Ipv4Addr>, Box<dyn Error>> {
+    let (ip, prefix) = cidr.split_once('/').unwrap_or((cidr, "32"));
+    let ip: Ipv4Addr = ip.parse()?;
+    let prefix: u8 = prefix.parse()?;
+    if !(MIN_PREFIX..=32).contains(&prefix) {
+        return Err(format!("Subnet prefix must be between {} and 32", MIN_PREFIX).into());
+    }
+
+    let mask = u32::MAX << (32 - prefix as u32);
+    let network = u32::from(ip) & mask;
+    let broadcast = network | !mask;
+    let range = match prefix {
+        31 | 32 => network..=broadcast,
+        _ => network + 1..=broadcast - 1,
+    };
+    Ok(range.map(Ipv4Addr::from))
+}
+
+async fn probe(ip: Ipv4Addr, timeout: Duration) -> Option<DiscoveredBulb> {
+    let address = SocketAddr::from((ip, CONTROL_PORT));
+    let stream = tokio::time::timeout(timeout, TcpStream::connect(address))
+        .await
+        .ok()?
+        .ok()?;
+
+    let mut bulb = Bulb::attach_tokio(stream);
+    let properties = Properties(vec![
+        Property::Name,
+        Property::Power,
+        Property::Bright,
+        Property::ColorMode,
+        Property::Ct,
+        Property::Rgb,
+        Property::Hue,
+        Property::Sat,
+    ]);
+    let values = tokio::time::timeout(timeout, bulb.get_prop(&properties))
+        .await
+        .ok()?
+        .ok()??;
+
+    let mut info: HashMap<_, _> = ["name"]
+        .iter()
+        .chain(STATE_PROPERTIES.iter())
+        .map(|key| key.to_string())
+        .zip(values)
+        .collect();
+    info.insert("Location".to_string(), format!("yeelight://{}", address));
+    log::debug!("Bulb found at {}", address);
+
+    Some(DiscoveredBulb {
+        uid: u32::from(ip) as u64,
+        response_address: address,
+        properties: info,
+    })
+}
+
+/// Read the state of the main light of the bulb at `addr` from its answer to a discovery
+/// request sent directly to it, without opening a connection.
+///
+/// It does not use the connection nor the command quotas of the bulb, so it is suitable for
+/// dashboards polling many bulbs. Only the properties included in discovery responses
+/// (`power`, `bright`, `color_mode`, `ct`, `rgb`, `hue` and `sat`) are present.
+///
+/// If `port` is 0, the default value (1982) is used. The request is sent up to 3 times,
+/// waiting [PROBE_TIMEOUT] for the answer each time, and fails with
+/// [TimedOut](std::io::ErrorKind::TimedOut) if the bulb does not answer.
+///
+/// # Example
+/// ```
+/// # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+/// use yeelight::{discover::query_state, Property};
+///
+/// let state = query_state("192.168.1.204", 0).await?;
+/// println!("Power: {:?}", state.get(Property::Power));
+/// # Ok(())
+/// # }
+/// ```
+pub async fn query_state(addr: &str, port: u16) -> Result<StateSnapshot, std::io::Error> {
+    let port = if port == 0 { DISCOVERY_PORT } else { port };
+    let target = tokio::net::lookup_host((addr, port))
+        .await?
+        .next()
+        .ok_or_else(|| {
+            let message = format!("No addresses found for {}", addr);
+            std::io::Error::new(std::io::ErrorKind::NotFound, message)
+        })?;
+    let socket = create_socket().await?;
+
+    let mut buf = [0; 2048];
+    for _ in 0..QUERY_ATTEMPTS {
+        socket.send_to(search_payload().as_bytes(), target).await?;
+        let answer = async {
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await?;
+                // Bulbs answer from their discovery port, only the address is checked
+                if from.ip() != target.ip() {
+                    continue;
+                }
+                if let Some(DiscoveryResponse(id, properties)) = parse(&buf, len) {
+                    let dbulb = DiscoveredBulb {
+                        uid: id,
+                        response_address: from,
+                        properties,
+                    };
+                    return Ok::<_, std::io::Error>(dbulb.info().state);
+                }
+            }
+        };
+        if let Ok(result) = tokio::time::timeout(PROBE_TIMEOUT, answer).await {
+            return result;
+        }
+    }
+    let message = format!("No discovery response from {}", target);
+    Err(std::io::Error::new(std::io::ErrorKind::TimedOut, message))
+}
+
 async fn create_socket() -> Result<UdpSocket, std::io::Error> {
     let addr: SocketAddr = LOCAL_ADDR.parse().unwrap();
     UdpSocket::bind(addr).await
 }
 
 async fn send_payload(socket: Arc<UdpSocket>) -> Result<usize, std::io::Error> {
-    let payload = format!(
+    let addr: SocketAddr = MULTICAST_ADDR.parse().unwrap();
+    socket.send_to(search_payload().as_bytes(), &addr).await
+}
+
+fn search_payload() -> String {
+    format!(
         "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nST: wifi_bulb\r\n",
         MULTICAST_ADDR
-    );
-    let addr: SocketAddr = MULTICAST_ADDR.parse().unwrap();
-    socket.send_to(payload.as_bytes(), &addr).await
+    )
 }
@@ -0,0 +1,1359 @@
+//! Light effects and tools for real time effects (ambilight, music visualizers, ...) over
+//! music mode connections.
+//!
+//! Effects run as color flows on the bulbs when possible. Effects that can not be expressed
+//! as a flow are played by sending a frame at a time, which should be done over a
+//! [music mode](Bulb::start_music) connection (normal connections are limited to 60 commands
+//! per minute).
+//!
+//! Effects run in the background until they are stopped with their [`EffectHandle`], which
+//! also gives back the bulb or group.
+
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use futures_util::future::{join_all, BoxFuture};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use crate::group::label;
+use crate::helpers::{hsv_to_rgb, MIN_DURATION};
+use crate::model::MAX_FLOW_TUPLES;
+use crate::shutdown;
+use crate::transition::MAX_SEGMENT;
+use crate::{
+    Bulb, BulbError, BulbGroup, CfAction, ColorSpec, Effect, FlowExpresion, FlowTuple,
+    GroupResults, Latency, Mode, Power, Properties, Property, Scene, StateSnapshot,
+};
+
+/// Bulb or group of bulbs an effect can be played on.
+///
+/// Operations on groups are sent to all the members and fail with the first error.
+pub trait EffectTarget: Send + 'static {
+    /// State saved to be restored when the effect is stopped, see [OnStop::Recover].
+    type State: Send;
+
+    /// Set the scene on the light (or all the lights of the group).
+    fn set_scene(&mut self, scene: Scene) -> BoxFuture<'_, Result<(), BulbError>>;
+
+    /// Stop the color flow, keeping the current state.
+    fn stop_cf(&mut self) -> BoxFuture<'_, Result<(), BulbError>>;
+
+    /// Turn off the light.
+    fn turn_off(&mut self) -> BoxFuture<'_, Result<(), BulbError>>;
+
+    /// Read the current state.
+    fn save_state(&mut self) -> BoxFuture<'_, Result<Self::State, BulbError>>;
+
+    /// Bring the light back to a state read with [EffectTarget::save_state].
+    fn restore_state<'a>(
+        &'a mut self,
+        state: &'a Self::State,
+    ) -> BoxFuture<'a, Result<(), BulbError>>;
+
+    /// Estimated time for a command to reach the light (half the median response time), if
+    /// it has been measured. Groups return the latency of their slowest member.
+    fn latency(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl EffectTarget for Bulb {
+    type State = StateSnapshot;
+
+    fn set_scene(&mut self, scene: Scene) -> BoxFuture<'_, Result<(), BulbError>> {
+        Box::pin(async move { Bulb::set_scene(self, scene).await.map(|_| ()) })
+    }
+
+    fn stop_cf(&mut self) -> BoxFuture<'_, Result<(), BulbError>> {
+        Box::pin(async move { Bulb::stop_cf(self).await.map(|_| ()) })
+    }
+
+    fn turn_off(&mut self) -> BoxFuture<'_, Result<(), BulbError>> {
+        Box::pin(async move {
+            self.set_power(Power::Off, Effect::Sudden, Duration::ZERO, Mode::Normal)
+                .await
+                .map(|_| ())
+        })
+    }
+
+    fn save_state(&mut self) -> BoxFuture<'_, Result<StateSnapshot, BulbError>> {
+        Box::pin(self.snapshot())
+    }
+
+    fn restore_state<'a>(
+        &'a mut self,
+        state: &'a StateSnapshot,
+    ) -> BoxFuture<'a, Result<(), BulbError>> {
+        Box::pin(self.restore(state))
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.stats().latency.map(|latency| latency.p50 / 2)
+    }
+}
+
+impl EffectTarget for BulbGroup {
+    type State = BTreeMap<String, StateSnapshot>;
+
+    fn set_scene(&mut self, scene: Scene) -> BoxFuture<'_, Result<(), BulbError>> {
+        Box::pin(async move { first_error(BulbGroup::set_scene(self, &scene).await) })
+    }
+
+    fn stop_cf(&mut self) -> BoxFuture<'_, Result<(), BulbError>> {
+        Box::pin(async move { first_error(self.for_each(|bulb| Box::pin(bulb.stop_cf())).await) })
+    }
+
+    fn turn_off(&mut self) -> BoxFuture<'_, Result<(), BulbError>> {
+        Box::pin(async move {
+            let results = self
+                .set_power(Power::Off, Effect::Sudden, Duration::ZERO, Mode::Normal)
+                .await;
+            first_error(results)
+        })
+    }
+
+    fn save_state(&mut self) -> BoxFuture<'_, Result<Self::State, BulbError>> {
+        Box::pin(async move {
+            self.snapshot()
+                .await
+                .into_iter()
+                .map(|(name, result)| result.map(|snapshot| (name, snapshot)))
+                .collect()
+        })
+    }
+
+    fn restore_state<'a>(
+        &'a mut self,
+        state: &'a Self::State,
+    ) -> BoxFuture<'a, Result<(), BulbError>> {
+        Box::pin(async move {
+            let restores = self.iter_mut().filter_map(|(name, bulb)| {
+                let snapshot = state.get(name)?;
+                let label = label(name, bulb);
+                Some(async move { bulb.restore(snapshot).await.map_err(|e| e.context(label)) })
+            });
+            join_all(restores).await.into_iter().collect()
+        })
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.iter().filter_map(|(_, bulb)| bulb.latency()).max()
+    }
+}
+
+fn first_error(results: GroupResults) -> Result<(), BulbError> {
+    results
+        .into_iter()
+        .try_for_each(|(_, result)| result.map(|_| ()))
+}
+
+/// What to do with the light when an effect is stopped (like [CfAction] for flows).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnStop {
+    /// Go back to the state before the effect started.
+    #[default]
+    Recover,
+    /// Keep the state at the moment the effect is stopped.
+    Stay,
+    /// Turn off the light.
+    Off,
+}
+
+/// Command run on the target of a running effect, see [`EffectHandle::control`].
+type Control<T> = Box<dyn for<'a> FnOnce(&'a mut T) -> BoxFuture<'a, ()> + Send>;
+
+/// Effect running in the background, returned by the effects of this module.
+///
+/// Dropping the handle leaves the effect running on the bulb (until the connection is closed
+/// or the effect fails), without applying the [OnStop] policy. Use [EffectHandle::stop] to
+/// stop it and get the bulb or group back.
+pub struct EffectHandle<T> {
+    running: watch::Receiver<bool>,
+    stop: oneshot::Sender<()>,
+    control: mpsc::Sender<Control<T>>,
+    task: JoinHandle<(T, Result<(), BulbError>)>,
+}
+
+impl<T: EffectTarget> EffectHandle<T> {
+    fn spawn(mut target: T, plan: Plan, on_stop: OnStop) -> Self {
+        let (running_sender, running) = watch::channel(true);
+        let (stop, stopped) = oneshot::channel::<()>();
+        let (control, mut controls) = mpsc::channel(8);
+
+        let task = shutdown::spawn(async move {
+            let result = async {
+                let saved = match on_stop {
+                    OnStop::Recover => Some(target.save_state().await?),
+                    _ => None,
+                };
+                let flow = matches!(plan, Plan::Flow(_) | Plan::Beats { .. });
+                tokio::select! {
+                    result = plan.play(&mut target, &mut controls) => return result,
+                    // Not when the handle is dropped without calling stop
+                    Ok(()) = stopped => {}
+                    // Left as when stopped before the connection is closed
+                    _ = shutdown::stopping() => {}
+                }
+                if flow && on_stop != OnStop::Off {
+                    target.stop_cf().await?;
+                }
+                match (on_stop, saved) {
+                    (OnStop::Off, _) => target.turn_off().await,
+                    (_, Some(saved)) => target.restore_state(&saved).await,
+                    _ => Ok(()),
+                }
+            }
+            .await;
+            running_sender.send_replace(false);
+            (target, result)
+        });
+
+        Self {
+            running,
+            stop,
+            control,
+            task,
+        }
+    }
+
+    /// Run a command on the bulb or group while the effect is running, such as reading its
+    /// state.
+    ///
+    /// Commands are run between the frames of the effect: at most one command is run after
+    /// each frame, so neither the effect nor the commands starve when the frames are sent
+    /// over a normal connection. The messages of the command are sent with the normal
+    /// [Priority](crate::Priority), after the frames already buffered (a high priority would
+    /// discard them, see [Bulb::priority](crate::Bulb::priority)). With effects running as a
+    /// flow on the bulb, commands are run immediately.
+    ///
+    /// Returns `None` if the effect is no longer running.
+    ///
+    /// **NOTE:** Commands that change the light interrupt flows.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test(effect: yeelight::effects::EffectHandle<yeelight::Bulb>) {
+    /// # use yeelight::*;
+    /// let props = Properties(vec![Property::Power]);
+    /// let power = effect
+    ///     .control(move |bulb| Box::pin(async move { bulb.get_prop(&props).await }))
+    ///     .await;
+    /// # }
+    /// ```
+    pub async fn control<F, R>(&self, f: F) -> Option<R>
+    where
+        F: for<'a> FnOnce(&'a mut T) -> BoxFuture<'a, R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let control: Control<T> = Box::new(move |target| {
+            Box::pin(async move {
+                let _ = sender.send(f(target).await);
+            })
+        });
+        self.control.send(control).await.ok()?;
+        receiver.await.ok()
+    }
+
+    /// Whether the effect is still running (it has not been stopped or failed).
+    pub fn is_running(&self) -> bool {
+        *self.running.borrow()
+    }
+
+    /// Wait until the effect is no longer running.
+    ///
+    /// Effects only end on their own when they fail, use [EffectHandle::stop] to get the
+    /// error.
+    pub async fn finished(&mut self) {
+        let _ = self.running.wait_for(|running| !running).await;
+    }
+
+    /// Stop the effect, apply the [OnStop] policy and give back the bulb or group.
+    ///
+    /// The inner result holds the error that stopped the effect or the error applying the
+    /// policy. Fails with [BulbError::Disconnected] if the effect was aborted by
+    /// [Coordinator::shutdown](crate::shutdown::Coordinator::shutdown), which drops the bulb
+    /// or group.
+    pub async fn stop(self) -> Result<(T, Result<(), BulbError>), BulbError> {
+        let _ = self.stop.send(());
+        shutdown::join(self.task).await
+    }
+}
+
+/// Samples of each half of a [`breathe`] cycle with a curved [`Easing`].
+const BREATHE_SAMPLES: usize = 8;
+
+/// Shape of the brightness changes of [`breathe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Easing {
+    /// Constant speed (triangle wave).
+    Linear,
+    /// Sinusoidal curve, slower near the minimum and maximum brightness.
+    #[default]
+    Sine,
+    /// Quadratic ease in and out, with longer pauses at the ends than [Easing::Sine].
+    Quadratic,
+}
+
+impl Easing {
+    /// Eased value of `x` (`0.0` to `1.0`).
+    fn apply(self, x: f64) -> f64 {
+        match self {
+            Easing::Linear => x,
+            Easing::Sine => (1.0 - (PI * x).cos()) / 2.0,
+            Easing::Quadratic if x < 0.5 => 2.0 * x * x,
+            Easing::Quadratic => 1.0 - 2.0 * (1.0 - x) * (1.0 - x),
+        }
+    }
+}
+
+/// How an effect is played.
+enum Plan {
+    /// Color flow looping forever.
+    Flow(FlowExpresion),
+    /// Frames sent every `period`, computed from the time since the start of the effect.
+    Frames {
+        period: Duration,
+        frame: Box<dyn FnMut(Duration) -> Scene + Send>,
+    },
+    /// Scene set on every beat, computed from the beat period (if known).
+    Beats {
+        beats: mpsc::Receiver<BeatEvent>,
+        scene: Box<dyn FnMut(Option<Duration>) -> Scene + Send>,
+    },
+    /// Colors of a real time source, filtered by the predictor.
+    Track {
+        frames: mpsc::Receiver<AmbilightFrame>,
+        predictor: ColorPredictor,
+        threshold: u8,
+    },
+}
+
+impl Plan {
+    /// Play the effect until it fails (or the future is dropped), running the `controls`
+    /// between frames.
+    async fn play<T: EffectTarget>(
+        self,
+        target: &mut T,
+        controls: &mut mpsc::Receiver<Control<T>>,
+    ) -> Result<(), BulbError> {
+        match self {
+            Plan::Flow(flow) => {
+                let scene = Scene::Cf {
+                    count: 0,
+                    action: CfAction::Recover,
+                    flow,
+                };
+                target.set_scene(scene).await?;
+                while let Some(control) = controls.recv().await {
+                    control(target).await;
+                }
+                std::future::pending().await
+            }
+            Plan::Frames { period, mut frame } => {
+                let mut interval = tokio::time::interval(period);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                let start = tokio::time::Instant::now();
+                // A control can run after each frame
+                let mut control_turn = false;
+                loop {
+                    tokio::select! {
+                        biased;
+                        tick = interval.tick() => {
+                            target.set_scene(frame(tick - start)).await?;
+                            control_turn = true;
+                        }
+                        Some(control) = controls.recv(), if control_turn => {
+                            control(target).await;
+                            control_turn = false;
+                        }
+                    }
+                }
+            }
+            Plan::Beats { beats, scene } => play_beats(target, controls, beats, scene).await,
+            Plan::Track {
+                mut frames,
+                mut predictor,
+                threshold,
+            } => {
+                let mut last_sent: Option<u32> = None;
+                let mut control_turn = false;
+                loop {
+                    tokio::select! {
+                        biased;
+                        Some(frame) = frames.recv() => {
+                            let latency = target.latency();
+                            let rgb = predictor.update(frame.captured, frame.rgb, latency);
+                            if last_sent.is_some_and(|last| max_difference(last, rgb) < threshold) {
+                                continue;
+                            }
+                            last_sent = Some(rgb);
+                            target.set_scene(rgb_scene(rgb)).await?;
+                            control_turn = true;
+                        }
+                        Some(control) = controls.recv(), if control_turn => {
+                            control(target).await;
+                            control_turn = false;
+                        }
+                        // The last color stays once the source is closed
+                        else => std::future::pending().await,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Set the scene on each beat received from `beats`, and on the beats predicted from the
+/// tempo when they do not arrive.
+async fn play_beats<T: EffectTarget>(
+    target: &mut T,
+    controls: &mut mpsc::Receiver<Control<T>>,
+    mut beats: mpsc::Receiver<BeatEvent>,
+    mut scene: Box<dyn FnMut(Option<Duration>) -> Scene + Send>,
+) -> Result<(), BulbError> {
+    let mut period: Option<Duration> = None;
+    // Set by `BeatEvent::Tempo`, otherwise the period is measured between beats
+    let mut fixed_tempo = false;
+    let mut last_beat: Option<tokio::time::Instant> = None;
+    let mut listening = true;
+    let mut control_turn = false;
+
+    loop {
+        let predicted = period.zip(last_beat).map(|(period, last)| last + period);
+        let next_predicted = async move {
+            match predicted {
+                Some(at) => tokio::time::sleep_until(at).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            biased;
+            event = beats.recv(), if listening => match event {
+                Some(BeatEvent::Beat) => {
+                    let now = tokio::time::Instant::now();
+                    let since = last_beat.map(|last| now - last);
+                    match (since, period) {
+                        // Close after a predicted beat: only correct the phase
+                        (Some(since), Some(period)) if since < period / 4 => {
+                            last_beat = Some(now);
+                            continue;
+                        }
+                        (Some(since), _) if !fixed_tempo && BEAT_PERIODS.contains(&since) => {
+                            period = Some(since);
+                        }
+                        _ => {}
+                    }
+                    last_beat = Some(now);
+                    target.set_scene(scene(period)).await?;
+                    control_turn = true;
+                }
+                Some(BeatEvent::Tempo { bpm }) if bpm > 0.0 => {
+                    period = Some(Duration::from_secs_f64(60.0 / bpm));
+                    fixed_tempo = true;
+                }
+                Some(BeatEvent::Tempo { .. }) => {
+                    period = None;
+                    fixed_tempo = false;
+                }
+                // Keep going at the last tempo
+                None => listening = false,
+            },
+            _ = next_predicted => {
+                last_beat = predicted;
+                target.set_scene(scene(period)).await?;
+                control_turn = true;
+            }
+            Some(control) = controls.recv(), if control_turn => {
+                control(target).await;
+                control_turn = false;
+            }
+        }
+    }
+}
+
+/// Continuously sweep the hue of the light, completing a turn every `period`.
+///
+/// The sweep runs on the bulb as an infinite color flow (moving between the corners of the
+/// color wheel, which the bulb interpolates exactly) when each step lasts between 50ms and
+/// one minute. Otherwise the frames are sent from here (every 50ms or one degree of hue).
+///
+/// # Example
+/// ```
+/// # async fn test() {
+/// # use yeelight::*;
+/// # use std::time::Duration;
+/// use yeelight::effects::{hue_rotate, OnStop};
+///
+/// let bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+/// let effect = hue_rotate(bulb, Duration::from_secs(30), 100, 80, OnStop::Recover);
+/// tokio::time::sleep(Duration::from_secs(60)).await;
+/// let (bulb, result) = effect.stop().await.unwrap();
+/// # }
+/// ```
+pub fn hue_rotate<T: EffectTarget>(
+    target: T,
+    period: Duration,
+    sat: u8,
+    bright: u8,
+    on_stop: OnStop,
+) -> EffectHandle<T> {
+    EffectHandle::spawn(target, hue_rotate_plan(period, sat, bright), on_stop)
+}
+
+/// Play `flow` in a loop as an effect, so it is stopped and recovered like the other effects
+/// of this module (for instance, presets such as a candle flicker).
+///
+/// # Example
+/// ```
+/// # async fn test() {
+/// # use yeelight::*;
+/// # use std::time::Duration;
+/// use yeelight::effects::{play_flow, OnStop};
+///
+/// let bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+/// let flow = FlowExpresion(vec![
+///     FlowTuple::ct(Duration::from_millis(800), 2700, 50),
+///     FlowTuple::ct(Duration::from_millis(1200), 2700, 80),
+/// ]);
+/// let effect = play_flow(bulb, flow, OnStop::Recover);
+/// tokio::time::sleep(Duration::from_secs(60)).await;
+/// let (bulb, result) = effect.stop().await.unwrap();
+/// # }
+/// ```
+pub fn play_flow<T: EffectTarget>(
+    target: T,
+    flow: FlowExpresion,
+    on_stop: OnStop,
+) -> EffectHandle<T> {
+    EffectHandle::spawn(target, Plan::Flow(flow), on_stop)
+}
+
+/// Pulse the brightness of the light between `min_bright` and `max_bright` every `period`.
+///
+/// The curve is sampled and played as an infinite color flow on the bulb, starting from
+/// `min_bright`. When the samples do not fit in a flow (periods below 100ms or longer than
+/// a few hours), the frames are sent from here.
+///
+/// # Example
+/// ```
+/// # async fn test() -> Result<(), yeelight::BulbError> {
+/// # use yeelight::*;
+/// # use std::time::Duration;
+/// use yeelight::effects::{breathe, Easing, OnStop};
+///
+/// let bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+/// let color = ColorSpec::Rgb(0x00_80_ff);
+/// let period = Duration::from_secs(4);
+/// let effect = breathe(bulb, color, period, 5, 60, Easing::Sine, OnStop::Recover);
+/// tokio::time::sleep(Duration::from_secs(60)).await;
+/// let (bulb, result) = effect.stop().await?;
+/// result?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn breathe<T: EffectTarget>(
+    target: T,
+    color: ColorSpec,
+    period: Duration,
+    min_bright: u8,
+    max_bright: u8,
+    easing: Easing,
+    on_stop: OnStop,
+) -> EffectHandle<T> {
+    let plan = breathe_plan(color, period, min_bright, max_bright, easing);
+    EffectHandle::spawn(target, plan, on_stop)
+}
+
+fn breathe_plan(
+    color: ColorSpec,
+    period: Duration,
+    min_bright: u8,
+    max_bright: u8,
+    easing: Easing,
+) -> Plan {
+    let min = min_bright.clamp(1, 100) as f64;
+    let max = max_bright.clamp(1, 100) as f64;
+    let level = move |cycle: f64| {
+        let x = 1.0 - (2.0 * cycle.fract() - 1.0).abs();
+        (min + (max - min) * easing.apply(x)).round() as u8
+    };
+
+    let half_samples = match easing {
+        Easing::Linear => 1,
+        _ => BREATHE_SAMPLES,
+    };
+    let half_samples = half_samples
+        .min(
+            (period / 2)
+                .as_millis()
+                .div_ceil(MIN_DURATION.as_millis())
+                .max(1) as usize,
+        )
+        .max((period / 2).as_millis().div_ceil(MAX_SEGMENT.as_millis()) as usize);
+    let steps = 2 * half_samples;
+    let step = period / steps as u32;
+    if steps < MAX_FLOW_TUPLES && step >= MIN_DURATION {
+        let mut flow = vec![color_tuple(MIN_DURATION, color, level(0.0))];
+        flow.extend((1..=steps).map(|i| color_tuple(step, color, level(i as f64 / steps as f64))));
+        // The first tuple holds the minimum, shorten the last one to keep the period
+        if let Some(last) = flow.last_mut() {
+            last.duration = step.saturating_sub(MIN_DURATION).max(MIN_DURATION);
+        }
+        return Plan::Flow(FlowExpresion(flow));
+    }
+
+    let period = period.max(MIN_DURATION);
+    Plan::Frames {
+        period: (period / 20).max(MIN_DURATION),
+        frame: Box::new(move |elapsed| {
+            let bright = level(elapsed.as_secs_f64() / period.as_secs_f64());
+            match color {
+                ColorSpec::Rgb(rgb) => Scene::Color { rgb, bright },
+                ColorSpec::Hsv(hue, sat) => Scene::Hsv { hue, sat, bright },
+                ColorSpec::Ct(ct) => Scene::Ct { ct, bright },
+            }
+        }),
+    }
+}
+
+/// Beat or tempo event fed to the beat synchronized effects ([`pulse`] and [`strobe`]).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BeatEvent {
+    /// A beat happened now.
+    Beat,
+    /// Tempo of the music, used to predict the beats that are not received. Without it, the
+    /// tempo is measured between consecutive beats. A tempo of `0` goes back to measuring.
+    Tempo { bpm: f64 },
+}
+
+/// Intervals between beats accepted as the tempo (30 to 300 bpm).
+const BEAT_PERIODS: std::ops::RangeInclusive<Duration> =
+    Duration::from_millis(200)..=Duration::from_secs(2);
+
+/// Flash the light to `max_bright` on every beat and fade it to `min_bright` until the next.
+///
+/// Beats are fed by external software (DJ software, an audio analyzer...) through `beats`.
+/// Once the tempo is known ([BeatEvent::Tempo] or measured between beats), beats that do
+/// not arrive are predicted, and received beats correct the phase. If the sender is
+/// dropped, the effect keeps going at the last tempo.
+///
+/// Each beat is a single command (a short color flow), so a [music
+/// mode](Bulb::start_music) connection is needed for tempos above 60 bpm.
+///
+/// # Example
+/// ```
+/// # async fn test() {
+/// # use yeelight::*;
+/// use yeelight::effects::{pulse, BeatEvent, OnStop};
+///
+/// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+/// let music = bulb.start_music("192.168.1.23").await.unwrap();
+/// let (beats, receiver) = tokio::sync::mpsc::channel(16);
+/// let effect = pulse(music, ColorSpec::Rgb(0xff_00_40), 5, 100, receiver, OnStop::Recover);
+/// beats.send(BeatEvent::Tempo { bpm: 128.0 }).await.unwrap();
+/// // On every beat detected
+/// beats.send(BeatEvent::Beat).await.unwrap();
+/// # }
+/// ```
+pub fn pulse<T: EffectTarget>(
+    target: T,
+    color: ColorSpec,
+    min_bright: u8,
+    max_bright: u8,
+    beats: mpsc::Receiver<BeatEvent>,
+    on_stop: OnStop,
+) -> EffectHandle<T> {
+    let min = min_bright.clamp(1, 100);
+    let max = max_bright.clamp(1, 100);
+    let scene = move |period: Option<Duration>| {
+        let fade = period.map_or(Duration::from_millis(250), |period| period * 9 / 10);
+        beat_flow(vec![
+            color_tuple(MIN_DURATION, color, max),
+            color_tuple(
+                fade.saturating_sub(MIN_DURATION).max(MIN_DURATION),
+                color,
+                min,
+            ),
+        ])
+    };
+    let plan = Plan::Beats {
+        beats,
+        scene: Box::new(scene),
+    };
+    EffectHandle::spawn(target, plan, on_stop)
+}
+
+/// Flash the light at `bright` for a moment on every beat, dimming it to 1% in between.
+///
+/// **See:** [`pulse`] for how the beats are fed and predicted.
+pub fn strobe<T: EffectTarget>(
+    target: T,
+    color: ColorSpec,
+    bright: u8,
+    beats: mpsc::Receiver<BeatEvent>,
+    on_stop: OnStop,
+) -> EffectHandle<T> {
+    let bright = bright.clamp(1, 100);
+    let scene = move |_| {
+        beat_flow(vec![
+            color_tuple(MIN_DURATION, color, bright),
+            FlowTuple::sleep(MIN_DURATION),
+            color_tuple(MIN_DURATION, color, 1),
+        ])
+    };
+    let plan = Plan::Beats {
+        beats,
+        scene: Box::new(scene),
+    };
+    EffectHandle::spawn(target, plan, on_stop)
+}
+
+fn beat_flow(tuples: Vec<FlowTuple>) -> Scene {
+    Scene::Cf {
+        count: tuples.len() as u8,
+        action: CfAction::Stay,
+        flow: FlowExpresion(tuples),
+    }
+}
+
+fn color_tuple(duration: Duration, color: ColorSpec, bright: u8) -> FlowTuple {
+    match color {
+        ColorSpec::Rgb(rgb) => FlowTuple::rgb(duration, rgb, bright as i8),
+        ColorSpec::Hsv(hue, sat) => {
+            FlowTuple::rgb(duration, hsv_to_rgb(hue as u32, sat as u32), bright as i8)
+        }
+        ColorSpec::Ct(ct) => FlowTuple::ct(duration, ct as u32, bright as i8),
+    }
+}
+
+fn hue_rotate_plan(period: Duration, sat: u8, bright: u8) -> Plan {
+    let sat = sat.min(100);
+    let bright = bright.clamp(1, 100);
+
+    // Steps of 60 degrees (or a divisor) so the bulb interpolates between exact colors
+    let turns = period
+        .as_millis()
+        .div_ceil(6 * MAX_SEGMENT.as_millis())
+        .max(1) as usize;
+    let steps = 6 * turns;
+    let step = period / steps as u32;
+    if steps <= MAX_FLOW_TUPLES && step >= MIN_DURATION {
+        let flow = (1..=steps)
+            .map(|i| {
+                let hue = (360 * i / steps) as u32;
+                FlowTuple::rgb(step, hsv_to_rgb(hue, sat as u32), bright as i8)
+            })
+            .collect();
+        return Plan::Flow(FlowExpresion(flow));
+    }
+
+    let period = period.max(MIN_DURATION);
+    Plan::Frames {
+        period: (period / 360).max(MIN_DURATION),
+        frame: Box::new(move |elapsed| {
+            let turn = elapsed.as_secs_f64() / period.as_secs_f64();
+            Scene::Hsv {
+                hue: (turn.fract() * 360.0).round() as u16 % 360,
+                sat,
+                bright,
+            }
+        }),
+    }
+}
+
+/// Cues late by more than this are skipped by default, see [Timeline::max_lateness].
+const DEFAULT_MAX_LATENESS: Duration = Duration::from_millis(250);
+
+/// Scenes placed at offsets from the start of a show, played on the members of a group.
+///
+/// Built with [`timeline`]. Cues are scheduled against the start of the show (not after the
+/// previous cue), so slow commands do not shift the rest of the show: a late cue is sent as
+/// soon as possible, and cues late by more than [Timeline::max_lateness] are skipped to
+/// catch up. Each bulb plays its cues independently, so a slow bulb does not delay the
+/// others.
+///
+/// Use a [music mode](Bulb::start_music) connection for shows with more than one cue per
+/// second.
+///
+/// # Example
+/// ```
+/// # async fn test(mut group: yeelight::BulbGroup) {
+/// # use yeelight::*;
+/// # use std::time::Duration;
+/// use yeelight::effects::timeline;
+///
+/// let ms = Duration::from_millis;
+/// let show = timeline()
+///     .color(ms(0), "desk", ColorSpec::Rgb(0xff_00_00), 100)
+///     .color(ms(250), "shelf", ColorSpec::Rgb(0x00_00_ff), 100)
+///     .at(ms(500), "desk", Scene::Ct { ct: 2700, bright: 50 });
+/// // Synchronized with a song that started playing at `start`
+/// let start = tokio::time::Instant::now();
+/// let report = show.play_from(&mut group, start).await;
+/// println!("{} cues sent, {} skipped", report.sent, report.skipped);
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    cues: Vec<Cue>,
+    lead: BTreeMap<String, Duration>,
+    max_lateness: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct Cue {
+    at: Duration,
+    bulb: String,
+    scene: Scene,
+}
+
+/// Empty [Timeline] to place cues on.
+pub fn timeline() -> Timeline {
+    Timeline {
+        cues: Vec::new(),
+        lead: BTreeMap::new(),
+        max_lateness: DEFAULT_MAX_LATENESS,
+    }
+}
+
+impl Timeline {
+    /// Set `scene` on the bulb named `bulb` (in the group) at `offset` from the start.
+    pub fn at(mut self, offset: Duration, bulb: &str, scene: Scene) -> Self {
+        // After the cues at the same offset, so they keep the order they were placed in
+        let index = self.cues.partition_point(|cue| cue.at <= offset);
+        let cue = Cue {
+            at: offset,
+            bulb: bulb.to_string(),
+            scene,
+        };
+        self.cues.insert(index, cue);
+        self
+    }
+
+    /// Set a color and brightness on the bulb at `offset` from the start.
+    pub fn color(self, offset: Duration, bulb: &str, color: ColorSpec, bright: u8) -> Self {
+        let scene = match color {
+            ColorSpec::Rgb(rgb) => Scene::Color { rgb, bright },
+            ColorSpec::Hsv(hue, sat) => Scene::Hsv { hue, sat, bright },
+            ColorSpec::Ct(ct) => Scene::Ct { ct, bright },
+        };
+        self.at(offset, bulb, scene)
+    }
+
+    /// Send the cues of `bulb` earlier by `lead`, to compensate its latency.
+    pub fn lead(mut self, bulb: &str, lead: Duration) -> Self {
+        self.lead.insert(bulb.to_string(), lead);
+        self
+    }
+
+    /// Skip the cues that can not be sent within `max_lateness` of their time (250ms by
+    /// default).
+    pub fn max_lateness(mut self, max_lateness: Duration) -> Self {
+        self.max_lateness = max_lateness;
+        self
+    }
+
+    /// Offset of the last cue.
+    pub fn duration(&self) -> Duration {
+        self.cues.last().map_or(Duration::ZERO, |cue| cue.at)
+    }
+
+    /// Play the show starting now.
+    pub async fn play(&self, group: &mut BulbGroup) -> TimelineReport {
+        self.play_from(group, tokio::time::Instant::now()).await
+    }
+
+    /// Play the show as if it had started at `start` (which may be in the past), to keep it
+    /// in sync with an external clock such as the position of a song.
+    ///
+    /// Cues of bulbs that are not in the group are counted as skipped.
+    pub async fn play_from(
+        &self,
+        group: &mut BulbGroup,
+        start: tokio::time::Instant,
+    ) -> TimelineReport {
+        let names: Vec<String> = group.names().map(str::to_string).collect();
+        let missing = self
+            .cues
+            .iter()
+            .filter(|cue| !names.contains(&cue.bulb))
+            .count();
+
+        let players = group.iter_mut().map(|(name, bulb)| {
+            let label = label(name, bulb);
+            let lead = self.lead.get(name).copied().unwrap_or_default();
+            let cues = self.cues.iter().filter(move |cue| cue.bulb == name);
+            async move {
+                let mut report = TimelineReport::default();
+                for cue in cues {
+                    let due = start + cue.at;
+                    tokio::time::sleep_until(due.checked_sub(lead).unwrap_or(due)).await;
+                    let lateness = tokio::time::Instant::now().saturating_duration_since(due);
+                    if lateness > self.max_lateness {
+                        report.skipped += 1;
+                        continue;
+                    }
+                    report.max_lateness = report.max_lateness.max(lateness);
+                    match bulb.set_scene(cue.scene.clone()).await {
+                        Ok(_) => report.sent += 1,
+                        Err(e) => report.errors.push((cue.at, e.context(label.clone()))),
+                    }
+                }
+                report
+            }
+        });
+
+        let mut report = TimelineReport {
+            skipped: missing,
+            ..Default::default()
+        };
+        for player in join_all(players).await {
+            report.sent += player.sent;
+            report.skipped += player.skipped;
+            report.max_lateness = report.max_lateness.max(player.max_lateness);
+            report.errors.extend(player.errors);
+        }
+        report.errors.sort_by_key(|(at, _)| *at);
+        report
+    }
+}
+
+/// Result of playing a [Timeline].
+#[derive(Debug, Default)]
+pub struct TimelineReport {
+    /// Cues sent.
+    pub sent: usize,
+    /// Cues skipped because they were too late or their bulb is not in the group.
+    pub skipped: usize,
+    /// Largest delay of a sent cue after its time.
+    pub max_lateness: Duration,
+    /// Cues that failed, by offset.
+    pub errors: Vec<(Duration, BulbError)>,
+}
+
+/// Color captured by an ambilight source (such as the average color of the screen).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AmbilightFrame {
+    /// Color in RGB format (`0x00_00_00` to `0xff_ff_ff`).
+    pub rgb: u32,
+    /// When the color was captured.
+    pub captured: Instant,
+}
+
+impl AmbilightFrame {
+    /// Frame captured now.
+    pub fn new(rgb: u32) -> Self {
+        Self {
+            rgb,
+            captured: Instant::now(),
+        }
+    }
+}
+
+/// Options for [`ambilight`] and [`ColorPredictor`].
+#[derive(Debug, Clone, Copy)]
+pub struct AmbilightOptions {
+    /// Extra time the colors are predicted ahead, added to the measured latency (for
+    /// instance, the time the bulb takes to render a change).
+    pub lead: Duration,
+    /// Time constant of the smoothing filter. Longer values reduce flicker at the cost of
+    /// tracking fast changes later. Zero disables it.
+    pub smoothing: Duration,
+    /// Latency from sending a command to the light changing, used when it can not be
+    /// measured (music mode connections get no responses).
+    pub fallback_latency: Duration,
+    /// Longest time the colors are extrapolated ahead.
+    pub max_prediction: Duration,
+    /// Changes smaller than this (in every RGB channel) are not sent, to avoid flicker.
+    pub threshold: u8,
+}
+
+impl Default for AmbilightOptions {
+    fn default() -> Self {
+        Self {
+            lead: Duration::ZERO,
+            smoothing: Duration::from_millis(100),
+            fallback_latency: Duration::from_millis(30),
+            max_prediction: Duration::from_millis(300),
+            threshold: 2,
+        }
+    }
+}
+
+/// Smoothing and latency compensation of the colors of a real time source.
+///
+/// Each color is filtered with an exponential smoothing that follows the trend of the
+/// colors (a critically damped alpha-beta filter), and extrapolated ahead by the average
+/// end-to-end latency: the time from the capture to the command being sent, plus the time
+/// for the command to reach the light (estimated from the response times, see
+/// [EffectTarget::latency]).
+///
+/// Used by [`ambilight`], it can also be used on its own to filter the colors sent in other
+/// ways.
+#[derive(Debug, Clone)]
+pub struct ColorPredictor {
+    options: AmbilightOptions,
+    /// Capture time, filtered color and its change per second of the last frame.
+    last: Option<(Instant, [f64; 3], [f64; 3])>,
+    latency: Option<Duration>,
+}
+
+impl ColorPredictor {
+    pub fn new(options: AmbilightOptions) -> Self {
+        Self {
+            options,
+            last: None,
+            latency: None,
+        }
+    }
+
+    /// Filter the color captured at `captured` and return the color to send now.
+    ///
+    /// `latency` is the measured time for a command to reach the light, if known.
+    pub fn update(&mut self, captured: Instant, rgb: u32, latency: Option<Duration>) -> u32 {
+        let sample = [16, 8, 0].map(|shift| ((rgb >> shift) & 0xff) as f64);
+        let (color, trend) = match self.last {
+            Some((last, color, trend)) if captured > last => {
+                let dt = (captured - last).as_secs_f64();
+                let alpha = match self.options.smoothing.as_secs_f64() {
+                    tau if tau > 0.0 => 1.0 - (-dt / tau).exp(),
+                    _ => 1.0,
+                };
+                let beta = alpha * alpha / (2.0 - alpha);
+                let mut next = ([0.0; 3], [0.0; 3]);
+                for i in 0..3 {
+                    let predicted = color[i] + trend[i] * dt;
+                    let residual = sample[i] - predicted;
+                    next.0[i] = predicted + alpha * residual;
+                    next.1[i] = trend[i] + beta * residual / dt;
+                }
+                next
+            }
+            // First frame, or frames out of order
+            _ => (sample, [0.0; 3]),
+        };
+        self.last = Some((captured, color, trend));
+
+        let latency = captured.elapsed() + latency.unwrap_or(self.options.fallback_latency);
+        let average = match self.latency {
+            Some(average) => average.mul_f64(0.9) + latency.mul_f64(0.1),
+            None => latency,
+        };
+        self.latency = Some(average);
+
+        let ahead = (average + self.options.lead)
+            .min(self.options.max_prediction)
+            .as_secs_f64();
+        (0..3).fold(0, |rgb, i| {
+            let channel = (color[i] + trend[i] * ahead).round().clamp(0.0, 255.0) as u32;
+            rgb | channel << (16 - 8 * i)
+        })
+    }
+
+    /// Average end-to-end latency (from the capture to the light changing), once a frame
+    /// has been filtered.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+}
+
+/// Make the light follow the colors of a real time source, such as the screen (ambilight).
+///
+/// Frames are filtered with a [ColorPredictor] so the light tracks the source without
+/// visible lag or flicker, and changes below [AmbilightOptions::threshold] are not sent.
+/// The brightness follows the brightest channel of the color. Frames should be sent over
+/// a [music mode](Bulb::start_music) connection; since they get no responses, the latency
+/// of the normal connection can be given as [AmbilightOptions::fallback_latency].
+///
+/// When the sender is dropped the light keeps the last color until the effect is stopped.
+///
+/// # Example
+/// ```
+/// # async fn test(capture_screen: impl Fn() -> u32) {
+/// # use yeelight::*;
+/// # use std::time::Duration;
+/// use yeelight::effects::{ambilight, AmbilightFrame, AmbilightOptions, OnStop};
+///
+/// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+/// bulb.get_prop(&Properties(vec![Property::Power])).await.unwrap();
+/// let options = AmbilightOptions {
+///     fallback_latency: bulb.stats().latency.map_or(Duration::ZERO, |l| l.p50 / 2),
+///     ..Default::default()
+/// };
+/// let music = bulb.start_music("192.168.1.23").await.unwrap();
+/// let (frames, receiver) = tokio::sync::mpsc::channel(4);
+/// let effect = ambilight(music, receiver, options, OnStop::Recover);
+/// loop {
+///     frames.send(AmbilightFrame::new(capture_screen())).await.unwrap();
+///     tokio::time::sleep(Duration::from_millis(50)).await;
+/// }
+/// # }
+/// ```
+pub fn ambilight<T: EffectTarget>(
+    target: T,
+    frames: mpsc::Receiver<AmbilightFrame>,
+    options: AmbilightOptions,
+    on_stop: OnStop,
+) -> EffectHandle<T> {
+    let plan = Plan::Track {
+        frames,
+        predictor: ColorPredictor::new(options),
+        threshold: options.threshold,
+    };
+    EffectHandle::spawn(target, plan, on_stop)
+}
+
+/// Scene with the color scaled to full brightness and the brightness of its brightest channel.
+fn rgb_scene(rgb: u32) -> Scene {
+    let max = [16, 8, 0]
+        .map(|shift| (rgb >> shift) & 0xff)
+        .into_iter()
+        .max();
+    let max = max.unwrap_or_default().max(1);
+    let rgb = [16, 8, 0].iter().fold(0, |scaled, shift| {
+        scaled | (((rgb >> shift) & 0xff) * 255 / max) << shift
+    });
+    Scene::Color {
+        rgb,
+        bright: (max * 100 / 255).max(1) as u8,
+    }
+}
+
+fn max_difference(a: u32, b: u32) -> u8 {
+    [16, 8, 0]
+        .map(|shift| (((a >> shift) & 0xff) as i32 - ((b >> shift) & 0xff) as i32).unsigned_abs())
+        .into_iter()
+        .max()
+        .unwrap_or_default() as u8
+}
+
+/// Options for [`benchmark`].
+#[derive(Debug, Clone)]
+pub struct BenchmarkOptions {
+    /// Frame rates to test, in frames per second.
+    pub fps: Vec<u32>,
+    /// Frames sent at each frame rate.
+    pub frames: u32,
+}
+
+impl Default for BenchmarkOptions {
+    fn default() -> Self {
+        Self {
+            fps: vec![5, 10, 20, 30, 60],
+            frames: 60,
+        }
+    }
+}
+
+/// Measurements at a given frame rate.
+#[derive(Debug, Clone)]
+pub struct FrameRateResult {
+    /// Target frame rate.
+    pub fps: u32,
+    /// Frame rate actually achieved.
+    pub achieved_fps: f64,
+    /// Time taken to write each frame to the connection.
+    pub write_latency: Latency,
+    /// Mean deviation of the time between frames from the target period.
+    pub jitter: Duration,
+    /// Frames that could not be sent on time and were skipped.
+    pub dropped_frames: u32,
+}
+
+impl FrameRateResult {
+    /// The frame rate can be sustained: no frames were dropped and writes and jitter take a
+    /// small fraction of the frame period.
+    pub fn is_sustainable(&self) -> bool {
+        let period = Duration::from_secs_f64(1.0 / self.fps as f64);
+        self.dropped_frames == 0 && self.write_latency.p99 < period / 2 && self.jitter < period / 4
+    }
+}
+
+/// Result of [`benchmark`].
+#[derive(Debug, Clone)]
+pub struct BenchmarkReport {
+    pub results: Vec<FrameRateResult>,
+    /// Highest [sustainable](FrameRateResult::is_sustainable) frame rate tested.
+    pub recommended_fps: Option<u32>,
+}
+
+impl fmt::Display for BenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "fps\tachieved\twrite p50\twrite p99\tjitter\tdropped")?;
+        for r in &self.results {
+            writeln!(
+                f,
+                "{}\t{:.1}\t{:?}\t{:?}\t{:?}\t{}",
+                r.fps,
+                r.achieved_fps,
+                r.write_latency.p50,
+                r.write_latency.p99,
+                r.jitter,
+                r.dropped_frames
+            )?;
+        }
+        match self.recommended_fps {
+            Some(fps) => write!(f, "Recommended: {} fps", fps),
+            None => write!(
+                f,
+                "Recommended: none of the tested frame rates is sustainable"
+            ),
+        }
+    }
+}
+
+/// Measure the frame rates that can be sent to the bulb.
+///
+/// Frames (hue changes) are sent at each of the rates in `options` and the write latency,
+/// jitter and dropped frames are measured on this side of the connection. Bulbs do not
+/// acknowledge commands in music mode, so a congested bulb or network shows up as writes
+/// taking longer (the socket buffers fill up).
+///
+/// This should be run over a [music mode](Bulb::start_music) connection without
+/// [write coalescing](Bulb::coalesce_writes) nor [frame cap](Bulb::max_frame_rate): normal
+/// connections are limited to 60 commands per minute. The color of the bulb is changed and
+/// not restored.
+///
+/// # Example
+/// ```
+/// # async fn test() {
+/// # use yeelight::*;
+/// use yeelight::effects::{benchmark, BenchmarkOptions};
+///
+/// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+/// let mut music = bulb.start_music("192.168.1.23").await.unwrap().max_frame_rate(None);
+/// let report = benchmark(&mut music, &BenchmarkOptions::default()).await.unwrap();
+/// println!("{}", report);
+/// # }
+/// ```
+pub async fn benchmark(
+    bulb: &mut Bulb,
+    options: &BenchmarkOptions,
+) -> Result<BenchmarkReport, BulbError> {
+    let mut results = Vec::with_capacity(options.fps.len());
+    for &fps in &options.fps {
+        results.push(run(bulb, fps.max(1), options.frames.max(2)).await?);
+    }
+
+    let recommended_fps = results
+        .iter()
+        .filter(|r| r.is_sustainable())
+        .map(|r| r.fps)
+        .max();
+
+    Ok(BenchmarkReport {
+        results,
+        recommended_fps,
+    })
+}
+
+async fn run(bulb: &mut Bulb, fps: u32, frames: u32) -> Result<FrameRateResult, BulbError> {
+    let period = Duration::from_secs_f64(1.0 / fps as f64);
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let mut latencies = Vec::with_capacity(frames as usize);
+    let mut deviation = Duration::ZERO;
+    let mut dropped_frames = 0;
+    let mut previous: Option<(tokio::time::Instant, Instant)> = None;
+    let mut first = None;
+
+    for frame in 0..frames {
+        let tick = interval.tick().await;
+        let now = Instant::now();
+
+        if let Some((previous_tick, previous_send)) = previous {
+            let ticks = (tick - previous_tick).as_secs_f64() / period.as_secs_f64();
+            dropped_frames += (ticks.round() as u32).saturating_sub(1);
+
+            let elapsed = now - previous_send;
+            deviation += elapsed.max(period) - elapsed.min(period);
+        }
+        previous = Some((tick, now));
+        first.get_or_insert(now);
+
+        let hue = (frame * 360 / frames) as u16;
+        bulb.set_hsv(hue, 100, Effect::Sudden, Duration::ZERO)
+            .await?;
+        latencies.push(now.elapsed());
+    }
+
+    let write_latency = Latency::from_samples(&mut latencies).expect("at least one frame");
+    let elapsed = match (first, previous) {
+        (Some(first), Some((_, last))) => last - first,
+        _ => Duration::ZERO,
+    };
+    Ok(FrameRateResult {
+        fps,
+        achieved_fps: (frames - 1) as f64 / elapsed.as_secs_f64(),
+        write_latency,
+        jitter: deviation / (frames - 1),
+        dropped_frames,
+    })
+}
+
+/// Result of [`benchmark_requests`].
+#[derive(Debug, Clone)]
+pub struct RequestReport {
+    pub requests: u32,
+    /// Time taken to send all the requests and receive their responses.
+    pub elapsed: Duration,
+    /// Time between sending each request and receiving its response.
+    pub latency: Latency,
+}
+
+impl RequestReport {
+    pub fn requests_per_second(&self) -> f64 {
+        self.requests as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for RequestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "requests\telapsed\treq/s\tlatency p50\tlatency p99")?;
+        write!(
+            f,
+            "{}\t{:?}\t{:.1}\t{:?}\t{:?}",
+            self.requests,
+            self.elapsed,
+            self.requests_per_second(),
+            self.latency.p50,
+            self.latency.p99
+        )
+    }
+}
+
+/// Measure the throughput of requests that wait for a response.
+///
+/// `requests` reads of the `power` property are sent one after the other (never answered
+/// from the cache, whatever the [freshness](Bulb::freshness) policy). Useful to compare
+/// the overhead of the client (such as with [response slots](Bulb::response_slots)) against
+/// a fast bulb or the simulator, since real bulbs limit the rate of commands.
+pub async fn benchmark_requests(
+    bulb: &mut Bulb,
+    requests: u32,
+) -> Result<RequestReport, BulbError> {
+    let requests = requests.max(1);
+    let properties = Properties(vec![Property::Power]);
+    let mut latencies = Vec::with_capacity(requests as usize);
+
+    let start = Instant::now();
+    for _ in 0..requests {
+        let sent = Instant::now();
+        bulb.read_prop(&properties).await?;
+        latencies.push(sent.elapsed());
+    }
+    let elapsed = start.elapsed();
+
+    Ok(RequestReport {
+        requests,
+        elapsed,
+        latency: Latency::from_samples(&mut latencies).expect("at least one request"),
+    })
+}
@@ -0,0 +1,300 @@
+//! Sessions recorded with real bulbs, replayed to test the parsing and the state cache
+//! against the quirks of each firmware without hardware.
+//!
+//! A fixture is a text file with one message per line:
+//!
+//! - `> {...}`: request sent to the bulb.
+//! - `< {...}`: message received from the bulb (response or notification).
+//! - `= {...}`: expected [cached state](crate::Bulb::cached_state) at this point, as a
+//!   [StateSnapshot] (only the listed properties are compared).
+//! - Empty lines and lines starting with `#` are ignored.
+//!
+//! Sessions are recorded by attaching the bulb through a [Recorder]. When replayed, the
+//! requests are sent again through a [Bulb] connected to a fake bulb that checks them
+//! against the recording and answers with the recorded messages.
+//!
+//! # Example
+//! ```
+//! # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+//! # use yeelight::*;
+//! use yeelight::fixture::{Fixture, Recorder};
+//!
+//! // Record a session with a real bulb
+//! let stream = tokio::net::TcpStream::connect("192.168.1.204:55443").await?;
+//! let (stream, transcript) = Recorder::new(stream);
+//! let mut bulb = Bulb::attach_io(stream);
+//! bulb.toggle().await?;
+//! bulb.get_prop(&Properties(vec![Property::Power, Property::Bright])).await?;
+//! transcript.expect(&bulb.cached_state());
+//! transcript.save("tests/fixtures/color-fw18.txt")?;
+//!
+//! // In the tests
+//! Fixture::load("tests/fixtures/color-fw18.txt")?.run().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+
+use crate::protocol::Request;
+use crate::{Bulb, BulbError, StateSnapshot};
+
+/// Time given to the replayed bulb to answer each request and to reach each expected state.
+const STEP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Recorded session, see the [module documentation](self).
+#[derive(Debug, Clone, Default)]
+pub struct Fixture {
+    /// Steps with their line number in the file.
+    steps: Vec<(usize, Step)>,
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    Send(Request),
+    Receive(String),
+    Expect(StateSnapshot),
+}
+
+impl Fixture {
+    /// Parse a fixture in the text format.
+    pub fn parse(content: &str) -> Result<Self, Box<dyn Error>> {
+        let mut steps = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (kind, message) = line.split_at(1);
+            let message = message.trim();
+            let step = match kind {
+                ">" => Step::Send(Request::parse(message).map_err(|e| at(line_number, e))?),
+                "<" => {
+                    serde_json::from_str::<Value>(message).map_err(|e| at(line_number, e))?;
+                    Step::Receive(message.to_string())
+                }
+                "=" => Step::Expect(serde_json::from_str(message).map_err(|e| at(line_number, e))?),
+                _ => return Err(at(line_number, "Lines must start with >, < or =").into()),
+            };
+            steps.push((line_number, step));
+        }
+        Ok(Self { steps })
+    }
+
+    /// Read a fixture file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Replay the session, failing if the requests are not answered as recorded (for
+    /// instance, if a response can not be parsed) or the cached state does not match the
+    /// expected one.
+    ///
+    /// Error responses of the bulb are not failures, they are part of the session.
+    pub async fn run(&self) -> Result<(), Box<dyn Error>> {
+        let (io, remote) = tokio::io::duplex(4096);
+        let bulb_side = tokio::spawn(play_bulb(self.steps.clone(), remote));
+        let mut bulb = Bulb::attach_io(io);
+
+        for (line, step) in &self.steps {
+            match step {
+                Step::Send(request) => {
+                    let send = bulb.writer.send(&request.method, &request.params);
+                    let result = match tokio::time::timeout(STEP_TIMEOUT, send).await {
+                        Ok(result) => result,
+                        Err(_) => return Err(at(*line, "No response to the request").into()),
+                    };
+                    match result {
+                        Ok(Some(values)) if request.method.ends_with("get_prop") => {
+                            let names: Vec<String> =
+                                serde_json::from_value(request.params.clone().into())?;
+                            bulb.cache.properties_by_name(&names, &values);
+                        }
+                        Ok(_) => {}
+                        Err(e) if matches!(e.inner(), BulbError::ErrResponse(..)) => {}
+                        Err(e) => return Err(at(*line, e).into()),
+                    }
+                }
+                Step::Receive(_) => {}
+                Step::Expect(expected) => {
+                    let mut state = bulb.watch();
+                    let matches = |state: &StateSnapshot| {
+                        expected.0.iter().all(|(k, v)| state.0.get(k) == Some(v))
+                    };
+                    let wait = state.wait_for(|state| matches(state));
+                    if tokio::time::timeout(STEP_TIMEOUT, wait).await.is_err() {
+                        let changes = expected.diff(&bulb.cached_state());
+                        let changes = changes
+                            .iter()
+                            .filter(|change| change.before.is_some())
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        return Err(at(*line, format!("Unexpected state ({})", changes)).into());
+                    }
+                }
+            }
+        }
+
+        drop(bulb);
+        bulb_side.await?.map_err(|e| e.into())
+    }
+}
+
+/// Answer the requests of the client with the recorded messages.
+///
+/// Responses get the id of the last request, so fixtures do not depend on the ids used when
+/// they were recorded.
+async fn play_bulb<S>(steps: Vec<(usize, Step)>, stream: S) -> Result<(), String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (read, mut write) = tokio::io::split(stream);
+    let mut lines = BufReader::new(read).lines();
+    // Id of the last request, used in place of the recorded ids of the responses
+    let mut id = None;
+
+    for (line, step) in steps {
+        match step {
+            Step::Send(expected) => {
+                let Ok(Some(received)) = lines.next_line().await else {
+                    return Err(at(line, "The request was not sent"));
+                };
+                let request = Request::parse(&received).map_err(|e| at(line, e))?;
+                if (&request.method, &request.params) != (&expected.method, &expected.params) {
+                    return Err(at(line, format!("Unexpected request {}", received)));
+                }
+                id = Some(request.id);
+            }
+            Step::Receive(mut message) => {
+                // Sent as recorded (firmware quirks included), unless the id has to change
+                let mut value: Value = serde_json::from_str(&message).map_err(|e| at(line, e))?;
+                let recorded = value.get("id").and_then(Value::as_u64);
+                if let (Some(id), Some(recorded)) = (id, recorded) {
+                    if id != recorded {
+                        value["id"] = id.into();
+                        message = value.to_string();
+                    }
+                }
+                let sent = write.write_all(format!("{}\r\n", message).as_bytes()).await;
+                sent.map_err(|e| at(line, e))?;
+            }
+            Step::Expect(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn at(line: usize, error: impl std::fmt::Display) -> String {
+    format!("Fixture line {}: {}", line, error)
+}
+
+/// Stream wrapper recording the messages exchanged with the bulb in the fixture format.
+///
+/// **See:** the [module documentation](self).
+#[derive(Debug)]
+pub struct Recorder<S> {
+    inner: S,
+    transcript: Transcript,
+    /// Partial lines read and written.
+    read: Vec<u8>,
+    written: Vec<u8>,
+}
+
+/// Messages recorded by a [Recorder].
+#[derive(Debug, Clone, Default)]
+pub struct Transcript(Arc<Mutex<String>>);
+
+impl<S> Recorder<S> {
+    pub fn new(inner: S) -> (Self, Transcript) {
+        let transcript = Transcript::default();
+        let recorder = Self {
+            inner,
+            transcript: transcript.clone(),
+            read: Vec::new(),
+            written: Vec::new(),
+        };
+        (recorder, transcript)
+    }
+}
+
+impl Transcript {
+    /// Recorded session in the fixture format.
+    pub fn fixture(&self) -> String {
+        self.lock().clone()
+    }
+
+    /// Write the recorded session to a fixture file.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.fixture())
+    }
+
+    /// Record that the cached state is expected to be `state` at this point.
+    pub fn expect(&self, state: &StateSnapshot) {
+        let state = serde_json::to_string(state).expect("Snapshots serialize");
+        self.lock().push_str(&format!("= {}\n", state));
+    }
+
+    /// Move the complete lines of `buffer` to the transcript.
+    fn push_lines(&self, prefix: char, buffer: &mut Vec<u8>) {
+        while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if !line.trim().is_empty() {
+                self.lock()
+                    .push_str(&format!("{} {}\n", prefix, line.trim()));
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, String> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorder<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.read.extend_from_slice(&buf.filled()[start..]);
+        this.transcript.push_lines('<', &mut this.read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorder<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.written.extend_from_slice(&buf[..written]);
+        this.transcript.push_lines('>', &mut this.written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::{Bulb, BulbError, CfAction, FlowExpresion, Property, StateSnapshot};
+
+/// How a flow started with [`Bulb::start_flow`] ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowEnd {
+    /// The flow ran for its whole duration.
+    Completed,
+    /// The bulb reported that the flow stopped before its expected end (it was stopped,
+    /// replaced by another command or the bulb was turned off).
+    Stopped,
+}
+
+/// Progress of a running color flow, see [`Bulb::start_flow`].
+#[derive(Debug)]
+pub struct FlowHandle {
+    started: Instant,
+    /// `None` for infinite flows.
+    duration: Option<Duration>,
+    flowing: Property,
+    state: watch::Receiver<StateSnapshot>,
+    /// The bulb has reported that the flow is running.
+    seen_flowing: bool,
+    ended: Option<FlowEnd>,
+}
+
+impl FlowExpresion {
+    /// Time taken to perform `count` state changes (`None` if `count` is `0`, which loops
+    /// forever, or the expression is empty).
+    pub fn duration(&self, count: u8) -> Option<Duration> {
+        if count == 0 || self.0.is_empty() {
+            return None;
+        }
+        Some(
+            self.0
+                .iter()
+                .cycle()
+                .take(count as usize)
+                .map(|tuple| tuple.duration)
+                .sum(),
+        )
+    }
+}
+
+impl FlowHandle {
+    /// Estimated duration of the flow (`None` for infinite flows).
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    /// Time since the flow was started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Estimated time until the flow ends (`None` for infinite flows).
+    pub fn remaining(&self) -> Option<Duration> {
+        match self.ended {
+            Some(_) => Some(Duration::ZERO),
+            None => self.duration.map(|d| d.saturating_sub(self.elapsed())),
+        }
+    }
+
+    /// Fraction of the flow performed, from `0.0` to `1.0` (`None` for infinite flows).
+    pub fn progress(&self) -> Option<f64> {
+        if self.ended.is_some() {
+            return Some(1.0);
+        }
+        let duration = self.duration?;
+        if duration.is_zero() {
+            return Some(1.0);
+        }
+        Some((self.elapsed().as_secs_f64() / duration.as_secs_f64()).min(1.0))
+    }
+
+    /// How the flow ended, if it did.
+    pub fn ended(&mut self) -> Option<FlowEnd> {
+        self.check_state();
+        if self.ended.is_none() && self.remaining() == Some(Duration::ZERO) {
+            self.ended = Some(FlowEnd::Completed);
+        }
+        self.ended
+    }
+
+    /// Wait for the flow to end.
+    ///
+    /// Early termination is detected with the `flowing` property notifications of the bulb
+    /// (see [Bulb::watch]). Without notifications, this resolves when the estimated duration
+    /// has elapsed, or never for infinite flows.
+    pub async fn finished(&mut self) -> FlowEnd {
+        let deadline = self.duration.map(|d| self.started + d);
+        let mut notifications = true;
+
+        while self.ended.is_none() {
+            let expired = async move {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                changed = self.state.changed(), if notifications => match changed {
+                    Ok(()) => self.check_state(),
+                    Err(_) => notifications = false,
+                },
+                _ = expired => self.ended = Some(FlowEnd::Completed),
+            }
+        }
+        self.ended.unwrap_or(FlowEnd::Completed)
+    }
+
+    fn check_state(&mut self) {
+        if self.ended.is_some() {
+            return;
+        }
+        let flowing = self
+            .state
+            .borrow_and_update()
+            .get(self.flowing)
+            .map(str::to_string);
+        match flowing.as_deref() {
+            Some("1") => self.seen_flowing = true,
+            Some("0") if self.seen_flowing => {
+                let completed = self.remaining().is_some_and(|r| r < Duration::from_secs(1));
+                self.ended = Some(match completed {
+                    true => FlowEnd::Completed,
+                    false => FlowEnd::Stopped,
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Bulb {
+    /// Start a color flow (see [Bulb::start_cf]) and track its progress.
+    ///
+    /// The duration of finite flows (`count > 0`) is estimated from the durations of the
+    /// tuples, so UIs can show when the effect ends.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// let flow = FlowExpresion(vec![
+    ///     FlowTuple::rgb(Duration::from_secs(1), 0xff_00_00, 100),
+    ///     FlowTuple::rgb(Duration::from_secs(1), 0x00_00_ff, 100),
+    /// ]);
+    /// let mut handle = bulb.start_flow(20, CfAction::Recover, flow).await.unwrap();
+    /// println!("Effect ends in {:?}", handle.remaining().unwrap());
+    /// match handle.finished().await {
+    ///     FlowEnd::Completed => println!("Done"),
+    ///     FlowEnd::Stopped => println!("Interrupted"),
+    /// }
+    /// # }
+    /// ```
+    pub async fn start_flow(
+        &mut self,
+        count: u8,
+        action: CfAction,
+        flow: FlowExpresion,
+    ) -> Result<FlowHandle, BulbError> {
+        let duration = flow.duration(count);
+        self.start_cf(count, action, flow).await?;
+        Ok(self.track_flow(Property::Flowing, duration))
+    }
+
+    /// Start a color flow on the **background** light and track its progress.
+    ///
+    /// **See:** [Bulb::start_flow]
+    pub async fn bg_start_flow(
+        &mut self,
+        count: u8,
+        action: CfAction,
+        flow: FlowExpresion,
+    ) -> Result<FlowHandle, BulbError> {
+        let duration = flow.duration(count);
+        self.bg_start_cf(count, action, flow).await?;
+        Ok(self.track_flow(Property::BgFlowing, duration))
+    }
+
+    fn track_flow(&self, flowing: Property, duration: Option<Duration>) -> FlowHandle {
+        let mut handle = FlowHandle {
+            started: Instant::now(),
+            duration,
+            flowing,
+            state: self.watch(),
+            seen_flowing: false,
+            ended: None,
+        };
+        // The notification of the start of the flow may have arrived before the response
+        handle.check_state();
+        handle
+    }
+}
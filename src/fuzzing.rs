@@ -0,0 +1,56 @@
+//! Entry points for fuzzers and property based tests.
+//!
+//! Only available with the `fuzzing` feature. These functions feed arbitrary data through the
+//! same parsing path used for bulb connections and must never panic.
+//!
+//! # Example
+//! ```ignore
+//! // fuzz/fuzz_targets/reader.rs
+//! #![no_main]
+//! libfuzzer_sys::fuzz_target!(|data: &[u8]| yeelight::fuzzing::feed(data));
+//! ```
+
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, Mutex};
+
+use crate::cache::StateCache;
+use crate::protocol::JsonResponse;
+use crate::reader::{Reader, Responses};
+use crate::stats::StatsCollector;
+
+/// Feed raw bytes (as received from the socket) to a connection reader.
+///
+/// Notifications found in the data are received and discarded.
+pub fn feed(data: &[u8]) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("Could not build runtime");
+
+    runtime.block_on(async {
+        let (sender, mut receiver) = mpsc::channel(10);
+        let drain = tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+
+        let reader = Reader::new(
+            Arc::new(Responses::default()),
+            Arc::new(Mutex::new(Some(sender))),
+            StatsCollector::new(),
+            StateCache::new(),
+        );
+        reader
+            .start(data)
+            .await
+            .expect("Reading from a slice can not fail");
+        // Drops the notification sender, so the drain ends
+        drop(reader);
+
+        drain.await.expect("Drain task panicked");
+    });
+}
+
+/// Parse a single line with the protocol parser.
+pub fn parse_line(line: &str) {
+    if let Ok(response) = JsonResponse::parse(line) {
+        let _ = response.id();
+    }
+}
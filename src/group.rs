@@ -0,0 +1,451 @@
+//! Groups of bulbs controlled together.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use futures_util::future::{join_all, BoxFuture};
+use tokio::sync::mpsc;
+
+use crate::rate::RateLimit;
+use crate::shutdown;
+use crate::state::property_name;
+use crate::{
+    Bulb, BulbError, Effect, Mode, Notification, Power, Properties, Property, Response, Scene,
+    StateSnapshot,
+};
+
+/// Result of a group operation for each member of the group (in the same order as the
+/// members).
+pub type GroupResults = Vec<(String, Result<Option<Response>, BulbError>)>;
+
+/// Options for [`BulbGroup::apply_verified`].
+#[derive(Debug, Clone, Copy)]
+pub struct VerifyOptions {
+    /// Number of times the scene is sent again to bulbs that did not reach it.
+    pub retries: u8,
+    /// Time to wait after sending the scene before reading back the properties.
+    pub delay: Duration,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            retries: 2,
+            delay: Duration::from_millis(300),
+        }
+    }
+}
+
+/// Outcome of [`BulbGroup::apply_verified`] for each member.
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    /// Bulbs that reached the scene on the first attempt.
+    pub verified: Vec<String>,
+    /// Bulbs that reached the scene after being retried.
+    pub retried: Vec<String>,
+    /// Bulbs that did not reach the scene.
+    pub failed: Vec<(String, ApplyError)>,
+}
+
+impl ApplyReport {
+    /// Whether all the members reached the scene.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Change of the state of a member, see [`BulbGroup::state_changes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateChange {
+    /// Properties that changed and their new values.
+    pub changed: BTreeMap<String, String>,
+    /// Known state of the bulb after the change.
+    pub state: StateSnapshot,
+}
+
+impl StateChange {
+    /// New value of `property`, `None` if it did not change.
+    pub fn get(&self, property: Property) -> Option<&str> {
+        self.changed
+            .get(&property_name(property))
+            .map(String::as_str)
+    }
+}
+
+/// Reason why a bulb did not reach the scene.
+#[derive(Debug)]
+pub enum ApplyError {
+    Bulb(BulbError),
+    /// The bulb did not answer the property query (`no_response` connection).
+    NoResponse,
+    /// Properties that did not match: property, expected value and actual value.
+    Mismatch(Vec<(Property, String, String)>),
+}
+
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bulb(e) => e.fmt(f),
+            Self::NoResponse => write!(f, "No response from bulb"),
+            Self::Mismatch(mismatches) => {
+                write!(f, "State mismatch:")?;
+                for (property, expected, actual) in mismatches {
+                    write!(f, " {}={} (expected {})", property, actual, expected)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApplyError {}
+
+/// Named collection of [`Bulb`]s.
+///
+/// Operations are sent to all the members concurrently and the results of each member are
+/// returned, so a failing bulb does not prevent the others from being updated.
+///
+/// # Example
+/// ```
+/// # async fn test() {
+/// # use yeelight::{Bulb, BulbGroup};
+/// let mut group = BulbGroup::new();
+/// group.add("desk", Bulb::connect("192.168.1.204", 0).await.unwrap());
+/// group.add("shelf", Bulb::connect("192.168.1.205", 0).await.unwrap());
+///
+/// for (name, result) in group.for_each(|bulb| Box::pin(bulb.toggle())).await {
+///     if let Err(e) = result {
+///         eprintln!("{}: {}", name, e);
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Default)]
+pub struct BulbGroup {
+    members: Vec<(String, Bulb)>,
+    /// Budget shared by the members, see [BulbGroup::rate_limit].
+    pub(crate) rate_limit: Option<RateLimit>,
+}
+
+impl BulbGroup {
+    /// Create an empty group
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a bulb to the group, replacing any member with the same name.
+    pub fn add(&mut self, name: impl Into<String>, mut bulb: Bulb) {
+        let name = name.into();
+        self.remove(&name);
+        if let Some(limit) = &self.rate_limit {
+            bulb.writer.set_rate_limit(limit.clone());
+        }
+        self.members.push((name, bulb));
+    }
+
+    /// Remove a bulb from the group.
+    pub fn remove(&mut self, name: &str) -> Option<Bulb> {
+        let index = self.members.iter().position(|(n, _)| n == name)?;
+        Some(self.members.remove(index).1)
+    }
+
+    /// Get a member of the group.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Bulb> {
+        self.members
+            .iter_mut()
+            .find(|(n, _)| n == name)
+            .map(|(_, bulb)| bulb)
+    }
+
+    /// Names of the members of the group.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.members.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Iterate over the members of the group.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Bulb)> {
+        self.members
+            .iter()
+            .map(|(name, bulb)| (name.as_str(), bulb))
+    }
+
+    /// Iterate over the members of the group.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &mut Bulb)> {
+        self.members
+            .iter_mut()
+            .map(|(name, bulb)| (name.as_str(), bulb))
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Receive the notifications of all members, tagged with the name of the bulb.
+    ///
+    /// This replaces the notification channel of every member (see [Bulb::set_notify]).
+    pub async fn notifications(&mut self) -> mpsc::Receiver<(String, Notification)> {
+        let (sender, receiver) = mpsc::channel(16);
+        for (name, bulb) in &mut self.members {
+            let mut notifications = bulb.get_notify().await;
+            let (name, sender) = (name.clone(), sender.clone());
+            shutdown::spawn_watcher(async move {
+                while let Some(notification) = notifications.recv().await {
+                    if sender.send((name.clone(), notification)).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        receiver
+    }
+
+    /// Receive the state changes of all members, tagged with the name of the bulb.
+    ///
+    /// Unlike [BulbGroup::notifications], this follows the [state](Bulb::watch) of each
+    /// member, so the notification channels are left untouched and the changes read with
+    /// [Bulb::get_prop] are also received.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test(group: yeelight::BulbGroup) {
+    /// let mut changes = group.state_changes();
+    /// while let Some((name, change)) = changes.recv().await {
+    ///     for (property, value) in &change.changed {
+    ///         println!("{}: {} = {}", name, property, value);
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn state_changes(&self) -> mpsc::Receiver<(String, StateChange)> {
+        let (sender, receiver) = mpsc::channel(16);
+        for (name, bulb) in &self.members {
+            let mut state = bulb.watch();
+            let (name, sender) = (name.clone(), sender.clone());
+            shutdown::spawn_watcher(async move {
+                let mut previous = state.borrow_and_update().clone();
+                while state.changed().await.is_ok() {
+                    let current = state.borrow_and_update().clone();
+                    let changed: BTreeMap<_, _> = current
+                        .0
+                        .iter()
+                        .filter(|(property, value)| previous.0.get(*property) != Some(value))
+                        .map(|(property, value)| (property.clone(), value.clone()))
+                        .collect();
+                    previous = current.clone();
+                    if changed.is_empty() {
+                        continue;
+                    }
+                    let change = StateChange {
+                        changed,
+                        state: current,
+                    };
+                    if sender.send((name.clone(), change)).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        receiver
+    }
+
+    /// Receive whether each member is connected: the current availability of all the members
+    /// and then the bulbs whose connection is closed (see [Bulb::disconnected]).
+    ///
+    /// Members added to the group later are not followed.
+    pub fn availability(&self) -> mpsc::Receiver<(String, bool)> {
+        let (sender, receiver) = mpsc::channel(self.members.len().max(1));
+        for (name, bulb) in &self.members {
+            let mut connected = bulb.connected.clone();
+            let (name, sender) = (name.clone(), sender.clone());
+            shutdown::spawn_watcher(async move {
+                let online = *connected.borrow_and_update();
+                if sender.send((name.clone(), online)).await.is_err() || !online {
+                    return;
+                }
+                let _ = connected.wait_for(|connected| !connected).await;
+                let _ = sender.send((name, false)).await;
+            });
+        }
+        receiver
+    }
+
+    /// Run the same operation on all members concurrently.
+    pub async fn for_each<F>(&mut self, f: F) -> GroupResults
+    where
+        F: for<'a> Fn(&'a mut Bulb) -> BoxFuture<'a, Result<Option<Response>, BulbError>>,
+    {
+        let (names, futures): (Vec<_>, Vec<_>) = self
+            .members
+            .iter_mut()
+            .map(|(name, bulb)| {
+                let label = label(name, bulb);
+                let future = f(bulb);
+                (name.clone(), async move {
+                    future.await.map_err(|e| e.context(label))
+                })
+            })
+            .unzip();
+
+        names.into_iter().zip(join_all(futures).await).collect()
+    }
+
+    /// [Bulb::set_power] on all members.
+    pub async fn set_power(
+        &mut self,
+        power: Power,
+        effect: Effect,
+        duration: Duration,
+        mode: Mode,
+    ) -> GroupResults {
+        self.for_each(|bulb| Box::pin(bulb.set_power(power, effect, duration, mode)))
+            .await
+    }
+
+    /// [Bulb::set_bright] on all members.
+    pub async fn set_bright(
+        &mut self,
+        brightness: u8,
+        effect: Effect,
+        duration: Duration,
+    ) -> GroupResults {
+        self.for_each(|bulb| Box::pin(bulb.set_bright(brightness, effect, duration)))
+            .await
+    }
+
+    /// Multiply the brightness of each member by `factor`, keeping the relative levels of
+    /// the bulbs (clamped to `1`-`100`).
+    ///
+    /// The brightness is taken from the [cached state](Bulb::cached_state) and only read
+    /// from the bulbs that have not reported it yet. Members that are off are left untouched
+    /// (their result is `Ok(None)`).
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test(mut group: yeelight::BulbGroup) {
+    /// # use yeelight::Effect;
+    /// # use std::time::Duration;
+    /// // Dim the room by 20%
+    /// group.scale_brightness(0.8, Effect::Smooth, Duration::from_millis(500)).await;
+    /// # }
+    /// ```
+    pub async fn scale_brightness(
+        &mut self,
+        factor: f64,
+        effect: Effect,
+        duration: Duration,
+    ) -> GroupResults {
+        self.for_each(|bulb| {
+            Box::pin(async move {
+                let mut state = bulb.cached_state();
+                if state.get(Property::Bright).is_none() {
+                    let props = Properties(vec![Property::Power, Property::Bright]);
+                    if let Some(values) = bulb.get_prop(&props).await? {
+                        state.0.extend(
+                            ["power", "bright"]
+                                .iter()
+                                .map(|p| p.to_string())
+                                .zip(values),
+                        );
+                    }
+                }
+                if state.get(Property::Power) == Some("off") {
+                    return Ok(None);
+                }
+                let bright = state
+                    .get(Property::Bright)
+                    .and_then(|b| b.parse::<f64>().ok())
+                    .ok_or_else(|| {
+                        BulbError::Io(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Unknown brightness",
+                        ))
+                    })?;
+                let bright = (bright * factor).round().clamp(1.0, 100.0) as u8;
+                bulb.set_bright(bright, effect, duration).await
+            })
+        })
+        .await
+    }
+
+    /// [Bulb::set_scene] on all members.
+    pub async fn set_scene(&mut self, scene: &Scene) -> GroupResults {
+        self.for_each(|bulb| Box::pin(bulb.set_scene(scene.clone())))
+            .await
+    }
+
+    /// Apply the scene to all members and read back their properties to verify it was
+    /// applied, retrying the members that did not reach it.
+    ///
+    /// Bulbs occasionally ignore commands (for example, with Wi-Fi congestion) while still
+    /// answering `ok`.
+    pub async fn apply_verified(&mut self, scene: &Scene, options: VerifyOptions) -> ApplyReport {
+        let results = join_all(self.members.iter_mut().map(|(name, bulb)| async move {
+            let label = label(name, bulb);
+            let result = apply(bulb, scene, options).await.map_err(|e| match e {
+                ApplyError::Bulb(e) => ApplyError::Bulb(e.context(label)),
+                e => e,
+            });
+            (name.clone(), result)
+        }))
+        .await;
+
+        let mut report = ApplyReport::default();
+        for (name, result) in results {
+            match result {
+                Ok(0) => report.verified.push(name),
+                Ok(_) => report.retried.push(name),
+                Err(e) => report.failed.push((name, e)),
+            }
+        }
+        report
+    }
+}
+
+/// Name and address of a member, to identify it in errors.
+pub(crate) fn label(name: &str, bulb: &Bulb) -> String {
+    match bulb.peer_addr() {
+        Some(addr) => format!("{} ({})", name, addr),
+        None => name.to_string(),
+    }
+}
+
+/// Apply the scene and verify it, returning the number of retries needed.
+async fn apply(bulb: &mut Bulb, scene: &Scene, options: VerifyOptions) -> Result<u8, ApplyError> {
+    let expected = scene.expected_properties();
+    let properties = Properties(expected.iter().map(|(p, _)| *p).collect());
+
+    let mut attempt = 0;
+    loop {
+        bulb.set_scene(scene.clone())
+            .await
+            .map_err(ApplyError::Bulb)?;
+        tokio::time::sleep(options.delay).await;
+
+        let values = bulb
+            .read_prop(&properties)
+            .await
+            .map_err(ApplyError::Bulb)?
+            .ok_or(ApplyError::NoResponse)?;
+
+        let mismatches: Vec<_> = expected
+            .iter()
+            .zip(values)
+            .filter(|((_, expected), actual)| expected != actual)
+            .map(|((property, expected), actual)| (*property, expected.clone(), actual))
+            .collect();
+
+        if mismatches.is_empty() {
+            return Ok(attempt);
+        }
+        if attempt >= options.retries {
+            return Err(ApplyError::Mismatch(mismatches));
+        }
+        attempt += 1;
+    }
+}
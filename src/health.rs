@@ -0,0 +1,73 @@
+use tokio::sync::broadcast;
+
+use crate::Bulb;
+
+/// Change in the connection to a bulb, see [`Bulb::health_events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthEvent {
+    /// The connection was opened again after being lost.
+    Connected,
+    /// The connection ended.
+    Disconnected(DisconnectReason),
+    /// Attempt to reconnect (starting at 1) is being made, see
+    /// [ConnectOptions::reconnect](crate::ConnectOptions::reconnect).
+    Reconnecting(u32),
+    /// Commands are sent in music mode faster than the bulb can show them (the cap, in frames
+    /// per second), and are being delayed, see [Bulb::max_frame_rate]. Sent again only after
+    /// the rate goes back under the cap.
+    FrameRateExceeded(u32),
+}
+
+/// Why the connection to a bulb ended, see [HealthEvent::Disconnected].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Closed with [Bulb::close].
+    Closed,
+    /// Closed by the bulb (for instance, when it restarts or closes an idle connection).
+    ClosedByBulb,
+    /// Error reading from the connection.
+    Error(String),
+}
+
+/// Events kept for receivers that are not keeping up.
+pub(crate) const CAPACITY: usize = 16;
+
+impl Bulb {
+    /// Receive the changes in the connection to the bulb, to react to them instead of
+    /// finding out through failed commands.
+    ///
+    /// Only the events after the call are received, use [Bulb::is_connected] for the current
+    /// state. Receivers that fall behind skip the oldest events (see
+    /// [RecvError::Lagged](broadcast::error::RecvError::Lagged)).
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// let options = ConnectOptions::default().reconnect(true);
+    /// let bulb = Bulb::connect_with("192.168.1.204", 0, &options).await.unwrap();
+    /// let mut events = bulb.health_events();
+    /// while let Ok(event) = events.recv().await {
+    ///     match event {
+    ///         HealthEvent::Connected => println!("Online"),
+    ///         HealthEvent::Disconnected(reason) => println!("Offline: {:?}", reason),
+    ///         HealthEvent::Reconnecting(attempt) => println!("Reconnecting ({})", attempt),
+    ///         HealthEvent::FrameRateExceeded(fps) => println!("Over {} frames per second", fps),
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn health_events(&self) -> broadcast::Receiver<HealthEvent> {
+        self.health.subscribe()
+    }
+}
+
+impl DisconnectReason {
+    /// Reason of a connection that ended with `result` when read.
+    pub(crate) fn of(result: &std::io::Result<()>) -> Self {
+        match result {
+            Ok(()) => Self::ClosedByBulb,
+            Err(e) => Self::Error(e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! High level helpers built on top of the API methods.
+
+use std::time::Duration;
+
+use crate::{
+    Bulb, BulbError, CfAction, Effect, FlowExpresion, FlowTuple, Mode, Power, Properties, Property,
+    Response, Scene, ToParam,
+};
+
+/// Minimum duration accepted by the bulb for smooth changes and flow tuples.
+pub(crate) const MIN_DURATION: Duration = Duration::from_millis(50);
+
+impl Bulb {
+    /// Gradually dim the light to 1% over `duration` and then turn it off.
+    ///
+    /// The fade is done with a color flow that ends by turning off the light, so the brightness
+    /// setting of the bulb is not modified and the next power on will use the original
+    /// brightness instead of staying at 1%.
+    ///
+    /// If the light is already off nothing is sent and `None` is returned.
+    ///
+    /// **NOTE:** When the connection is in [no_response](Bulb::no_response) mode the current
+    /// state can not be read, so the fade is done with [Bulb::set_bright] followed by
+    /// [Bulb::set_power] and the brightness is left at 1%.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::Bulb;
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// bulb.fade_off(Duration::from_secs(30)).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn fade_off(&mut self, duration: Duration) -> Result<Option<Response>, BulbError> {
+        let duration = duration.max(MIN_DURATION);
+
+        let props = Properties(vec![
+            Property::Power,
+            Property::ColorMode,
+            Property::Ct,
+            Property::Rgb,
+            Property::Hue,
+            Property::Sat,
+        ]);
+
+        let values = match self.get_prop(&props).await? {
+            Some(values) => values,
+            None => {
+                self.set_bright(1, Effect::Smooth, duration).await?;
+                tokio::time::sleep(duration).await;
+                return self
+                    .set_power(Power::Off, Effect::Sudden, Duration::ZERO, Mode::Normal)
+                    .await;
+            }
+        };
+
+        let value = |i: usize| values.get(i).map(String::as_str).unwrap_or_default();
+        let number = |i: usize| value(i).parse::<u32>().unwrap_or_default();
+
+        if value(0) == "off" {
+            return Ok(None);
+        }
+
+        let tuple = match value(1) {
+            // RGB mode
+            "1" => FlowTuple::rgb(duration, number(3), 1),
+            // HSV mode
+            "3" => FlowTuple::rgb(duration, hsv_to_rgb(number(4), number(5)), 1),
+            // CT mode (and any other)
+            _ => FlowTuple::ct(duration, number(2), 1),
+        };
+
+        self.start_cf(1, CfAction::Off, FlowExpresion(vec![tuple]))
+            .await
+    }
+
+    /// Switch on or off both the main and the background lights.
+    ///
+    /// `dev_toggle` is the only command of the bulbs acting on both lights, so this sends
+    /// [Bulb::set_power] and [Bulb::bg_set_power] together and waits for both responses,
+    /// returned in that order. An error is returned if any of them fails.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// bulb.set_both_power(Power::On, Effect::Smooth, Duration::from_secs(1), Mode::Normal)
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub async fn set_both_power(
+        &mut self,
+        power: Power,
+        effect: Effect,
+        duration: Duration,
+        mode: Mode,
+    ) -> Result<(Option<Response>, Option<Response>), BulbError> {
+        let params = vec![
+            power.to_param(),
+            effect.to_param(),
+            duration.to_param(),
+            mode.to_param(),
+        ];
+        self.send_both("set_power", "bg_set_power", params).await
+    }
+
+    /// Set the brightness of both the main and the background lights.
+    ///
+    /// **See:** [Bulb::set_both_power]
+    pub async fn set_both_bright(
+        &mut self,
+        brightness: u8,
+        effect: Effect,
+        duration: Duration,
+    ) -> Result<(Option<Response>, Option<Response>), BulbError> {
+        let params = vec![
+            brightness.to_param(),
+            effect.to_param(),
+            duration.to_param(),
+        ];
+        self.send_both("set_bright", "bg_set_bright", params).await
+    }
+
+    /// Save the current state of the light (or `scene`, applied first) as the state it
+    /// powers on into.
+    ///
+    /// The bulbs only save the state while the light is on, so without a scene the power is
+    /// read first and [BulbError::LightOff] is returned if it is off, instead of the error
+    /// response of the firmware. Scenes turn the light on when applied.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// let scene = Scene::Ct { ct: 2700, bright: 80 };
+    /// bulb.save_as_power_on_default(Some(scene)).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn save_as_power_on_default(
+        &mut self,
+        scene: Option<Scene>,
+    ) -> Result<Option<Response>, BulbError> {
+        self.save_default(scene, false).await
+    }
+
+    /// Same as [Bulb::save_as_power_on_default] for the background light.
+    pub async fn bg_save_as_power_on_default(
+        &mut self,
+        scene: Option<Scene>,
+    ) -> Result<Option<Response>, BulbError> {
+        self.save_default(scene, true).await
+    }
+
+    async fn save_default(
+        &mut self,
+        scene: Option<Scene>,
+        bg: bool,
+    ) -> Result<Option<Response>, BulbError> {
+        match (scene, bg) {
+            (Some(scene), false) => {
+                self.set_scene(scene).await?;
+            }
+            (Some(scene), true) => {
+                self.bg_set_scene(scene).await?;
+            }
+            (None, _) => {
+                let power = match bg {
+                    true => Property::BgPower,
+                    false => Property::Power,
+                };
+                // Not known in no_response mode, the bulb decides
+                let values = self.get_prop(&Properties(vec![power])).await?;
+                if values.as_ref().and_then(|v| v.first()).map(String::as_str) == Some("off") {
+                    let method = if bg { "bg_set_default" } else { "set_default" };
+                    return Err(BulbError::LightOff.method_context(method));
+                }
+            }
+        }
+        match bg {
+            true => self.bg_set_default().await,
+            false => self.set_default().await,
+        }
+    }
+
+    async fn send_both(
+        &mut self,
+        method: &str,
+        bg_method: &str,
+        params: Vec<serde_json::Value>,
+    ) -> Result<(Option<Response>, Option<Response>), BulbError> {
+        let mut results = self
+            .writer
+            .send_all(&[(method, params.clone()), (bg_method, params)])
+            .await
+            .into_iter();
+        match (results.next(), results.next()) {
+            (Some(main), Some(bg)) => Ok((main?, bg?)),
+            _ => unreachable!("One result per message"),
+        }
+    }
+}
+
+/// Convert hue (`0` to `359`) and saturation (`0` to `100`) to RGB at full value.
+pub(crate) fn hsv_to_rgb(hue: u32, sat: u32) -> u32 {
+    let hue = (hue % 360) as f64;
+    let sat = sat.min(100) as f64 / 100.0;
+
+    let c = sat;
+    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let m = 1.0 - c;
+
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    let to_byte = |v: f64| ((v + m) * 255.0).round() as u32;
+    (to_byte(r) << 16) | (to_byte(g) << 8) | to_byte(b)
+}
+
+/// Convert an RGB color to hue (`0` to `359`) and saturation (`0` to `100`).
+pub(crate) fn rgb_to_hsv(rgb: u32) -> (u16, u8) {
+    let channel = |shift: u32| ((rgb >> shift) & 0xff) as f64 / 255.0;
+    let (r, g, b) = (channel(16), channel(8), channel(0));
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    if delta == 0.0 {
+        return (0, 0);
+    }
+
+    let hue = match max {
+        _ if max == r => 60.0 * ((g - b) / delta).rem_euclid(6.0),
+        _ if max == g => 60.0 * ((b - r) / delta + 2.0),
+        _ => 60.0 * ((r - g) / delta + 4.0),
+    };
+    let sat = delta / max * 100.0;
+    (hue.round() as u16 % 360, sat.round() as u8)
+}
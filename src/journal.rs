@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::protocol::Request;
+use crate::{Bulb, BulbError};
+
+/// Last desired state of each bulb, to re-apply it after reconnecting.
+///
+/// Bulbs attached with [Bulb::journal] record the last command that set each part of their
+/// state (power, brightness, color or scene, for the main and background lights). Commands
+/// are recorded even if they fail, so changes made while the bulb was unreachable can be
+/// replayed with [Bulb::replay_journal] once the connection is back. Connections opened
+/// again automatically (see [ConnectOptions::backoff](crate::ConnectOptions::backoff)) replay
+/// it by themselves. Only the final state is replayed, not every intermediate command.
+///
+/// Relative changes (such as `set_adjust`) and color flows are not recorded.
+///
+/// # Example
+/// ```
+/// # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+/// # use yeelight::{Bulb, Journal};
+/// let journal = Journal::open("journal.json")?;
+/// loop {
+///     let Ok(bulb) = Bulb::connect("192.168.1.204", 0).await else {
+///         tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+///         continue;
+///     };
+///     let mut bulb = bulb.journal(journal.clone(), "desk");
+///     bulb.replay_journal().await?;
+///     bulb.disconnected().await;
+///     journal.save()?;
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Journal {
+    entries: Arc<Mutex<BTreeMap<String, Entries>>>,
+    path: Option<PathBuf>,
+}
+
+/// Last command of each part of the state of a bulb, by slot name.
+type Entries = BTreeMap<String, Command>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Command {
+    method: String,
+    params: Vec<Value>,
+}
+
+impl Journal {
+    /// Journal kept in memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Journal persisted to a JSON file, loading the states already saved in it (if the file
+    /// exists).
+    ///
+    /// The file is written with [Journal::save].
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let entries = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            entries: Arc::new(Mutex::new(entries)),
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Write the journal to its file (does nothing for journals created with [Journal::new]).
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let content = serde_json::to_string_pretty(&*self.lock())?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// Commands that would be replayed for `bulb`, as `(method, params)` pairs.
+    pub fn desired(&self, bulb: &str) -> Vec<(String, Vec<Value>)> {
+        self.lock()
+            .get(bulb)
+            .map(replay_order)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|c| (c.method, c.params))
+            .collect()
+    }
+
+    /// Forget the desired state of `bulb`.
+    pub fn clear(&self, bulb: &str) {
+        self.lock().remove(bulb);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Entries>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn record(&self, bulb: &str, method: &str, params: &[Value]) {
+        let Some((prefix, name, slot)) = slot(method, params) else {
+            return;
+        };
+
+        let mut journal = self.lock();
+        let entries = journal.entry(bulb.to_string()).or_default();
+        let mut update = |prefix: &str| match slot {
+            "toggle" => {
+                let slot = format!("{}power", prefix);
+                if let Some(power) = entries.get_mut(&slot) {
+                    power.params = toggled(&power.params);
+                }
+            }
+            _ => {
+                if slot == "scene" {
+                    // Scenes set the power, brightness and color at once
+                    for slot in ["power", "bright", "color"] {
+                        entries.remove(&format!("{}{}", prefix, slot));
+                    }
+                }
+                let command = Command {
+                    method: format!("{}{}", prefix, name),
+                    params: params.to_vec(),
+                };
+                entries.insert(format!("{}{}", prefix, slot), command);
+            }
+        };
+        match name {
+            "dev_toggle" => {
+                update("");
+                update("bg_");
+            }
+            _ => update(prefix),
+        }
+    }
+
+    /// Whether sending the command would not change the recorded state of `bulb` (it is the
+    /// last command recorded for its part of the state).
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn is_current(&self, bulb: &str, method: &str, params: &[Value]) -> bool {
+        let Some((prefix, _, slot)) = slot(method, params) else {
+            return false;
+        };
+        slot != "toggle"
+            && self
+                .lock()
+                .get(bulb)
+                .and_then(|entries| entries.get(&format!("{}{}", prefix, slot)))
+                .is_some_and(|c| c.method == method && c.params == params)
+    }
+
+    /// Forget the commands contradicted by the properties notified by `bulb` (the state was
+    /// changed by other means, such as the wall switch or another app).
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn observe(&self, bulb: &str, props: &serde_json::Map<String, Value>) {
+        let mut journal = self.lock();
+        let Some(entries) = journal.get_mut(bulb) else {
+            return;
+        };
+        for (property, value) in props {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            if let Some((slot, expected)) = expected(entries, property) {
+                if expected != value {
+                    entries.remove(&slot);
+                }
+            }
+        }
+    }
+}
+
+/// Prefix (`bg_` for the background light), method name without it and part of the state
+/// set by a command, `None` if it is not recorded.
+fn slot<'a>(method: &'a str, params: &[Value]) -> Option<(&'static str, &'a str, &'static str)> {
+    let (prefix, name) = match method.strip_prefix("bg_") {
+        Some(name) => ("bg_", name),
+        None => ("", method),
+    };
+    let slot = match name {
+        "set_power" => "power",
+        "set_bright" => "bright",
+        "set_ct_abx" | "set_rgb" | "set_hsv" => "color",
+        "set_scene" if params.first().and_then(Value::as_str) != Some("cf") => "scene",
+        "toggle" | "dev_toggle" => "toggle",
+        _ => return None,
+    };
+    Some((prefix, name, slot))
+}
+
+#[cfg_attr(not(unix), allow(dead_code))]
+/// Slot and value of `property` according to the recorded commands, if they set it.
+fn expected(entries: &Entries, property: &str) -> Option<(String, String)> {
+    let (prefix, name) = match property.strip_prefix("bg_") {
+        Some(name) => ("bg_", name),
+        None => ("", property),
+    };
+    let param = |command: &Command, i: usize| {
+        Some(match command.params.get(i)? {
+            Value::String(param) => param.clone(),
+            param => param.to_string(),
+        })
+    };
+    let get = |slot: &str| {
+        let slot = format!("{}{}", prefix, slot);
+        entries.get(&slot).map(|command| (slot, command))
+    };
+
+    let (slot, command) = match name {
+        "power" => get("power").or_else(|| get("scene"))?,
+        "bright" => get("bright").or_else(|| get("scene"))?,
+        "color_mode" | "ct" | "rgb" | "hue" | "sat" => get("color").or_else(|| get("scene"))?,
+        _ => return None,
+    };
+    let method = command.method.trim_start_matches("bg_");
+    let value = match (name, method, param(command, 0).as_deref()) {
+        ("power", "set_power", _) => param(command, 0),
+        ("power", "set_scene", _) => Some("on".to_string()),
+        ("bright", "set_bright", _) => param(command, 0),
+        ("bright", "set_scene", Some("hsv")) => param(command, 3),
+        ("bright", "set_scene", Some("color" | "ct" | "auto_delay_off")) => param(command, 2),
+        ("color_mode", "set_rgb", _) | ("color_mode", "set_scene", Some("color")) => {
+            Some("1".to_string())
+        }
+        ("color_mode", "set_ct_abx", _) | ("color_mode", "set_scene", Some("ct")) => {
+            Some("2".to_string())
+        }
+        ("color_mode", "set_hsv", _) | ("color_mode", "set_scene", Some("hsv")) => {
+            Some("3".to_string())
+        }
+        ("ct", "set_ct_abx", _) | ("rgb", "set_rgb", _) | ("hue", "set_hsv", _) => {
+            param(command, 0)
+        }
+        ("sat", "set_hsv", _) => param(command, 1),
+        ("ct", "set_scene", Some("ct"))
+        | ("rgb", "set_scene", Some("color"))
+        | ("hue", "set_scene", Some("hsv")) => param(command, 1),
+        ("sat", "set_scene", Some("hsv")) => param(command, 2),
+        _ => None,
+    }?;
+    Some((slot, value))
+}
+
+/// Order in which the commands are replayed so that the final state matches the journal:
+/// scenes first (they turn the light on), and power off last (other commands fail on lights
+/// that are off).
+fn replay_order(entries: &Entries) -> Vec<Command> {
+    let mut commands = Vec::new();
+    for prefix in ["", "bg_"] {
+        let get = |slot: &str| entries.get(&format!("{}{}", prefix, slot)).cloned();
+        let power = get("power");
+        let off = power
+            .as_ref()
+            .is_some_and(|p| power_param(p) == Some("off"));
+
+        commands.extend(get("scene"));
+        if !off {
+            commands.extend(power.clone());
+        }
+        commands.extend(get("color"));
+        commands.extend(get("bright"));
+        if off {
+            commands.extend(power);
+        }
+    }
+    commands
+}
+
+fn power_param(command: &Command) -> Option<&str> {
+    command.params.first().and_then(Value::as_str)
+}
+
+fn toggled(params: &[Value]) -> Vec<Value> {
+    let mut params = params.to_vec();
+    if let Some(power) = params.first_mut() {
+        *power = match power.as_str() {
+            Some("off") => "on".into(),
+            _ => "off".into(),
+        };
+    }
+    params
+}
+
+/// Journal replayed when the connection is opened again, shared by the clones of a [Bulb].
+#[derive(Clone)]
+pub(crate) struct Replay {
+    journal: Arc<Mutex<Option<(Journal, String)>>>,
+    /// Id of the last message of the connection.
+    counter: Arc<AtomicU64>,
+}
+
+impl Replay {
+    pub fn new(counter: Arc<AtomicU64>) -> Self {
+        Self {
+            journal: Arc::default(),
+            counter,
+        }
+    }
+
+    pub fn set(&self, journal: Journal, name: &str) {
+        *self.journal.lock().unwrap_or_else(|e| e.into_inner()) = Some((journal, name.into()));
+    }
+
+    /// Write the desired state recorded in the journal (if any) to a new connection, before
+    /// the requests that failed are sent again. The responses are ignored.
+    pub async fn write(&self, writer: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        let journal = self
+            .journal
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let Some((journal, name)) = journal else {
+            return Ok(());
+        };
+        for (method, params) in journal.desired(&name) {
+            let id = self.counter.fetch_add(1, Ordering::Relaxed) + 1;
+            let request = Request::new(id, &method, params);
+            log::info!("replayed -> {}", request);
+            writer.write_all(request.to_string().as_bytes()).await?;
+        }
+        writer.flush().await
+    }
+}
+
+impl Bulb {
+    /// Record the commands sent to this bulb in `journal` under the given name.
+    ///
+    /// When the connection is opened again automatically (see
+    /// [ConnectOptions::backoff](crate::ConnectOptions::backoff)), the desired state is
+    /// replayed on the new connection before the requests that failed are sent again.
+    ///
+    /// **See:** [Journal]
+    pub fn journal(mut self, journal: Journal, name: &str) -> Self {
+        self.writer.set_journal(journal, name);
+        self
+    }
+
+    /// Re-apply the last desired state recorded in the journal (see [Bulb::journal]).
+    ///
+    /// Does nothing if the bulb has no journal.
+    pub async fn replay_journal(&mut self) -> Result<(), BulbError> {
+        let Some((journal, name)) = self.writer.journal() else {
+            return Ok(());
+        };
+        for (method, params) in journal.desired(&name) {
+            self.writer.send(&method, &params).await?;
+        }
+        Ok(())
+    }
+}
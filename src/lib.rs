@@ -1,37 +1,142 @@
 #![cfg_attr(not(doctest), doc = include_str!("../README.md"))]
 
-use std::collections::HashMap;
-use std::error::Error;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
-use tokio::net::{tcp::OwnedReadHalf, TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
-use tokio::task::spawn;
-
-#[cfg(feature = "from-str")]
-use itertools::Itertools;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{lookup_host, TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
 
+mod auto_off;
+mod builder;
+mod cache;
+mod capture;
+mod color;
+mod command;
+pub mod effects;
+pub mod fixture;
+mod flow;
+mod group;
+mod health;
+mod helpers;
+mod journal;
+mod method;
+pub mod model;
+mod music;
+pub mod pipeline;
+mod poll;
+pub mod presets;
+mod proxy;
+mod rate;
 mod reader;
+mod reconcile;
+mod reconnect;
+mod room;
+pub mod shutdown;
+mod socket;
+pub mod solar;
+mod state;
+mod stats;
+mod timer;
+pub mod transition;
 mod writer;
 
+#[cfg(unix)]
+pub mod broker;
 #[cfg(feature = "discover")]
 pub mod discover;
 
-pub use reader::{BulbError, Notification, Response};
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzzing;
+#[cfg(feature = "scripting")]
+pub mod script;
+#[cfg(feature = "simulator")]
+pub mod simulator;
+#[cfg(feature = "webhooks")]
+pub mod webhook;
 
-use reader::{NotifyChan, Reader};
+pub use auto_off::AutoOff;
+pub use builder::{BulbBuilder, ChangeCommand, FlowCommand, PowerCommand};
+pub use capture::Capture;
+pub use color::{ColorPolicy, ColorSpec};
+pub use command::Command;
+pub use flow::{FlowEnd, FlowHandle};
+pub use group::{ApplyError, ApplyReport, BulbGroup, GroupResults, StateChange, VerifyOptions};
+pub use health::{DisconnectReason, HealthEvent};
+pub use journal::Journal;
+pub use method::Method;
+pub use poll::{PollOptions, Poller};
+pub use protocol::{Notification, RawResponse, Response};
+pub use proxy::{ConnectOptions, InvalidProxy, Proxy};
+pub use rate::{QuotaLimiter, QuotaPolicy, RateLimit};
+pub use reader::{BulbError, ConnectError};
+pub use reconcile::{Drift, ReconcileMode, ReconcileResults};
+pub use reconnect::Backoff;
+pub use room::{ReloadReport, Room, RoomConfig, ScheduleEntry, TimeOfDay};
+pub use socket::SocketOptions;
+pub use state::{BulbState, LightState, PropertyChange, StateGuard, StateSnapshot};
+pub use stats::{Latency, Stats, COMMAND_QUOTA};
+pub use timer::TimeStatus;
+pub use yeelight_protocol as protocol;
+
+pub use cache::Freshness;
+use cache::{SharedCache, StateCache};
+use reader::{NotifyChan, Reader, Responses};
+use stats::StatsCollector;
 use writer::Writer;
 
+pub use writer::{Priority, WriteCoalescing};
+
 /// Bulb connection
+///
+/// Cloning a [Bulb] gives another handle to the same connection, so it can be used from
+/// several tasks at the same time. The requests of all the handles are written by the task
+/// that owns the connection and their responses are matched by id, so a handle does not wait
+/// for the requests of the others. Settings such as [no_response](Bulb::no_response) or
+/// [set_timeout](Bulb::set_timeout) only apply to the handle they are set on, while
+/// [write coalescing](Bulb::coalesce_writes) and [response slots](Bulb::response_slots)
+/// apply to the connection.
+///
+/// # Example
+/// ```
+/// # async fn test() {
+/// # use yeelight::Bulb;
+/// let bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+/// for _ in 0..3 {
+///     let mut bulb = bulb.clone();
+///     tokio::spawn(async move { bulb.toggle().await });
+/// }
+/// # }
+/// ```
+#[derive(Clone)]
 pub struct Bulb {
     notify_chan: NotifyChan,
     writer: writer::Writer,
+    cache: SharedCache,
+    model: Option<&'static model::Model>,
+    color_policy: ColorPolicy,
+    /// Methods supported by the bulb, if known.
+    support: Option<Vec<String>>,
+    peer: Option<SocketAddr>,
+    connected: watch::Receiver<bool>,
+    /// Stops the reading task when set, see [Bulb::close].
+    closing: Arc<watch::Sender<bool>>,
+    /// Task reading from the connection, until taken with [Bulb::take_reader_task].
+    reader_task: Arc<std::sync::Mutex<Option<ReaderTask>>>,
+    health: tokio::sync::broadcast::Sender<HealthEvent>,
+    /// Capacity of the channels created by [Bulb::get_notify].
+    notify_buffer: usize,
+    /// Music mode connection for the color commands, see [Bulb::auto_music].
+    auto_music: Option<music::AutoMusic>,
 }
 
+/// Handle of the task reading from the connection, see [Bulb::take_reader_task].
+pub type ReaderTask = JoinHandle<std::io::Result<()>>;
+
 /// Error generated when parsing value from string.
 #[cfg(feature = "from-str")]
 #[derive(Debug)]
@@ -40,7 +145,10 @@ pub struct ParseError(String);
 impl Bulb {
     /// Connect to bulb at the specified address and port.
     ///
-    /// If `port` is 0, the default value (55443) is used.
+    /// If `port` is 0, the default value (55443) is used. IPv6 addresses can be given with or
+    /// without brackets (`[fe80::204]`), see [Bulb::connect_addr] to connect to a
+    /// [SocketAddr]. Fails with [ConnectError::Resolve] if the address can not be resolved
+    /// and [ConnectError::Io] if the bulb can not be reached.
     ///
     /// # Example
     /// ```
@@ -52,20 +160,96 @@ impl Bulb {
     /// bulb.toggle().await.unwrap();
     /// # }
     /// ```
-    pub async fn connect(addr: &str, mut port: u16) -> Result<Self, Box<dyn Error>> {
-        if port == 0 {
-            port = 55443
+    pub async fn connect(addr: &str, port: u16) -> Result<Self, ConnectError> {
+        Self::connect_with(addr, port, &ConnectOptions::default()).await
+    }
+
+    /// Connect to bulb at the given address, such as a [SocketAddr], an `(IpAddr, u16)` tuple
+    /// or a `"host:port"` string.
+    ///
+    /// Unlike [Bulb::connect], there is no default port. Fails with [ConnectError::Resolve]
+    /// if the address can not be resolved and [ConnectError::Io] if the bulb can not be
+    /// reached.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::Bulb;
+    /// use std::net::{Ipv6Addr, SocketAddr};
+    ///
+    /// let addr = SocketAddr::from((Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x204), 55443));
+    /// let mut bulb = Bulb::connect_addr(addr).await.expect("Connection failed");
+    /// bulb.toggle().await.unwrap();
+    /// # }
+    /// ```
+    pub async fn connect_addr(addr: impl ToSocketAddrs) -> Result<Self, ConnectError> {
+        let addrs: Vec<SocketAddr> = lookup_host(addr)
+            .await
+            .map_err(ConnectError::Resolve)?
+            .collect();
+        if addrs.is_empty() {
+            let error = std::io::Error::new(std::io::ErrorKind::NotFound, "No addresses found");
+            return Err(ConnectError::Resolve(error));
         }
+        let stream = TcpStream::connect(&addrs[..]).await?;
+        Ok(Self::attach_tokio(stream))
+    }
 
-        let stream = TcpStream::connect(format!("{}:{}", addr, port)).await?;
+    /// Same as [Bulb::connect], failing with [ConnectError::Timeout] if the bulb is not
+    /// reached within `timeout`.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::Bulb;
+    /// # use std::time::Duration;
+    /// let result = Bulb::connect_timeout("192.168.1.204", 0, Duration::from_secs(2)).await;
+    /// let mut bulb = result.expect("Connection failed");
+    /// bulb.toggle().await.unwrap();
+    /// # }
+    /// ```
+    pub async fn connect_timeout(
+        addr: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> Result<Self, ConnectError> {
+        let options = ConnectOptions::default().connect_timeout(timeout);
+        Self::connect_with(addr, port, &options).await
+    }
 
-        let (reader, writer, reader_half, notify_chan) = Self::build_rw(stream);
+    /// Same as [Bulb::connect] with the given [ConnectOptions], such as a proxy.
+    ///
+    /// **NOTE:** In [music mode](Bulb::start_music) the bulb opens a connection to the given
+    /// host, which does not go through the proxy.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::{Bulb, ConnectOptions, Proxy};
+    /// let proxy: Proxy = "socks5://127.0.0.1:1080".parse().unwrap();
+    /// let options = ConnectOptions::default().proxy(proxy);
+    /// let mut bulb = Bulb::connect_with("192.168.1.204", 0, &options).await
+    ///     .expect("Connection failed");
+    /// bulb.toggle().await.unwrap();
+    /// # }
+    /// ```
+    pub async fn connect_with(
+        addr: &str,
+        mut port: u16,
+        options: &ConnectOptions,
+    ) -> Result<Self, ConnectError> {
+        if port == 0 {
+            port = 55443
+        }
 
-        spawn(reader.start(reader_half));
+        let stream = options.connect(addr, port).await?;
+        let peer = stream.peer_addr().ok();
+        let (reader_half, writer_half) = stream.into_split();
+        let target = Some((addr, port, options));
 
         Ok(Self {
-            notify_chan,
-            writer,
+            peer,
+            ..Self::from_halves(reader_half, Box::new(writer_half), target)
         })
     }
 
@@ -81,7 +265,7 @@ impl Bulb {
     /// bulb.toggle().await.unwrap();
     /// # }
     /// ```
-    pub fn attach(stream: ::std::net::TcpStream) -> Result<Self, Box<dyn Error>> {
+    pub fn attach(stream: ::std::net::TcpStream) -> Result<Self, ConnectError> {
         let stream = TcpStream::from_std(stream)?;
 
         Ok(Self::attach_tokio(stream))
@@ -89,35 +273,236 @@ impl Bulb {
 
     /// Same as `attach(stream: std::net::TcpStream)` but for `tokio::net::TcpStream`;
     pub fn attach_tokio(stream: TcpStream) -> Self {
-        let (reader, writer, reader_half, notify_chan) = Self::build_rw(stream);
+        let peer = stream.peer_addr().ok();
+        let (reader_half, writer_half) = stream.into_split();
+        Self {
+            peer,
+            ..Self::from_halves(reader_half, Box::new(writer_half), None)
+        }
+    }
+
+    /// Attach to any stream, such as an SSH channel, a proxied connection or a TLS tunnel.
+    ///
+    /// **NOTE:** [Bulb::start_music] still needs the bulb to be able to open a TCP connection
+    /// to the given host, which may not be possible through the tunnel.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::Bulb;
+    /// // Any AsyncRead + AsyncWrite stream works, a TcpStream is used for brevity
+    /// let stream = tokio::net::TcpStream::connect("192.168.1.204:55443").await.unwrap();
+    /// let mut bulb = Bulb::attach_io(tokio::io::BufStream::new(stream));
+    /// bulb.toggle().await.unwrap();
+    /// # }
+    /// ```
+    pub fn attach_io<T>(io: T) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (reader_half, writer_half) = tokio::io::split(io);
+        Self::from_halves(reader_half, Box::new(writer_half), None)
+    }
+
+    /// Bulb using the given connection, opened again by connecting to `target` (the host,
+    /// port and options) when it is lost if reconnection is enabled in the options.
+    fn from_halves<R>(
+        reader_half: R,
+        writer_half: writer::WriteHalf,
+        target: Option<(&str, u16, &ConnectOptions)>,
+    ) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        let resp_chan = Arc::new(Responses::default());
+        let notify_chan = Arc::new(Mutex::new(None));
+        let stats = StatsCollector::new();
+        let cache = StateCache::new();
+
+        let reader = Reader::new(
+            resp_chan.clone(),
+            notify_chan.clone(),
+            stats.clone(),
+            cache.clone(),
+        );
+        let (health, _) = tokio::sync::broadcast::channel(health::CAPACITY);
+        let (halves, new_halves) = mpsc::unbounded_channel();
+        let (generation, reconnected) = watch::channel(0);
+        let reconnect = target.and_then(|(_, _, options)| options.reconnect);
+        let (ready, reader_ready) = tokio::sync::oneshot::channel();
+        let writer = Writer::new(
+            writer_half,
+            resp_chan.clone(),
+            stats.clone(),
+            cache.clone(),
+            reader_ready,
+            new_halves,
+            reconnect.map(|_| reconnected),
+        );
+        let link = target.and_then(|(host, port, options)| {
+            Some(reconnect::Link {
+                host: host.to_string(),
+                port,
+                options: options.clone(),
+                backoff: reconnect?,
+                halves,
+                generation,
+                health: health.clone(),
+                replay: writer.replay(),
+            })
+        });
 
-        spawn(reader.start(reader_half));
+        let (connected_sender, connected) = watch::channel(true);
+        let (closing, mut close) = watch::channel(false);
+        let events = health.clone();
+        let reader_cache = cache.clone();
+        let reader_task = shutdown::spawn_connection(async move {
+            // The writing task waits for this, so no request is written before
+            let _ = ready.send(());
+            let closing = shutdown::closing();
+            let closed = async move {
+                // Dropping all the handles does not stop the task, only closing them (or
+                // shutting down the coordinator) does
+                if close.wait_for(|close| *close).await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            };
+            let closed = async move {
+                tokio::select! {
+                    _ = closed => {}
+                    _ = closing => {}
+                }
+            };
+            let read = async {
+                let mut reader_half: reconnect::ReadHalf = Box::new(reader_half);
+                loop {
+                    let result = reader.start(&mut reader_half).await;
+                    let _ = events.send(HealthEvent::Disconnected(DisconnectReason::of(&result)));
+                    let Some(link) = &link else {
+                        return result;
+                    };
+                    match &result {
+                        Ok(()) => log::warn!("Connection closed by the bulb, reconnecting"),
+                        Err(e) => log::warn!("Connection to bulb lost, reconnecting: {}", e),
+                    }
+                    connected_sender.send_replace(false);
+                    let Some(half) = link.reconnect().await else {
+                        return result;
+                    };
+                    reader_half = half;
+                    resp_chan.reopen();
+                    connected_sender.send_replace(true);
+                    link.generation.send_modify(|generation| *generation += 1);
+                    stats.reconnected();
+                    reader_cache.expire();
+                    let _ = events.send(HealthEvent::Connected);
+                }
+            };
+            let result = tokio::select! {
+                result = read => result,
+                _ = closed => {
+                    resp_chan.close().await;
+                    let _ = events.send(HealthEvent::Disconnected(DisconnectReason::Closed));
+                    Ok(())
+                }
+            };
+            if let Err(e) = &result {
+                log::warn!("Connection to bulb lost: {}", e);
+            }
+            connected_sender.send_replace(false);
+            result
+        });
 
         Self {
             notify_chan,
             writer,
+            cache,
+            model: None,
+            color_policy: ColorPolicy::default(),
+            support: None,
+            peer: None,
+            connected,
+            closing: Arc::new(closing),
+            reader_task: Arc::new(std::sync::Mutex::new(Some(reader_task))),
+            health,
+            notify_buffer: 10,
+            auto_music: None,
         }
     }
 
-    fn build_rw(stream: TcpStream) -> (Reader, Writer, OwnedReadHalf, NotifyChan) {
-        let (reader_half, writer_half) = stream.into_split();
+    /// Address of the bulb, if connected over TCP.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
 
-        let resp_chan = HashMap::new();
-        let resp_chan = Arc::new(Mutex::new(resp_chan));
-        let notify_chan = Arc::new(Mutex::new(None));
+    /// Whether the connection to the bulb is still open.
+    ///
+    /// Bulbs close idle connections and drop them when they lose power, so this can be
+    /// used to report the availability of the bulb and reconnect.
+    pub fn is_connected(&self) -> bool {
+        *self.connected.borrow()
+    }
+
+    /// Wait until the connection to the bulb is closed.
+    pub async fn disconnected(&self) {
+        let mut connected = self.connected.clone();
+        // The sender is only dropped after the connection is closed
+        let _ = connected.wait_for(|connected| !connected).await;
+    }
 
-        let reader = Reader::new(resp_chan.clone(), notify_chan.clone());
-        let writer = Writer::new(writer_half, resp_chan);
+    /// Close the connection: write the buffered messages, shut down the socket and stop the
+    /// task reading from it.
+    ///
+    /// Requests waiting for a response fail with [BulbError::Disconnected] and the ones sent
+    /// afterwards fail too. The connection is shared by all the clones of the [Bulb], so it
+    /// is closed for all of them.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+    /// # use yeelight::Bulb;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await?;
+    /// bulb.toggle().await?;
+    /// bulb.close().await?;
+    /// assert!(!bulb.is_connected());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn close(&mut self) -> std::io::Result<()> {
+        let shutdown = self.writer.shutdown().await;
+        self.closing.send_replace(true);
+        let task = self
+            .reader_task
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        match task {
+            Some(task) => {
+                let _ = task.await;
+            }
+            None => self.disconnected().await,
+        }
+        shutdown
+    }
 
-        (reader, writer, reader_half, notify_chan)
+    /// Take the handle of the task reading from the connection, to await it or check if it
+    /// is still running. Its result is the error that ended the connection, if any.
+    ///
+    /// Only the first call (on any clone) gets the handle, use [Bulb::disconnected] to wait
+    /// for the connection to end from several places.
+    pub fn take_reader_task(&self) -> Option<ReaderTask> {
+        self.reader_task
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
     }
 
     /// Set the [Bulb] connection so that it does not wait for response from the bulb
     ///
     /// If this is used, all the methods will return `None` even if they fail.
     /// It is not recommended unless you know the bulb will not respond (Poor connection,
-    /// music mode or firewall). However it is often better to wrap the calls in
-    /// a tokio timeout.
+    /// music mode or firewall). However it is often better to set a
+    /// [timeout](Bulb::set_timeout).
     ///
     /// # Example
     /// ```
@@ -142,14 +527,174 @@ impl Bulb {
         self
     }
 
+    /// Fail the commands with [BulbError::Timeout] if the bulb does not respond within
+    /// `timeout` (`None`, the default, waits forever).
+    ///
+    /// Late responses to the commands that timed out are ignored.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::{Bulb, BulbError};
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// bulb.set_timeout(Some(Duration::from_secs(2)));
+    /// match bulb.toggle().await {
+    ///     Err(e) if matches!(e.inner(), BulbError::Timeout) => eprintln!("No response"),
+    ///     result => println!("{:?}", result),
+    /// }
+    /// # }
+    /// ```
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.writer.set_timeout(timeout);
+        self
+    }
+
+    /// Buffer small messages and write them together to reduce the number of syscalls.
+    ///
+    /// Only messages sent in [no_response](Bulb::no_response) mode are buffered (when waiting
+    /// for responses each message is written immediately). Useful when sending many commands
+    /// per second (for example in music mode), at the cost of up to `max_delay` extra latency.
+    /// Use [Bulb::flush] to write the buffered messages immediately.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::{Bulb, WriteCoalescing};
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// let mut music = bulb.start_music("192.168.1.23").await.unwrap()
+    ///     .coalesce_writes(WriteCoalescing {
+    ///         max_delay: Duration::from_millis(10),
+    ///         max_bytes: 512,
+    ///     });
+    /// # }
+    /// ```
+    pub fn coalesce_writes(mut self, config: WriteCoalescing) -> Self {
+        self.writer.set_coalescing(config);
+        self
+    }
+
+    /// Reuse a fixed pool of `capacity` slots for the responses instead of allocating a
+    /// channel for each request (`0` goes back to a channel per request).
+    ///
+    /// Reduces allocations when sending many requests, see
+    /// [benchmark_requests](effects::benchmark_requests). The request with id `n` uses the
+    /// slot `n % capacity`, so at most `capacity` requests can wait for their response at the
+    /// same time: older requests still waiting when their slot is reused fail.
+    pub fn response_slots(self, capacity: usize) -> Self {
+        self.writer.set_response_slots(capacity);
+        self
+    }
+
+    /// Set the [Priority] of the next command.
+    ///
+    /// [High](Priority::High) priority commands are written before the messages buffered by
+    /// [Bulb::coalesce_writes], which are discarded so that they do not override it (messages
+    /// sent afterwards are kept) and fail with [BulbError::Preempted]. Turning the bulb off and
+    /// stopping color flows is always high priority. Without write coalescing, commands are
+    /// written as soon as they are sent, so there is nothing to preempt.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test(mut music: yeelight::Bulb) {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// music.priority(Priority::High)
+    ///     .set_bright(1, Effect::Sudden, Duration::ZERO)
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub fn priority(&mut self, priority: Priority) -> &mut Self {
+        self.writer.set_priority(priority);
+        self
+    }
+
+    /// Number of requests sent and still waiting for a response.
+    ///
+    /// Requests whose future was dropped (for example, by a timeout) stop waiting. A growing
+    /// number means the bulb or the network are congested.
+    pub async fn pending_requests(&self) -> usize {
+        self.writer.pending().await
+    }
+
+    /// Number of messages that have not been written to the connection yet (buffered by
+    /// [Bulb::coalesce_writes] or waiting for the connection to accept them).
+    ///
+    /// Real time clients (such as ambilight) can use it to reduce their frame rate when the
+    /// connection can not keep up.
+    pub fn queued_messages(&self) -> usize {
+        self.writer.queued()
+    }
+
+    /// Write all the messages buffered by [Bulb::coalesce_writes].
+    pub async fn flush(&mut self) -> Result<(), BulbError> {
+        Ok(self.writer.flush().await?)
+    }
+
+    /// Get a receiver of the last known state of the bulb.
+    ///
+    /// The state is updated with the property notifications sent by the bulb and the results
+    /// of [Bulb::get_prop]. Only the properties that have been received are present.
+    ///
+    /// **See also:** [Bulb::poll_when_idle] for connections without notifications.
+    pub fn watch(&self) -> tokio::sync::watch::Receiver<StateSnapshot> {
+        self.cache.watch()
+    }
+
+    /// Answer [Bulb::get_prop] from the [cached state](Bulb::watch) for the properties
+    /// received recently enough according to `policy`, replacing the previous policy.
+    ///
+    /// Saves quota and round trips for clients that read the same properties often (such as
+    /// dashboards) while the bulb already notifies their changes. Values received before the
+    /// connection was lost are never fresh, nor the ones a command sent since may have
+    /// changed (until they are notified or read). Applies to all the handles of the
+    /// connection.
+    ///
+    /// The pollers and checks of the crate ([Bulb::poll_when_idle], [Bulb::snapshot],
+    /// [Bulb::state], [BulbGroup::apply_verified] and
+    /// [benchmark_requests](effects::benchmark_requests)) always read the bulb.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// use std::time::Duration;
+    ///
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0)
+    ///     .await
+    ///     .unwrap()
+    ///     .freshness(Freshness::max_age(Duration::from_secs(30)));
+    /// let props = Properties(vec![Property::Power, Property::Bright]);
+    /// loop {
+    ///     // Only sent when the values are older than 30 seconds
+    ///     println!("{:?}", bulb.get_prop(&props).await);
+    ///     tokio::time::sleep(Duration::from_secs(1)).await;
+    /// }
+    /// # }
+    /// ```
+    pub fn freshness(self, policy: Freshness) -> Self {
+        self.cache.set_freshness(policy);
+        self
+    }
+
+    /// Last known state of the bulb (see [Bulb::watch]).
+    pub fn cached_state(&self) -> StateSnapshot {
+        self.cache.state()
+    }
+
     /// Get a new notification reciever from the Bulb
     ///
     /// This method creates a new channel and replaces the old one.
     ///
-    /// **NOTE:** The channel has 10 message buffer. If more are needed
-    /// manually create a [mpsc::channel] and use [Bulb::set_notify]
+    /// **NOTE:** The channel has 10 message buffer (see [BulbBuilder::notify_buffer]). If more
+    /// are needed manually create a [mpsc::channel] and use [Bulb::set_notify]
+    ///
+    /// Notifications received while there is no channel are discarded and counted in
+    /// [Stats::dropped_notifications].
     pub async fn get_notify(&mut self) -> mpsc::Receiver<Notification> {
-        let (sender, receiver) = mpsc::channel(10);
+        let (sender, receiver) = mpsc::channel(self.notify_buffer);
         self.set_notify(sender).await;
         receiver
     }
@@ -163,28 +708,165 @@ impl Bulb {
         self.notify_chan.lock().await.replace(chan);
     }
 
+    /// Store the values notified by the bulb as `name` as the property `property` in the
+    /// [cached state](Bulb::watch).
+    ///
+    /// Some firmwares notify properties with other names than the ones used to read them.
+    /// `main_power` (stored as [Property::Power]) and `bg_color_mode` (stored as
+    /// [Property::BgColorMode]) are mapped by default.
+    pub fn notification_alias(self, name: &str, property: Property) -> Self {
+        self.cache.alias(name, property);
+        self
+    }
+
+    /// Set the model of the bulb (as reported by discovery), see [model].
+    ///
+    /// Unknown models are ignored.
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model::lookup(model);
+        self
+    }
+
+    /// Model of the bulb if known.
+    pub fn model(&self) -> Option<&'static model::Model> {
+        self.model
+    }
+
+    /// Limits of the values accepted by the bulb according to its [model](Bulb::with_model),
+    /// or the widest ones of the protocol if the model is unknown.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::Bulb;
+    /// let bulb = Bulb::connect("192.168.1.204", 0).await.unwrap().with_model("ceiling1");
+    /// let (min, max) = bulb.limits().ct_range.unwrap_or((1700, 6500));
+    /// println!("Color temperature slider from {}K to {}K", min, max);
+    /// # }
+    /// ```
+    pub fn limits(&self) -> model::Limits {
+        self.model
+            .map_or_else(model::Limits::default, model::Model::limits)
+    }
+
+    /// Set the methods supported by the bulb (as reported by discovery in the `support` field).
+    ///
+    /// Methods not in the list fail with an [Unsupported](std::io::ErrorKind::Unsupported)
+    /// error without being sent. Bulbs connected through
+    /// [`DiscoveredBulb::connect`](discover::DiscoveredBulb::connect) know their supported
+    /// methods automatically.
+    pub fn with_support<S: Into<String>>(mut self, methods: impl IntoIterator<Item = S>) -> Self {
+        self.support = Some(methods.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Whether the bulb supports `method`, `None` if the supported methods are not known.
+    pub fn supports(&self, method: Method) -> Option<bool> {
+        self.support
+            .as_ref()
+            .map(|support| support.iter().any(|m| m == method.as_str()))
+    }
+
+    fn check_support(&self, method: &str) -> Result<(), BulbError> {
+        match &self.support {
+            Some(support) if !support.iter().any(|m| m == method) => {
+                let message = format!("Method {} not supported by the bulb", method);
+                Err(std::io::Error::new(std::io::ErrorKind::Unsupported, message).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Set the [ColorPolicy] used by [Bulb::set_color].
+    pub fn color_policy(mut self, policy: ColorPolicy) -> Self {
+        self.color_policy = policy;
+        self
+    }
+
+    /// Get the statistics of this connection (commands sent, errors, latency...)
+    ///
+    /// They are displayed as a report to troubleshoot the connection.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::Bulb;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// bulb.toggle().await.unwrap();
+    /// let stats = bulb.stats();
+    /// println!("{} commands, {:?}", stats.commands, stats.latency);
+    /// println!("{}", stats);
+    /// # }
+    /// ```
+    pub fn stats(&self) -> Stats {
+        self.writer.stats().snapshot()
+    }
+
     /// Establishes a Music mode connection with bulb.
     ///
     /// This method returns another `Bulb` object to send commands to the bulb in music mode. Note
     /// that all commands send to the bulb get no response and produce no notification message, so
     /// there is no way to know if the command was executed successfully by the bulb.
-    pub async fn start_music(&mut self, host: &str) -> Result<Self, Box<dyn Error>> {
-        let addr = format!("0.0.0.0:{}", 0).parse::<SocketAddr>()?;
+    ///
+    /// The bulb connects directly to `host`, even if this connection goes through a
+    /// [proxy](Bulb::connect_with), so `host` has to be reachable from the bulb. Fails with
+    /// [ConnectError::Music] if the bulb refuses to start music mode.
+    ///
+    /// The commands sent through the new connection are capped to the frame rate of the model
+    /// of the bulb, see [Bulb::max_frame_rate].
+    pub async fn start_music(&mut self, host: &str) -> Result<Self, ConnectError> {
+        self.start_music_with(host, &SocketOptions::default()).await
+    }
+
+    /// Same as [Bulb::start_music], setting the given [SocketOptions] on the connection
+    /// opened by the bulb.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::{Bulb, SocketOptions};
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// // Send each frame right away
+    /// let socket = SocketOptions::default().nodelay(true);
+    /// let mut music = bulb.start_music_with("192.168.1.10", &socket).await.unwrap();
+    /// music.set_bright(50, yeelight::Effect::Sudden, std::time::Duration::ZERO).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn start_music_with(
+        &mut self,
+        host: &str,
+        socket: &SocketOptions,
+    ) -> Result<Self, ConnectError> {
+        let addr = SocketAddr::from(([0, 0, 0, 0], 0));
         let listener = TcpListener::bind(&addr).await?;
 
         let port = listener.local_addr()?.port();
 
-        self.set_music(MusicAction::On, host, port).await?;
+        // Sent directly instead of with set_music, which may start music mode itself (see
+        // Bulb::auto_music)
+        self.check_support("set_music")
+            .map_err(ConnectError::Music)?;
+        let params = [MusicAction::On.to_param(), host.to_param(), port.to_param()];
+        self.writer
+            .send("set_music", &params)
+            .await
+            .map_err(ConnectError::Music)?;
 
-        let (socket, _) = listener.accept().await?;
-        Ok(Self::attach_tokio(socket).no_response())
+        let (stream, _) = listener.accept().await?;
+        socket.apply(&stream)?;
+        let mut music = Self::attach_tokio(stream).no_response();
+        music.model = self.model;
+        music.color_policy = self.color_policy;
+        music.support = self.support.clone();
+        let fps = music.limits().max_frame_rate;
+        Ok(music.max_frame_rate(Some(fps)))
     }
 }
 
 #[cfg(feature = "from-str")]
-impl ToString for ParseError {
-    fn to_string(&self) -> String {
-        self.0.to_string()
+impl ::std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.write_str(&self.0)
     }
 }
 
@@ -195,22 +877,36 @@ impl From<::std::num::ParseIntError> for ParseError {
     }
 }
 
-trait Stringify {
-    fn stringify(&self) -> String;
+/// Parameters of a request, as JSON values.
+trait ToParams {
+    fn to_params(&self) -> Vec<serde_json::Value>;
+}
+
+/// Parameter of a request that is a single JSON value.
+///
+/// Values are encoded with `serde_json`, so strings (such as names) are always escaped.
+trait ToParam {
+    fn to_param(&self) -> serde_json::Value;
+}
+
+impl<T: ToParam + ?Sized> ToParams for T {
+    fn to_params(&self) -> Vec<serde_json::Value> {
+        vec![self.to_param()]
+    }
 }
 
-impl Stringify for str {
-    fn stringify(&self) -> String {
-        format!("\"{}\"", self)
+impl ToParam for str {
+    fn to_param(&self) -> serde_json::Value {
+        self.into()
     }
 }
 
 macro_rules! stringify_nums {
     ($($type:ty),*) => {
         $(
-        impl Stringify for $type {
-            fn stringify(&self) -> String {
-                self.to_string()
+        impl ToParam for $type {
+            fn to_param(&self) -> serde_json::Value {
+                (*self).into()
             }
         }
         )*
@@ -224,7 +920,7 @@ macro_rules! enum_str {
     ($(#[$comment:meta])* $name:ident: $($variant:ident -> $val:literal),* $(,)?) => {
 
         $(#[$comment])*
-        #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
         pub enum $name {
             $($variant),*
         }
@@ -237,9 +933,11 @@ macro_rules! enum_str {
             }
         }
 
-        impl Stringify for $name {
-            fn stringify(&self) -> String {
-                self.to_string()
+        impl ToParam for $name {
+            fn to_param(&self) -> serde_json::Value {
+                match *self {
+                    $($name::$variant => $val.into(),)+
+                }
             }
         }
 
@@ -362,6 +1060,76 @@ enum_str!(FlowMode:
     Ct -> 2,
     Sleep -> 7,
 );
+enum_str!(
+    /// Color mode of a light (`color_mode` and `bg_lmode` properties).
+    ColorMode:
+    Rgb -> 1,
+    Ct -> 2,
+    Hsv -> 3,
+);
+
+impl Power {
+    /// Parse the value of the `power` (or `bg_power`) property, as received in responses and
+    /// notifications (`"on"` or `"off"`, quoted or not).
+    ///
+    /// Unlike [FromStr](std::str::FromStr), which parses the variant names for the CLI, this
+    /// is always available. Unknown values are `None`.
+    pub fn from_property_str(value: &str) -> Option<Self> {
+        match value.trim().trim_matches('"') {
+            "on" => Some(Power::On),
+            "off" => Some(Power::Off),
+            _ => None,
+        }
+    }
+
+    /// Value of the `power` property in this state.
+    pub fn as_property_str(&self) -> &'static str {
+        match self {
+            Power::On => "on",
+            Power::Off => "off",
+        }
+    }
+}
+
+impl Mode {
+    /// Parse a mode as a number (`"0"` to `"5"`, quoted or not). Unknown values are `None`.
+    pub fn from_property_str(value: &str) -> Option<Self> {
+        match value.trim().trim_matches('"') {
+            "0" => Some(Mode::Normal),
+            "1" => Some(Mode::Ct),
+            "2" => Some(Mode::Rgb),
+            "3" => Some(Mode::Hsv),
+            "4" => Some(Mode::Cf),
+            "5" => Some(Mode::NightLight),
+            _ => None,
+        }
+    }
+
+    /// The mode as a number (`"0"` to `"5"`).
+    pub fn as_property_str(&self) -> &'static str {
+        match self {
+            Mode::Normal => "0",
+            Mode::Ct => "1",
+            Mode::Rgb => "2",
+            Mode::Hsv => "3",
+            Mode::Cf => "4",
+            Mode::NightLight => "5",
+        }
+    }
+}
+
+impl ColorMode {
+    /// Parse the value of the `color_mode` (or `bg_lmode`) property (`"1"` to `"3"`, quoted or
+    /// not). Unknown values are `None`.
+    pub fn from_property_str(value: &str) -> Option<Self> {
+        match value.trim().trim_matches('"') {
+            "1" => Some(ColorMode::Rgb),
+            "2" => Some(ColorMode::Ct),
+            "3" => Some(ColorMode::Hsv),
+            _ => None,
+        }
+    }
+}
 
 /// State Change used to build [`FlowExpresion`](struct.FlowExpresion.html)s
 ///
@@ -443,9 +1211,10 @@ impl FlowTuple {
     }
 }
 
-impl ToString for FlowTuple {
-    fn to_string(&self) -> String {
-        format!(
+impl ::std::fmt::Display for FlowTuple {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(
+            f,
             "{},{},{},{}",
             self.duration.as_millis(),
             self.mode,
@@ -480,43 +1249,50 @@ impl ToString for FlowTuple {
 ///     FlowTuple::sleep(duration),
 /// ]);
 ///```
-#[derive(Debug, Serialize, Deserialize, Clone)]
+///
+/// When serialized with serde, the expression is represented as the protocol string
+/// (`"500,1,16711680,100,500,1,255,100"`), exactly as the bulb expects it. Use
+/// [`flow_tuples`] to get a list of [`FlowTuple`] structs instead.
+#[derive(Debug, Clone)]
 pub struct FlowExpresion(pub Vec<FlowTuple>);
 
-impl Stringify for FlowExpresion {
-    fn stringify(&self) -> String {
-        let mut s = '"'.to_string();
-        for tuple in self.0.iter() {
-            s.push_str(&tuple.to_string());
-            s.push(',');
+impl FlowExpresion {
+    /// Parse the protocol representation of a flow (comma separated groups of
+    /// `duration,mode,value,brightness`).
+    fn parse(s: &str) -> Result<Self, String> {
+        if s.is_empty() {
+            return Ok(FlowExpresion(Vec::new()));
+        }
+
+        let values: Vec<&str> = s.split(',').map(str::trim).collect();
+        if !values.len().is_multiple_of(4) {
+            return Err(format!(
+                "Could not parse FlowExpresion: {}\nexpected groups of 4 values (duration,mode,value,brightness)",
+                s
+            ));
         }
-        s.pop();
-        s.push('"');
-        s
-    }
-}
 
-#[cfg(feature = "from-str")]
-impl ::std::str::FromStr for FlowExpresion {
-    type Err = ParseError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut v = Vec::new();
-        for (duration, mode, value, brightness) in s.split(',').tuples() {
-            let duration = Duration::from_millis(duration.parse::<u64>()?);
-            let value = value.parse::<u32>()?;
-            let mode = match FlowMode::from_str(mode) {
-                Ok(m) => Ok(m),
-                Err(_) => match mode {
-                    "1" => Ok(FlowMode::Color),
-                    "2" => Ok(FlowMode::Ct),
-                    "7" => Ok(FlowMode::Sleep),
-                    _ => Err(ParseError(format!(
+        for tuple in values.chunks(4) {
+            let (duration, mode, value, brightness) = (tuple[0], tuple[1], tuple[2], tuple[3]);
+            let duration =
+                Duration::from_millis(duration.parse::<u64>().map_err(|e| e.to_string())?);
+            let value = value.parse::<u32>().map_err(|e| e.to_string())?;
+            let mode = match mode {
+                "1" => FlowMode::Color,
+                "2" => FlowMode::Ct,
+                "7" => FlowMode::Sleep,
+                _ if mode.eq_ignore_ascii_case("color") => FlowMode::Color,
+                _ if mode.eq_ignore_ascii_case("ct") => FlowMode::Ct,
+                _ if mode.eq_ignore_ascii_case("sleep") => FlowMode::Sleep,
+                _ => {
+                    return Err(format!(
                         "Could not parse FlowMode: {}\nvalid values: 1 (Color), 2(Ct), 7(Sleep)",
                         mode
-                    ))),
-                },
-            }?;
-            let brightness = brightness.parse::<i8>()?;
+                    ))
+                }
+            };
+            let brightness = brightness.parse::<i8>().map_err(|e| e.to_string())?;
             v.push(FlowTuple {
                 duration,
                 mode,
@@ -528,6 +1304,79 @@ impl ::std::str::FromStr for FlowExpresion {
     }
 }
 
+impl ::std::fmt::Display for FlowExpresion {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let mut tuples = self.0.iter();
+        if let Some(first) = tuples.next() {
+            write!(f, "{}", first)?;
+        }
+        for tuple in tuples {
+            write!(f, ",{}", tuple)?;
+        }
+        Ok(())
+    }
+}
+
+impl ToParam for FlowExpresion {
+    fn to_param(&self) -> serde_json::Value {
+        self.to_string().into()
+    }
+}
+
+impl Serialize for FlowExpresion {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for FlowExpresion {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        FlowExpresion::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Serialize a [`FlowExpresion`] as a list of [`FlowTuple`] structs.
+///
+/// The default representation of [`FlowExpresion`] is the protocol string, use this module
+/// with `#[serde(with = "yeelight::flow_tuples")]` to get a structured representation instead.
+///
+/// # Example
+///```
+///# use yeelight::FlowExpresion;
+///# use serde::{Deserialize, Serialize};
+/// #[derive(Serialize, Deserialize)]
+/// struct SceneFile {
+///     #[serde(with = "yeelight::flow_tuples")]
+///     flow: FlowExpresion,
+/// }
+///```
+pub mod flow_tuples {
+    use super::{FlowExpresion, FlowTuple};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        flow: &FlowExpresion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        flow.0.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<FlowExpresion, D::Error> {
+        Vec::<FlowTuple>::deserialize(deserializer).map(FlowExpresion)
+    }
+}
+
+#[cfg(feature = "from-str")]
+impl ::std::str::FromStr for FlowExpresion {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FlowExpresion::parse(s).map_err(ParseError)
+    }
+}
+
 /// List of `Property` (used by `get_prop`)
 ///
 /// # Example
@@ -543,31 +1392,126 @@ impl ::std::str::FromStr for FlowExpresion {
 ///     Property::Flowing,
 /// ]);
 ///```
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Properties(pub Vec<Property>);
 
-impl Stringify for Properties {
-    fn stringify(&self) -> String {
-        self.0
-            .iter()
-            .map(|x| x.to_string())
-            .collect::<Vec<String>>()
-            .join(",")
+impl ToParams for Properties {
+    fn to_params(&self) -> Vec<serde_json::Value> {
+        self.0.iter().map(ToParam::to_param).collect()
     }
 }
 
-impl Stringify for Duration {
-    fn stringify(&self) -> String {
-        format!("{}", self.as_millis())
+impl ToParam for Duration {
+    fn to_param(&self) -> serde_json::Value {
+        (self.as_millis() as u64).into()
     }
 }
 
-// Convert function parameters into comma separated string
+// Convert function parameters into the JSON values of the request
 macro_rules! params {
     ($($v:tt),+) => {
-        vec!( $( $v.stringify() ),+ ).join(",")
+        [ $( $v.to_params() ),+ ].concat()
     };
-    () => {""};
+    () => {Vec::new()};
+}
+
+/// Scene used by [`Bulb::set_scene`] to set the bulb directly into a state.
+///
+/// The bulb is turned on if it was off.
+///
+/// # Example
+///```
+///# use yeelight::{CfAction, FlowExpresion, FlowTuple, Scene};
+///# use std::time::Duration;
+/// // Turn on directly into a candle-like flow
+/// let candle = Scene::Cf {
+///     count: 0,
+///     action: CfAction::Recover,
+///     flow: FlowExpresion(vec![
+///         FlowTuple::ct(Duration::from_millis(800), 2700, 50),
+///         FlowTuple::ct(Duration::from_millis(800), 2700, 30),
+///     ]),
+/// };
+///```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Scene {
+    /// Color in RGB format (`0x00_00_00` to `0xff_ff_ff`) and brightness (`1` to `100`).
+    Color { rgb: u32, bright: u8 },
+    /// Hue (`0` to `359`), saturation (`0` to `100`) and brightness (`1` to `100`).
+    Hsv { hue: u16, sat: u8, bright: u8 },
+    /// Color temperature (`1700` to `6500`) and brightness (`1` to `100`).
+    Ct { ct: u16, bright: u8 },
+    /// Color flow, see [`Bulb::start_cf`].
+    ///
+    /// Combined with [`Bulb::set_default`] this allows the bulb to power on into a flow.
+    Cf {
+        count: u8,
+        action: CfAction,
+        flow: FlowExpresion,
+    },
+    /// Turn on with the given brightness (`1` to `100`) and start a sleep timer to turn off after
+    /// `minutes`.
+    AutoDelayOff { bright: u8, minutes: u64 },
+}
+
+impl Scene {
+    /// [`Class`] of the scene
+    pub fn class(&self) -> Class {
+        match self {
+            Scene::Color { .. } => Class::Color,
+            Scene::Hsv { .. } => Class::Hsv,
+            Scene::Ct { .. } => Class::Ct,
+            Scene::Cf { .. } => Class::Cf,
+            Scene::AutoDelayOff { .. } => Class::AutoDelayOff,
+        }
+    }
+}
+
+impl Scene {
+    /// Property values the light should report once the scene is applied.
+    pub(crate) fn expected_properties(&self) -> Vec<(Property, String)> {
+        let on = (Property::Power, "on".to_string());
+        match self {
+            Scene::Color { rgb, bright } => vec![
+                on,
+                (Property::ColorMode, "1".to_string()),
+                (Property::Rgb, rgb.to_string()),
+                (Property::Bright, bright.to_string()),
+            ],
+            Scene::Hsv { hue, sat, bright } => vec![
+                on,
+                (Property::ColorMode, "3".to_string()),
+                (Property::Hue, hue.to_string()),
+                (Property::Sat, sat.to_string()),
+                (Property::Bright, bright.to_string()),
+            ],
+            Scene::Ct { ct, bright } => vec![
+                on,
+                (Property::ColorMode, "2".to_string()),
+                (Property::Ct, ct.to_string()),
+                (Property::Bright, bright.to_string()),
+            ],
+            Scene::Cf { .. } => vec![on, (Property::Flowing, "1".to_string())],
+            Scene::AutoDelayOff { bright, .. } => vec![on, (Property::Bright, bright.to_string())],
+        }
+    }
+}
+
+impl ToParams for Scene {
+    fn to_params(&self) -> Vec<serde_json::Value> {
+        let class = self.class();
+        match self {
+            Scene::Color { rgb, bright } => params!(class, rgb, bright),
+            Scene::Hsv { hue, sat, bright } => params!(class, hue, sat, bright),
+            Scene::Ct { ct, bright } => params!(class, ct, bright),
+            Scene::Cf {
+                count,
+                action,
+                flow,
+            } => params!(class, count, action, flow),
+            Scene::AutoDelayOff { bright, minutes } => params!(class, bright, minutes),
+        }
+    }
 }
 
 // Generate function
@@ -576,9 +1520,12 @@ macro_rules! gen_func {
 
             $(#[$comment])*
             pub async fn $name(&mut self, $($p : $t),*) -> Result<Option<Response>, BulbError> {
-                self.writer.send(
-                    &stringify!($name), &params!($($p),*)
-                ).await
+                self.check_support(stringify!($name))?;
+                let params = params!($($p),*);
+                if let Some(result) = self.send_music(stringify!($name), &params).await {
+                    return result;
+                }
+                self.writer.send(&stringify!($name), &params).await
             }
 
     };
@@ -625,15 +1572,103 @@ macro_rules! gen_func {
 /// [`Response`]: enum.Response.html
 // #[rustfmt::skip]
 impl Bulb {
-    gen_func!(
-        /// Retrieve current propertes of smart LED.
-        ///
-        /// Parameters:
-        ///
-        /// - `properties`: List of properties. The answer will follow the same order.
-        get_prop
-            - properties: &Properties
-    );
+    /// Retrieve current propertes of smart LED.
+    ///
+    /// Parameters:
+    ///
+    /// - `properties`: List of properties. The answer will follow the same order.
+    ///
+    /// With a [freshness policy](Bulb::freshness), the properties with fresh values in the
+    /// [cached state](Bulb::watch) are answered from it, and only the rest are read (nothing
+    /// is sent if all of them are fresh).
+    pub async fn get_prop(
+        &mut self,
+        properties: &Properties,
+    ) -> Result<Option<Response>, BulbError> {
+        let cached = self.cache.fresh(&properties.0);
+        let stale: Vec<Property> = (properties.0.iter().zip(&cached))
+            .filter(|(_, value)| value.is_none())
+            .map(|(property, _)| *property)
+            .collect();
+        if stale.len() == properties.0.len() {
+            return self.read_prop(properties).await;
+        }
+        if stale.is_empty() {
+            return Ok(Some(cached.into_iter().flatten().collect()));
+        }
+
+        let Some(values) = self.read_prop(&Properties(stale)).await? else {
+            return Ok(None);
+        };
+        let mut values = values.into_iter();
+        let merged = cached
+            .into_iter()
+            .map(|value| value.or_else(|| values.next()).unwrap_or_default());
+        Ok(Some(merged.collect()))
+    }
+
+    /// Read the properties from the bulb, even if their cached values are fresh (for the
+    /// pollers and checks of the crate, which must see the current state).
+    pub(crate) async fn read_prop(
+        &mut self,
+        properties: &Properties,
+    ) -> Result<Option<Response>, BulbError> {
+        let response = self.writer.send("get_prop", &params!(properties)).await?;
+        if let Some(values) = &response {
+            self.cache.properties(&properties.0, values);
+        }
+        Ok(response)
+    }
+
+    /// Send a method with the given parameters and get the values of the response as
+    /// received, for methods answering with numbers or objects (such as `cron_get`) that
+    /// the other methods return as strings.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+    /// # use yeelight::*;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await?;
+    /// if let Some(values) = bulb.call(Method::CronGet, &[0.into()]).await? {
+    ///     // Such as {"type":0,"delay":15,"mix":0}
+    ///     let delay = values.first().and_then(|cron| cron["delay"].as_u64());
+    ///     println!("Turns off in {:?} minutes", delay);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn call(
+        &mut self,
+        method: Method,
+        params: &[serde_json::Value],
+    ) -> Result<Option<RawResponse>, BulbError> {
+        self.send_raw(method.as_str(), params).await
+    }
+
+    /// Same as [Bulb::call] with the method given by name, for the methods of newer firmwares
+    /// that are not in [Method] yet.
+    ///
+    /// The request gets an id and its response is awaited as with any other method. Fails
+    /// with an [Unsupported](std::io::ErrorKind::Unsupported) error if the
+    /// [supported methods](Bulb::with_support) are known and `method` is not one of them.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+    /// # use yeelight::*;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await?;
+    /// bulb.send_raw("set_fancy_mode", &["party".into(), 500.into()]).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_raw(
+        &mut self,
+        method: &str,
+        params: &[serde_json::Value],
+    ) -> Result<Option<RawResponse>, BulbError> {
+        self.check_support(method)?;
+        self.writer.send_raw(method, params).await
+    }
 
     gen_func!(
         /// Switch on or off the smart LED (software managed on/off).
@@ -646,7 +1681,9 @@ impl Bulb {
         /// `effect` is `Sudden`)
         /// - `mode`: Mode in which the lamp will turn on (`Mode::Normal` to keep the current mode)
         set_power
-            / ///
+            / /// Switch on or off the background light.
+            ///
+            /// **See:** [Bulb::set_power]
             bg_set_power
             - power: Power,
         effect: Effect,
@@ -714,6 +1751,16 @@ impl Bulb {
         effect: Effect,
         duration: Duration
     );
+    gen_func!(
+        /// Set the color of lights with a separate white channel, such as newer ceiling
+        /// lights.
+        ///
+        /// `wrgb_value` is the white level and the color in `0xWW_RR_GG_BB` format.
+        set_wrgb
+            - wrgb_value: u32,
+        effect: Effect,
+        duration: Duration
+    );
     gen_func!(
         set_hsv / bg_set_hsv - hue: u16,
         sat: u8,
@@ -726,10 +1773,16 @@ impl Bulb {
         duration: Duration
     );
     gen_func!(
-        set_scene / bg_set_scene - class: Class,
-        val1: u64,
-        val2: u64,
-        val3: u64
+        /// Set the smart LED directly to specified state. If the smart LED is off, then it will
+        /// turn on the smart LED firstly and then apply the specified command.
+        ///
+        /// **See:** [Scene]
+        set_scene
+            / /// Set the **background** smart LED directly to specified state.
+            ///
+            /// **See:** [Bulb::set_scene]
+            bg_set_scene
+            - scene: Scene
     );
 
     gen_func!(
@@ -836,11 +1889,17 @@ mod tests {
 
     use super::*;
 
+    use serde_json::json;
     use tokio::{
         net::{TcpListener, TcpStream},
         task::JoinHandle,
     };
 
+    /// Params of a request, from a JSON array.
+    fn json_params(params: serde_json::Value) -> Vec<serde_json::Value> {
+        serde_json::from_value(params).unwrap()
+    }
+
     async fn fake_bulb(expect: &'static str, response: &'static str) -> (Bulb, JoinHandle<()>) {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
@@ -871,135 +1930,3287 @@ mod tests {
         (Bulb::attach_tokio(stream), task)
     }
 
+    /// Fake bulb that expects the given requests in order and answers each one with its response.
+    async fn fake_bulb_seq(
+        exchanges: &'static [(&'static str, &'static str)],
+    ) -> (Bulb, JoinHandle<()>) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+
+            for (expect, response) in exchanges {
+                let line = lines.next_line().await.unwrap().unwrap();
+                assert_eq!(format!("{}\r\n", line), *expect);
+                write.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        (Bulb::attach_tokio(stream), task)
+    }
+
+    #[test]
+    fn hsv_to_rgb() {
+        assert_eq!(helpers::hsv_to_rgb(0, 100), 0xff_00_00);
+        assert_eq!(helpers::hsv_to_rgb(120, 100), 0x00_ff_00);
+        assert_eq!(helpers::hsv_to_rgb(240, 100), 0x00_00_ff);
+        assert_eq!(helpers::hsv_to_rgb(60, 0), 0xff_ff_ff);
+    }
+
     #[tokio::test]
-    async fn get_prop() {
-        let expect = "{\"id\":1,\"method\":\"get_prop\",\"params\":[\"name\",\"power\"]}\r\n";
-        let response = "{\"id\":1, \"result\":[\"bulb_name\",\"on\"]}\r\n";
+    async fn presets() {
+        use crate::presets::Preset;
 
-        let (mut bulb, task) = fake_bulb(expect, response).await;
+        assert_eq!(
+            "nightreading".parse::<Preset>().unwrap(),
+            Preset::NightReading
+        );
+        assert!("sunset".parse::<Preset>().is_err());
+        assert!(Preset::Candle.flow().is_some());
+        assert!(Preset::Reading.flow().is_none());
 
-        let prop = &Properties(vec![Property::Name, Property::Power]);
+        let (mut bulb, capture) = Bulb::with_capture();
+        Preset::Reading.apply(&mut bulb).await.unwrap();
+        Preset::Notify.apply(&mut bulb).await.unwrap();
+        assert_eq!(capture.methods(), ["set_scene", "start_cf"]);
+        assert_eq!(
+            capture.requests()[0].params,
+            json_params(json!(["ct", 3500, 100]))
+        );
+    }
 
-        let (tres, res) = tokio::join!(task, bulb.get_prop(prop));
-        tres.unwrap();
+    #[tokio::test]
+    async fn method_support() {
+        use crate::protocol::Request;
 
-        if let Ok(Some(properties)) = res {
-            assert_eq!(properties, vec!["bulb_name", "on"]);
-        } else {
-            panic!("Unexpected result: {:?}", res);
-        }
+        assert_eq!("set_wrgb".parse::<Method>().unwrap(), Method::SetWrgb);
+        assert_eq!(Method::BgSetCtAbx.to_string(), "bg_set_ct_abx");
+
+        let (bulb, capture) = Bulb::with_capture();
+        assert_eq!(bulb.supports(Method::SetWrgb), None);
+        let mut bulb = bulb.with_support("get_prop set_power set_wrgb".split(' '));
+        assert_eq!(bulb.supports(Method::SetWrgb), Some(true));
+        assert_eq!(bulb.supports(Method::SetRgb), Some(false));
+
+        bulb.set_wrgb(0x40_ff_00_00, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap();
+        let error = bulb
+            .set_rgb(0xff_00_00, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, BulbError::Io(e) if e.kind() == std::io::ErrorKind::Unsupported));
+
+        assert_eq!(
+            capture.take(),
+            [Request::new(
+                1,
+                "set_wrgb",
+                json_params(json!([1090453504, "sudden", 0]))
+            )]
+        );
     }
 
     #[tokio::test]
-    async fn set_power() {
-        let expect = "{\"id\":1,\"method\":\"set_power\",\"params\":[\"on\",\"smooth\",500,0]}\r\n";
-        let response = "{\"id\":1, \"result\":[\"ok\"]}\r\n";
+    async fn step_hue() {
+        use crate::protocol::Request;
 
-        let (mut bulb, task) = fake_bulb(expect, response).await;
+        assert_eq!(helpers::rgb_to_hsv(0x00_00_ff), (240, 100));
+        assert_eq!(helpers::rgb_to_hsv(0xff_80_80), (0, 50));
 
-        let (tres, res) = tokio::join!(
-            task,
-            bulb.set_power(
-                Power::On,
-                Effect::Smooth,
-                Duration::from_millis(500),
-                Mode::Normal
-            )
+        let (mut bulb, capture) = Bulb::with_capture();
+        capture.respond(
+            "get_prop",
+            vec!["3".into(), "16711680".into(), "350".into(), "80".into()],
         );
-        tres.unwrap();
 
-        if let Ok(Some(properties)) = res {
-            assert_eq!(properties, vec!["ok"]);
-        } else {
-            panic!("Unexpected result: {:?}", res);
-        }
+        bulb.step_hue(20, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap();
+        // The second step uses the cached hue, without reading it again
+        bulb.step_hue(-30, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap();
+
+        assert_eq!(capture.methods(), ["get_prop", "set_hsv", "set_hsv"]);
+        let requests = capture.requests();
+        assert_eq!(
+            requests[1],
+            Request::new(2, "set_hsv", json_params(json!([10, 80, "sudden", 0])))
+        );
+        assert_eq!(
+            requests[2],
+            Request::new(3, "set_hsv", json_params(json!([340, 80, "sudden", 0])))
+        );
     }
 
     #[tokio::test]
-    async fn unsupported() {
-        let expect = "{\"id\":1,\"method\":\"set_power\",\"params\":[\"on\",\"smooth\",500,0]}\r\n";
-        let response =
-            "{\"id\":1, \"error\":{\"code\":-1, \"message\":\"unsupported method\"}}\r\n";
+    async fn fade_off() {
+        let (mut bulb, task) = fake_bulb_seq(&[
+            (
+                "{\"id\":1,\"method\":\"get_prop\",\"params\":[\"power\",\"color_mode\",\"ct\",\"rgb\",\"hue\",\"sat\"]}\r\n",
+                "{\"id\":1, \"result\":[\"on\",\"2\",\"2700\",\"255\",\"0\",\"0\"]}\r\n",
+            ),
+            (
+                "{\"id\":2,\"method\":\"start_cf\",\"params\":[1,2,\"10000,2,2700,1\"]}\r\n",
+                "{\"id\":2, \"result\":[\"ok\"]}\r\n",
+            ),
+        ])
+        .await;
 
-        let (mut bulb, task) = fake_bulb(expect, response).await;
+        let (tres, res) = tokio::join!(task, bulb.fade_off(Duration::from_secs(10)));
+        tres.unwrap();
+        assert_eq!(res.unwrap(), Some(vec!["ok".to_string()]));
+    }
 
-        let (tres, res) = tokio::join!(
-            task,
-            bulb.set_power(
+    #[tokio::test]
+    async fn flow_progress() {
+        let flow = FlowExpresion(vec![
+            FlowTuple::rgb(Duration::from_secs(1), 0xff_00_00, 100),
+            FlowTuple::sleep(Duration::from_secs(2)),
+        ]);
+        assert_eq!(flow.duration(0), None);
+        assert_eq!(flow.duration(3), Some(Duration::from_secs(4)));
+
+        let (mut bulb, task) = fake_bulb_seq(&[(
+            "{\"id\":1,\"method\":\"start_cf\",\"params\":[3,0,\"1000,1,16711680,100,2000,7,0,-1\"]}\r\n",
+            "{\"method\":\"props\",\"params\":{\"flowing\":1}}\r\n{\"id\":1, \"result\":[\"ok\"]}\r\n",
+        ), (
+            "{\"id\":2,\"method\":\"stop_cf\",\"params\":[]}\r\n",
+            "{\"method\":\"props\",\"params\":{\"flowing\":0}}\r\n{\"id\":2, \"result\":[\"ok\"]}\r\n",
+        )])
+        .await;
+
+        let mut handle = bulb.start_flow(3, CfAction::Recover, flow).await.unwrap();
+        assert_eq!(handle.duration(), Some(Duration::from_secs(4)));
+        assert_eq!(handle.ended(), None);
+        assert!(handle.progress().unwrap() < 0.5);
+
+        bulb.stop_cf().await.unwrap();
+        assert_eq!(handle.finished().await, FlowEnd::Stopped);
+        assert_eq!(handle.progress(), Some(1.0));
+        assert_eq!(handle.remaining(), Some(Duration::ZERO));
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn set_both() {
+        let (mut bulb, task) = fake_bulb_seq(&[
+            (
+                "{\"id\":1,\"method\":\"set_power\",\"params\":[\"on\",\"smooth\",500,0]}\r\n",
+                "{\"id\":1, \"result\":[\"ok\"]}\r\n",
+            ),
+            (
+                "{\"id\":2,\"method\":\"bg_set_power\",\"params\":[\"on\",\"smooth\",500,0]}\r\n",
+                "{\"id\":2, \"result\":[\"ok\"]}\r\n",
+            ),
+            (
+                "{\"id\":3,\"method\":\"set_bright\",\"params\":[50,\"sudden\",0]}\r\n",
+                "{\"id\":3, \"result\":[\"ok\"]}\r\n",
+            ),
+            (
+                "{\"id\":4,\"method\":\"bg_set_bright\",\"params\":[50,\"sudden\",0]}\r\n",
+                "{\"id\":4, \"error\":{\"code\":-1, \"message\":\"unsupported method\"}}\r\n",
+            ),
+        ])
+        .await;
+
+        let ok = Some(vec!["ok".to_string()]);
+        let res = bulb
+            .set_both_power(
                 Power::On,
                 Effect::Smooth,
                 Duration::from_millis(500),
-                Mode::Normal
+                Mode::Normal,
             )
+            .await
+            .unwrap();
+        assert_eq!(res, (ok.clone(), ok));
+
+        let res = bulb
+            .set_both_bright(50, Effect::Sudden, Duration::ZERO)
+            .await;
+        assert!(res.is_err());
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn capture() {
+        use crate::protocol::Request;
+
+        let (mut bulb, capture) = Bulb::with_capture();
+        capture.respond("get_prop", vec!["on".to_string(), "30".to_string()]);
+
+        bulb.set_bright(30, Effect::Smooth, Duration::from_millis(500))
+            .await
+            .unwrap();
+        let props = Properties(vec![Property::Power, Property::Bright]);
+        let values = bulb.get_prop(&props).await.unwrap();
+
+        assert_eq!(values, Some(vec!["on".to_string(), "30".to_string()]));
+        assert_eq!(capture.methods(), ["set_bright", "get_prop"]);
+        assert_eq!(
+            capture.take(),
+            [
+                Request::new(1, "set_bright", json_params(json!([30, "smooth", 500]))),
+                Request::new(2, "get_prop", json_params(json!(["power", "bright"]))),
+            ]
         );
-        tres.unwrap();
+        assert!(capture.requests().is_empty());
+    }
 
-        if let Err(error) = res {
-            assert_eq!(
-                "Bulb response error: unsupported method (code -1)",
-                error.to_string()
-            );
-            if let BulbError::ErrResponse(code, message) = error {
-                assert_eq!(code, -1);
-                assert_eq!(message, "unsupported method");
-            }
-        } else {
-            panic!("Unexpected result: {:?}", res);
+    #[tokio::test]
+    async fn scale_brightness() {
+        use crate::protocol::Request;
+
+        let mut group = BulbGroup::new();
+        let mut captures = Vec::new();
+        for (name, power, bright) in [
+            ("desk", "on", "40"),
+            ("sofa", "on", "90"),
+            ("hall", "off", "50"),
+        ] {
+            let (bulb, capture) = Bulb::with_capture();
+            capture.respond("get_prop", vec![power.to_string(), bright.to_string()]);
+            group.add(name, bulb);
+            captures.push(capture);
+        }
+
+        let results = group
+            .scale_brightness(1.5, Effect::Sudden, Duration::ZERO)
+            .await;
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        assert!(results[2].1.as_ref().unwrap().is_none());
+
+        let set_bright =
+            |bright: u8| Request::new(2, "set_bright", json_params(json!([bright, "sudden", 0])));
+        assert_eq!(captures[0].requests()[1], set_bright(60));
+        assert_eq!(captures[1].requests()[1], set_bright(100));
+        assert_eq!(captures[2].methods(), ["get_prop"]);
+    }
+
+    /// Wait until the captured bulb has received `count` requests.
+    async fn wait_requests(capture: &Capture, count: usize) {
+        while capture.requests().len() < count {
+            tokio::task::yield_now().await;
         }
     }
 
     #[tokio::test]
-    async fn no_response() {
-        let expect = "{\"id\":1,\"method\":\"set_power\",\"params\":[\"on\",\"smooth\",500,0]}\r\n";
-        let response = "{\"empty\"}";
+    async fn hue_rotate() {
+        use crate::effects::{hue_rotate, OnStop};
 
-        let (mut bulb, task) = fake_bulb(expect, response).await;
+        let (bulb, capture) = Bulb::with_capture();
+        let effect = hue_rotate(bulb, Duration::from_secs(6), 100, 80, OnStop::Stay);
+        wait_requests(&capture, 1).await;
+        assert!(effect.is_running());
+        let (bulb, result) = effect.stop().await.unwrap();
+        result.unwrap();
+        assert_eq!(capture.methods(), ["set_scene", "stop_cf"]);
+        assert_eq!(
+            capture.take()[0].params,
+            json_params(json!(["cf", 0, 0, "1000,1,16776960,80,1000,1,65280,80,1000,1,65535,80,1000,1,255,80,1000,1,16711935,80,1000,1,16711680,80"]))
+        );
 
-        bulb = bulb.get_response().no_response();
+        // Too fast for a flow, frames are sent instead
+        let mut group = BulbGroup::new();
+        group.add("desk", bulb);
+        let effect = hue_rotate(group, Duration::from_millis(120), 100, 80, OnStop::Off);
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        let (_, result) = effect.stop().await.unwrap();
+        result.unwrap();
+        let frames = capture.take();
+        assert_eq!(frames[0].params, json_params(json!(["hsv", 0, 100, 80])));
+        assert_eq!(frames[1].params[..2], json_params(json!(["hsv", 150])));
+        assert_eq!(frames.last().unwrap().method, "set_power");
+    }
 
-        let (tres, res) = tokio::join!(
-            task,
-            bulb.set_power(
-                Power::On,
-                Effect::Smooth,
-                Duration::from_millis(500),
-                Mode::Normal
-            )
+    #[tokio::test]
+    async fn shutdown_coordinator() {
+        use crate::effects::{hue_rotate, OnStop};
+        use crate::shutdown::{Coordinator, ShutdownReport};
+
+        let coordinator = Coordinator::new();
+        let (io, _remote) = tokio::io::duplex(4096);
+        let (effect, capture, stuck) = coordinator
+            .scope(async {
+                let (bulb, capture) = Bulb::with_capture();
+                let effect = hue_rotate(bulb, Duration::from_secs(6), 100, 80, OnStop::Off);
+                wait_requests(&capture, 1).await;
+                // Never answers, so turning the light off does not end
+                let silent = Bulb::attach_io(io);
+                let stuck = hue_rotate(silent, Duration::from_secs(6), 100, 80, OnStop::Off);
+                (effect, capture, stuck)
+            })
+            .await;
+        coordinator.spawn(std::future::pending::<()>());
+        // Reader, writer, capture and effect of each bulb (but the capture) and the pending task
+        assert_eq!(coordinator.tasks(), 8);
+
+        let report = coordinator.shutdown(Duration::from_millis(200)).await;
+        assert_eq!(
+            report,
+            ShutdownReport {
+                stopped: 6,
+                aborted: 2
+            }
         );
-        tres.unwrap();
-        assert_eq!(res.unwrap(), None);
+        // The aborted effect dropped the bulb
+        assert!(matches!(stuck.stop().await, Err(BulbError::Disconnected)));
+        assert_eq!(coordinator.tasks(), 0);
+        assert!(coordinator.is_shutting_down());
+        // The effect turned the light off before the connection was closed
+        assert_eq!(capture.methods(), ["set_scene", "set_power"]);
+        assert!(!effect.is_running());
+        let (bulb, result) = effect.stop().await.unwrap();
+        result.unwrap();
+        assert!(!bulb.is_connected());
     }
 
     #[tokio::test]
-    async fn notify() {
-        let expect = "{\"id\":1,\"method\":\"set_power\",\"params\":[\"on\",\"smooth\",500,0]}\r\n";
-        let response = "{\"method\":\"props\",\"params\":{\"power\":\"on\", \"bright\":\"10\"}}\r\n{\"id\":1, \"result\":[\"ok\"]}\r\n";
+    async fn effect_dropped_handle() {
+        use crate::effects::{hue_rotate, OnStop};
 
-        let (mut bulb, task) = fake_bulb(expect, response).await;
-        let mut recv = bulb.get_notify().await;
+        let (bulb, capture) = Bulb::with_capture();
+        let effect = hue_rotate(bulb, Duration::from_millis(60), 100, 80, OnStop::Off);
+        wait_requests(&capture, 1).await;
+        drop(effect);
 
-        let (tres, res) = tokio::join!(
-            task,
-            bulb.set_power(
-                Power::On,
-                Effect::Smooth,
-                Duration::from_millis(500),
-                Mode::Normal
+        // The frames keep being sent and the light is not turned off
+        let frames = wait_requests(&capture, 4);
+        tokio::time::timeout(Duration::from_secs(1), frames)
+            .await
+            .unwrap();
+        assert!(capture.methods().iter().all(|method| method == "set_scene"));
+    }
+
+    #[tokio::test]
+    async fn effect_control() {
+        use crate::effects::{hue_rotate, OnStop};
+
+        let (bulb, capture) = Bulb::with_capture();
+        capture.respond("get_prop", vec!["on".to_string()]);
+        fn get_power(bulb: &mut Bulb) -> futures_util::future::BoxFuture<'_, Option<Response>> {
+            Box::pin(async move {
+                let props = Properties(vec![Property::Power]);
+                bulb.get_prop(&props).await.unwrap()
+            })
+        }
+
+        // Frames: the control runs after a frame and before the next one
+        let effect = hue_rotate(bulb, Duration::from_millis(120), 100, 80, OnStop::Stay);
+        let power = effect.control(get_power).await;
+        assert_eq!(power, Some(Some(vec!["on".to_string()])));
+        assert_eq!(capture.methods(), ["set_scene", "get_prop"]);
+
+        // Flow: the control runs right away
+        let (bulb, _) = effect.stop().await.unwrap();
+        capture.take();
+        let effect = hue_rotate(bulb, Duration::from_secs(6), 100, 80, OnStop::Stay);
+        assert!(effect.control(get_power).await.is_some());
+        assert_eq!(capture.methods(), ["set_scene", "get_prop"]);
+        effect.stop().await.unwrap().1.unwrap();
+    }
+
+    #[tokio::test]
+    async fn timeline() {
+        use crate::effects::timeline;
+
+        let mut group = BulbGroup::new();
+        let (desk, desk_capture) = Bulb::with_capture();
+        let (shelf, shelf_capture) = Bulb::with_capture();
+        group.add("desk", desk);
+        group.add("shelf", shelf);
+
+        let ms = Duration::from_millis;
+        let show = timeline()
+            .at(
+                ms(40),
+                "desk",
+                Scene::Ct {
+                    ct: 2700,
+                    bright: 50,
+                },
             )
+            .color(ms(0), "desk", ColorSpec::Rgb(0xff_00_00), 100)
+            .color(ms(20), "shelf", ColorSpec::Hsv(240, 100), 80)
+            .color(ms(0), "lamp", ColorSpec::Ct(4000), 100);
+        assert_eq!(show.duration(), ms(40));
+
+        let start = tokio::time::Instant::now();
+        let report = show.play(&mut group).await;
+        assert!(start.elapsed() >= ms(40));
+        assert_eq!((report.sent, report.skipped), (3, 1));
+        assert!(report.errors.is_empty());
+        let params: Vec<_> = desk_capture.take().into_iter().map(|r| r.params).collect();
+        assert_eq!(
+            params,
+            [
+                json_params(json!(["color", 16711680, 100])),
+                json_params(json!(["ct", 2700, 50]))
+            ]
+        );
+        assert_eq!(
+            shelf_capture.take()[0].params,
+            json_params(json!(["hsv", 240, 100, 80]))
         );
-        tres.unwrap();
 
-        if let Ok(Some(properties)) = res {
-            assert_eq!(properties, vec!["ok"]);
-        } else {
-            panic!("Unexpected result: {:?}", res);
-        }
+        // Started too long ago: all the cues are too late
+        let report = show
+            .play_from(&mut group, tokio::time::Instant::now() - ms(1000))
+            .await;
+        assert_eq!((report.sent, report.skipped), (0, 4));
+        assert!(desk_capture.requests().is_empty());
+    }
 
-        if let Some(Notification(i)) = recv.recv().await {
-            println!("Something");
-            for (k, v) in i.iter() {
-                println!("{} {}", k, v);
-            }
+    #[tokio::test]
+    async fn ambilight() {
+        use crate::effects::{ambilight, AmbilightFrame, AmbilightOptions, ColorPredictor, OnStop};
+
+        // Red channel rising 100 per second, predicted 200ms ahead
+        let options = AmbilightOptions {
+            lead: Duration::from_millis(200),
+            smoothing: Duration::ZERO,
+            fallback_latency: Duration::ZERO,
+            max_prediction: Duration::from_secs(1),
+            threshold: 0,
+        };
+        let mut predictor = ColorPredictor::new(options);
+        let start = std::time::Instant::now() + Duration::from_secs(5);
+        assert_eq!(predictor.update(start, 0x10_00_00, None), 0x10_00_00);
+        let second = start + Duration::from_secs(1);
+        assert_eq!(
+            predictor.update(second, 0x74_00_00, None),
+            (0x74 + 20) << 16
+        );
+        assert!(predictor.latency().unwrap() < Duration::from_millis(500));
+
+        let (bulb, capture) = Bulb::with_capture();
+        let (frames, receiver) = tokio::sync::mpsc::channel(4);
+        let options = AmbilightOptions {
+            smoothing: Duration::ZERO,
+            max_prediction: Duration::ZERO,
+            threshold: 4,
+            ..Default::default()
+        };
+        let effect = ambilight(bulb, receiver, options, OnStop::Stay);
+        for rgb in [0x80_40_00, 0x81_41_00, 0x00_00_ff] {
+            frames.send(AmbilightFrame::new(rgb)).await.unwrap();
         }
+        drop(frames);
+        wait_requests(&capture, 2).await;
+        effect.stop().await.unwrap().1.unwrap();
+        let requests = capture.take();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].params,
+            json_params(json!(["color", 0xff_7f_00, 50]))
+        );
+        assert_eq!(
+            requests[1].params,
+            json_params(json!(["color", 0x00_00_ff, 100]))
+        );
+    }
+
+    #[tokio::test]
+    async fn beats() {
+        use crate::effects::{pulse, strobe, BeatEvent, OnStop};
+
+        let (bulb, capture) = Bulb::with_capture();
+        let (beats, receiver) = tokio::sync::mpsc::channel(4);
+        let color = ColorSpec::Rgb(0xff_00_00);
+        let mut effect = pulse(bulb, color, 5, 100, receiver, OnStop::Stay);
+        beats.send(BeatEvent::Tempo { bpm: 240.0 }).await.unwrap();
+        beats.send(BeatEvent::Beat).await.unwrap();
+        wait_requests(&capture, 1).await;
+        // Predicted beat, even without the sender
+        drop(beats);
+        let start = std::time::Instant::now();
+        wait_requests(&capture, 2).await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        let (bulb, result) = effect.stop().await.unwrap();
+        result.unwrap();
+        assert_eq!(capture.methods(), ["set_scene", "set_scene", "stop_cf"]);
+        let requests = capture.take();
+        let flow = json_params(json!(["cf", 2, 1, "50,1,16711680,100,175,1,16711680,5"]));
+        assert_eq!(requests[0].params, flow);
+        assert_eq!(requests[1].params, flow);
+
+        let (beats, receiver) = tokio::sync::mpsc::channel(4);
+        effect = strobe(bulb, color, 80, receiver, OnStop::Stay);
+        beats.send(BeatEvent::Beat).await.unwrap();
+        wait_requests(&capture, 1).await;
+        effect.stop().await.unwrap().1.unwrap();
+        assert_eq!(
+            capture.take()[0].params,
+            json_params(json!([
+                "cf",
+                3,
+                1,
+                "50,1,16711680,80,50,7,0,-1,50,1,16711680,1"
+            ]))
+        );
+    }
+
+    #[tokio::test]
+    async fn breathe() {
+        use crate::effects::{breathe, Easing, OnStop};
+
+        let (bulb, capture) = Bulb::with_capture();
+        let mut state = vec![String::new(); 14];
+        state[..4].clone_from_slice(&["on".into(), "30".into(), "2".into(), "4000".into()]);
+        capture.respond("get_prop", state);
+
+        let color = ColorSpec::Ct(2700);
+        let period = Duration::from_secs(2);
+        let mut effect = breathe(bulb, color, period, 10, 50, Easing::Linear, OnStop::Recover);
+        wait_requests(&capture, 2).await;
+        let (bulb, result) = effect.stop().await.unwrap();
+        result.unwrap();
+        assert_eq!(
+            capture.methods(),
+            ["get_prop", "set_scene", "stop_cf", "set_scene"]
+        );
+        let requests = capture.take();
+        assert_eq!(
+            requests[1].params,
+            json_params(json!([
+                "cf",
+                0,
+                0,
+                "50,2,2700,10,1000,2,2700,50,950,2,2700,10"
+            ]))
+        );
+        assert_eq!(requests[3].params, json_params(json!(["ct", 4000, 30])));
+
+        let color = ColorSpec::Rgb(0xff_00_00);
+        let period = Duration::from_millis(1600);
+        effect = breathe(bulb, color, period, 1, 100, Easing::Sine, OnStop::Stay);
+        wait_requests(&capture, 1).await;
+        effect.stop().await.unwrap().1.unwrap();
+        let params = capture.take()[0].params.clone();
+        let values: Vec<&str> = params[3].as_str().unwrap().split(',').collect();
+        let brights: Vec<&str> = values.chunks(4).map(|tuple| tuple[3]).collect();
+        assert_eq!(brights.len(), 17);
+        assert_eq!(brights[0], "1");
+        assert_eq!(brights[1], "5");
+        assert_eq!(brights[8], "100");
+        assert_eq!(brights[16], "1");
+    }
+
+    #[tokio::test]
+    async fn builders() {
+        use crate::protocol::Request;
+
+        let (mut bulb, capture) = Bulb::with_capture();
+        bulb.power()
+            .on()
+            .smooth(Duration::from_millis(500))
+            .mode(Mode::Ct)
+            .send()
+            .await
+            .unwrap();
+        bulb.power().off().bg().send().await.unwrap();
+        bulb.hsv(120, 50).bg().send().await.unwrap();
+        let flow = FlowExpresion(vec![FlowTuple::sleep(Duration::from_secs(1))]);
+        bulb.flow(flow)
+            .count(4)
+            .action(CfAction::Off)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            capture.take(),
+            [
+                Request::new(1, "set_power", json_params(json!(["on", "smooth", 500, 1]))),
+                Request::new(
+                    2,
+                    "bg_set_power",
+                    json_params(json!(["off", "sudden", 0, 0]))
+                ),
+                Request::new(3, "bg_set_hsv", json_params(json!([120, 50, "sudden", 0]))),
+                Request::new(4, "start_cf", json_params(json!([4, 2, "1000,7,0,-1"]))),
+            ]
+        );
+    }
+
+    #[test]
+    fn property_conversions() {
+        assert_eq!(Power::from_property_str("on"), Some(Power::On));
+        assert_eq!(Power::from_property_str("\"off\""), Some(Power::Off));
+        assert_eq!(Power::from_property_str(""), None);
+        assert_eq!(Power::Off.as_property_str(), "off");
+        assert_eq!(Mode::from_property_str("5"), Some(Mode::NightLight));
+        assert_eq!(Mode::from_property_str("6"), None);
+        for mode in [Mode::Normal, Mode::Ct, Mode::Rgb, Mode::Hsv, Mode::Cf] {
+            assert_eq!(Mode::from_property_str(mode.as_property_str()), Some(mode));
+        }
+
+        let state: StateSnapshot =
+            serde_json::from_str("{\"power\":\"on\",\"bg_power\":\"off\"}").unwrap();
+        assert_eq!(state.power(), Some(Power::On));
+        assert_eq!(state.bg_power(), Some(Power::Off));
+    }
+
+    #[tokio::test]
+    async fn time_status() {
+        let (mut bulb, capture) = Bulb::with_capture();
+        capture.respond("get_prop", vec!["on".to_string(), "12".to_string()]);
+        let status = bulb.time_status().await.unwrap().unwrap();
+        assert!(status.on);
+        assert_eq!(status.delay_off, Some(Duration::from_secs(12 * 60)));
+        assert!(status.remaining().unwrap() <= Duration::from_secs(12 * 60));
+
+        capture.respond("get_prop", vec!["off".to_string(), "0".to_string()]);
+        assert_eq!(bulb.delay_off().await.unwrap(), None);
+        assert_eq!(
+            capture.requests()[0].params,
+            json_params(json!(["power", "delayoff"]))
+        );
+    }
+
+    #[tokio::test]
+    async fn journal() {
+        let journal = Journal::new();
+        let (bulb, capture) = Bulb::with_capture();
+        let mut bulb = bulb.journal(journal.clone(), "desk");
+        bulb.set_bright(20, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap();
+        bulb.set_rgb(0xff_00_00, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap();
+        bulb.set_bright(40, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap();
+        bulb.set_power(Power::On, Effect::Sudden, Duration::ZERO, Mode::Normal)
+            .await
+            .unwrap();
+        bulb.toggle().await.unwrap();
+        bulb.bg_set_ct_abx(2700, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap();
+        capture.take();
+
+        let (bulb, capture) = Bulb::with_capture();
+        let mut bulb = bulb.journal(journal.clone(), "desk");
+        bulb.replay_journal().await.unwrap();
+        let requests: Vec<_> = capture
+            .requests()
+            .into_iter()
+            .map(|r| (r.method, r.params))
+            .collect();
+        assert_eq!(
+            requests,
+            [
+                (
+                    "set_rgb".to_string(),
+                    json_params(json!([16711680, "sudden", 0]))
+                ),
+                (
+                    "set_bright".to_string(),
+                    json_params(json!([40, "sudden", 0]))
+                ),
+                (
+                    "set_power".to_string(),
+                    json_params(json!(["off", "sudden", 0, 0]))
+                ),
+                (
+                    "bg_set_ct_abx".to_string(),
+                    json_params(json!([2700, "sudden", 0]))
+                ),
+            ]
+        );
+
+        bulb.set_scene(Scene::Ct {
+            ct: 4000,
+            bright: 80,
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            journal.desired("desk")[0],
+            (
+                "set_scene".to_string(),
+                json_params(json!(["ct", 4000, 80]))
+            )
+        );
+        journal.clear("desk");
+        assert!(journal.desired("desk").is_empty());
+    }
+
+    #[tokio::test]
+    async fn long_fade() {
+        use crate::transition::{long_fade, FadeTarget};
+
+        let (mut bulb, task) = fake_bulb_seq(&[
+            (
+                "{\"id\":1,\"method\":\"get_prop\",\"params\":[\"power\",\"bright\",\"color_mode\",\"ct\",\"rgb\",\"hue\",\"sat\"]}\r\n",
+                "{\"id\":1, \"result\":[\"on\",\"10\",\"2\",\"2700\",\"255\",\"0\",\"0\"]}\r\n",
+            ),
+            (
+                "{\"id\":2,\"method\":\"set_scene\",\"params\":[\"cf\",3,1,\"50000,2,3133,40,50000,2,3567,70,50000,2,4000,100\"]}\r\n",
+                "{\"id\":2, \"result\":[\"ok\"]}\r\n",
+            ),
+        ])
+        .await;
+
+        let target = FadeTarget::Ct {
+            ct: 4000,
+            bright: 100,
+        };
+        let res = long_fade(&mut bulb, target, Duration::from_secs(150)).await;
+        task.await.unwrap();
+        assert_eq!(res.unwrap(), Some(vec!["ok".to_string()]));
+
+        let too_long = Duration::from_secs(5 * 3600);
+        assert!(long_fade(&mut bulb, target, too_long).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn scoped_state() {
+        let (mut bulb, task) = fake_bulb_seq(&[
+            (
+                "{\"id\":1,\"method\":\"get_prop\",\"params\":[\"power\",\"bright\",\"color_mode\",\"ct\",\"rgb\",\"hue\",\"sat\",\"active_mode\",\"nl_br\",\"bg_power\",\"bg_bright\",\"bg_lmode\",\"bg_ct\",\"bg_rgb\",\"bg_hue\",\"bg_sat\"]}\r\n",
+                "{\"id\":1, \"result\":[\"on\",\"40\",\"2\",\"2700\",\"255\",\"10\",\"20\",\"\",\"\",\"\",\"\",\"\",\"\",\"\",\"\",\"\"]}\r\n",
+            ),
+            (
+                "{\"id\":2,\"method\":\"set_rgb\",\"params\":[16711680,\"sudden\",0]}\r\n",
+                "{\"id\":2, \"result\":[\"ok\"]}\r\n",
+            ),
+            (
+                "{\"id\":3,\"method\":\"set_scene\",\"params\":[\"ct\",2700,40]}\r\n",
+                "{\"id\":3, \"result\":[\"ok\"]}\r\n",
+            ),
+        ])
+        .await;
+
+        {
+            let mut guard = bulb.scoped_state().await.unwrap();
+            assert_eq!(guard.snapshot().get(Property::Ct), Some("2700"));
+            assert_eq!(guard.snapshot().get(Property::BgPower), None);
+            guard
+                .set_rgb(0xff_00_00, Effect::Sudden, Duration::ZERO)
+                .await
+                .unwrap();
+        }
+
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn room() {
+        let (bulb, task) = fake_bulb_seq(&[(
+            "{\"id\":1,\"method\":\"set_scene\",\"params\":[\"ct\",2700,20]}\r\n",
+            "{\"id\":1, \"result\":[\"ok\"]}\r\n",
+        )])
+        .await;
+
+        let config: RoomConfig = serde_yaml::from_str(
+            "name: living room\nbulbs:\n  sofa: 127.0.0.1\nscenes:\n  movie: !Ct { ct: 2700, bright: 20 }\nschedule:\n  - at: \"07:30\"\n    scene: reading\n  - at: \"21:00\"\n    scene: movie\n",
+        )
+        .unwrap();
+
+        let mut group = BulbGroup::new();
+        group.add("sofa", bulb);
+        let mut room = Room::new(config, group);
+
+        let scheduled = |h, m| room.scheduled_scene(TimeOfDay::new(h, m).unwrap());
+        assert_eq!(scheduled(8, 0), Some("reading"));
+        assert_eq!(scheduled(22, 0), Some("movie"));
+        assert_eq!(scheduled(3, 0), Some("movie"));
+
+        assert!(room.apply("unknown").await.is_none());
+        let results = room.apply("movie").await.unwrap();
+        task.await.unwrap();
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, "sofa");
+        assert_eq!(
+            results[0].1.as_ref().unwrap(),
+            &Some(vec!["ok".to_string()])
+        );
+
+        let yaml = serde_yaml::to_string(room.config()).unwrap();
+        assert!(yaml.contains("at: 07:30") || yaml.contains("at: '07:30'"));
+    }
+
+    #[tokio::test]
+    async fn room_reload() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = tokio::spawn(async move { listener.accept().await.unwrap() });
+
+        let config: RoomConfig =
+            serde_yaml::from_str("name: room\nbulbs:\n  sofa: 127.0.0.1\n  desk: 127.0.0.1\n")
+                .unwrap();
+        let (sofa, desk) = (Bulb::with_capture().0, Bulb::with_capture().0);
+        let mut group = BulbGroup::new();
+        group.add("sofa", sofa.clone());
+        group.add("desk", desk.clone());
+        let mut room = Room::new(config, group);
+
+        let config: RoomConfig = serde_yaml::from_str(&format!(
+            "name: living room\nbulbs:\n  sofa: 127.0.0.1\n  lamp: {}\n  broken: 127.0.0.1:1\n",
+            addr
+        ))
+        .unwrap();
+        let report = room.reload(config).await;
+        accept.await.unwrap();
+
+        assert_eq!(report.connected, vec!["lamp"]);
+        assert_eq!(report.removed, vec!["desk"]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "broken");
+        assert!(matches!(report.failed[0].1, ConnectError::Io(_)));
+        // The connections of the removed bulbs are closed
+        assert!(!desk.is_connected());
+        assert!(sofa.is_connected());
+        assert_eq!(room.config().name, "living room");
+        assert_eq!(room.group().names().collect::<Vec<_>>(), ["sofa", "lamp"]);
+
+        // IPv6 literals (when the loopback interface has IPv6)
+        if let Ok(listener) = TcpListener::bind("[::1]:0").await {
+            let port = listener.local_addr().unwrap().port();
+            let accept = tokio::spawn(async move { listener.accept().await.unwrap() });
+            let config: RoomConfig = serde_yaml::from_str(&format!(
+                "name: living room\nbulbs:\n  sofa: \"[::1]:{}\"\n",
+                port
+            ))
+            .unwrap();
+            let report = room.reload(config).await;
+            accept.await.unwrap();
+            assert_eq!(report.connected, vec!["sofa"]);
+            // Replaced by the connection to the new address
+            assert!(!sofa.is_connected());
+        }
+
+        // Unreachable bulbs do not prevent connecting to the others
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = tokio::spawn(async move { listener.accept().await.unwrap() });
+        let config: RoomConfig = serde_yaml::from_str(&format!(
+            "name: room\nbulbs:\n  lamp: {}\n  broken: 127.0.0.1:1\n",
+            addr
+        ))
+        .unwrap();
+        let (mut room, report) = Room::connect(config).await;
+        accept.await.unwrap();
+        assert_eq!(report.connected, vec!["lamp"]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "broken");
+        assert_eq!(room.group().names().collect::<Vec<_>>(), ["lamp"]);
+        assert_eq!(room.config().bulbs.len(), 2);
+
+        use crate::room::split_address;
+        assert_eq!(split_address("10.0.0.2").unwrap(), ("10.0.0.2", 0));
+        assert_eq!(
+            split_address("lamp.local:55443").unwrap(),
+            ("lamp.local", 55443)
+        );
+        assert_eq!(split_address("fe80::1").unwrap(), ("fe80::1", 0));
+        assert_eq!(split_address("[fe80::1]").unwrap(), ("fe80::1", 0));
+        assert_eq!(
+            split_address("[fe80::1]:55443").unwrap(),
+            ("fe80::1", 55443)
+        );
+        for invalid in ["10.0.0.2:port", "[fe80::1", "[fe80::1]55443", ":55443"] {
+            assert!(matches!(
+                split_address(invalid),
+                Err(ConnectError::Resolve(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn group_error_context() {
+        let (bulb, task) = fake_bulb_seq(&[(
+            "{\"id\":1,\"method\":\"set_bright\",\"params\":[0,\"sudden\",0]}\r\n",
+            "{\"id\":1, \"error\":{\"code\":-1, \"message\":\"invalid params\"}}\r\n",
+        )])
+        .await;
+        let addr = bulb.peer_addr().unwrap();
+
+        let mut group = BulbGroup::new();
+        group.add("desk", bulb);
+        let mut results = group.set_bright(0, Effect::Sudden, Duration::ZERO).await;
+        task.await.unwrap();
+
+        let error = results.pop().unwrap().1.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "desk ({}): set_bright: Bulb response error: invalid params (code -1)",
+                addr
+            )
+        );
+        assert!(matches!(error.inner(), BulbError::ErrResponse(-1, _)));
+    }
+
+    #[tokio::test]
+    async fn group_rate_limit() {
+        let mut group = BulbGroup::new();
+        let (desk, desk_capture) = Bulb::with_capture();
+        group.add("desk", desk);
+        // 100 commands per second, 2 at once
+        group.rate_limit(RateLimit::new(100, 2));
+        let (shelf, shelf_capture) = Bulb::with_capture();
+        group.add("shelf", shelf);
+
+        let start = std::time::Instant::now();
+        for _ in 0..3 {
+            let results = group.for_each(|bulb| Box::pin(bulb.toggle())).await;
+            assert!(results.iter().all(|(_, result)| result.is_ok()));
+        }
+        // The first 2 commands are sent right away and the other 4 wait 10ms each
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(desk_capture.methods().len(), 3);
+        assert_eq!(shelf_capture.methods().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn quota_limit() {
+        let (bulb, capture) = Bulb::with_capture();
+        assert_eq!(bulb.remaining_quota(), None);
+        let limiter = QuotaLimiter::new(QuotaPolicy::Reject).limit(3);
+        let mut bulb = bulb.quota_limit(limiter);
+        assert_eq!(bulb.remaining_quota(), Some(3));
+
+        // Shared with the clones
+        bulb.clone().toggle().await.unwrap();
+        bulb.toggle().await.unwrap();
+        assert_eq!(bulb.remaining_quota(), Some(1));
+        let results = bulb
+            .writer
+            .send_all(&[("toggle", Vec::new()), ("toggle", Vec::new())])
+            .await;
+        assert!(results[0].is_ok());
+        let error = results[1].as_ref().unwrap_err();
+        assert!(matches!(error.inner(), BulbError::QuotaExceeded));
+
+        let error = bulb.toggle().await.unwrap_err();
+        assert!(matches!(error.inner(), BulbError::QuotaExceeded));
+        assert_eq!(error.to_string(), "toggle: Command quota exceeded");
+        assert_eq!(bulb.remaining_quota(), Some(0));
+        assert_eq!(capture.methods().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn auto_music() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use tokio::sync::mpsc;
+
+        // Bulb answering every command, and opening the music mode connection
+        let (io, remote) = tokio::io::duplex(4096);
+        let (music_tx, mut music_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(remote);
+            let mut lines = BufReader::new(read).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+                let response = format!("{{\"id\":{},\"result\":[\"ok\"]}}\r\n", request["id"]);
+                write.write_all(response.as_bytes()).await.unwrap();
+                if request["method"] == "set_music" {
+                    let port = request["params"][2].as_u64().unwrap() as u16;
+                    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                    let music_tx = music_tx.clone();
+                    tokio::spawn(async move {
+                        let mut lines = BufReader::new(stream).lines();
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            music_tx.send(line).unwrap();
+                        }
+                    });
+                }
+            }
+        });
+        let limiter = QuotaLimiter::new(QuotaPolicy::Reject).limit(3);
+        let mut bulb = Bulb::attach_io(io)
+            .quota_limit(limiter)
+            .auto_music("127.0.0.1");
+
+        let set_bright = |bulb: &mut Bulb, bright| {
+            let mut bulb = bulb.clone();
+            async move {
+                bulb.set_bright(bright, Effect::Sudden, Duration::ZERO)
+                    .await
+            }
+        };
+        assert!(set_bright(&mut bulb, 10).await.unwrap().is_some());
+        assert!(set_bright(&mut bulb, 20).await.unwrap().is_some());
+        // The last command of the quota starts music mode
+        assert_eq!(bulb.remaining_quota(), Some(1));
+        assert_eq!(set_bright(&mut bulb, 30).await.unwrap(), None);
+        assert_eq!(bulb.remaining_quota(), Some(0));
+        assert_eq!(set_bright(&mut bulb, 40).await.unwrap(), None);
+        for bright in ["30", "40"] {
+            let line = music_rx.recv().await.unwrap();
+            assert!(
+                line.contains("set_bright") && line.contains(bright),
+                "{}",
+                line
+            );
+        }
+
+        // Other commands are still sent through the normal connection
+        let error = bulb.toggle().await.unwrap_err();
+        assert!(matches!(error.inner(), BulbError::QuotaExceeded));
+    }
+
+    #[tokio::test]
+    async fn state_changes() {
+        let (desk, desk_capture) = Bulb::with_capture();
+        let (shelf, shelf_capture) = Bulb::with_capture();
+        desk_capture.respond("get_prop", vec!["on".to_string(), "10".to_string()]);
+        shelf_capture.respond("get_prop", vec!["on".to_string(), "20".to_string()]);
+
+        let mut group = BulbGroup::new();
+        group.add("desk", desk);
+        group.add("shelf", shelf);
+        let mut changes = group.state_changes();
+
+        let props = Properties(vec![Property::Power, Property::Bright]);
+        let changed = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<std::collections::BTreeMap<_, _>>()
+        };
+
+        group
+            .get_mut("shelf")
+            .unwrap()
+            .get_prop(&props)
+            .await
+            .unwrap();
+        let (name, change) = changes.recv().await.unwrap();
+        assert_eq!(name, "shelf");
+        assert_eq!(
+            change.changed,
+            changed(&[("bright", "20"), ("power", "on")])
+        );
+
+        group
+            .get_mut("desk")
+            .unwrap()
+            .get_prop(&props)
+            .await
+            .unwrap();
+        let (name, change) = changes.recv().await.unwrap();
+        assert_eq!(name, "desk");
+        assert_eq!(
+            change.changed,
+            changed(&[("bright", "10"), ("power", "on")])
+        );
+
+        // Only the properties that changed are reported
+        desk_capture.respond("get_prop", vec!["off".to_string(), "10".to_string()]);
+        group
+            .get_mut("desk")
+            .unwrap()
+            .get_prop(&props)
+            .await
+            .unwrap();
+        let (name, change) = changes.recv().await.unwrap();
+        assert_eq!(name, "desk");
+        assert_eq!(change.changed, changed(&[("power", "off")]));
+        assert_eq!(change.state.get(Property::Bright), Some("10"));
+    }
+
+    #[tokio::test]
+    async fn apply_verified() {
+        let (bulb, task) = fake_bulb_seq(&[
+            (
+                "{\"id\":1,\"method\":\"set_scene\",\"params\":[\"ct\",2700,20]}\r\n",
+                "{\"id\":1, \"result\":[\"ok\"]}\r\n",
+            ),
+            (
+                "{\"id\":2,\"method\":\"get_prop\",\"params\":[\"power\",\"color_mode\",\"ct\",\"bright\"]}\r\n",
+                "{\"id\":2, \"result\":[\"on\",\"2\",\"4000\",\"100\"]}\r\n",
+            ),
+            (
+                "{\"id\":3,\"method\":\"set_scene\",\"params\":[\"ct\",2700,20]}\r\n",
+                "{\"id\":3, \"result\":[\"ok\"]}\r\n",
+            ),
+            (
+                "{\"id\":4,\"method\":\"get_prop\",\"params\":[\"power\",\"color_mode\",\"ct\",\"bright\"]}\r\n",
+                "{\"id\":4, \"result\":[\"on\",\"2\",\"2700\",\"20\"]}\r\n",
+            ),
+        ])
+        .await;
+
+        let mut group = BulbGroup::new();
+        group.add("desk", bulb);
+
+        let options = VerifyOptions {
+            retries: 1,
+            delay: Duration::ZERO,
+        };
+        let report = group
+            .apply_verified(
+                &Scene::Ct {
+                    ct: 2700,
+                    bright: 20,
+                },
+                options,
+            )
+            .await;
+        task.await.unwrap();
+
+        assert!(report.is_success());
+        assert!(report.verified.is_empty());
+        assert_eq!(report.retried, vec!["desk"]);
+    }
+
+    #[test]
+    fn rgb_to_ct() {
+        // Warm white
+        let (ct, bright) = color::rgb_to_ct(0xff_b4_6b, Some((1700, 6500)));
+        assert!((2700..=3300).contains(&ct), "{}", ct);
+        assert_eq!(bright, 100);
+        // Cold white at half brightness
+        let (ct, bright) = color::rgb_to_ct(0x80_80_80, Some((2700, 6500)));
+        assert!((6000..=6600).contains(&ct), "{}", ct);
+        assert_eq!(bright, 50);
+        // Clamped to range
+        assert_eq!(color::rgb_to_ct(0xff_00_00, Some((2700, 6500))).0, 2700);
+        assert_eq!(color::rgb_to_ct(0, Some((2700, 6500))), (2700, 1));
+    }
+
+    #[tokio::test]
+    async fn set_color_emulated() {
+        let (bulb, task) = fake_bulb_seq(&[
+            (
+                "{\"id\":1,\"method\":\"set_ct_abx\",\"params\":[2700,\"sudden\",0]}\r\n",
+                "{\"id\":1, \"result\":[\"ok\"]}\r\n",
+            ),
+            (
+                "{\"id\":2,\"method\":\"set_bright\",\"params\":[100,\"sudden\",0]}\r\n",
+                "{\"id\":2, \"result\":[\"ok\"]}\r\n",
+            ),
+        ])
+        .await;
+        let mut bulb = bulb
+            .with_model("ct_bulb")
+            .color_policy(ColorPolicy::EmulateWithCt);
+
+        let res = bulb
+            .set_color(ColorSpec::Rgb(0xff_00_00), Effect::Sudden, Duration::ZERO)
+            .await;
+        task.await.unwrap();
+        assert_eq!(res.unwrap(), Some(vec!["ok".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let (bulb, _) = Bulb::with_capture();
+        let limits = bulb.limits();
+        assert_eq!(limits.ct_range, Some((1700, 6500)));
+        assert_eq!(limits.min_smooth_duration, Duration::from_millis(50));
+        assert_eq!(limits.bright_range, (1, 100));
+
+        let bulb = bulb.with_model("ct_bulb");
+        assert_eq!(bulb.limits().ct_range, Some((2700, 6500)));
+        let bulb = bulb.with_model("mono");
+        assert_eq!(bulb.limits().ct_range, None);
+        assert_eq!(bulb.limits().max_flow_tuples, limits.max_flow_tuples);
+    }
+
+    #[tokio::test]
+    async fn freshness() {
+        let (bulb, capture) = Bulb::with_capture();
+        let policy =
+            Freshness::max_age(Duration::from_secs(60)).property(Property::Flowing, Duration::ZERO);
+        let mut bulb = bulb.freshness(policy);
+        let props = |props: &[Property]| Properties(props.to_vec());
+
+        capture.respond("get_prop", vec!["on".to_string(), "10".to_string()]);
+        let response = bulb
+            .get_prop(&props(&[Property::Power, Property::Bright]))
+            .await
+            .unwrap();
+        assert_eq!(response.unwrap(), ["on", "10"]);
+        assert_eq!(capture.take().len(), 1);
+
+        // Fresh values are not read again
+        let response = bulb
+            .clone()
+            .get_prop(&props(&[Property::Bright, Property::Power]))
+            .await
+            .unwrap();
+        assert_eq!(response.unwrap(), ["10", "on"]);
+        assert!(capture.take().is_empty());
+
+        capture.respond("get_prop", vec!["0".to_string(), "4000".to_string()]);
+        let response = bulb
+            .get_prop(&props(&[Property::Flowing, Property::Power, Property::Ct]))
+            .await
+            .unwrap();
+        assert_eq!(response.unwrap(), ["0", "on", "4000"]);
+        let requests = capture.take();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].params, json_params(json!(["flowing", "ct"])));
+
+        // Commands make the values they change stale
+        bulb.set_bright(50, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap();
+        capture.respond("get_prop", vec!["on".to_string(), "50".to_string()]);
+        let response = bulb
+            .get_prop(&props(&[Property::Power, Property::Bright]))
+            .await
+            .unwrap();
+        assert_eq!(response.unwrap(), ["on", "50"]);
+        assert_eq!(
+            capture.take()[1].params,
+            json_params(json!(["power", "bright"]))
+        );
+
+        // The state is always read
+        capture.respond("get_prop", vec!["on".to_string()]);
+        bulb.state().await.ok();
+        assert_eq!(capture.take().len(), 1);
+
+        // Without a policy, everything is read
+        let mut bulb = bulb.freshness(Freshness::default());
+        capture.respond("get_prop", vec!["on".to_string()]);
+        bulb.get_prop(&props(&[Property::Power])).await.unwrap();
+        assert_eq!(capture.take().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn send_raw() {
+        let (bulb, capture) = Bulb::with_capture();
+        let mut bulb = bulb.with_support(["get_prop", "set_fancy_mode"]);
+        capture.respond("set_fancy_mode", vec!["ok".to_string()]);
+        let response = bulb
+            .send_raw("set_fancy_mode", &["party".into(), 500.into()])
+            .await
+            .unwrap();
+        assert_eq!(response.unwrap(), [serde_json::json!("ok")]);
+        let request = capture.requests().pop().unwrap();
+        assert_eq!(request.method, "set_fancy_mode");
+        assert_eq!(request.params, json_params(json!(["party", 500])));
+
+        let error = bulb.send_raw("set_other_mode", &[]).await.unwrap_err();
+        assert!(
+            matches!(error.inner(), BulbError::Io(e) if e.kind() == std::io::ErrorKind::Unsupported)
+        );
+        assert_eq!(capture.methods().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn send_command() {
+        let commands = r#"
+- !SetPower { power: On, effect: Smooth, duration: 500, mode: Ct }
+- Toggle
+- !BgSetScene { scene: !Ct { ct: 2700, bright: 40 } }
+- !SetName { name: desk }
+- !GetProp { properties: [Power, Bright] }
+"#;
+        let commands: Vec<Command> = serde_yaml::from_str(commands).unwrap();
+        let (mut bulb, capture) = Bulb::with_capture();
+        capture.respond("get_prop", vec!["on".to_string(), "40".to_string()]);
+        for command in &commands {
+            bulb.send_command(command).await.unwrap();
+        }
+        let requests = capture.take();
+        let sent: Vec<_> = requests
+            .iter()
+            .map(|r| (r.method.as_str(), r.params.clone()))
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                ("set_power", json_params(json!(["on", "smooth", 500, 1]))),
+                ("toggle", json_params(json!([]))),
+                ("bg_set_scene", json_params(json!(["ct", 2700, 40]))),
+                ("set_name", json_params(json!(["desk"]))),
+                ("get_prop", json_params(json!(["power", "bright"]))),
+            ]
+        );
+        assert_eq!(bulb.cached_state().get(Property::Bright), Some("40"));
+
+        assert_eq!(commands[2].method(), Method::BgSetScene);
+        let yaml = serde_yaml::to_string(&commands[0]).unwrap();
+        let command: Command = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(command.params(), commands[0].params());
+    }
+
+    #[tokio::test]
+    async fn escaped_params() {
+        use crate::protocol::Request;
+
+        let (mut bulb, capture) = Bulb::with_capture();
+        bulb.set_name(r#"Desk "left" \ 2"#).await.unwrap();
+        let request = capture.requests().pop().unwrap();
+        assert_eq!(request.params, json_params(json!(["Desk \"left\" \\ 2"])));
+
+        let line = request.to_string();
+        let parsed: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(parsed["params"][0], r#"Desk "left" \ 2"#);
+        assert_eq!(Request::parse(line.trim_end()).unwrap(), request);
+
+        let line = Request::new(1, "set_\"odd", Vec::new()).to_string();
+        let parsed: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(parsed["method"], "set_\"odd");
+    }
+
+    #[tokio::test]
+    async fn frame_cap() {
+        let (bulb, capture) = Bulb::with_capture();
+        assert_eq!(bulb.limits().max_frame_rate, 30);
+        let bulb = bulb.with_model("color4");
+        assert_eq!(bulb.limits().max_frame_rate, 60);
+
+        let mut events = bulb.health_events();
+        let mut bulb = bulb.max_frame_rate(Some(20));
+        let start = std::time::Instant::now();
+        for _ in 0..4 {
+            bulb.toggle().await.unwrap();
+        }
+        // The first two frames are sent right away, and the others every 50ms
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            HealthEvent::FrameRateExceeded(20)
+        );
+        assert!(events.try_recv().is_err());
+        assert_eq!(capture.methods().len(), 4);
+
+        let mut bulb = bulb.max_frame_rate(None);
+        let start = std::time::Instant::now();
+        for _ in 0..4 {
+            bulb.toggle().await.unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let expect = "{\"id\":1,\"method\":\"get_prop\",\"params\":[\"power\",\"bright\"]}\r\n";
+        let response = "{\"id\":1, \"result\":[\"on\",\"10\"]}\r\n{\"method\":\"props\",\"params\":{\"power\":\"off\",\"ct\":2700}}\r\n";
+
+        let (mut bulb, task) = fake_bulb(expect, response).await;
+        let mut state = bulb.watch();
+        let props = Properties(vec![Property::Power, Property::Bright]);
+
+        let (tres, res) = tokio::join!(task, bulb.get_prop(&props));
+        tres.unwrap();
+        res.unwrap();
+
+        state
+            .wait_for(|s| s.get(Property::Ct).is_some())
+            .await
+            .unwrap();
+        let state = bulb.cached_state();
+        assert_eq!(state.get(Property::Bright), Some("10"));
+        assert_eq!(state.get(Property::Ct), Some("2700"));
+    }
+
+    #[test]
+    fn snapshot_diff() {
+        let before: StateSnapshot =
+            serde_json::from_str(r#"{"power":"on","bright":"50","ct":"2700"}"#).unwrap();
+        let after: StateSnapshot =
+            serde_json::from_str(r#"{"power":"on","bright":"80","bg_power":"off"}"#).unwrap();
+
+        let changes = before.diff(&after);
+        let lines: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "bg_power: unknown -> off",
+                "bright: 50 -> 80",
+                "ct: 2700 -> unknown"
+            ]
+        );
+        assert_eq!(changes[1].after.as_deref(), Some("80"));
+        assert!(after.diff(&after).is_empty());
+    }
+
+    #[tokio::test]
+    async fn bulb_state() {
+        let (mut bulb, capture) = Bulb::with_capture();
+        let values = [
+            "on", "80", "2", "2700", "16711680", "", "", "1", "10", "0", "15", "0", "desk", "off",
+            "20", "1", "", "255", "", "",
+        ];
+        capture.respond("get_prop", values.map(String::from).to_vec());
+
+        let state = bulb.state().await.unwrap();
+        assert_eq!(state.power, Power::On);
+        assert_eq!(state.bright, 80);
+        assert_eq!(state.color_mode, Some(ColorMode::Ct));
+        assert_eq!(
+            (state.ct, state.rgb, state.hue),
+            (Some(2700), Some(0xff0000), None)
+        );
+        assert_eq!(
+            (state.flowing, state.delay_off, state.music_on),
+            (false, 15, false)
+        );
+        assert_eq!(state.name, "desk");
+        assert_eq!((state.night_light, state.nl_bright), (Some(true), Some(10)));
+        let bg = state.bg.unwrap();
+        assert_eq!((bg.power, bg.bright), (Power::Off, 20));
+        assert_eq!((bg.color_mode, bg.rgb), (Some(ColorMode::Rgb), Some(255)));
+        assert_eq!(bulb.cached_state().get(Property::Ct), Some("2700"));
+
+        // White bulb without background light
+        capture.respond("get_prop", vec!["off".to_string(), "5".to_string()]);
+        let state = bulb.state().await.unwrap();
+        assert_eq!((state.power, state.bright, state.ct), (Power::Off, 5, None));
+        assert!(state.bg.is_none() && state.night_light.is_none());
+
+        capture.respond("get_prop", vec![]);
+        assert!(bulb.state().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fixtures() {
+        use crate::fixture::{Fixture, Recorder};
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let result = Fixture::load(&path).unwrap().run().await;
+            assert!(
+                result.is_ok(),
+                "{}: {}",
+                path.display(),
+                result.unwrap_err()
+            );
+        }
+
+        // Record a session and replay it
+        let (io, remote) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(remote);
+            let mut lines = BufReader::new(read).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+                let response = format!(
+                    "{{\"id\":{}, \"result\":[\"ok\"]}}\r\n{{\"method\":\"props\",\"params\":{{\"bright\":30}}}}\r\n",
+                    request["id"]
+                );
+                write.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let (io, transcript) = Recorder::new(io);
+        let mut bulb = Bulb::attach_io(io);
+        bulb.set_bright(30, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap();
+        let mut state = bulb.watch();
+        state.wait_for(|state| !state.0.is_empty()).await.unwrap();
+        transcript.expect(&bulb.cached_state());
+        assert_eq!(
+            transcript.fixture(),
+            "> {\"id\":1,\"method\":\"set_bright\",\"params\":[30,\"sudden\",0]}\n\
+             < {\"id\":1, \"result\":[\"ok\"]}\n\
+             < {\"method\":\"props\",\"params\":{\"bright\":30}}\n\
+             = {\"bright\":\"30\"}\n"
+        );
+        let fixture = Fixture::parse(&transcript.fixture()).unwrap();
+        fixture.run().await.unwrap();
+
+        // A different state or request fails
+        let wrong = transcript
+            .fixture()
+            .replace("= {\"bright\":\"30\"}", "= {\"bright\":\"40\"}");
+        let error = Fixture::parse(&wrong).unwrap().run().await.unwrap_err();
+        assert!(error.to_string().contains("line 4"), "{}", error);
+        assert!(Fixture::parse("toggle").is_err());
+    }
+
+    #[tokio::test]
+    async fn reconcile() {
+        let (mut bulb, task) = fake_bulb_seq(&[
+            (
+                "{\"id\":1,\"method\":\"get_prop\",\"params\":[\"power\",\"bright\",\"color_mode\",\"ct\",\"rgb\",\"hue\",\"sat\",\"active_mode\",\"nl_br\",\"bg_power\",\"bg_bright\",\"bg_lmode\",\"bg_ct\",\"bg_rgb\",\"bg_hue\",\"bg_sat\"]}\r\n",
+                "{\"id\":1, \"result\":[\"on\",\"100\",\"2\",\"4000\",\"255\",\"0\",\"0\",\"\",\"\",\"\",\"\",\"\",\"\",\"\",\"\",\"\"]}\r\n",
+            ),
+            (
+                "{\"id\":2,\"method\":\"set_scene\",\"params\":[\"ct\",2700,100]}\r\n",
+                "{\"id\":2, \"result\":[\"ok\"]}\r\n",
+            ),
+        ])
+        .await;
+
+        let desired: StateSnapshot = serde_json::from_str(
+            r#"{"power":"on","bright":"100","color_mode":"2","ct":"2700","rgb":"0"}"#,
+        )
+        .unwrap();
+
+        let drift = bulb
+            .reconcile(&desired, ReconcileMode::Apply)
+            .await
+            .unwrap();
+        task.await.unwrap();
+
+        assert_eq!(drift.len(), 1);
+        assert_eq!(drift[0].to_string(), "ct: 4000 (desired 2700)");
+    }
+
+    #[cfg(feature = "scripting")]
+    #[test]
+    fn script() {
+        use crate::script::{ScriptAction, ScriptHost};
+
+        let mut host = ScriptHost::new(
+            r#"
+            set_bright("desk", 500);
+            every(1, || set_power("desk", false));
+
+            fn on_notification(bulb, props) {
+                if props.power == "on" {
+                    set_ct("porch", 2700, 40);
+                }
+            }
+            "#,
+        )
+        .unwrap();
+
+        let commands = host.take_commands();
+        assert!(matches!(commands[0].action, ScriptAction::Bright(100)));
+        assert!(host.next_timer().is_some());
+
+        let notification: Notification =
+            serde_json::from_str(r#"{"method":"props","params":{"power":"on","bright":10}}"#)
+                .unwrap();
+        let commands = host.on_notification("hall", &notification).unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].bulb, "porch");
+        assert!(matches!(
+            commands[0].action,
+            ScriptAction::Scene(Scene::Ct {
+                ct: 2700,
+                bright: 40
+            })
+        ));
+
+        std::thread::sleep(Duration::from_millis(2));
+        let commands = host.run_timers().unwrap();
+        assert!(matches!(
+            commands[0].action,
+            ScriptAction::Power(Power::Off)
+        ));
+
+        assert!(ScriptHost::new("loop {}").is_err());
+    }
+
+    #[cfg(feature = "webhooks")]
+    #[test]
+    fn webhook() {
+        use crate::webhook::Webhooks;
+
+        let hooks = Webhooks::from_yaml(
+            r#"
+            - url: http://localhost/on
+              property: power
+              value: "on"
+              template: '{"message": "{{bulb}} turned {{value}}", "props": {{props}}}'
+            - url: http://localhost/bright
+              property: bright
+            "#,
+        )
+        .unwrap();
+
+        let notification: Notification =
+            serde_json::from_str(r#"{"method":"props","params":{"power":"on","bright":10}}"#)
+                .unwrap();
+        let triggered = hooks.triggered("desk \"lamp\"", &notification);
+        assert_eq!(
+            triggered,
+            vec![
+                (
+                    "http://localhost/on".to_string(),
+                    r#"{"message": "desk \"lamp\" turned on", "props": {"bright":10,"power":"on"}}"#
+                        .to_string()
+                ),
+                (
+                    "http://localhost/bright".to_string(),
+                    r#"{"bulb":"desk \"lamp\"","property":"bright","value":"10"}"#.to_string()
+                ),
+            ]
+        );
+
+        let notification: Notification =
+            serde_json::from_str(r#"{"method":"props","params":{"power":"off"}}"#).unwrap();
+        assert!(hooks.triggered("desk", &notification).is_empty());
+    }
+
+    #[test]
+    fn sun_times() {
+        use crate::solar::{Date, Location};
+
+        let minutes = |t: Option<TimeOfDay>| {
+            let t = t.unwrap();
+            t.hour as i32 * 60 + t.minute as i32
+        };
+        let close = |t: Option<TimeOfDay>, hour: i32, minute: i32| {
+            (minutes(t) - (hour * 60 + minute)).abs() <= 3
+        };
+
+        let barcelona = Location {
+            latitude: 41.39,
+            longitude: 2.17,
+            utc_offset: 120,
+        };
+        let sun = barcelona.sun_times(Date::new(2024, 6, 21).unwrap());
+        assert!(close(sun.sunrise, 6, 18), "{:?}", sun);
+        assert!(close(sun.sunset, 21, 29), "{:?}", sun);
+        assert!(close(Some(sun.solar_noon), 13, 53), "{:?}", sun);
+        assert!(minutes(sun.dawn) < minutes(sun.sunrise));
+        assert!(minutes(sun.dusk) > minutes(sun.sunset));
+
+        let london = Location {
+            latitude: 51.51,
+            longitude: -0.13,
+            utc_offset: 0,
+        };
+        let sun = london.sun_times(Date::new(2024, 12, 21).unwrap());
+        assert!(close(sun.sunrise, 8, 4), "{:?}", sun);
+        assert!(close(sun.sunset, 15, 54), "{:?}", sun);
+
+        let tromso = Location {
+            latitude: 69.65,
+            longitude: 18.96,
+            utc_offset: 120,
+        };
+        let sun = tromso.sun_times(Date::new(2024, 6, 21).unwrap());
+        assert_eq!(sun.sunrise, None);
+        assert_eq!(sun.sunset, None);
+
+        assert_eq!(Date::new(2023, 2, 29), None);
+        assert!(Date::new(2024, 2, 29).is_some());
+    }
+
+    #[tokio::test]
+    async fn auto_off() {
+        let (bulb, task) = fake_bulb_seq(&[
+            (
+                "{\"id\":1,\"method\":\"get_prop\",\"params\":[\"power\"]}\r\n",
+                "{\"id\":1, \"result\":[\"on\"]}\r\n",
+            ),
+            (
+                "{\"id\":2,\"method\":\"get_prop\",\"params\":[\"power\",\"color_mode\",\"ct\",\"rgb\",\"hue\",\"sat\"]}\r\n",
+                "{\"id\":2, \"result\":[\"on\",\"2\",\"2700\",\"255\",\"0\",\"0\"]}\r\n",
+            ),
+            (
+                "{\"id\":3,\"method\":\"start_cf\",\"params\":[1,2,\"1000,2,2700,1\"]}\r\n",
+                "{\"id\":3, \"result\":[\"ok\"]}\r\n",
+            ),
+        ])
+        .await;
+
+        let mut group = BulbGroup::new();
+        group.add("hall", bulb);
+
+        let timers: AutoOff = serde_yaml::from_str("after: 0\nfade: 1").unwrap();
+        let timers = [("hall".to_string(), timers)].into_iter().collect();
+
+        tokio::select! {
+            _ = group.auto_off(&timers) => panic!("auto_off returned"),
+            result = task => result.unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn benchmark() {
+        use crate::effects::{benchmark, BenchmarkOptions};
+        use tokio::io::AsyncReadExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut bulb = Bulb::attach_tokio(stream).no_response();
+
+        let options = BenchmarkOptions {
+            fps: vec![50, 100],
+            frames: 5,
+        };
+        let report = benchmark(&mut bulb, &options).await.unwrap();
+
+        assert_eq!(report.results.len(), 2);
+        assert_eq!(report.results[1].fps, 100);
+        assert!(report.results.iter().all(|r| r.achieved_fps > 0.0));
+        assert_eq!(bulb.stats().commands, 10);
+    }
+
+    #[cfg(feature = "simulator")]
+    #[tokio::test]
+    async fn simulator() {
+        use crate::simulator::Simulator;
+
+        let simulator = Simulator::new("color");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(simulator.clone().serve(listener));
+
+        let mut bulb = Bulb::connect("127.0.0.1", addr.port()).await.unwrap();
+        let mut notifications = bulb.get_notify().await;
+
+        bulb.set_scene(Scene::Hsv {
+            hue: 120,
+            sat: 50,
+            bright: 30,
+        })
+        .await
+        .unwrap();
+        let props = Properties(vec![Property::ColorMode, Property::Hue, Property::Bright]);
+        let values = bulb.get_prop(&props).await.unwrap().unwrap();
+        assert_eq!(values, ["3", "120", "30"]);
+
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(
+            notification.params.get("hue"),
+            Some(&serde_json::json!(120))
+        );
+
+        assert!(matches!(
+            bulb.set_music(MusicAction::Off, "", 0).await,
+            Ok(Some(_))
+        ));
+        let error = bulb.bg_toggle().await.unwrap_err();
+        assert!(matches!(error.inner(), BulbError::ErrResponse(-1, _)));
+        assert_eq!(simulator.state()["power"], "on");
+    }
+
+    #[tokio::test]
+    async fn attach_io() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut bulb = Bulb::attach_io(client);
+
+        let task = tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(server);
+            let mut lines = BufReader::new(read).lines();
+            let line = lines.next_line().await.unwrap().unwrap();
+            assert_eq!(line, "{\"id\":1,\"method\":\"toggle\",\"params\":[]}");
+            write
+                .write_all(b"{\"id\":1, \"result\":[\"ok\"]}\r\n")
+                .await
+                .unwrap();
+        });
+
+        let res = bulb.toggle().await.unwrap();
+        task.await.unwrap();
+        assert_eq!(res, Some(vec!["ok".to_string()]));
+    }
+
+    #[cfg(feature = "discover")]
+    #[test]
+    fn discovered_info() {
+        use discover::DiscoveredBulb;
+
+        let properties = [
+            ("Location", "yeelight://192.168.1.239:55443"),
+            ("model", "mono"),
+            ("fw_ver", "18"),
+            (
+                "support",
+                "get_prop set_default set_power toggle set_bright",
+            ),
+            ("power", "on"),
+            ("bright", "100"),
+            ("color_mode", "2"),
+            ("ct", "4000"),
+            ("rgb", ""),
+            ("name", "desk"),
+        ];
+        let bulb = DiscoveredBulb {
+            uid: 0x4f,
+            response_address: "192.168.1.239:1982".parse().unwrap(),
+            properties: properties
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+
+        let info = bulb.info();
+        assert_eq!(info.address, "192.168.1.239:55443");
+        assert!(info.supports("set_bright"));
+        assert!(!info.supports("set_rgb"));
+        assert!(!info.model().unwrap().color);
+        assert_eq!(info.state.get(Property::Ct), Some("4000"));
+        assert_eq!(info.state.get(Property::Rgb), None);
+
+        let json = serde_json::to_string(&info).unwrap();
+        assert_eq!(
+            serde_json::from_str::<discover::BulbInfo>(&json).unwrap(),
+            info
+        );
+    }
+
+    #[cfg(feature = "discover")]
+    #[tokio::test]
+    async fn connect_all() {
+        use discover::{connect_all, DiscoveredBulb};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = tokio::spawn(async move { listener.accept().await.unwrap() });
+
+        let discovered = |location: Option<String>| DiscoveredBulb {
+            uid: 1,
+            response_address: addr,
+            properties: location
+                .map(|l| ("Location".to_string(), l))
+                .into_iter()
+                .collect(),
+        };
+        let bulbs = [
+            discovered(Some(format!("yeelight://{}", addr))),
+            discovered(Some("yeelight://127.0.0.1:1".to_string())),
+            discovered(None),
+        ];
+
+        let results = connect_all(&bulbs).await;
+        accept.await.unwrap();
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(ConnectError::Io(_))));
+        assert!(matches!(results[2], Err(ConnectError::Resolve(_))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn broker() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use tokio::net::UnixListener;
+
+        // Fake bulb answering each request with its method, followed by a notification
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut lines = BufReader::new(read).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+                        let response = format!(
+                            "{}\r\n{{\"method\":\"props\",\"params\":{{\"power\":\"on\"}}}}\r\n",
+                            serde_json::json!({ "id": request["id"], "result": [request["method"]] })
+                        );
+                        write.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let socket = std::env::temp_dir().join(format!("yeelight-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        tokio::spawn(broker::Broker::new().serve_listener(listener));
+
+        let mut first = broker::connect(&socket, "127.0.0.1", port).await.unwrap();
+        let mut second = broker::connect(&socket, "127.0.0.1", port).await.unwrap();
+        let mut state = first.watch();
+
+        // Both clients use the same request ids, the broker keeps them apart
+        let (toggle, set_default) = tokio::join!(first.toggle(), second.set_default());
+        assert_eq!(toggle.unwrap(), Some(vec!["toggle".to_string()]));
+        assert_eq!(set_default.unwrap(), Some(vec!["set_default".to_string()]));
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        // Notifications reach all the clients
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            state.wait_for(|state| state.power() == Some(Power::On)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        std::fs::remove_file(&socket).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn broker_dedup() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use tokio::net::UnixListener;
+
+        // Fake bulb recording the methods, notifications are injected through `notify`
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (notify, mut notifications) = tokio::sync::mpsc::unbounded_channel::<String>();
+        let methods = received.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            loop {
+                let line = tokio::select! {
+                    Ok(Some(line)) = lines.next_line() => {
+                        let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+                        methods.lock().unwrap().push(request["method"].clone());
+                        serde_json::json!({ "id": request["id"], "result": ["ok"] }).to_string()
+                    }
+                    Some(line) = notifications.recv() => line,
+                };
+                write
+                    .write_all(format!("{}\r\n", line).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let socket =
+            std::env::temp_dir().join(format!("yeelight-dedup-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        tokio::spawn(broker::Broker::new().dedup(true).serve_listener(listener));
+
+        let mut first = broker::connect(&socket, "127.0.0.1", port).await.unwrap();
+        let mut second = broker::connect(&socket, "127.0.0.1", port).await.unwrap();
+        let mut forced = broker::connect_forced(&socket, "127.0.0.1", port)
+            .await
+            .unwrap();
+        async fn on(bulb: &mut Bulb) -> Option<Vec<String>> {
+            let power = bulb.set_power(Power::On, Effect::Sudden, Duration::ZERO, Mode::Normal);
+            power.await.unwrap()
+        }
+
+        assert_eq!(on(&mut first).await, Some(vec!["ok".to_string()]));
+        assert_eq!(on(&mut second).await, Some(vec!["ok".to_string()]));
+        on(&mut forced).await;
+        assert_eq!(received.lock().unwrap().len(), 2);
+
+        // Notifications of the same state keep it, different ones (a wall switch) forget it
+        let props = |power: &str| {
+            format!(
+                "{{\"method\":\"props\",\"params\":{{\"power\":\"{}\"}}}}",
+                power
+            )
+        };
+        let mut state = first.watch();
+        notify.send(props("on")).unwrap();
+        state
+            .wait_for(|state| state.power() == Some(Power::On))
+            .await
+            .unwrap();
+        on(&mut second).await;
+        assert_eq!(received.lock().unwrap().len(), 2);
+        notify.send(props("off")).unwrap();
+        state
+            .wait_for(|state| state.power() == Some(Power::Off))
+            .await
+            .unwrap();
+        on(&mut second).await;
+        assert_eq!(
+            *received.lock().unwrap(),
+            ["set_power", "set_power", "set_power"]
+        );
+
+        std::fs::remove_file(&socket).unwrap();
+    }
+
+    #[cfg(feature = "discover")]
+    #[tokio::test]
+    async fn discovery_relay_stops() {
+        use std::sync::Arc;
+        use tokio::net::UdpSocket;
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let address = socket.local_addr().unwrap();
+        let (send, mut recv) = tokio::sync::mpsc::channel(1);
+        let task = tokio::spawn(discover::relay(socket.clone(), send));
+
+        let response = "HTTP/1.1 200 OK\r\nid: 0x1f\r\nname: desk\r\n";
+        let bulb = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        bulb.send_to(response.as_bytes(), address).await.unwrap();
+        assert_eq!(recv.recv().await.unwrap().uid, 0x1f);
+
+        // Dropping the receiver ends the task and releases the socket
+        drop(recv);
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Arc::strong_count(&socket), 1);
+    }
+
+    #[cfg(feature = "discover")]
+    #[tokio::test]
+    async fn probe_subnet() {
+        use discover::probe_subnet;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        assert!(probe_subnet("10.0.0.0/8", 16).await.is_err());
+        assert!(probe_subnet("10.0.0.300/24", 16).await.is_err());
+
+        // The control port is fixed, skip if it is in use
+        let Ok(listener) = TcpListener::bind("127.0.0.2:55443").await else {
+            return;
+        };
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            let request = lines.next_line().await.unwrap().unwrap();
+            assert!(request.contains("get_prop"));
+            let response = "{\"id\":1,\"result\":[\"desk\",\"on\",\"50\",\"2\",\"4000\",\"0\",\"0\",\"0\"]}\r\n";
+            write.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let bulbs = probe_subnet("127.0.0.0/30", 2).await.unwrap();
+        assert_eq!(bulbs.len(), 1);
+        let info = bulbs[0].info();
+        assert_eq!(info.address, "127.0.0.2:55443");
+        assert_eq!(info.name, "desk");
+        assert_eq!(info.state.get(Property::Bright), Some("50"));
+    }
+
+    #[cfg(feature = "discover")]
+    #[tokio::test]
+    async fn query_state() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            // The first request is lost
+            socket.recv_from(&mut buf).await.unwrap();
+            let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+            assert!(std::str::from_utf8(&buf[..len])
+                .unwrap()
+                .starts_with("M-SEARCH"));
+            let response = "HTTP/1.1 200 OK\r\nLocation: yeelight://127.0.0.1:55443\r\nid: 0x000000000015243f\r\nmodel: color\r\npower: on\r\nbright: 40\r\ncolor_mode: 2\r\nct: 3500\r\nrgb: 0\r\nhue: \r\nsat: \r\nname: desk\r\n";
+            socket.send_to(response.as_bytes(), addr).await.unwrap();
+        });
+
+        let state = discover::query_state("127.0.0.1", port).await.unwrap();
+        assert_eq!(state.get(Property::Power), Some("on"));
+        assert_eq!(state.get(Property::Ct), Some("3500"));
+        assert_eq!(state.get(Property::Hue), None);
+        assert_eq!(state.get(Property::Name), None);
+    }
+
+    #[tokio::test]
+    async fn disconnected() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut group = BulbGroup::new();
+        group.add("desk", Bulb::attach_io(client));
+        let mut availability = group.availability();
+        assert_eq!(availability.recv().await, Some(("desk".to_string(), true)));
+        assert!(group.get_mut("desk").unwrap().is_connected());
+
+        drop(server);
+        let bulb = group.get_mut("desk").unwrap();
+        bulb.disconnected().await;
+        assert!(!bulb.is_connected());
+        assert_eq!(availability.recv().await, Some(("desk".to_string(), false)));
+    }
+
+    #[tokio::test]
+    async fn cancelled_send() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        // The buffer is smaller than a message, so the write blocks until the bulb reads
+        let (client, server) = tokio::io::duplex(8);
+        let mut bulb = Bulb::attach_io(client);
+        let send = bulb.set_bright(50, Effect::Sudden, Duration::ZERO);
+        let cancelled = tokio::time::timeout(Duration::from_millis(20), send).await;
+        assert!(cancelled.is_err());
+
+        let send = bulb.set_bright(60, Effect::Sudden, Duration::ZERO);
+        let bulb_task = tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(server);
+            let mut lines = BufReader::new(read).lines();
+            let first = lines.next_line().await.unwrap().unwrap();
+            let second = lines.next_line().await.unwrap().unwrap();
+            write
+                .write_all(b"{\"id\":2, \"result\":[\"ok\"]}\r\n")
+                .await
+                .unwrap();
+            // The connection is kept open, the cancelled request is not answered
+            (first, second, write)
+        });
+        assert!(send.await.is_ok());
+
+        // The cancelled message was written whole before the next one
+        let (first, second, _server) = bulb_task.await.unwrap();
+        assert_eq!(
+            first,
+            "{\"id\":1,\"method\":\"set_bright\",\"params\":[50,\"sudden\",0]}"
+        );
+        assert!(second.starts_with("{\"id\":2,"));
+        assert_eq!(bulb.pending_requests().await, 0);
+    }
+
+    #[tokio::test]
+    async fn response_slots() {
+        let (bulb, capture) = Bulb::with_capture();
+        // The benchmark is not answered from the cache
+        capture.respond("get_prop", vec!["on".to_string()]);
+        let freshness = Freshness::max_age(Duration::from_secs(60));
+        let mut bulb = bulb.response_slots(2).freshness(freshness);
+        for bright in 1..=5 {
+            let response = bulb
+                .set_bright(bright, Effect::Sudden, Duration::ZERO)
+                .await;
+            assert_eq!(response.unwrap(), Some(vec!["ok".to_string()]));
+        }
+        let report = effects::benchmark_requests(&mut bulb, 10).await.unwrap();
+        assert_eq!(report.requests, 10);
+        assert_eq!(capture.requests().len(), 15);
+        assert_eq!(bulb.pending_requests().await, 0);
+
+        // Requests dropped while waiting for the response free their slot
+        let (client, _server) = tokio::io::duplex(1024);
+        let mut bulb = Bulb::attach_io(client).response_slots(1);
+        for _ in 0..3 {
+            let send = bulb.toggle();
+            assert!(tokio::time::timeout(Duration::from_millis(10), send)
+                .await
+                .is_err());
+            assert_eq!(bulb.pending_requests().await, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn socks5_proxy() {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 2, 0, 2]);
+            stream.write_all(&[5, 2]).await.unwrap();
+
+            let mut buf = [0; 10];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"\x01\x04user\x03pwd");
+            stream.write_all(&[1, 0]).await.unwrap();
+
+            let mut buf = [0; 10];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 1, 0, 1, 10, 0, 0, 7, 0xd8, 0x93]);
+            stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+
+            let mut lines = BufReader::new(stream);
+            let mut line = String::new();
+            lines.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "{\"id\":1,\"method\":\"toggle\",\"params\":[]}\r\n");
+            lines
+                .get_mut()
+                .write_all(b"{\"id\":1, \"result\":[\"ok\"]}\r\n")
+                .await
+                .unwrap();
+        });
+
+        let proxy: Proxy = format!("socks5://user:pwd@{}", addr).parse().unwrap();
+        let options = ConnectOptions::default().proxy(proxy);
+        let mut bulb = Bulb::connect_with("10.0.0.7", 0, &options).await.unwrap();
+
+        let res = bulb.toggle().await.unwrap();
+        task.await.unwrap();
+        assert_eq!(res, Some(vec!["ok".to_string()]));
+
+        assert_eq!(
+            "http://proxy".parse::<Proxy>(),
+            Ok(Proxy::Http {
+                host: "proxy".to_string(),
+                port: 80,
+                credentials: None
+            })
+        );
+        assert!("ftp://proxy".parse::<Proxy>().is_err());
+
+        // Fields longer than a byte can count are not sent
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = Proxy::Socks5 {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            credentials: Some(("u".repeat(256), "pwd".to_string())),
+        };
+        let options = ConnectOptions::default().proxy(proxy);
+        let result = Bulb::connect_with("10.0.0.7", 0, &options).await;
+        assert!(
+            matches!(result, Err(ConnectError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput)
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_when_idle() {
+        let (bulb, task) = fake_bulb_seq(&[(
+            "{\"id\":1,\"method\":\"get_prop\",\"params\":[\"power\"]}\r\n",
+            "{\"id\":1, \"result\":[\"off\"]}\r\n",
+        )])
+        .await;
+
+        let poller = bulb.poll_when_idle(PollOptions {
+            properties: vec![Property::Power],
+            interval: Duration::from_millis(10),
+        });
+        poller
+            .watch()
+            .wait_for(|s| s.get(Property::Power) == Some("off"))
+            .await
+            .unwrap();
+        task.await.unwrap();
+
+        let bulb = poller.stop().await.unwrap();
+        assert_eq!(bulb.cached_state().get(Property::Power), Some("off"));
+    }
+
+    #[test]
+    fn flow_expression_serde() {
+        let flow = FlowExpresion(vec![
+            FlowTuple::rgb(Duration::from_millis(500), 0xff_00_00, 100),
+            FlowTuple::sleep(Duration::from_millis(250)),
+            FlowTuple::ct(Duration::from_secs(1), 2700, -1),
+        ]);
+
+        let json = serde_json::to_string(&flow).unwrap();
+        assert_eq!(json, "\"500,1,16711680,100,250,7,0,-1,1000,2,2700,-1\"");
+        assert_eq!(flow.to_param().to_string(), json);
+
+        let parsed: FlowExpresion = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.to_string(), flow.to_string());
+
+        assert!(serde_json::from_str::<FlowExpresion>("\"500,1,255\"").is_err());
+        assert!(serde_json::from_str::<FlowExpresion>("\"500,3,255,100\"").is_err());
+    }
+
+    #[test]
+    fn flow_expression_serde_tuples() {
+        #[derive(Serialize, Deserialize)]
+        struct Wrapper {
+            #[serde(with = "flow_tuples")]
+            flow: FlowExpresion,
+        }
+
+        let flow = FlowExpresion(vec![FlowTuple::rgb(Duration::from_millis(500), 0xff, 50)]);
+        let json = serde_json::to_string(&Wrapper { flow: flow.clone() }).unwrap();
+        assert!(json.starts_with("{\"flow\":[{"));
+
+        let parsed: Wrapper = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.flow.to_string(), flow.to_string());
+    }
+
+    #[test]
+    fn malformed_input_does_not_panic() {
+        let valid = "{\"id\":1, \"result\":[\"ok\"]}\r\n{\"method\":\"props\",\"params\":{\"power\":\"on\"}}\r\n";
+
+        for input in [
+            "",
+            "\r\n\r\n",
+            "{",
+            "not json\r\n",
+            "{\"id\":\"1\",\"result\":[\"ok\"]}\r\n",
+            "{\"id\":1,\"result\":[1,2]}\r\n",
+            "{\"id\":1,\"error\":{}}\r\n",
+            "{\"method\":\"props\",\"params\":[]}\r\n",
+            valid,
+        ] {
+            fuzzing::feed(input.as_bytes());
+            fuzzing::parse_line(input);
+        }
+
+        // Deterministic pseudo-random mutations of a valid message
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..500 {
+            let mut data = valid.as_bytes().to_vec();
+            for _ in 0..4 {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                let pos = (seed % data.len() as u64) as usize;
+                data[pos] = (seed >> 32) as u8;
+            }
+            fuzzing::feed(&data);
+            fuzzing::parse_line(&String::from_utf8_lossy(&data));
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_line_is_skipped() {
+        let expect = "{\"id\":1,\"method\":\"toggle\",\"params\":[]}\r\n";
+        let response = "garbage\r\n{\"id\":1, \"result\":[\"ok\"]}\r\n";
+
+        let (mut bulb, task) = fake_bulb(expect, response).await;
+
+        let (tres, res) = tokio::join!(task, bulb.toggle());
+        tres.unwrap();
+        assert_eq!(res.unwrap(), Some(vec!["ok".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn get_prop() {
+        let expect = "{\"id\":1,\"method\":\"get_prop\",\"params\":[\"name\",\"power\"]}\r\n";
+        let response = "{\"id\":1, \"result\":[\"bulb_name\",\"on\"]}\r\n";
+
+        let (mut bulb, task) = fake_bulb(expect, response).await;
+
+        let prop = &Properties(vec![Property::Name, Property::Power]);
+
+        let (tres, res) = tokio::join!(task, bulb.get_prop(prop));
+        tres.unwrap();
+
+        if let Ok(Some(properties)) = res {
+            assert_eq!(properties, vec!["bulb_name", "on"]);
+        } else {
+            panic!("Unexpected result: {:?}", res);
+        }
+    }
+
+    #[tokio::test]
+    async fn set_scene_cf() {
+        let expect =
+            "{\"id\":1,\"method\":\"set_scene\",\"params\":[\"cf\",0,1,\"500,2,2700,50\"]}\r\n";
+        let response = "{\"id\":1, \"result\":[\"ok\"]}\r\n";
+
+        let (mut bulb, task) = fake_bulb(expect, response).await;
+
+        let scene = Scene::Cf {
+            count: 0,
+            action: CfAction::Stay,
+            flow: FlowExpresion(vec![FlowTuple::ct(Duration::from_millis(500), 2700, 50)]),
+        };
+
+        let (tres, res) = tokio::join!(task, bulb.set_scene(scene));
+        tres.unwrap();
+        assert_eq!(res.unwrap(), Some(vec!["ok".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn set_power() {
+        let expect = "{\"id\":1,\"method\":\"set_power\",\"params\":[\"on\",\"smooth\",500,0]}\r\n";
+        let response = "{\"id\":1, \"result\":[\"ok\"]}\r\n";
+
+        let (mut bulb, task) = fake_bulb(expect, response).await;
+
+        let (tres, res) = tokio::join!(
+            task,
+            bulb.set_power(
+                Power::On,
+                Effect::Smooth,
+                Duration::from_millis(500),
+                Mode::Normal
+            )
+        );
+        tres.unwrap();
+
+        if let Ok(Some(properties)) = res {
+            assert_eq!(properties, vec!["ok"]);
+        } else {
+            panic!("Unexpected result: {:?}", res);
+        }
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let expect = "{\"id\":1,\"method\":\"toggle\",\"params\":[]}\r\n";
+        let response = "{\"method\":\"props\",\"params\":{\"power\":\"on\"}}\r\n{\"id\":1, \"result\":[\"ok\"]}\r\n";
+
+        let (mut bulb, task) = fake_bulb(expect, response).await;
+
+        let (tres, res) = tokio::join!(task, bulb.toggle());
+        tres.unwrap();
+        res.unwrap();
+
+        let stats = bulb.stats();
+        assert_eq!(stats.commands, 1);
+        assert_eq!(stats.errors, 0);
+        assert_eq!(stats.notifications, 1);
+        // Nobody subscribed to the notifications
+        assert_eq!(stats.dropped_notifications, 1);
+        assert_eq!(stats.bytes_sent, expect.len() as u64);
+        assert_eq!(stats.bytes_received, response.len() as u64);
+        assert!(stats.latency.is_some());
+        assert_eq!(stats.commands_last_minute, 1);
+        assert_eq!(stats.notifications_last_minute, 1);
+        assert_eq!(stats.reconnects, 0);
+        let report = stats.to_string();
+        assert!(report.contains("Quota usage:     1/60 commands in the last minute\n"));
+    }
+
+    #[tokio::test]
+    async fn unsupported() {
+        let expect = "{\"id\":1,\"method\":\"set_power\",\"params\":[\"on\",\"smooth\",500,0]}\r\n";
+        let response =
+            "{\"id\":1, \"error\":{\"code\":-1, \"message\":\"unsupported method\"}}\r\n";
+
+        let (mut bulb, task) = fake_bulb(expect, response).await;
+
+        let (tres, res) = tokio::join!(
+            task,
+            bulb.set_power(
+                Power::On,
+                Effect::Smooth,
+                Duration::from_millis(500),
+                Mode::Normal
+            )
+        );
+        tres.unwrap();
+
+        if let Err(error) = res {
+            assert_eq!(
+                "set_power: Bulb response error: unsupported method (code -1)",
+                error.to_string()
+            );
+            assert_eq!(error.method(), Some("set_power"));
+            if let BulbError::ErrResponse(code, message) = error.inner() {
+                assert_eq!(*code, -1);
+                assert_eq!(message, "unsupported method");
+            }
+        } else {
+            panic!("Unexpected result: {:?}", res);
+        }
+    }
+
+    #[tokio::test]
+    async fn no_response() {
+        let expect = "{\"id\":1,\"method\":\"set_power\",\"params\":[\"on\",\"smooth\",500,0]}\r\n";
+        let response = "{\"empty\"}";
+
+        let (mut bulb, task) = fake_bulb(expect, response).await;
+
+        bulb = bulb.get_response().no_response();
+
+        let (tres, res) = tokio::join!(
+            task,
+            bulb.set_power(
+                Power::On,
+                Effect::Smooth,
+                Duration::from_millis(500),
+                Mode::Normal
+            )
+        );
+        tres.unwrap();
+        assert_eq!(res.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn coalesce_writes() {
+        let expect = "{\"id\":1,\"method\":\"toggle\",\"params\":[]}\r\n{\"id\":2,\"method\":\"toggle\",\"params\":[]}\r\n{\"id\":3,\"method\":\"toggle\",\"params\":[]}\r\n";
+
+        let (bulb, task) = fake_bulb(expect, "").await;
+        let mut bulb = bulb.no_response().coalesce_writes(WriteCoalescing {
+            max_delay: Duration::from_secs(10),
+            max_bytes: 4096,
+        });
+
+        for _ in 0..3 {
+            assert_eq!(bulb.toggle().await.unwrap(), None);
+        }
+        bulb.flush().await.unwrap();
+
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn priority() {
+        use tokio::io::AsyncReadExt;
+
+        let expect = "{\"id\":3,\"method\":\"set_power\",\"params\":[\"off\",\"sudden\",0,0]}\r\n{\"id\":4,\"method\":\"toggle\",\"params\":[]}\r\n{\"id\":5,\"method\":\"set_bright\",\"params\":[1,\"sudden\",0]}\r\n";
+
+        let (io, mut remote) = tokio::io::duplex(4096);
+        let mut bulb = Bulb::attach_io(io)
+            .no_response()
+            .coalesce_writes(WriteCoalescing {
+                max_delay: Duration::from_secs(10),
+                max_bytes: 4096,
+            });
+
+        // Buffered messages are discarded by the ones with high priority
+        bulb.toggle().await.unwrap();
+        bulb.toggle().await.unwrap();
+        bulb.set_power(Power::Off, Effect::Sudden, Duration::ZERO, Mode::Normal)
+            .await
+            .unwrap();
+        bulb.toggle().await.unwrap();
+        bulb.flush().await.unwrap();
+
+        bulb.priority(Priority::High)
+            .set_bright(1, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap();
+
+        let mut buf = vec![0; expect.len()];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(std::str::from_utf8(&buf).unwrap(), expect);
+
+        // Waiting for the response of a discarded message fails without retrying
+        let (bulb, capture) = Bulb::with_capture();
+        let bulb = bulb.coalesce_writes(WriteCoalescing {
+            max_delay: Duration::from_secs(10),
+            max_bytes: 4096,
+        });
+        let off = json_params(json!(["off", "sudden", 0]));
+        let results = bulb
+            .writer
+            .clone()
+            .send_all(&[("toggle", Vec::new()), ("set_power", off)])
+            .await;
+        assert!(matches!(results[0], Err(ref e) if matches!(e.inner(), BulbError::Preempted)));
+        assert!(results[1].is_ok());
+        assert_eq!(capture.methods(), ["set_power"]);
+    }
+
+    #[tokio::test]
+    async fn queue_depth() {
+        let (io, _remote) = tokio::io::duplex(4096);
+        let bulb = Bulb::attach_io(io);
+
+        // The bulb never answers
+        let mut waiting = bulb.clone();
+        let request = tokio::spawn(async move { waiting.toggle().await });
+        while bulb.pending_requests().await == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(bulb.pending_requests().await, 1);
+        // Not waited for once the request is dropped
+        request.abort();
+        let _ = request.await;
+        assert_eq!(bulb.pending_requests().await, 0);
+
+        let mut bulb = bulb.no_response().coalesce_writes(WriteCoalescing {
+            max_delay: Duration::from_secs(10),
+            max_bytes: 4096,
+        });
+        for _ in 0..3 {
+            bulb.toggle().await.unwrap();
+        }
+        assert_eq!(bulb.queued_messages(), 3);
+        bulb.flush().await.unwrap();
+        assert_eq!(bulb.queued_messages(), 0);
+    }
+
+    #[tokio::test]
+    async fn builder() {
+        let result = Bulb::builder().connect().await;
+        assert!(matches!(result, Err(ConnectError::Resolve(_))));
+
+        // Never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut bulb = Bulb::builder()
+            .addr("127.0.0.1")
+            .port(port)
+            .connect_timeout(Duration::from_secs(5))
+            .response_timeout(Duration::from_millis(50))
+            .tcp_nodelay(true)
+            .notify_buffer(100)
+            .connect()
+            .await
+            .unwrap();
+        let (_stream, _) = listener.accept().await.unwrap();
+        let error = bulb.toggle().await.unwrap_err();
+        assert!(matches!(error.inner(), BulbError::Timeout));
+        let _notifications = bulb.get_notify().await;
+        let sender = bulb.notify_chan.lock().await.clone().unwrap();
+        assert_eq!(sender.max_capacity(), 100);
+
+        let mut bulb = Bulb::builder()
+            .addr("127.0.0.1")
+            .port(port)
+            .no_response()
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(bulb.toggle().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn connect_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bulb = Bulb::connect_addr(addr).await.unwrap();
+        assert_eq!(bulb.peer_addr(), Some(addr));
+        let bulb = Bulb::connect_addr((addr.ip(), addr.port())).await.unwrap();
+        assert_eq!(bulb.peer_addr(), Some(addr));
+
+        // IPv6 literals, with and without brackets (skipped if IPv6 is not available)
+        if let Ok(listener) = TcpListener::bind("[::1]:0").await {
+            let port = listener.local_addr().unwrap().port();
+            for host in ["::1", "[::1]"] {
+                let bulb = Bulb::connect(host, port).await.unwrap();
+                assert_eq!(bulb.peer_addr(), Some(listener.local_addr().unwrap()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_errors() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let result = Bulb::connect("bulb.invalid", 0).await;
+        assert!(matches!(result, Err(ConnectError::Resolve(_))));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let result = Bulb::connect("127.0.0.1", port).await;
+        assert!(
+            matches!(result, Err(ConnectError::Io(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused)
+        );
+
+        // A proxy that never answers the handshake
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = Proxy::Socks5 {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            credentials: None,
+        };
+        let timeout = Duration::from_millis(50);
+        let options = ConnectOptions::default()
+            .proxy(proxy)
+            .connect_timeout(timeout);
+        let result = Bulb::connect_with("192.168.1.204", 0, &options).await;
+        assert!(matches!(result, Err(ConnectError::Timeout(t)) if t == timeout));
+        drop(listener);
+
+        // The bulb refuses music mode
+        let (io, remote) = tokio::io::duplex(4096);
+        let mut bulb = Bulb::attach_io(io);
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(remote);
+            let mut lines = BufReader::new(read).lines();
+            lines.next_line().await.unwrap();
+            let response = "{\"id\":1,\"error\":{\"code\":-1,\"message\":\"unsupported\"}}\r\n";
+            write.write_all(response.as_bytes()).await.unwrap();
+            lines.next_line().await.unwrap();
+        });
+        let result = bulb.start_music("127.0.0.1").await;
+        assert!(matches!(
+            result,
+            Err(ConnectError::Music(e)) if matches!(e.inner(), BulbError::ErrResponse(-1, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn night_light_state() {
+        use tokio::io::AsyncWriteExt;
+
+        let (io, mut remote) = tokio::io::duplex(1024);
+        let bulb = Bulb::attach_io(io).notification_alias("moon_br", Property::NightLightBright);
+        let notification = r#"{"method":"props","params":{"power":"on","main_power":"off","bg_color_mode":2,"active_mode":1,"moon_br":5}}"#;
+        remote
+            .write_all(format!("{}\r\n", notification).as_bytes())
+            .await
+            .unwrap();
+        let mut state = bulb.watch();
+        let state = state
+            .wait_for(|s| s.get(Property::NightLightBright).is_some())
+            .await
+            .unwrap()
+            .clone();
+        assert_eq!(state.get(Property::Power), Some("off"));
+        assert_eq!(state.get(Property::BgColorMode), Some("2"));
+        assert_eq!(state.get(Property::NightLightBright), Some("5"));
+        assert!(!state.0.contains_key("main_power"));
+
+        let snapshot: StateSnapshot = serde_json::from_str(
+            r#"{"power":"on","bright":"80","ct":"4000","color_mode":"2","active_mode":"1","nl_br":"5"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            snapshot.restore_commands(),
+            [
+                ("set_power", json_params(json!(["on", "sudden", 0, 5]))),
+                ("set_bright", json_params(json!([5, "sudden", 0]))),
+            ]
+        );
+        assert_eq!(
+            snapshot.relevant_properties(),
+            [
+                Property::Power,
+                Property::ActiveMode,
+                Property::NightLightBright
+            ]
+        );
+
+        let change = StateChange {
+            changed: [("nl_br".to_string(), "5".to_string())].into(),
+            state: snapshot,
+        };
+        assert_eq!(change.get(Property::NightLightBright), Some("5"));
+        assert_eq!(change.get(Property::Power), None);
+    }
+
+    #[tokio::test]
+    async fn error_variants() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (io, remote) = tokio::io::duplex(4096);
+        let mut bulb = Bulb::attach_io(io);
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(remote);
+            let mut lines = BufReader::new(read).lines();
+            lines.next_line().await.unwrap();
+            write
+                .write_all(b"{\"id\":1,\"result\":\"ok\"}\r\n")
+                .await
+                .unwrap();
+            // Close the connection without answering the second request
+            lines.next_line().await.unwrap();
+        });
+
+        let error = bulb.toggle().await.unwrap_err();
+        assert!(matches!(error.inner(), BulbError::Protocol(_)));
+        assert_eq!(error.method(), Some("toggle"));
+
+        let error = bulb.set_bright(10, Effect::Sudden, Duration::ZERO).await;
+        let error = error.unwrap_err();
+        assert!(matches!(error.inner(), BulbError::Disconnected));
+        assert_eq!(error.to_string(), "set_bright: Connection to the bulb lost");
+    }
+
+    #[tokio::test]
+    async fn cloned_handles() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (io, remote) = tokio::io::duplex(4096);
+        let bulb = Bulb::attach_io(io);
+        // Answers both requests once they are received, in reverse order
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(remote);
+            let mut lines = BufReader::new(read).lines();
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                let line = lines.next_line().await.unwrap().unwrap();
+                let request = protocol::Request::parse(&line).unwrap();
+                ids.push((request.id, request.method));
+            }
+            for (id, method) in ids.into_iter().rev() {
+                let response = format!("{{\"id\":{},\"result\":[\"{}\"]}}\r\n", id, method);
+                write.write_all(response.as_bytes()).await.unwrap();
+            }
+            lines.next_line().await.unwrap();
+        });
+
+        let mut toggle = bulb.clone();
+        let mut bright = bulb.clone();
+        let (toggled, brightened) = tokio::join!(
+            tokio::spawn(async move { toggle.toggle().await }),
+            tokio::spawn(
+                async move { bright.set_bright(10, Effect::Sudden, Duration::ZERO).await }
+            ),
+        );
+        assert_eq!(toggled.unwrap().unwrap(), Some(vec!["toggle".to_string()]));
+        assert_eq!(
+            brightened.unwrap().unwrap(),
+            Some(vec!["set_bright".to_string()])
+        );
+        assert_eq!(bulb.stats().commands, 2);
+    }
+
+    #[tokio::test]
+    async fn close() {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+
+        let (io, remote) = tokio::io::duplex(4096);
+        let mut bulb = Bulb::attach_io(io);
+        // Reads the requests without answering them, until the write half is shut down
+        let (read, _write) = tokio::io::split(remote);
+        let fake = tokio::spawn(async move {
+            let mut lines = BufReader::new(read).lines();
+            let mut received = Vec::new();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                received.push(protocol::Request::parse(&line).unwrap().method);
+            }
+            received
+        });
+
+        let mut pending = bulb.clone();
+        let pending = tokio::spawn(async move { pending.toggle().await });
+        while bulb.writer.pending().await == 0 {
+            tokio::task::yield_now().await;
+        }
+        bulb.close().await.unwrap();
+
+        let error = pending.await.unwrap().unwrap_err();
+        assert!(matches!(error.inner(), BulbError::Disconnected));
+        assert!(!bulb.is_connected());
+        assert!(bulb.take_reader_task().is_none());
+        assert!(bulb.toggle().await.is_err());
+        assert_eq!(fake.await.unwrap(), vec!["toggle"]);
+        // Closing again does nothing
+        bulb.close().await.unwrap();
+
+        // The task ends with the connection when the bulb closes it
+        let (io, remote) = tokio::io::duplex(4096);
+        let bulb = Bulb::attach_io(io);
+        let task = bulb.take_reader_task().unwrap();
+        drop(remote);
+        assert!(task.await.unwrap().is_ok());
+        assert!(!bulb.is_connected());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn startup_race() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        // Answers every request as soon as it is read
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut lines = BufReader::new(read).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let id = protocol::Request::parse(&line).unwrap().id;
+                        let response = format!("{{\"id\":{},\"result\":[\"ok\"]}}\r\n", id);
+                        if write.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        // Requests sent right after connecting, on a loaded runtime
+        let attempts = (0..200).map(|_| async move {
+            let mut bulb = Bulb::connect("127.0.0.1", port).await.unwrap();
+            bulb.set_timeout(Some(Duration::from_secs(5)));
+            let mut other = bulb.clone();
+            let (first, second) = tokio::join!(other.toggle(), bulb.toggle());
+            (first.unwrap(), second.unwrap())
+        });
+        let results = futures_util::future::join_all(attempts).await;
+        let ok = Some(vec!["ok".to_string()]);
+        assert!(results
+            .iter()
+            .all(|result| *result == (ok.clone(), ok.clone())));
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        // Drops the first connection without answering (as if the bulb rebooted) and
+        // answers on the second one
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let fake = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            let first = lines.next_line().await.unwrap().unwrap();
+            drop(lines);
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            let line = lines.next_line().await.unwrap().unwrap();
+            let request = protocol::Request::parse(&line).unwrap();
+            let response = format!("{{\"id\":{},\"result\":[\"ok\"]}}\r\n", request.id);
+            let notification = "{\"method\":\"props\",\"params\":{\"power\":\"on\"}}\r\n";
+            write.write_all(response.as_bytes()).await.unwrap();
+            write.write_all(notification.as_bytes()).await.unwrap();
+            lines.next_line().await.unwrap();
+            (
+                protocol::Request::parse(&first).unwrap().method,
+                request.method,
+            )
+        });
+
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        };
+        let options = ConnectOptions::default().backoff(backoff);
+        let mut bulb = Bulb::connect_with("127.0.0.1", port, &options)
+            .await
+            .unwrap();
+        bulb.set_timeout(Some(Duration::from_secs(5)));
+        let mut notifications = bulb.get_notify().await;
+
+        let response = bulb.toggle().await.unwrap();
+        assert_eq!(response, Some(vec!["ok".to_string()]));
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(notification.params["power"], "on");
+        assert!(bulb.is_connected());
+        assert_eq!(bulb.stats().reconnects, 1);
+
+        bulb.close().await.unwrap();
+        let methods = fake.await.unwrap();
+        assert_eq!(methods, ("toggle".to_string(), "toggle".to_string()));
+    }
+
+    #[cfg(feature = "simulator")]
+    #[tokio::test]
+    async fn reconnect_replays_journal() {
+        use crate::simulator::Simulator;
+
+        // Forwards the first connection to a bulb until told to drop it, and the second one
+        // to the same bulb after it rebooted (back to its default state)
+        let before = Simulator::new("color");
+        let after = Simulator::new("color");
+        let mut addrs = Vec::new();
+        for simulator in [&before, &after] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.push(listener.local_addr().unwrap());
+            tokio::spawn(simulator.clone().serve(listener));
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (drop_first, dropped) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut bulb = TcpStream::connect(addrs[0]).await.unwrap();
+            tokio::select! {
+                _ = tokio::io::copy_bidirectional(&mut client, &mut bulb) => {}
+                _ = dropped => {}
+            }
+            drop(client);
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut bulb = TcpStream::connect(addrs[1]).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut bulb).await;
+        });
+
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        };
+        let options = ConnectOptions::default().backoff(backoff);
+        let bulb = Bulb::connect_with("127.0.0.1", port, &options)
+            .await
+            .unwrap();
+        let mut bulb = bulb.journal(Journal::new(), "desk");
+        bulb.set_rgb(0xff_00_00, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap();
+        bulb.set_bright(30, Effect::Sudden, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(before.state()["bright"], "30");
+
+        let mut changes = after.subscribe();
+        drop_first.send(()).unwrap();
+        while after.state()["bright"] != "30" {
+            tokio::time::timeout(Duration::from_secs(2), changes.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        let state = after.state();
+        assert_eq!(state["rgb"], (0xff_00_00).to_string());
+        assert_eq!(state["color_mode"], "1");
+        assert!(bulb.is_connected());
+    }
+
+    #[tokio::test]
+    async fn health_events() {
+        use tokio::io::AsyncReadExt;
+
+        // Drops the first connection when told to and keeps the second one open
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (drop_first, dropped) = tokio::sync::oneshot::channel::<()>();
+        let fake = tokio::spawn(async move {
+            let (first, _) = listener.accept().await.unwrap();
+            dropped.await.unwrap();
+            drop(first);
+            let (mut second, _) = listener.accept().await.unwrap();
+            second.read_to_end(&mut Vec::new()).await.unwrap();
+        });
+
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        };
+        let options = ConnectOptions::default().backoff(backoff);
+        let mut bulb = Bulb::connect_with("127.0.0.1", port, &options)
+            .await
+            .unwrap();
+        let mut events = bulb.health_events();
+        drop_first.send(()).unwrap();
+
+        let expected = [
+            HealthEvent::Disconnected(DisconnectReason::ClosedByBulb),
+            HealthEvent::Reconnecting(1),
+            HealthEvent::Connected,
+        ];
+        for event in expected {
+            assert_eq!(events.recv().await.unwrap(), event);
+        }
+        assert!(bulb.is_connected());
+
+        bulb.close().await.unwrap();
+        let event = events.recv().await.unwrap();
+        assert_eq!(event, HealthEvent::Disconnected(DisconnectReason::Closed));
+        fake.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let socket = SocketOptions::default()
+            .nodelay(true)
+            .keepalive(Duration::from_secs(30))
+            .recv_buffer_size(64 * 1024)
+            .send_buffer_size(64 * 1024);
+        let options = ConnectOptions::default().socket(socket);
+
+        let stream = options.connect("127.0.0.1", port).await.unwrap();
+        let socket = socket2::SockRef::from(&stream);
+        assert!(stream.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+
+        // Left as opened by default
+        let stream = ConnectOptions::default()
+            .connect("127.0.0.1", port)
+            .await
+            .unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(!socket2::SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn raw_results() {
+        let (io, remote) = tokio::io::duplex(4096);
+        let mut bulb = Bulb::attach_io(io);
+
+        // Numbers and objects are stringified by the legacy API, and kept by call
+        let responses = [
+            "{\"id\":1,\"result\":[\"on\",100,null]}\r\n",
+            "{\"id\":2,\"result\":[{\"type\":0,\"delay\":15,\"mix\":0}]}\r\n",
+        ];
+        let fake = tokio::spawn(async move {
+            use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+            let (read, mut write) = tokio::io::split(remote);
+            let mut lines = BufReader::new(read).lines();
+            for response in responses {
+                lines.next_line().await.unwrap();
+                write.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let props = Properties(vec![Property::Power, Property::Bright, Property::Name]);
+        let values = bulb.get_prop(&props).await.unwrap().unwrap();
+        assert_eq!(values, vec!["on", "100", ""]);
+        assert_eq!(bulb.cached_state().get(Property::Bright), Some("100"));
+
+        let cron = bulb
+            .call(Method::CronGet, &[0.into()])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(cron[0]["delay"], 15);
+        fake.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pipelines() {
+        use pipeline::Pipeline;
+
+        let yaml = r#"
+evening:
+  steps:
+    - !Scene { to: !Ct { ct: 4000, bright: 100 } }
+    - !Fade { to: !Ct { ct: 2700, bright: 40 }, duration: 0 }
+    - !Wait 0
+    - !Preset Candle
+    - !OffAt "23:30"
+"#;
+        let pipelines: std::collections::BTreeMap<String, Pipeline> =
+            serde_yaml::from_str(yaml).unwrap();
+        let (mut bulb, capture) = Bulb::with_capture();
+        bulb.run_pipeline(&pipelines, "evening").await.unwrap();
+        assert_eq!(
+            capture.methods(),
+            ["set_scene", "get_prop", "set_scene", "start_cf", "cron_add"]
+        );
+        let cron = capture.requests().pop().unwrap();
+        assert_eq!(cron.params[0], 0);
+        assert!((1..=1440).contains(&cron.params[1].as_u64().unwrap()));
+
+        let error = bulb.run_pipeline(&pipelines, "morning").await.unwrap_err();
+        assert_eq!(error.to_string(), "Unknown pipeline: morning");
+    }
+
+    #[tokio::test]
+    async fn adaptive_presets() {
+        use presets::Preset;
+
+        let (mut bulb, capture) = Bulb::with_capture();
+        assert!(Preset::Reading.is_adaptive());
+        assert!(!Preset::Candle.is_adaptive());
+        for (time, expected) in [
+            ("12:00", json_params(json!(["ct", 4200, 100]))),
+            ("22:00", json_params(json!(["ct", 3200, 100]))),
+            ("03:00", json_params(json!(["ct", 3200, 100]))),
+            ("07:00", json_params(json!(["ct", 4200, 100]))),
+        ] {
+            let time = time.parse().unwrap();
+            Preset::Reading.apply_at(&mut bulb, time).await.unwrap();
+            assert_eq!(capture.requests().pop().unwrap().params, expected);
+            capture.take();
+        }
+
+        // Following the sun, and the clock for the events that do not happen
+        let at = |hour, minute| TimeOfDay { hour, minute };
+        let winter = solar::SunTimes {
+            dawn: None,
+            sunrise: Some(at(8, 15)),
+            solar_noon: at(13, 0),
+            sunset: Some(at(17, 30)),
+            dusk: None,
+        };
+        let polar_night = solar::SunTimes {
+            sunrise: None,
+            sunset: None,
+            ..winter
+        };
+        for (time, sun, expected) in [
+            ("08:00", &winter, json_params(json!(["ct", 3200, 100]))),
+            ("12:00", &winter, json_params(json!(["ct", 4200, 100]))),
+            ("18:00", &winter, json_params(json!(["ct", 3200, 100]))),
+            ("18:00", &polar_night, json_params(json!(["ct", 4200, 100]))),
+        ] {
+            let time = time.parse().unwrap();
+            Preset::Reading
+                .apply_at_sun(&mut bulb, time, sun)
+                .await
+                .unwrap();
+            assert_eq!(capture.take().pop().unwrap().params, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn save_power_on_default() {
+        let (mut bulb, capture) = Bulb::with_capture();
+
+        capture.respond("get_prop", vec!["off".to_string()]);
+        let error = bulb.save_as_power_on_default(None).await.unwrap_err();
+        assert!(matches!(error.inner(), BulbError::LightOff));
+        assert_eq!(error.method(), Some("set_default"));
+        assert_eq!(capture.methods(), vec!["get_prop"]);
+
+        capture.respond("get_prop", vec!["on".to_string()]);
+        let response = bulb.bg_save_as_power_on_default(None).await.unwrap();
+        assert_eq!(response, Some(vec!["ok".to_string()]));
+
+        // Scenes turn the light on, so the power is not read
+        let scene = Scene::Ct {
+            ct: 2700,
+            bright: 80,
+        };
+        bulb.save_as_power_on_default(Some(scene)).await.unwrap();
+        let requests = capture.requests();
+        let methods: Vec<_> = requests.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(
+            methods,
+            vec![
+                "get_prop",
+                "get_prop",
+                "bg_set_default",
+                "set_scene",
+                "set_default"
+            ]
+        );
+        assert_eq!(requests[1].params, json_params(json!(["bg_power"])));
+    }
+
+    #[tokio::test]
+    async fn response_timeout() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (io, remote) = tokio::io::duplex(4096);
+        let mut bulb = Bulb::attach_io(io);
+        bulb.set_timeout(Some(Duration::from_millis(20)));
+
+        // The first request is answered late, the second one in time
+        let (read, mut write) = tokio::io::split(remote);
+        let mut lines = BufReader::new(read).lines();
+        let fake = tokio::spawn(async move {
+            lines.next_line().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            lines.next_line().await.unwrap();
+            write
+                .write_all(b"{\"id\":1,\"result\":[\"ok\"]}\r\n{\"id\":2,\"result\":[\"ok\"]}\r\n")
+                .await
+                .unwrap();
+            (lines, write)
+        });
+
+        let error = bulb.toggle().await.unwrap_err();
+        assert!(matches!(error.inner(), BulbError::Timeout));
+        assert_eq!(error.method(), Some("toggle"));
+        assert_eq!(bulb.pending_requests().await, 0);
+        assert_eq!(bulb.stats().errors, 1);
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert!(bulb.toggle().await.unwrap().is_some());
+        let _connection = fake.await.unwrap();
+
+        bulb.set_timeout(None);
+        let waiting = tokio::time::timeout(Duration::from_millis(30), bulb.toggle()).await;
+        assert!(waiting.is_err());
+    }
+
+    #[tokio::test]
+    async fn notify() {
+        let expect = "{\"id\":1,\"method\":\"set_power\",\"params\":[\"on\",\"smooth\",500,0]}\r\n";
+        let response = "{\"method\":\"props\",\"params\":{\"power\":\"on\", \"bright\":\"10\"}}\r\n{\"method\":\"other\",\"params\":{\"power\":\"off\"}}\r\n{\"id\":1, \"result\":[\"ok\"]}\r\n";
+
+        let (mut bulb, task) = fake_bulb(expect, response).await;
+        let mut recv = bulb.get_notify().await;
+
+        let (tres, res) = tokio::join!(
+            task,
+            bulb.set_power(
+                Power::On,
+                Effect::Smooth,
+                Duration::from_millis(500),
+                Mode::Normal
+            )
+        );
+        tres.unwrap();
+
+        if let Ok(Some(properties)) = res {
+            assert_eq!(properties, vec!["ok"]);
+        } else {
+            panic!("Unexpected result: {:?}", res);
+        }
+
+        let notification = recv.recv().await.unwrap();
+        assert_eq!(notification.method, "props");
+        assert_eq!(
+            notification.props().unwrap().get("bright"),
+            Some(&"10".into())
+        );
+
+        // Only props notifications update the state
+        let notification = recv.recv().await.unwrap();
+        assert_eq!(notification.method, "other");
+        assert!(notification.props().is_none());
+        assert_eq!(bulb.cached_state().get(Property::Power), Some("on"));
     }
 }
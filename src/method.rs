@@ -0,0 +1,83 @@
+use std::fmt;
+
+macro_rules! methods {
+    ($($variant:ident -> $name:literal),* $(,)?) => {
+        /// Method of the protocol, as listed in the `support` field of the discovery
+        /// responses.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Method {
+            $($variant),*
+        }
+
+        impl Method {
+            /// All the methods known by this crate.
+            pub const ALL: &'static [Method] = &[$(Method::$variant),*];
+
+            /// Name of the method as used by the protocol.
+            pub fn as_str(self) -> &'static str {
+                match self {
+                    $(Method::$variant => $name),*
+                }
+            }
+        }
+    };
+}
+
+methods!(
+    GetProp -> "get_prop",
+    SetCtAbx -> "set_ct_abx",
+    SetRgb -> "set_rgb",
+    SetWrgb -> "set_wrgb",
+    SetHsv -> "set_hsv",
+    SetBright -> "set_bright",
+    SetPower -> "set_power",
+    Toggle -> "toggle",
+    SetDefault -> "set_default",
+    StartCf -> "start_cf",
+    StopCf -> "stop_cf",
+    SetScene -> "set_scene",
+    CronAdd -> "cron_add",
+    CronGet -> "cron_get",
+    CronDel -> "cron_del",
+    SetAdjust -> "set_adjust",
+    AdjustBright -> "adjust_bright",
+    AdjustCt -> "adjust_ct",
+    AdjustColor -> "adjust_color",
+    SetMusic -> "set_music",
+    SetName -> "set_name",
+    BgSetRgb -> "bg_set_rgb",
+    BgSetHsv -> "bg_set_hsv",
+    BgSetCtAbx -> "bg_set_ct_abx",
+    BgStartCf -> "bg_start_cf",
+    BgStopCf -> "bg_stop_cf",
+    BgSetScene -> "bg_set_scene",
+    BgSetDefault -> "bg_set_default",
+    BgSetPower -> "bg_set_power",
+    BgSetBright -> "bg_set_bright",
+    BgSetAdjust -> "bg_set_adjust",
+    BgAdjustBright -> "bg_adjust_bright",
+    BgAdjustCt -> "bg_adjust_ct",
+    BgAdjustColor -> "bg_adjust_color",
+    BgToggle -> "bg_toggle",
+    DevToggle -> "dev_toggle",
+);
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "from-str")]
+impl std::str::FromStr for Method {
+    type Err = crate::ParseError;
+
+    /// Parse the protocol name of the method (such as `set_rgb`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Method::ALL
+            .iter()
+            .find(|method| method.as_str().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| crate::ParseError(format!("Unknown method {}", s)))
+    }
+}
@@ -0,0 +1,128 @@
+//! Database of known bulb models and their capabilities.
+//!
+//! The model of a bulb is reported in the discovery response (`model` property). Bulbs
+//! connected through [`DiscoveredBulb::connect`](crate::discover::DiscoveredBulb::connect) know
+//! their model automatically, otherwise use [`Bulb::with_model`](crate::Bulb::with_model).
+
+use std::time::Duration;
+
+use crate::helpers::MIN_DURATION;
+
+/// Capabilities of a bulb model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Model {
+    /// Model name as reported by discovery.
+    pub name: &'static str,
+    /// Supports RGB/HSV colors.
+    pub color: bool,
+    /// Supported color temperature range (K), `None` if the temperature can not be changed.
+    pub ct_range: Option<(u16, u16)>,
+    /// Has a background (ambient) light.
+    pub background: bool,
+    /// Frames per second the bulb can show in music mode without stuttering.
+    pub max_frame_rate: u32,
+}
+
+impl Model {
+    /// Limits of the values accepted by bulbs of this model.
+    pub fn limits(&self) -> Limits {
+        Limits {
+            ct_range: self.ct_range,
+            max_frame_rate: self.max_frame_rate,
+            ..Limits::default()
+        }
+    }
+}
+
+/// Maximum number of tuples of a color flow (its count is sent as a byte).
+pub const MAX_FLOW_TUPLES: usize = 255;
+
+/// Ranges of the values accepted by a bulb, obtained with [`Bulb::limits`](crate::Bulb::limits).
+///
+/// Useful to set the ranges of the controls of a user interface: the firmware clamps (or
+/// rejects) values outside them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Shortest smooth change or flow tuple, shorter durations are clamped to it.
+    pub min_smooth_duration: Duration,
+    /// Maximum number of tuples of a color flow.
+    pub max_flow_tuples: usize,
+    /// Brightness range (%).
+    pub bright_range: (u8, u8),
+    /// Color temperature range (K), `None` if the temperature can not be changed.
+    pub ct_range: Option<(u16, u16)>,
+    /// Longest name accepted by [`Bulb::set_name`](crate::Bulb::set_name) (in bytes).
+    pub max_name_len: usize,
+    /// Frames per second the bulb can show in music mode without stuttering, the default cap
+    /// of [`Bulb::start_music`](crate::Bulb::start_music).
+    pub max_frame_rate: u32,
+}
+
+/// Limits of bulbs of unknown models: the widest ranges of the protocol (and the frame rate
+/// all the models sustain).
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            min_smooth_duration: MIN_DURATION,
+            max_flow_tuples: MAX_FLOW_TUPLES,
+            bright_range: (1, 100),
+            ct_range: FULL_CT,
+            max_name_len: 64,
+            max_frame_rate: 30,
+        }
+    }
+}
+
+const fn model(
+    name: &'static str,
+    color: bool,
+    ct_range: Option<(u16, u16)>,
+    background: bool,
+    max_frame_rate: u32,
+) -> Model {
+    Model {
+        name,
+        color,
+        ct_range,
+        background,
+        max_frame_rate,
+    }
+}
+
+const FULL_CT: Option<(u16, u16)> = Some((1700, 6500));
+const WHITE_CT: Option<(u16, u16)> = Some((2700, 6500));
+
+/// Known models.
+pub static MODELS: &[Model] = &[
+    model("mono", false, None, false, 30),
+    model("mono1", false, None, false, 30),
+    model("color", true, FULL_CT, false, 60),
+    model("color1", true, FULL_CT, false, 60),
+    model("color2", true, FULL_CT, false, 60),
+    model("color4", true, FULL_CT, false, 60),
+    model("stripe", true, FULL_CT, false, 60),
+    model("strip1", true, FULL_CT, false, 60),
+    model("strip4", true, FULL_CT, false, 60),
+    model("bslamp", true, FULL_CT, false, 60),
+    model("bslamp1", true, FULL_CT, false, 60),
+    model("bslamp2", true, FULL_CT, false, 60),
+    model("bslamp3", true, FULL_CT, false, 60),
+    model("ct_bulb", false, WHITE_CT, false, 30),
+    model("ct2", false, WHITE_CT, false, 30),
+    model("lamp1", false, WHITE_CT, false, 30),
+    model("desklamp", false, WHITE_CT, false, 30),
+    model("ceiling", false, WHITE_CT, false, 30),
+    model("ceiling1", false, WHITE_CT, false, 30),
+    model("ceiling2", false, WHITE_CT, false, 30),
+    model("ceiling3", false, WHITE_CT, false, 30),
+    model("ceiling4", false, WHITE_CT, true, 30),
+    model("ceiling10", false, WHITE_CT, true, 30),
+    model("ceiling13", false, WHITE_CT, false, 30),
+    model("ceiling20", false, WHITE_CT, true, 30),
+    model("ceila", false, WHITE_CT, false, 30),
+];
+
+/// Find the model with the given name.
+pub fn lookup(name: &str) -> Option<&'static Model> {
+    MODELS.iter().find(|model| model.name == name)
+}
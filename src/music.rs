@@ -0,0 +1,177 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{broadcast, Mutex};
+
+use crate::{Bulb, BulbError, HealthEvent, QuotaLimiter, QuotaPolicy, RateLimit, Response};
+
+/// Frames sent right away before the [FrameCap] delays them, to absorb the jitter of
+/// consumers sending frames close to the cap.
+const FRAME_BURST: u32 = 2;
+
+/// Color and brightness methods sent through the music mode connection by
+/// [Bulb::auto_music].
+const MUSIC_METHODS: &[&str] = &[
+    "set_ct_abx",
+    "bg_set_ct_abx",
+    "set_rgb",
+    "bg_set_rgb",
+    "set_hsv",
+    "bg_set_hsv",
+    "set_bright",
+    "bg_set_bright",
+    "set_scene",
+    "bg_set_scene",
+    "adjust_bright",
+    "bg_adjust_bright",
+    "adjust_ct",
+    "bg_adjust_ct",
+    "adjust_color",
+    "bg_adjust_color",
+];
+
+/// Music mode connection opened by [Bulb::auto_music] when needed, shared by the clones of
+/// the connection.
+#[derive(Clone)]
+pub(crate) struct AutoMusic {
+    host: String,
+    music: Arc<Mutex<Option<Bulb>>>,
+}
+
+/// Cap of the frames per second of a music mode connection, see [Bulb::max_frame_rate].
+#[derive(Debug, Clone)]
+pub(crate) struct FrameCap {
+    fps: u32,
+    limit: RateLimit,
+    /// Frames are being delayed (the warning is sent when this changes).
+    exceeded: Arc<AtomicBool>,
+    health: broadcast::Sender<HealthEvent>,
+}
+
+impl FrameCap {
+    /// Wait until the next frame can be sent, warning when the frames start being delayed.
+    pub(crate) async fn acquire(&self) {
+        let delayed = self.limit.acquire_waiting().await;
+        if delayed && !self.exceeded.swap(true, Ordering::Relaxed) {
+            log::warn!("Over {} frames per second in music mode", self.fps);
+            let _ = self.health.send(HealthEvent::FrameRateExceeded(self.fps));
+        } else if !delayed {
+            self.exceeded.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Bulb {
+    /// Cap the commands per second sent by this handle to `fps`, replacing the previous cap,
+    /// or remove it with `None`.
+    ///
+    /// Meant for [music mode](Bulb::start_music), where there is no quota and bulbs stutter
+    /// when they get more frames than they can show. The connection returned by
+    /// [Bulb::start_music] is capped to the
+    /// [max_frame_rate](crate::model::Limits::max_frame_rate) of its model. Frames over the cap
+    /// are delayed, and [HealthEvent::FrameRateExceeded] is sent when that starts.
+    ///
+    /// Applies to this handle and the clones made after the call.
+    ///
+    /// # Panics
+    /// If `fps` is `Some(0)`.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap().with_model("color4");
+    /// // This one handles more than its model
+    /// let mut music = bulb
+    ///     .start_music("192.168.1.10")
+    ///     .await
+    ///     .unwrap()
+    ///     .max_frame_rate(Some(90));
+    /// # }
+    /// ```
+    pub fn max_frame_rate(mut self, fps: Option<u32>) -> Self {
+        let cap = fps.map(|fps| FrameCap {
+            fps,
+            limit: RateLimit::new(fps, FRAME_BURST),
+            exceeded: Arc::default(),
+            health: self.health.clone(),
+        });
+        self.writer.set_frame_cap(cap);
+        self
+    }
+
+    /// Send the color and brightness commands through a music mode connection once the
+    /// command quota of this connection runs out.
+    ///
+    /// The bulb connects to `host` (see [Bulb::start_music]) the first time a command would
+    /// have to wait for the [QuotaLimiter] (one with the default [QuotaPolicy::Delay] is set
+    /// if there is none), and again if the music connection is lost. The last command of the
+    /// quota is kept to start music mode, and the commands are sent through this connection
+    /// if the bulb refuses it. Commands sent in music mode get no response (`Ok(None)`), and
+    /// the other commands (such as [Bulb::set_power] or [Bulb::get_prop]) are always sent
+    /// through this connection.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0)
+    ///     .await
+    ///     .unwrap()
+    ///     .auto_music("192.168.1.10");
+    /// // Only the first commands are sent over the normal connection
+    /// for bright in (1..=100).cycle().take(1000) {
+    ///     bulb.set_bright(bright, Effect::Sudden, Duration::ZERO).await.unwrap();
+    ///     tokio::time::sleep(Duration::from_millis(20)).await;
+    /// }
+    /// # }
+    /// ```
+    pub fn auto_music(mut self, host: &str) -> Self {
+        if self.remaining_quota().is_none() {
+            self.writer.set_quota(QuotaLimiter::new(QuotaPolicy::Delay));
+        }
+        self.auto_music = Some(AutoMusic {
+            host: host.to_string(),
+            music: Arc::default(),
+        });
+        self
+    }
+
+    /// Send the command through the music mode connection of [Bulb::auto_music], starting it
+    /// if the quota ran out. `None` if it has to be sent through this connection.
+    pub(crate) async fn send_music(
+        &mut self,
+        method: &str,
+        params: &[serde_json::Value],
+    ) -> Option<Result<Option<Response>, BulbError>> {
+        let auto = self.auto_music.clone()?;
+        if !MUSIC_METHODS.contains(&method) {
+            return None;
+        }
+        let mut music = auto.music.lock().await;
+        if music.as_ref().is_some_and(|music| !music.is_connected()) {
+            log::info!("Music mode connection lost");
+            *music = None;
+        }
+        if music.is_none() {
+            if !matches!(self.remaining_quota(), Some(0 | 1)) {
+                return None;
+            }
+            match self.start_music(&auto.host).await {
+                Ok(connection) => {
+                    log::info!("Command quota exhausted, switching to music mode");
+                    *music = Some(connection);
+                }
+                Err(e) => {
+                    log::warn!("Could not start music mode: {}", e);
+                    return None;
+                }
+            }
+        }
+        let music = music.as_mut()?;
+        // Sent through the other connection, so the values here go stale too
+        self.cache.sent(method);
+        Some(music.writer.send(method, params).await)
+    }
+}
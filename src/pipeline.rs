@@ -0,0 +1,138 @@
+//! Named sequences of scenes, presets and transitions, such as an "evening" routine.
+//!
+//! Pipelines are usually defined in a configuration file:
+//!
+//! ```yaml
+//! evening:
+//!   utc_offset: 120 # CEST
+//!   steps:
+//!     - !Scene { to: !Ct { ct: 4000, bright: 100 } }
+//!     # Fade to 2700K at 40% over 10 minutes
+//!     - !Fade { to: !Ct { ct: 2700, bright: 40 }, duration: 600 }
+//!     - !Preset Candle
+//!     - !OffAt "23:30"
+//! ```
+//!
+//! # Example
+//! ```
+//! # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+//! # use yeelight::Bulb;
+//! use std::collections::BTreeMap;
+//! use yeelight::pipeline::Pipeline;
+//!
+//! let pipelines: BTreeMap<String, Pipeline> =
+//!     serde_yaml::from_str(&std::fs::read_to_string("pipelines.yaml")?)?;
+//! let mut bulb = Bulb::connect("192.168.1.204", 0).await?;
+//! bulb.run_pipeline(&pipelines, "evening").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::io;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::auto_off::seconds;
+use crate::presets::Preset;
+use crate::solar::Location;
+use crate::transition::{long_fade, FadeTarget};
+use crate::{Bulb, BulbError, CronType, Scene, TimeOfDay};
+
+/// Sequence of steps run one after the other, see the [module documentation](self).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Pipeline {
+    pub steps: Vec<Step>,
+    /// Offset from UTC in minutes of the times of the steps ([Step::OffAt]) and of the
+    /// [adaptive presets](Preset::apply_at).
+    #[serde(default)]
+    pub utc_offset: i32,
+    /// Where the bulbs are, so the adaptive presets follow its sunrise and sunset (see
+    /// [Preset::apply_at_location]) instead of the clock.
+    #[serde(default)]
+    pub location: Option<Location>,
+}
+
+/// Step of a [Pipeline].
+///
+/// Durations are serialized in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Step {
+    /// Apply a scene ([Bulb::set_scene]).
+    Scene { to: Scene },
+    /// Apply a preset, in its variant for the current time of the day (the color flows keep
+    /// running during the next steps).
+    Preset(Preset),
+    /// Change the light gradually (see [long_fade]) and wait for the change to end.
+    Fade {
+        to: FadeTarget,
+        #[serde(with = "seconds")]
+        duration: Duration,
+    },
+    /// Wait before the next step.
+    Wait(#[serde(with = "seconds")] Duration),
+    /// Set the sleep timer of the bulb to turn it off at the given time of the day (today or
+    /// tomorrow), so it is turned off even if the pipeline is no longer running.
+    OffAt(TimeOfDay),
+}
+
+impl Pipeline {
+    /// Run the steps on the bulb, stopping at the first one that fails.
+    pub async fn run(&self, bulb: &mut Bulb) -> Result<(), BulbError> {
+        for step in &self.steps {
+            log::debug!("Pipeline step: {:?}", step);
+            match step {
+                Step::Scene { to } => {
+                    bulb.set_scene(to.clone()).await?;
+                }
+                Step::Preset(preset) => {
+                    match &self.location {
+                        Some(location) => preset.apply_at_location(bulb, location).await?,
+                        None => preset.apply_now(bulb, self.utc_offset).await?,
+                    };
+                }
+                Step::Fade { to, duration } => {
+                    long_fade(bulb, *to, *duration).await?;
+                    tokio::time::sleep(*duration).await;
+                }
+                Step::Wait(duration) => tokio::time::sleep(*duration).await,
+                Step::OffAt(at) => {
+                    let minutes = minutes_until(*at, TimeOfDay::now(self.utc_offset));
+                    bulb.cron_add(CronType::Off, minutes).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Minutes from `now` until the next `at` (1 to 1440).
+fn minutes_until(at: TimeOfDay, now: TimeOfDay) -> u64 {
+    let minutes = |t: TimeOfDay| t.hour as i64 * 60 + t.minute as i64;
+    match (minutes(at) - minutes(now)).rem_euclid(1440) {
+        0 => 1440,
+        minutes => minutes as u64,
+    }
+}
+
+impl Bulb {
+    /// Run the pipeline with the given name (see [Pipeline::run]).
+    ///
+    /// Fails with an [InvalidInput](io::ErrorKind::InvalidInput) error if there is no
+    /// pipeline with that name.
+    pub async fn run_pipeline(
+        &mut self,
+        pipelines: &BTreeMap<String, Pipeline>,
+        name: &str,
+    ) -> Result<(), BulbError> {
+        let Some(pipeline) = pipelines.get(name) else {
+            let message = format!("Unknown pipeline: {}", name);
+            return Err(BulbError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                message,
+            )));
+        };
+        pipeline.run(self).await
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+
+use crate::{shutdown, Bulb, BulbError, Properties, Property, StateSnapshot};
+
+/// Options for [`Bulb::poll_when_idle`].
+#[derive(Debug, Clone)]
+pub struct PollOptions {
+    /// Properties to refresh.
+    pub properties: Vec<Property>,
+    /// Poll when no notification has been received for this long.
+    pub interval: Duration,
+}
+
+/// Background task refreshing the state of a bulb, see [`Bulb::poll_when_idle`].
+pub struct Poller {
+    state: watch::Receiver<StateSnapshot>,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<Bulb>,
+}
+
+impl Poller {
+    /// Receiver of the state of the polled bulb.
+    pub fn watch(&self) -> watch::Receiver<StateSnapshot> {
+        self.state.clone()
+    }
+
+    /// Stop polling and get the connection back.
+    ///
+    /// Fails with [BulbError::Disconnected] if the poller was aborted by
+    /// [Coordinator::shutdown](crate::shutdown::Coordinator::shutdown), which drops the
+    /// connection.
+    pub async fn stop(self) -> Result<Bulb, BulbError> {
+        let _ = self.stop.send(());
+        shutdown::join(self.task).await
+    }
+}
+
+impl Bulb {
+    /// Refresh the given properties periodically when no notifications are received.
+    ///
+    /// Connections in music mode and some firmwares do not send `props` notifications, so
+    /// the state returned by [Bulb::watch] goes stale. The poller issues a `get_prop` only
+    /// when no notification arrived during the last `interval`, so it does not consume the
+    /// command quota when notifications work.
+    ///
+    /// The connection is moved to a background task, use [Poller::stop] to get it back.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// let mut music = bulb.start_music("192.168.1.23").await.unwrap();
+    ///
+    /// // The main connection is not used while in music mode, so we use it to poll
+    /// let poller = bulb.poll_when_idle(PollOptions {
+    ///     properties: vec![Property::Power, Property::Bright],
+    ///     interval: Duration::from_secs(5),
+    /// });
+    /// let mut state = poller.watch();
+    /// while state.changed().await.is_ok() {
+    ///     println!("{:?}", state.borrow().get(Property::Bright));
+    /// }
+    /// # }
+    /// ```
+    pub fn poll_when_idle(mut self, options: PollOptions) -> Poller {
+        let state = self.watch();
+        let (stop, mut stopped) = oneshot::channel();
+
+        let task = shutdown::spawn(async move {
+            let stopping = shutdown::stopping();
+            tokio::pin!(stopping);
+            let properties = Properties(options.properties);
+            let mut last_poll = Instant::now();
+
+            loop {
+                let last_update = self
+                    .cache
+                    .last_notification()
+                    .map_or(last_poll, |t| t.max(last_poll));
+
+                tokio::select! {
+                    _ = &mut stopped => return self,
+                    _ = &mut stopping => return self,
+                    _ = tokio::time::sleep_until((last_update + options.interval).into()) => {}
+                }
+
+                let idle = self
+                    .cache
+                    .last_notification()
+                    .is_none_or(|t| t.elapsed() >= options.interval);
+                if idle {
+                    last_poll = Instant::now();
+                    if let Err(e) = self.read_prop(&properties).await {
+                        log::warn!("Could not poll bulb state: {}", e);
+                    }
+                }
+            }
+        });
+
+        Poller { state, stop, task }
+    }
+}
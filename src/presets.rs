@@ -0,0 +1,450 @@
+//! Named scenes and color flows, such as the ones of the Yeelight app.
+//!
+//! # Example
+//! ```
+//! # async fn test() {
+//! # use yeelight::Bulb;
+//! use yeelight::presets::Preset;
+//!
+//! let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+//! Preset::Candle.apply(&mut bulb).await.unwrap();
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use crate::solar::{Location, SunTimes};
+use crate::{Bulb, BulbError, CfAction, FlowExpresion, FlowTuple, Response, Scene, TimeOfDay};
+
+macro_rules! presets {
+    ($($variant:ident),* $(,)?) => {
+        /// Predefined scene or color flow.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, ::serde::Serialize, ::serde::Deserialize)]
+        pub enum Preset {
+            $($variant),*
+        }
+
+        #[cfg(feature = "from-str")]
+        impl ::std::str::FromStr for Preset {
+            type Err = crate::ParseError;
+
+            /// Parse the name of the preset (case insensitive).
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                $(if s.eq_ignore_ascii_case(stringify!($variant)) {
+                    return Ok(Preset::$variant);
+                })+
+                Err(crate::ParseError(format!(
+                    "Could not parse {} \n Valid values: {}",
+                    s,
+                    Self::variants().join(" ")
+                )))
+            }
+        }
+
+        #[cfg(feature = "from-str")]
+        impl Preset {
+            /// Names of the presets.
+            pub fn variants() -> Vec<&'static str> {
+                vec![$(stringify!($variant),)+]
+            }
+        }
+    };
+}
+
+presets!(
+    Candle,
+    Reading,
+    NightReading,
+    CosyHome,
+    Romantic,
+    Birthday,
+    DateNight,
+    Teatime,
+    PcMode,
+    Concentration,
+    Movie,
+    Night,
+    Notify,
+    Notify2,
+    PulseRed,
+    PulseBlue,
+    PulseGreen,
+    Red,
+    Green,
+    Blue,
+    Police,
+    Police2,
+    Disco,
+    Temp,
+);
+
+/// Start of a variant of an adaptive preset: a sun event, or the time of the day used
+/// without a [Location] (or when the event does not happen, near the poles).
+#[derive(Clone, Copy)]
+enum Start {
+    Sunrise(TimeOfDay),
+    Sunset(TimeOfDay),
+}
+
+impl Start {
+    fn resolve(self, sun: Option<&SunTimes>) -> TimeOfDay {
+        let (event, clock) = match self {
+            Start::Sunrise(clock) => (sun.and_then(|sun| sun.sunrise), clock),
+            Start::Sunset(clock) => (sun.and_then(|sun| sun.sunset), clock),
+        };
+        event.unwrap_or(clock)
+    }
+}
+
+#[derive(Clone)]
+enum PresetValue {
+    Rgb(u32, u8),
+    Hsv(u16, u8, u8),
+    Ct(u16, u8),
+    Flow(FlowExpresion, u8, CfAction),
+}
+
+impl Preset {
+    /// Apply the preset to the bulb (with [Bulb::set_scene] or [Bulb::start_cf]).
+    pub async fn apply(self, bulb: &mut Bulb) -> Result<Option<Response>, BulbError> {
+        Self::send(bulb, self.value()).await
+    }
+
+    /// Apply the variant of the preset for the given time of the day.
+    ///
+    /// Some presets adapt to the time of the day (see [Preset::is_adaptive]), such as
+    /// [Reading](Preset::Reading), which is cooler during the day and warmer in the evening.
+    /// Without the sun times (see [Preset::apply_at_location]) the day variants start at 07:00
+    /// and the evening ones at 21:00. The rest of the presets are applied as with
+    /// [Preset::apply].
+    pub async fn apply_at(
+        self,
+        bulb: &mut Bulb,
+        time: TimeOfDay,
+    ) -> Result<Option<Response>, BulbError> {
+        Self::send(bulb, self.value_at(time, None)).await
+    }
+
+    /// Apply the variant of the preset for the given time of the day, with the day variants
+    /// starting at `sun.sunrise` and the evening ones at `sun.sunset`.
+    pub async fn apply_at_sun(
+        self,
+        bulb: &mut Bulb,
+        time: TimeOfDay,
+        sun: &SunTimes,
+    ) -> Result<Option<Response>, BulbError> {
+        Self::send(bulb, self.value_at(time, Some(sun))).await
+    }
+
+    /// Apply the variant of the preset for the current time at `location`, following its
+    /// sunrise and sunset (see [Preset::apply_at_sun]).
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::Bulb;
+    /// use yeelight::presets::Preset;
+    /// use yeelight::solar::Location;
+    ///
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// let barcelona = Location {
+    ///     latitude: 41.39,
+    ///     longitude: 2.17,
+    ///     utc_offset: 120,
+    /// };
+    /// // Warmer after sunset
+    /// Preset::Reading.apply_at_location(&mut bulb, &barcelona).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn apply_at_location(
+        self,
+        bulb: &mut Bulb,
+        location: &Location,
+    ) -> Result<Option<Response>, BulbError> {
+        let now = TimeOfDay::now(location.utc_offset);
+        self.apply_at_sun(bulb, now, &location.sun_times_today())
+            .await
+    }
+
+    /// Apply the variant of the preset for the current time at the given offset from UTC
+    /// (in minutes), see [Preset::apply_at]. The variants follow the clock, see
+    /// [Preset::apply_at_location] to follow the sun.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::Bulb;
+    /// use yeelight::presets::Preset;
+    ///
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// // 4200K at noon, 3200K at night (CEST)
+    /// Preset::Reading.apply_now(&mut bulb, 120).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn apply_now(
+        self,
+        bulb: &mut Bulb,
+        utc_offset: i32,
+    ) -> Result<Option<Response>, BulbError> {
+        self.apply_at(bulb, TimeOfDay::now(utc_offset)).await
+    }
+
+    /// Whether the preset has variants for different times of the day.
+    pub fn is_adaptive(self) -> bool {
+        !self.time_variants().is_empty()
+    }
+
+    async fn send(bulb: &mut Bulb, value: PresetValue) -> Result<Option<Response>, BulbError> {
+        match value {
+            PresetValue::Flow(expression, count, action) => {
+                bulb.start_cf(count, action, expression).await
+            }
+            PresetValue::Rgb(rgb, bright) => bulb.set_scene(Scene::Color { rgb, bright }).await,
+            PresetValue::Hsv(hue, sat, bright) => {
+                bulb.set_scene(Scene::Hsv { hue, sat, bright }).await
+            }
+            PresetValue::Ct(ct, bright) => bulb.set_scene(Scene::Ct { ct, bright }).await,
+        }
+    }
+
+    /// Flow of the preset, `None` for presets that set a fixed color.
+    pub fn flow(self) -> Option<FlowExpresion> {
+        match self.value() {
+            PresetValue::Flow(flow, _, _) => Some(flow),
+            _ => None,
+        }
+    }
+
+    /// Value of the variant active at `time`: the last one starting before `time`, or the
+    /// last one of the day before the first one starts.
+    fn value_at(self, time: TimeOfDay, sun: Option<&SunTimes>) -> PresetValue {
+        let mut variants: Vec<(TimeOfDay, PresetValue)> = self
+            .time_variants()
+            .into_iter()
+            .map(|(start, value)| (start.resolve(sun), value))
+            .collect();
+        variants.sort_by_key(|(start, _)| *start);
+        let active = variants
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= time)
+            .or(variants.last());
+        match active {
+            Some((_, value)) => value.clone(),
+            None => self.value(),
+        }
+    }
+
+    /// Variants of the preset for the times of the day, with the time they start at.
+    fn time_variants(self) -> Vec<(Start, PresetValue)> {
+        use Preset::*;
+        let day = Start::Sunrise(TimeOfDay { hour: 7, minute: 0 });
+        let evening = Start::Sunset(TimeOfDay {
+            hour: 21,
+            minute: 0,
+        });
+        match self {
+            Reading => vec![
+                (day, PresetValue::Ct(4200, 100)),
+                (evening, PresetValue::Ct(3200, 100)),
+            ],
+            Concentration => vec![
+                (day, PresetValue::Ct(5000, 100)),
+                (evening, PresetValue::Ct(4000, 100)),
+            ],
+            CosyHome => vec![
+                (day, PresetValue::Ct(3000, 80)),
+                (evening, PresetValue::Ct(2700, 60)),
+            ],
+            _ => vec![],
+        }
+    }
+
+    fn value(self) -> PresetValue {
+        use Preset::*;
+        let red = 0xFF_00_00;
+        let green = 0x00_FF_00;
+        let blue = 0x00_00_FF;
+        match self {
+            Candle => candle(),
+            Reading => reading(),
+            NightReading => night_reading(),
+            CosyHome => cosy_home(),
+            Romantic => romantic(),
+            Birthday => birthday(),
+            DateNight => date_night(),
+            Teatime => teatime(),
+            PcMode => pc_mode(),
+            Concentration => concentration(),
+            Movie => movie(),
+            Night => night(),
+            Notify => notify(),
+            Notify2 => notify2(),
+
+            Red => PresetValue::Rgb(red, 100),
+            Green => PresetValue::Rgb(green, 100),
+            Blue => PresetValue::Rgb(blue, 100),
+
+            PulseRed => pulse(red, 100, 250),
+            PulseGreen => pulse(green, 100, 250),
+            PulseBlue => pulse(blue, 100, 250),
+            Police => police(100),
+            Police2 => police2(100),
+            Disco => disco(120),
+            Temp => temp(2600, 5000, 100),
+        }
+    }
+}
+
+fn disco(bpm: u64) -> PresetValue {
+    let duration = Duration::from_millis(1000 / bpm);
+    let expr = FlowExpresion(vec![
+        FlowTuple::rgb(duration, 0xFF_00_00, 100),
+        FlowTuple::rgb(duration, 0xFF_00_00, 1),
+        FlowTuple::rgb(duration, 0x80_FF_00, 100),
+        FlowTuple::rgb(duration, 0x80_FF_00, 1),
+        FlowTuple::rgb(duration, 0x00_FF_FF, 100),
+        FlowTuple::rgb(duration, 0x00_FF_FF, 1),
+        FlowTuple::rgb(duration, 0x80_00_FF, 100),
+        FlowTuple::rgb(duration, 0x80_00_FF, 1),
+    ]);
+    PresetValue::Flow(expr, 0, CfAction::Stay)
+}
+
+fn temp(a: u32, b: u32, brightness: i8) -> PresetValue {
+    let duration = Duration::from_millis(40_000);
+    let expr = FlowExpresion(vec![
+        FlowTuple::ct(duration, a, brightness),
+        FlowTuple::ct(duration, b, brightness),
+    ]);
+    PresetValue::Flow(expr, 0, CfAction::Stay)
+}
+
+fn pulse(rgb: u32, brightness: i8, duration: u64) -> PresetValue {
+    let duration = Duration::from_millis(duration);
+    let expr = FlowExpresion(vec![
+        FlowTuple::rgb(duration, rgb, brightness),
+        FlowTuple::rgb(duration, rgb, 1),
+    ]);
+    PresetValue::Flow(expr, 2, CfAction::Recover)
+}
+
+fn police(brightness: i8) -> PresetValue {
+    let duration = Duration::from_millis(300);
+    let (red, blue) = (0xFF_00_00, 0x00_00_FF);
+    let expr = FlowExpresion(vec![
+        FlowTuple::rgb(duration, red, brightness),
+        FlowTuple::rgb(duration, blue, brightness),
+    ]);
+    PresetValue::Flow(expr, 0, CfAction::Stay)
+}
+
+fn police2(brightness: i8) -> PresetValue {
+    let duration = Duration::from_millis(300);
+    let (red, blue) = (0xFF_00_00, 0x00_00_FF);
+    let expr = FlowExpresion(vec![
+        FlowTuple::rgb(duration, red, brightness),
+        FlowTuple::rgb(duration, red, 1),
+        FlowTuple::rgb(duration, red, brightness),
+        FlowTuple::sleep(duration),
+        FlowTuple::rgb(duration, blue, brightness),
+        FlowTuple::rgb(duration, blue, 1),
+        FlowTuple::rgb(duration, blue, brightness),
+        FlowTuple::sleep(duration),
+    ]);
+    PresetValue::Flow(expr, 0, CfAction::Stay)
+}
+
+fn candle() -> PresetValue {
+    let ct = 2700;
+    let expr = FlowExpresion(vec![
+        FlowTuple::ct(Duration::from_millis(800), ct, 50),
+        FlowTuple::ct(Duration::from_millis(800), ct, 30),
+        FlowTuple::ct(Duration::from_millis(1200), ct, 80),
+        FlowTuple::ct(Duration::from_millis(800), ct, 60),
+        FlowTuple::ct(Duration::from_millis(1200), ct, 90),
+        FlowTuple::ct(Duration::from_millis(2400), ct, 50),
+        FlowTuple::ct(Duration::from_millis(1200), ct, 80),
+        FlowTuple::ct(Duration::from_millis(800), ct, 60),
+        FlowTuple::ct(Duration::from_millis(400), ct, 70),
+    ]);
+    PresetValue::Flow(expr, 0, CfAction::Stay)
+}
+fn reading() -> PresetValue {
+    PresetValue::Ct(3500, 100)
+}
+fn night_reading() -> PresetValue {
+    PresetValue::Ct(4000, 40)
+}
+
+fn cosy_home() -> PresetValue {
+    PresetValue::Ct(2700, 80)
+}
+
+fn romantic() -> PresetValue {
+    let expr = FlowExpresion(vec![
+        FlowTuple::rgb(Duration::from_millis(4000), 0x59_15_6D, 1),
+        FlowTuple::rgb(Duration::from_millis(4000), 0x66_14_2A, 1),
+    ]);
+    PresetValue::Flow(expr, 0, CfAction::Stay)
+}
+
+fn birthday() -> PresetValue {
+    let expr = FlowExpresion(vec![
+        FlowTuple::rgb(Duration::from_millis(1996), 0xDC_50_19, 80),
+        FlowTuple::rgb(Duration::from_millis(1996), 0xDC_78_1E, 80),
+        FlowTuple::rgb(Duration::from_millis(1996), 0xAA_32_14, 80),
+    ]);
+    PresetValue::Flow(expr, 0, CfAction::Stay)
+}
+
+fn date_night() -> PresetValue {
+    PresetValue::Hsv(24, 100, 50)
+}
+
+fn teatime() -> PresetValue {
+    PresetValue::Ct(3000, 50)
+}
+
+fn pc_mode() -> PresetValue {
+    PresetValue::Ct(2700, 30)
+}
+fn concentration() -> PresetValue {
+    PresetValue::Ct(5000, 100)
+}
+
+fn movie() -> PresetValue {
+    PresetValue::Hsv(240, 60, 50)
+}
+
+fn night() -> PresetValue {
+    PresetValue::Hsv(36, 100, 1)
+}
+
+fn notify() -> PresetValue {
+    let duration = Duration::from_millis(300);
+    let temp = 5000;
+    let expr = FlowExpresion(vec![
+        FlowTuple::ct(duration, temp, 100),
+        FlowTuple::ct(duration, temp, 1),
+        FlowTuple::ct(duration, temp, 100),
+        FlowTuple::ct(duration, temp, 1),
+        FlowTuple::ct(duration, temp, 100),
+        FlowTuple::ct(duration, temp, 1),
+    ]);
+    let len = &expr.0.len();
+    PresetValue::Flow(expr, *len as u8, CfAction::Recover)
+}
+
+fn notify2() -> PresetValue {
+    let duration = Duration::from_millis(200);
+    let temp = 5000;
+    let expr = FlowExpresion(vec![
+        FlowTuple::ct(duration, temp, 100),
+        FlowTuple::ct(duration, temp, 1),
+        FlowTuple::ct(duration, temp, 100),
+        FlowTuple::ct(duration, temp, 1),
+    ]);
+    let len = &expr.0.len();
+    PresetValue::Flow(expr, *len as u8, CfAction::Recover)
+}
@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::{Bulb, BulbError, BulbGroup, COMMAND_QUOTA};
+
+/// Window of the command quota of the bulbs.
+const QUOTA_WINDOW: Duration = Duration::from_secs(60);
+
+/// Budget of commands per second shared by several bulbs (a token bucket).
+///
+/// Bulbs on the same congested access point drop commands when all of them are sent at once.
+/// Sharing a `RateLimit` between them, with [Bulb::rate_limit] or [BulbGroup::rate_limit],
+/// spreads the bursts: up to `burst` commands are sent right away and the rest wait for their
+/// turn, in the order they were sent. This is in addition to the limit of commands per minute
+/// of each bulb (see [COMMAND_QUOTA](crate::COMMAND_QUOTA)).
+///
+/// Clones share the same budget.
+///
+/// # Example
+/// ```
+/// # async fn test() {
+/// # use yeelight::*;
+/// let limit = RateLimit::new(20, 5);
+/// let desk = Bulb::connect("192.168.1.204", 0).await.unwrap().rate_limit(limit.clone());
+/// let shelf = Bulb::connect("192.168.1.205", 0).await.unwrap().rate_limit(limit);
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateLimit(Arc<Mutex<Bucket>>);
+
+#[derive(Debug)]
+struct Bucket {
+    /// Time between commands.
+    interval: Duration,
+    burst: u32,
+    /// Time at which the bucket is full (in the past if it is full now).
+    full_at: Instant,
+}
+
+impl RateLimit {
+    /// Allow `per_second` commands per second, and bursts of up to `burst` commands (at least
+    /// 1).
+    ///
+    /// # Panics
+    /// If `per_second` is 0.
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        assert!(per_second > 0, "The rate limit must allow some commands");
+        Self(Arc::new(Mutex::new(Bucket {
+            interval: Duration::from_secs(1) / per_second,
+            burst: burst.max(1),
+            full_at: Instant::now(),
+        })))
+    }
+
+    /// Wait until a command can be sent, and take it from the budget.
+    pub async fn acquire(&self) {
+        self.acquire_waiting().await;
+    }
+
+    /// Same as [RateLimit::acquire], returning whether the command had to wait.
+    pub(crate) async fn acquire_waiting(&self) -> bool {
+        let ready_at = {
+            let mut bucket = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            // Each command moves the time at which the bucket is full by one interval, and
+            // waits until the bucket has room for it (`burst` intervals before it is full)
+            let full_at = bucket.full_at.max(now) + bucket.interval;
+            bucket.full_at = full_at;
+            full_at.checked_sub(bucket.interval * bucket.burst)
+        };
+        match ready_at {
+            Some(ready_at) if ready_at > Instant::now() => {
+                tokio::time::sleep_until(ready_at).await;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What to do with the commands over the quota, see [QuotaLimiter].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuotaPolicy {
+    /// Wait until the oldest command of the last minute leaves the window.
+    #[default]
+    Delay,
+    /// Fail with [BulbError::QuotaExceeded].
+    Reject,
+}
+
+/// Keeps a connection under the quota of commands per minute of the bulbs, see
+/// [Bulb::quota_limit].
+///
+/// Bulbs ignore or disconnect clients that send more than [COMMAND_QUOTA] commands per minute
+/// over a normal connection ([music mode](Bulb::start_music) has no quota).
+#[derive(Debug, Clone)]
+pub struct QuotaLimiter {
+    limit: u32,
+    policy: QuotaPolicy,
+    /// Times of the commands sent in the last [QUOTA_WINDOW].
+    sent: VecDeque<Instant>,
+}
+
+impl QuotaLimiter {
+    /// Allow [COMMAND_QUOTA] commands per minute, handling the rest with `policy`.
+    pub fn new(policy: QuotaPolicy) -> Self {
+        Self {
+            limit: COMMAND_QUOTA,
+            policy,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Allow only `limit` commands per minute, to leave room for other clients of the bulb
+    /// (such as the Yeelight app).
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = limit.max(1);
+        self
+    }
+
+    /// Commands that can be sent right away.
+    pub fn remaining(&mut self) -> u32 {
+        self.expire();
+        self.limit.saturating_sub(self.sent.len() as u32)
+    }
+
+    /// Take a command from the quota, or return the time to wait for one.
+    fn try_acquire(&mut self) -> Result<(), Instant> {
+        self.expire();
+        match self.sent.front() {
+            Some(&oldest) if self.sent.len() >= self.limit as usize => Err(oldest + QUOTA_WINDOW),
+            _ => {
+                self.record();
+                Ok(())
+            }
+        }
+    }
+
+    /// Count a command sent without waiting for the quota.
+    fn record(&mut self) {
+        self.expire();
+        self.sent.push_back(Instant::now());
+    }
+
+    fn expire(&mut self) {
+        while self
+            .sent
+            .front()
+            .is_some_and(|sent| sent.elapsed() >= QUOTA_WINDOW)
+        {
+            self.sent.pop_front();
+        }
+    }
+}
+
+/// [QuotaLimiter] shared by the handles of a connection.
+pub(crate) type SharedQuota = Arc<Mutex<Option<QuotaLimiter>>>;
+
+/// Count a command sent without waiting for the quota.
+pub(crate) fn record_quota(quota: &SharedQuota) {
+    let mut quota = quota.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(limiter) = quota.as_mut() {
+        limiter.record();
+    }
+}
+
+/// Take a command from the quota, waiting or failing according to the policy.
+pub(crate) async fn acquire_quota(quota: &SharedQuota) -> Result<(), BulbError> {
+    loop {
+        let ready_at = {
+            let mut quota = quota.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(limiter) = quota.as_mut() else {
+                return Ok(());
+            };
+            match limiter.try_acquire() {
+                Ok(()) => return Ok(()),
+                Err(_) if limiter.policy == QuotaPolicy::Reject => {
+                    return Err(BulbError::QuotaExceeded)
+                }
+                Err(ready_at) => ready_at,
+            }
+        };
+        tokio::time::sleep_until(ready_at).await;
+    }
+}
+
+impl Bulb {
+    /// Keep this connection under the command quota of the bulb with the given
+    /// [QuotaLimiter], replacing the previous one.
+    ///
+    /// Applies to all the handles of the connection (its clones). Commands sent without
+    /// waiting (such as the ones sent when dropped) count towards the quota but are never
+    /// delayed nor rejected.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0)
+    ///     .await
+    ///     .unwrap()
+    ///     .quota_limit(QuotaLimiter::new(QuotaPolicy::Reject));
+    /// match bulb.toggle().await {
+    ///     Err(e) if matches!(e.inner(), BulbError::QuotaExceeded) => {
+    ///         eprintln!("Too many commands, try again later")
+    ///     }
+    ///     result => println!("{:?}", result),
+    /// }
+    /// println!("{:?} commands left", bulb.remaining_quota());
+    /// # }
+    /// ```
+    pub fn quota_limit(self, limiter: QuotaLimiter) -> Self {
+        self.writer.set_quota(limiter);
+        self
+    }
+
+    /// Commands that can be sent right away according to the [QuotaLimiter], `None` if there
+    /// is none (see [Bulb::quota_limit]).
+    pub fn remaining_quota(&self) -> Option<u32> {
+        self.writer.remaining_quota()
+    }
+
+    /// Wait for the given [RateLimit] before sending each command (commands sent without
+    /// waiting, such as the ones sent when dropped, are not limited).
+    ///
+    /// Applies to this handle only, not to its existing clones.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.writer.set_rate_limit(limit);
+        self
+    }
+}
+
+impl BulbGroup {
+    /// Share the given [RateLimit] between all the members of the group, including the ones
+    /// added later.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test(mut group: yeelight::BulbGroup) {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// // Turning on 20 bulbs takes a second
+    /// group.rate_limit(RateLimit::new(20, 1));
+    /// group.set_power(Power::On, Effect::Smooth, Duration::from_millis(500), Mode::Normal).await;
+    /// # }
+    /// ```
+    pub fn rate_limit(&mut self, limit: RateLimit) {
+        for (_, bulb) in self.iter_mut() {
+            bulb.writer.set_rate_limit(limit.clone());
+        }
+        self.rate_limit = Some(limit);
+    }
+}
@@ -1,99 +1,477 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
-use std::sync::Arc;
-
-use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use tokio::io::AsyncBufReadExt;
-use tokio::io::BufReader;
-use tokio::net::tcp::OwnedReadHalf;
+use tokio::io::{AsyncRead, BufReader};
 use tokio::sync::{
     mpsc,
     oneshot::{error::RecvError, Sender},
-    Mutex,
+    Mutex, Notify,
 };
 
-/// Event Notification
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Notification(pub serde_json::Map<String, serde_json::Value>);
+use crate::cache::SharedCache;
+use crate::protocol::{ErrDetails, JsonResponse, Notification, RawResponse};
+use crate::stats::StatsCollector;
 
-/// Response from the bulb.
-pub type Response = Vec<String>;
 pub type NotifyChan = Arc<Mutex<Option<mpsc::Sender<Notification>>>>;
-pub type RespChan = Arc<Mutex<HashMap<u64, Sender<Result<Response, BulbError>>>>>;
+pub type RespChan = Arc<Responses>;
+
+/// Sender of the response to a request.
+pub enum Responder {
+    Channel(Sender<Result<RawResponse, BulbError>>),
+    Slot(SlotRef),
+}
+
+/// Requests waiting for a response.
+///
+/// Each request gets its own channel, unless response slots are enabled (see
+/// [`Bulb::response_slots`](crate::Bulb::response_slots)). Slots are allocated once and
+/// reused, the request with id `n` uses the slot `n % capacity`.
+#[derive(Default)]
+pub struct Responses {
+    channels: Mutex<HashMap<u64, Sender<Result<RawResponse, BulbError>>>>,
+    slots: RwLock<Arc<[Slot]>>,
+    /// The connection is closed, no more responses will arrive.
+    closed: AtomicBool,
+}
+
+impl Responses {
+    /// Use `capacity` reusable slots for the responses (`0` to use a channel per request).
+    pub fn set_slots(&self, capacity: usize) {
+        let slots = (0..capacity).map(|_| Slot::default()).collect();
+        *self.slots.write().unwrap_or_else(|e| e.into_inner()) = slots;
+    }
+
+    /// Slot for the response to the request `id`, if slots are enabled.
+    pub fn slot(&self, id: u64) -> Option<SlotRef> {
+        let slots = self.slots.read().unwrap_or_else(|e| e.into_inner()).clone();
+        match slots.len() {
+            0 => None,
+            len => Some(SlotRef {
+                index: (id % len as u64) as usize,
+                slots,
+                id,
+            }),
+        }
+    }
+
+    /// Wait for the response to the request that will be sent with `responder`.
+    pub async fn register(&self, id: u64, responder: Responder) {
+        match responder {
+            // Nobody is waiting anymore (the request timed out before being written)
+            Responder::Channel(sender) if sender.is_closed() => {}
+            Responder::Channel(sender) => {
+                let mut channels = self.channels.lock().await;
+                // Requests dropped while the lock was taken (see Responses::forget)
+                channels.retain(|_, sender| !sender.is_closed());
+                match self.closed.load(Ordering::SeqCst) {
+                    true => {
+                        let _ = sender.send(Err(BulbError::Disconnected));
+                    }
+                    false => {
+                        channels.insert(id, sender);
+                    }
+                }
+            }
+            Responder::Slot(slot) => {
+                slot.get().start(id);
+                if self.closed.load(Ordering::SeqCst) {
+                    slot.get().finish(id, Err(BulbError::Disconnected));
+                }
+            }
+        }
+    }
+
+    /// Fail the requests waiting for a response (and the ones registered later) with
+    /// [BulbError::Disconnected].
+    pub async fn close(&self) {
+        let mut channels = self.channels.lock().await;
+        self.closed.store(true, Ordering::SeqCst);
+        for (_, sender) in channels.drain() {
+            let _ = sender.send(Err(BulbError::Disconnected));
+        }
+        drop(channels);
+
+        let slots = self.slots.read().unwrap_or_else(|e| e.into_inner()).clone();
+        for slot in slots.iter() {
+            let id = slot.lock().id;
+            slot.finish(id, Err(BulbError::Disconnected));
+        }
+    }
+
+    /// Wait for responses again, on a new connection.
+    pub fn reopen(&self) {
+        self.closed.store(false, Ordering::SeqCst);
+    }
+
+    /// Send the response (or error) to the request `id`.
+    ///
+    /// Returns `false` if nobody is waiting for it.
+    pub async fn respond(&self, id: u64, result: Result<RawResponse, BulbError>) -> bool {
+        if let Some(sender) = self.channels.lock().await.remove(&id) {
+            if sender.send(result).is_err() {
+                log::debug!("Response to a dropped request (msg_id={})", id);
+            }
+            return true;
+        }
+        match self.slot(id) {
+            Some(slot) => slot.get().finish(id, result),
+            None => false,
+        }
+    }
+
+    /// Stop waiting for the response to the request `id` (it timed out).
+    pub async fn cancel(&self, id: u64) {
+        if self.channels.lock().await.remove(&id).is_none() {
+            if let Some(slot) = self.slot(id) {
+                slot.get().cancel(id);
+            }
+        }
+    }
+
+    /// Stop waiting for the response to the request `id` because it was dropped, without
+    /// waiting for the lock (the entry is removed by the next request otherwise).
+    pub fn forget(&self, id: u64) {
+        if let Ok(mut channels) = self.channels.try_lock() {
+            channels.remove(&id);
+        }
+        if let Some(slot) = self.slot(id) {
+            slot.get().cancel(id);
+        }
+    }
+
+    /// Number of requests waiting for a response.
+    pub async fn len(&self) -> usize {
+        let slots = self.slots.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let waiting = slots.iter().filter(|slot| slot.lock().waiting).count();
+        let channels = self.channels.lock().await;
+        let requests = channels
+            .values()
+            .filter(|sender| !sender.is_closed())
+            .count();
+        requests + waiting
+    }
+}
+
+/// Reusable place for the response to a request.
+#[derive(Default)]
+pub struct Slot {
+    state: std::sync::Mutex<SlotState>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct SlotState {
+    /// Id of the last request that used the slot.
+    id: u64,
+    waiting: bool,
+    result: Option<Result<RawResponse, BulbError>>,
+}
+
+impl Slot {
+    fn lock(&self) -> std::sync::MutexGuard<'_, SlotState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn start(&self, id: u64) {
+        *self.lock() = SlotState {
+            id,
+            waiting: true,
+            result: None,
+        };
+        // Wake up the previous request, if it is still waiting
+        self.notify.notify_waiters();
+    }
+
+    fn cancel(&self, id: u64) {
+        let mut state = self.lock();
+        if state.id == id {
+            state.waiting = false;
+        }
+    }
+
+    fn finish(&self, id: u64, result: Result<RawResponse, BulbError>) -> bool {
+        let mut state = self.lock();
+        if state.id != id || !state.waiting {
+            return false;
+        }
+        state.waiting = false;
+        state.result = Some(result);
+        self.notify.notify_waiters();
+        true
+    }
+}
+
+/// Slot assigned to a request.
+#[derive(Clone)]
+pub struct SlotRef {
+    slots: Arc<[Slot]>,
+    index: usize,
+    id: u64,
+}
+
+impl SlotRef {
+    fn get(&self) -> &Slot {
+        &self.slots[self.index]
+    }
+
+    /// Fail the request without sending it.
+    pub fn fail(&self, error: BulbError) {
+        let slot = self.get();
+        slot.start(self.id);
+        slot.finish(self.id, Err(error));
+    }
+
+    /// Wait for the response.
+    ///
+    /// Fails if the slot is reused by a newer request before the response arrives (more
+    /// requests than slots were waiting).
+    pub async fn recv(&self) -> Result<RawResponse, BulbError> {
+        let slot = self.get();
+        loop {
+            let notified = slot.notify.notified();
+            {
+                let mut state = slot.lock();
+                // Ids increase, a lower one means the request has not been written yet
+                if state.id > self.id {
+                    return Err(BulbError::Io(std::io::Error::other(
+                        "Response slot reused by a newer request",
+                    )));
+                }
+                if state.id == self.id {
+                    if let Some(result) = state.result.take() {
+                        return result;
+                    }
+                }
+            }
+            notified.await;
+        }
+    }
+}
 
 pub struct Reader {
     notify_chan: NotifyChan,
     resp_chan: RespChan,
+    stats: StatsCollector,
+    cache: SharedCache,
 }
 
 impl Reader {
-    pub fn new(resp_chan: RespChan, notify_chan: NotifyChan) -> Self {
+    pub fn new(
+        resp_chan: RespChan,
+        notify_chan: NotifyChan,
+        stats: StatsCollector,
+        cache: SharedCache,
+    ) -> Self {
         Reader {
             notify_chan,
             resp_chan,
+            stats,
+            cache,
         }
     }
 
-    pub async fn start(self, reader: OwnedReadHalf) -> Result<(), ::std::io::Error> {
+    pub async fn start<R: AsyncRead + Unpin>(&self, reader: R) -> Result<(), ::std::io::Error> {
         let reader = BufReader::new(reader);
-        let mut lines = reader.lines();
-        while let Some(line) = lines.next_line().await? {
-            log::info!("recv <- {}", &line);
-            let r: JsonResponse = serde_json::from_slice(&line.into_bytes())?;
-            match r {
-                JsonResponse::Result { id, result } => {
-                    if let Some(sender) = self.resp_chan.lock().await.remove(&id) {
-                        if sender.send(Ok(result)).is_err() {
-                            log::error!("Could not send result (msg_id={})", id)
-                        }
-                    }
+        let mut lines = reader.split(b'\n');
+        let result = async {
+            while let Some(line) = lines.next_segment().await? {
+                self.handle_line(&line).await;
+            }
+            Ok(())
+        }
+        .await;
+        self.resp_chan.close().await;
+        result
+    }
+
+    /// Parse and dispatch a single line received from the bulb.
+    ///
+    /// Malformed lines are logged and skipped so that a single bad message does not kill the
+    /// connection.
+    pub async fn handle_line(&self, line: &[u8]) {
+        self.stats.received(line.len() + 1);
+
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+
+        log::info!("recv <- {}", line);
+        let r = match JsonResponse::parse(line) {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!("Could not parse message from bulb: {} ({})", line, e);
+                // Fail the request instead of leaving it waiting, if the id can be found
+                if let Some(id) = message_id(line) {
+                    let error = BulbError::Protocol(format!("{} ({})", line, e));
+                    self.resp_chan.respond(id, Err(error)).await;
                 }
-                JsonResponse::Error {
-                    id,
-                    error: ErrDetails { code, message },
-                } => {
-                    if let Some(sender) = self.resp_chan.lock().await.remove(&id) {
-                        if sender
-                            .send(Err(BulbError::ErrResponse(code, message)))
-                            .is_err()
-                        {
-                            log::error!("Could not send error (msg_id={})", id)
-                        }
-                    }
+                return;
+            }
+        };
+
+        match r {
+            JsonResponse::Result { id, result } => {
+                self.resp_chan.respond(id, Ok(result)).await;
+            }
+            JsonResponse::Error {
+                id,
+                error: ErrDetails { code, message },
+            } => {
+                let error = BulbError::ErrResponse(code, message);
+                self.resp_chan.respond(id, Err(error)).await;
+            }
+            JsonResponse::Notification { method, params } => {
+                self.stats.notification();
+                let notification = Notification { method, params };
+                match notification.props() {
+                    Some(props) => self.cache.notification(props),
+                    None => log::debug!("Received {} notification", notification.method),
                 }
-                JsonResponse::Notification { params, .. } => {
-                    if let Some(sender) = &mut *self.notify_chan.lock().await {
-                        if sender.send(Notification(params)).await.is_err() {
+                match &mut *self.notify_chan.lock().await {
+                    Some(sender) => {
+                        if sender.send(notification).await.is_err() {
+                            self.stats.dropped_notification();
                             log::error!("Could not send notification")
                         }
                     }
+                    None => {
+                        self.stats.dropped_notification();
+                        log::debug!(
+                            "Dropped notification, nobody subscribed with get_notify: {}",
+                            line
+                        );
+                    }
                 }
             }
         }
-        Ok(())
     }
 }
 
+/// Id of a message that is not a valid response (such as truncated or with unexpected
+/// values).
+fn message_id(line: &str) -> Option<u64> {
+    if let Ok(value) = serde_json::from_str::<serde_json::Value>(line) {
+        return value.get("id")?.as_u64();
+    }
+    let (_, rest) = line.split_once("\"id\"")?;
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
 /// Error Response from the bulb.
+///
+/// Errors of the commands are wrapped in [BulbError::Method] with the method that failed, use
+/// [BulbError::inner] to match the underlying error.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum BulbError {
     Io(::std::io::Error),
     ErrResponse(i32, String),
-    Recv(RecvError),
+    /// Error of a member of a [BulbGroup](crate::BulbGroup), with the name and address of the
+    /// bulb.
+    Context {
+        bulb: String,
+        error: Box<BulbError>,
+    },
+    /// No response was received within the [timeout](crate::Bulb::set_timeout).
+    Timeout,
+    /// The connection was closed before the response was received.
+    Disconnected,
+    /// The response of the bulb could not be parsed.
+    Protocol(String),
+    /// Error of a command, with the method that failed (such as `set_power`).
+    Method {
+        method: String,
+        error: Box<BulbError>,
+    },
+    /// The light is off and the command only works when it is on (such as saving the
+    /// power-on default state).
+    LightOff,
+    /// The command was not sent because the command quota of the connection is used up,
+    /// see [Bulb::quota_limit](crate::Bulb::quota_limit).
+    QuotaExceeded,
+    /// The command was discarded before being written, preempted by a
+    /// [high priority](crate::Bulb::priority) one.
+    Preempted,
 }
 
-impl Error for BulbError {}
+impl BulbError {
+    /// Wrap the error with the identity of the bulb (see [BulbError::Context]).
+    pub fn context(self, bulb: impl Into<String>) -> Self {
+        let error = match self {
+            Self::Context { error, .. } => error,
+            error => Box::new(error),
+        };
+        Self::Context {
+            bulb: bulb.into(),
+            error,
+        }
+    }
+
+    /// Wrap the error with the method that failed (see [BulbError::Method]).
+    pub(crate) fn method_context(self, method: &str) -> Self {
+        match self {
+            error @ Self::Method { .. } => error,
+            error => Self::Method {
+                method: method.to_string(),
+                error: Box::new(error),
+            },
+        }
+    }
+
+    /// The error without the [context](BulbError::Context) nor the
+    /// [method](BulbError::Method).
+    pub fn inner(&self) -> &BulbError {
+        match self {
+            Self::Context { error, .. } | Self::Method { error, .. } => error.inner(),
+            error => error,
+        }
+    }
+
+    /// Method of the command that failed, if known.
+    pub fn method(&self) -> Option<&str> {
+        match self {
+            Self::Method { method, .. } => Some(method),
+            Self::Context { error, .. } => error.method(),
+            _ => None,
+        }
+    }
+}
+
+impl Error for BulbError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Context { error, .. } | Self::Method { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for BulbError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Io(e) => e.fmt(f),
-            Self::Recv(e) => e.fmt(f),
             Self::ErrResponse(code, message) => {
                 write!(f, "Bulb response error: {} (code {})", message, code)
             }
+            Self::Context { bulb, error } => write!(f, "{}: {}", bulb, error),
+            Self::Timeout => write!(f, "No response from the bulb"),
+            Self::Disconnected => write!(f, "Connection to the bulb lost"),
+            Self::Protocol(message) => write!(f, "Invalid response from the bulb: {}", message),
+            Self::Method { method, error } => write!(f, "{}: {}", method, error),
+            Self::LightOff => write!(f, "The light is off"),
+            Self::QuotaExceeded => write!(f, "Command quota exceeded"),
+            Self::Preempted => write!(f, "Command preempted by a high priority one"),
         }
     }
 }
@@ -105,30 +483,63 @@ impl From<::std::io::Error> for BulbError {
 }
 
 impl From<RecvError> for BulbError {
-    fn from(e: RecvError) -> Self {
-        BulbError::Recv(e)
+    /// The sender of the response is only dropped when the connection is closed.
+    fn from(_: RecvError) -> Self {
+        BulbError::Disconnected
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
-enum JsonResponse {
-    Result {
-        id: u64,
-        result: Vec<String>,
-    },
-    Error {
-        id: u64,
-        error: ErrDetails,
-    },
-    Notification {
-        method: String,
-        params: serde_json::Map<String, serde_json::Value>,
-    },
+/// Error connecting to a bulb, see [Bulb::connect](crate::Bulb::connect) and
+/// [Bulb::start_music](crate::Bulb::start_music).
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The address of the bulb could not be resolved.
+    Resolve(::std::io::Error),
+    /// The connection could not be opened (the bulb or the proxy are not reachable) or
+    /// attached.
+    Io(::std::io::Error),
+    /// The bulb refused to start music mode.
+    Music(BulbError),
+    /// The connection was not opened within the
+    /// [connect timeout](crate::ConnectOptions::connect_timeout).
+    Timeout(Duration),
+}
+
+impl Error for ConnectError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Resolve(e) | Self::Io(e) => Some(e),
+            Self::Music(e) => Some(e),
+            Self::Timeout(_) => None,
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ErrDetails {
-    code: i32,
-    message: String,
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Resolve(e) => write!(f, "Could not resolve the address of the bulb: {}", e),
+            Self::Io(e) => e.fmt(f),
+            Self::Music(e) => write!(f, "Could not start music mode: {}", e),
+            Self::Timeout(timeout) => write!(f, "Connection timed out after {:?}", timeout),
+        }
+    }
+}
+
+impl From<::std::io::Error> for ConnectError {
+    fn from(e: ::std::io::Error) -> Self {
+        ConnectError::Io(e)
+    }
+}
+
+impl From<ConnectError> for ::std::io::Error {
+    fn from(e: ConnectError) -> Self {
+        match e {
+            ConnectError::Resolve(e) | ConnectError::Io(e) => e,
+            ConnectError::Music(e) => ::std::io::Error::other(e.to_string()),
+            ConnectError::Timeout(_) => {
+                ::std::io::Error::new(::std::io::ErrorKind::TimedOut, e.to_string())
+            }
+        }
+    }
 }
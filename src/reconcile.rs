@@ -0,0 +1,123 @@
+//! Compare bulbs with a desired state and bring them back to it.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::group::label;
+use crate::{Bulb, BulbError, BulbGroup, Property, StateSnapshot};
+
+/// What to do with the bulbs that drifted from the desired state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReconcileMode {
+    /// Only report the differences.
+    #[default]
+    Report,
+    /// Report the differences and [restore](Bulb::restore) the desired state.
+    Apply,
+}
+
+/// Property whose value differs from the desired state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Drift {
+    pub property: Property,
+    pub desired: String,
+    /// `None` if the bulb did not report the property.
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} (desired {})",
+            crate::state::property_name(self.property),
+            self.actual.as_deref().unwrap_or("unknown"),
+            self.desired
+        )
+    }
+}
+
+/// Result of [`BulbGroup::reconcile`] for each member with a desired state.
+pub type ReconcileResults = Vec<(String, Result<Vec<Drift>, BulbError>)>;
+
+impl StateSnapshot {
+    /// Differences between this (desired) state and `actual`.
+    ///
+    /// Only the properties that define the visible state are compared: the color
+    /// temperature of a light that is off or in RGB mode is ignored.
+    pub fn drift(&self, actual: &StateSnapshot) -> Vec<Drift> {
+        let changes = self.diff(actual);
+        self.relevant_properties()
+            .into_iter()
+            .filter_map(|property| {
+                let name = crate::state::property_name(property);
+                let change = changes.iter().find(|change| change.property == name)?;
+                Some(Drift {
+                    property,
+                    desired: change.before.clone()?,
+                    actual: change.after.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Bulb {
+    /// Compare the current state of the bulb with `desired`.
+    ///
+    /// With [`ReconcileMode::Apply`] the desired state is restored if there is any difference.
+    /// The returned drift is the one found before applying.
+    ///
+    /// This is intended to be run at startup with the last state that was set (see
+    /// [Bulb::snapshot]), since the bulbs may have been changed manually or lost power in the
+    /// meantime.
+    pub async fn reconcile(
+        &mut self,
+        desired: &StateSnapshot,
+        mode: ReconcileMode,
+    ) -> Result<Vec<Drift>, BulbError> {
+        let drift = desired.drift(&self.snapshot().await?);
+        if mode == ReconcileMode::Apply && !drift.is_empty() {
+            self.restore(desired).await?;
+        }
+        Ok(drift)
+    }
+}
+
+impl BulbGroup {
+    /// [Bulb::snapshot] of all members, for instance to persist them and
+    /// [reconcile](BulbGroup::reconcile) later.
+    pub async fn snapshot(&mut self) -> Vec<(String, Result<StateSnapshot, BulbError>)> {
+        join_all(self.iter_mut().map(|(name, bulb)| async move {
+            let label = label(name, bulb);
+            let result = bulb.snapshot().await.map_err(|e| e.context(label));
+            (name.to_string(), result)
+        }))
+        .await
+    }
+
+    /// [Bulb::reconcile] the members with an entry in `desired` concurrently.
+    ///
+    /// Members without a desired state are skipped.
+    pub async fn reconcile(
+        &mut self,
+        desired: &BTreeMap<String, StateSnapshot>,
+        mode: ReconcileMode,
+    ) -> ReconcileResults {
+        join_all(self.iter_mut().filter_map(|(name, bulb)| {
+            let desired = desired.get(name)?;
+            Some(async move {
+                let label = label(name, bulb);
+                let result = bulb
+                    .reconcile(desired, mode)
+                    .await
+                    .map_err(|e| e.context(label));
+                (name.to_string(), result)
+            })
+        }))
+        .await
+    }
+}
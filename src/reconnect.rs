@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use tokio::io::AsyncRead;
+use tokio::sync::{broadcast, mpsc, watch};
+
+use crate::journal::Replay;
+use crate::writer::WriteHalf;
+use crate::{ConnectOptions, HealthEvent};
+
+/// Delays between the attempts to reconnect, see [`ConnectOptions::reconnect`].
+///
+/// The first attempt is made right away, then the delay starts at `initial` and doubles
+/// after each failed attempt, up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Read half of a connection opened again.
+pub(crate) type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Bulb to reconnect to and the handles of the tasks using the connection.
+pub(crate) struct Link {
+    pub host: String,
+    pub port: u16,
+    pub options: ConnectOptions,
+    pub backoff: Backoff,
+    /// New write halves for the writing task.
+    pub halves: mpsc::UnboundedSender<WriteHalf>,
+    /// Incremented after each reconnection, the requests that failed wait for it.
+    pub generation: watch::Sender<u64>,
+    pub health: broadcast::Sender<HealthEvent>,
+    /// Desired state written to the new connections.
+    pub replay: Replay,
+}
+
+impl Link {
+    /// Open a new connection, retrying with the backoff, replay the journal on it and hand
+    /// its write half to the writing task.
+    ///
+    /// Returns `None` if the writing task stopped (all the handles were dropped) meanwhile.
+    pub async fn reconnect(&self) -> Option<ReadHalf> {
+        let mut delay = self.backoff.initial;
+        for attempt in 1.. {
+            if self.halves.is_closed() {
+                return None;
+            }
+            let _ = self.health.send(HealthEvent::Reconnecting(attempt));
+            match self.options.connect(&self.host, self.port).await {
+                Ok(stream) => {
+                    let (read, mut write) = stream.into_split();
+                    // Written before any request of the writing task
+                    if let Err(e) = self.replay.write(&mut write).await {
+                        log::warn!("Could not replay the journal: {}", e);
+                    }
+                    self.halves.send(Box::new(write)).ok()?;
+                    log::info!("Reconnected to {}:{}", self.host, self.port);
+                    return Some(Box::new(read));
+                }
+                Err(e) => log::warn!(
+                    "Could not reconnect to {}:{}, retrying in {:?}: {}",
+                    self.host,
+                    self.port,
+                    delay,
+                    e
+                ),
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(self.backoff.max);
+        }
+        None
+    }
+}
@@ -0,0 +1,407 @@
+//! Rooms: a group of bulbs with named scenes and a daily schedule.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::group::{ApplyReport, BulbGroup, GroupResults, VerifyOptions};
+use crate::{shutdown, solar, Bulb, ConnectError, Effect, Mode, Power, Scene};
+
+/// Serializable description of a [`Room`].
+///
+/// # Example
+/// ```yaml
+/// name: living room
+/// bulbs:
+///   sofa: 192.168.1.204
+///   tv: 192.168.1.205:55443
+/// scenes:
+///   movie: !Ct { ct: 2700, bright: 20 }
+///   reading: !Ct { ct: 4000, bright: 100 }
+/// schedule:
+///   - at: "07:30"
+///     scene: reading
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoomConfig {
+    pub name: String,
+    /// Name and address (`ip` or `ip:port`) of the bulbs in the room.
+    pub bulbs: BTreeMap<String, String>,
+    #[serde(default)]
+    pub scenes: BTreeMap<String, Scene>,
+    #[serde(default)]
+    pub schedule: Vec<ScheduleEntry>,
+}
+
+impl RoomConfig {
+    /// Read the configuration from a YAML file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_yaml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Receive the configuration of the file every time it is modified.
+    ///
+    /// The modification time of the file is checked every `interval`. Files that can not be
+    /// read or parsed are logged and skipped, so a half written file does not stop the
+    /// watcher. Use [Room::reload] to apply the received configurations.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+    /// # use yeelight::{Room, RoomConfig};
+    /// # use std::time::Duration;
+    /// let (mut room, _) = Room::connect(RoomConfig::from_file("living.yaml")?).await;
+    /// let mut configs = RoomConfig::watch_file("living.yaml", Duration::from_secs(2));
+    /// while let Some(config) = configs.recv().await {
+    ///     for (bulb, e) in room.reload(config).await.failed {
+    ///         eprintln!("Could not connect to {}: {}", bulb, e);
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn watch_file(path: impl Into<PathBuf>, interval: Duration) -> mpsc::Receiver<Self> {
+        let path = path.into();
+        let (sender, receiver) = mpsc::channel(1);
+        shutdown::spawn_watcher(async move {
+            async fn modified(path: &Path) -> Option<SystemTime> {
+                tokio::fs::metadata(path)
+                    .await
+                    .and_then(|m| m.modified())
+                    .ok()
+            }
+            let mut last = modified(&path).await;
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let current = modified(&path).await;
+                if current.is_none() || current == last {
+                    continue;
+                }
+                last = current;
+                let config = match tokio::fs::read_to_string(&path).await {
+                    Ok(content) => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                let config = match config {
+                    Ok(config) => config,
+                    Err(e) => {
+                        log::error!("Could not load {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                if sender.send(config).await.is_err() {
+                    return;
+                }
+            }
+        });
+        receiver
+    }
+}
+
+/// Changes made by [`Room::reload`] (or bulbs connected by [`Room::connect`]).
+#[derive(Debug, Default)]
+pub struct ReloadReport {
+    /// Bulbs connected (new or with a new address).
+    pub connected: Vec<String>,
+    /// Bulbs removed from the room.
+    pub removed: Vec<String>,
+    /// Bulbs that could not be connected, they are not part of the room until the next
+    /// reload.
+    pub failed: Vec<(String, ConnectError)>,
+}
+
+/// Scene to apply at a given time of the day.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleEntry {
+    pub at: TimeOfDay,
+    pub scene: String,
+}
+
+/// Time of the day (`HH:MM`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    pub hour: u8,
+    pub minute: u8,
+}
+
+impl TimeOfDay {
+    pub fn new(hour: u8, minute: u8) -> Option<Self> {
+        (hour < 24 && minute < 60).then_some(Self { hour, minute })
+    }
+
+    /// Current time of the day at the given offset from UTC (in minutes).
+    pub fn now(utc_offset: i32) -> Self {
+        let minutes = solar::local_minutes(utc_offset).rem_euclid(1440);
+        Self {
+            hour: (minutes / 60) as u8,
+            minute: (minutes % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.hour, self.minute)
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid time of day: {} (expected HH:MM)", s);
+        let (hour, minute) = s.split_once(':').ok_or_else(invalid)?;
+        let hour = hour.trim().parse().map_err(|_| invalid())?;
+        let minute = minute.trim().parse().map_err(|_| invalid())?;
+        TimeOfDay::new(hour, minute).ok_or_else(invalid)
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> Self {
+        t.to_string()
+    }
+}
+
+/// Group of bulbs with named scenes and a daily schedule.
+///
+/// # Example
+/// ```
+/// # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+/// # use yeelight::{Room, RoomConfig};
+/// let config: RoomConfig = serde_yaml::from_str(&std::fs::read_to_string("living.yaml")?)?;
+/// let (mut room, report) = Room::connect(config).await;
+/// for (bulb, e) in report.failed {
+///     eprintln!("Could not connect to {}: {}", bulb, e);
+/// }
+/// room.apply("movie").await;
+/// room.brightness(40).await;
+/// room.all_off().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Room {
+    config: RoomConfig,
+    group: BulbGroup,
+}
+
+impl Room {
+    /// Connect to all the bulbs of the room concurrently.
+    ///
+    /// Bulbs that could not be connected are in [ReloadReport::failed], they are not part of
+    /// the room until the next [reload](Room::reload).
+    pub async fn connect(config: RoomConfig) -> (Self, ReloadReport) {
+        let empty = RoomConfig {
+            bulbs: BTreeMap::new(),
+            ..config.clone()
+        };
+        let mut room = Self::new(empty, BulbGroup::new());
+        let report = room.reload(config).await;
+        (room, report)
+    }
+
+    /// Apply a new configuration without disconnecting the bulbs that did not change.
+    ///
+    /// Bulbs that are new or have a new address are connected concurrently, the connections of the bulbs
+    /// no longer in the configuration (or with a new address) are closed and the name, scenes
+    /// and schedule are replaced.
+    pub async fn reload(&mut self, config: RoomConfig) -> ReloadReport {
+        let mut report = ReloadReport::default();
+
+        let removed: Vec<String> = self
+            .group
+            .names()
+            .filter(|name| !config.bulbs.contains_key(*name))
+            .map(str::to_string)
+            .collect();
+        for name in removed {
+            if let Some(bulb) = self.group.remove(&name) {
+                close(&name, bulb).await;
+            }
+            report.removed.push(name);
+        }
+
+        let changed: Vec<(&String, &String)> = config
+            .bulbs
+            .iter()
+            .filter(|(name, address)| {
+                let unchanged = self.config.bulbs.get(*name) == Some(*address);
+                !unchanged || !self.group.names().any(|member| member == *name)
+            })
+            .collect();
+        let connections = join_all(changed.iter().map(|(_, address)| connect(address))).await;
+        for ((name, _), connected) in changed.into_iter().zip(connections) {
+            if let Some(bulb) = self.group.remove(name) {
+                close(name, bulb).await;
+            }
+            match connected {
+                Ok(bulb) => {
+                    self.group.add(name.clone(), bulb);
+                    report.connected.push(name.clone());
+                }
+                Err(e) => report.failed.push((name.clone(), e)),
+            }
+        }
+
+        self.config = config;
+        report
+    }
+
+    /// Create a room from an already connected group.
+    pub fn new(config: RoomConfig, group: BulbGroup) -> Self {
+        Self { config, group }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// Configuration of the room (can be serialized to save the room)
+    pub fn config(&self) -> &RoomConfig {
+        &self.config
+    }
+
+    pub fn group(&mut self) -> &mut BulbGroup {
+        &mut self.group
+    }
+
+    /// Add or replace a named scene.
+    pub fn add_scene(&mut self, name: impl Into<String>, scene: Scene) {
+        self.config.scenes.insert(name.into(), scene);
+    }
+
+    /// Names of the scenes of the room.
+    pub fn scenes(&self) -> impl Iterator<Item = &str> {
+        self.config.scenes.keys().map(String::as_str)
+    }
+
+    /// Apply the named scene to all the bulbs of the room.
+    ///
+    /// Returns `None` if the room has no scene with the given name.
+    pub async fn apply(&mut self, scene: &str) -> Option<GroupResults> {
+        let scene = self.config.scenes.get(scene)?;
+        Some(self.group.set_scene(scene).await)
+    }
+
+    /// Apply the named scene and verify that all the bulbs reached it, see
+    /// [BulbGroup::apply_verified].
+    ///
+    /// Returns `None` if the room has no scene with the given name.
+    pub async fn apply_verified(
+        &mut self,
+        scene: &str,
+        options: VerifyOptions,
+    ) -> Option<ApplyReport> {
+        let scene = self.config.scenes.get(scene)?;
+        Some(self.group.apply_verified(scene, options).await)
+    }
+
+    /// Turn off all the bulbs of the room.
+    pub async fn all_off(&mut self) -> GroupResults {
+        self.group
+            .set_power(
+                Power::Off,
+                Effect::Smooth,
+                Duration::from_millis(500),
+                Mode::Normal,
+            )
+            .await
+    }
+
+    /// Set the brightness of all the bulbs of the room.
+    pub async fn brightness(&mut self, brightness: u8) -> GroupResults {
+        self.group
+            .set_bright(brightness, Effect::Smooth, Duration::from_millis(500))
+            .await
+    }
+
+    /// Scene scheduled at the given time (last entry of the schedule at or before `time`,
+    /// wrapping around midnight).
+    pub fn scheduled_scene(&self, time: TimeOfDay) -> Option<&str> {
+        let entries = &self.config.schedule;
+        entries
+            .iter()
+            .filter(|entry| entry.at <= time)
+            .max_by_key(|entry| entry.at)
+            .or_else(|| entries.iter().max_by_key(|entry| entry.at))
+            .map(|entry| entry.scene.as_str())
+    }
+
+    /// Apply the scene scheduled at the given time (see [Room::scheduled_scene]).
+    pub async fn apply_scheduled(&mut self, time: TimeOfDay) -> Option<GroupResults> {
+        let scene = self.scheduled_scene(time)?.to_string();
+        self.apply(&scene).await
+    }
+}
+
+/// Connect to a bulb given its address (see [split_address]).
+async fn connect(address: &str) -> Result<Bulb, ConnectError> {
+    let (host, port) = split_address(address)?;
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Bulb::connect_addr((ip, if port == 0 { 55443 } else { port })).await,
+        Err(_) => Bulb::connect(host, port).await,
+    }
+}
+
+/// Close the connection of a bulb removed from the room.
+async fn close(name: &str, mut bulb: Bulb) {
+    if let Err(e) = bulb.close().await {
+        log::debug!("Could not close the connection to {}: {}", name, e);
+    }
+}
+
+/// Host and port (`0` for the default one) of an address of the configuration: `host`,
+/// `host:port`, an IPv6 address or `[ipv6]:port`.
+pub(crate) fn split_address(address: &str) -> Result<(&str, u16), ConnectError> {
+    let invalid = || {
+        let message = format!("Invalid bulb address: {}", address);
+        ConnectError::Resolve(io::Error::new(io::ErrorKind::InvalidInput, message))
+    };
+    let (host, port) = match address.strip_prefix('[') {
+        Some(rest) => {
+            let (host, port) = rest.split_once(']').ok_or_else(invalid)?;
+            match port {
+                "" => (host, None),
+                port => (host, Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+            }
+        }
+        // Bare IPv6 addresses have more than one colon and no port
+        None => match address.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (address, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| invalid())?,
+        None => 0,
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host, port))
+}
+
+impl From<Room> for BulbGroup {
+    fn from(room: Room) -> Self {
+        room.group
+    }
+}
@@ -0,0 +1,343 @@
+//! Automation scripts written in [Rhai](https://rhai.rs) (requires the `scripting` feature).
+//!
+//! Scripts can define an `on_notification(bulb, props)` function, called with the name of the
+//! bulb and a map of the changed properties, and register periodic handlers with
+//! `every(milliseconds, handler)`. The top level of the script is run once when it is loaded.
+//!
+//! Scripts do not have access to the bulbs themselves, only to the following functions which
+//! queue commands that are sent by the [`ScriptHost`] after the handler returns:
+//!
+//! - `set_power(bulb, on)`
+//! - `set_bright(bulb, bright)`
+//! - `set_ct(bulb, ct, bright)`
+//! - `set_rgb(bulb, rgb, bright)`
+//! - `set_hsv(bulb, hue, sat, bright)`
+//!
+//! Values out of range are clamped. `print` writes to the log.
+//!
+//! ```rhai
+//! every(60_000, || set_ct("desk", 2700, 40));
+//!
+//! fn on_notification(bulb, props) {
+//!     if bulb == "hall" && props.power == "on" {
+//!         set_power("porch", true);
+//!     }
+//! }
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, FnPtr, ParseError, Scope, AST};
+
+use crate::{BulbGroup, Effect, GroupResults, Mode, Notification, Power, Scene};
+
+/// Maximum number of operations a single handler can run, to stop runaway loops.
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// Command queued by a script.
+#[derive(Debug, Clone)]
+pub struct ScriptCommand {
+    /// Name of the bulb in the [`BulbGroup`].
+    pub bulb: String,
+    pub action: ScriptAction,
+}
+
+/// Operation requested by a script, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub enum ScriptAction {
+    Power(Power),
+    Bright(u8),
+    Scene(Scene),
+}
+
+/// Error loading or running a script.
+#[derive(Debug)]
+pub enum ScriptError {
+    Parse(ParseError),
+    Eval(Box<EvalAltResult>),
+}
+
+impl Error for ScriptError {}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "Script parse error: {}", e),
+            Self::Eval(e) => write!(f, "Script error: {}", e),
+        }
+    }
+}
+
+impl From<ParseError> for ScriptError {
+    fn from(e: ParseError) -> Self {
+        Self::Parse(e)
+    }
+}
+
+impl From<Box<EvalAltResult>> for ScriptError {
+    fn from(e: Box<EvalAltResult>) -> Self {
+        Self::Eval(e)
+    }
+}
+
+type Queue = Arc<Mutex<Vec<ScriptCommand>>>;
+
+struct Timer {
+    period: Duration,
+    next: Instant,
+    handler: FnPtr,
+}
+
+/// Loaded script and its registered handlers.
+pub struct ScriptHost {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    queue: Queue,
+    timers: Vec<Timer>,
+}
+
+impl ScriptHost {
+    /// Compile the script and run its top level.
+    pub fn new(source: &str) -> Result<Self, ScriptError> {
+        let queue = Queue::default();
+        let timers = Arc::new(Mutex::new(Vec::new()));
+
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(MAX_OPERATIONS)
+            .set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new())
+            .disable_symbol("eval")
+            .on_print(|s| log::info!("script: {}", s))
+            .on_debug(|s, _, pos| log::debug!("script ({}): {}", pos, s));
+
+        register_commands(&mut engine, &queue);
+
+        let registered = timers.clone();
+        engine.register_fn("every", move |ms: i64, handler: FnPtr| {
+            let period = Duration::from_millis(ms.max(1) as u64);
+            lock(&registered).push(Timer {
+                period,
+                next: Instant::now() + period,
+                handler,
+            });
+        });
+
+        let ast = engine.compile(source)?;
+        let mut scope = Scope::new();
+        engine.run_ast_with_scope(&mut scope, &ast)?;
+
+        let timers = std::mem::take(&mut *lock(&timers));
+        Ok(Self {
+            engine,
+            ast,
+            scope,
+            queue,
+            timers,
+        })
+    }
+
+    /// Commands queued by the top level of the script.
+    pub fn take_commands(&mut self) -> Vec<ScriptCommand> {
+        std::mem::take(&mut *lock(&self.queue))
+    }
+
+    /// Run the `on_notification` handler (if defined) and return the queued commands.
+    ///
+    /// Only `props` notifications are passed to the handler.
+    pub fn on_notification(
+        &mut self,
+        bulb: &str,
+        notification: &Notification,
+    ) -> Result<Vec<ScriptCommand>, ScriptError> {
+        let defined = self
+            .ast
+            .iter_functions()
+            .any(|f| f.name == "on_notification" && f.params.len() == 2);
+
+        if let (true, Some(props)) = (defined, notification.props()) {
+            let props: rhai::Map = props
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        serde_json::Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    };
+                    (key.as_str().into(), Dynamic::from(value))
+                })
+                .collect();
+
+            // The top level was already run when loading the script
+            let options = CallFnOptions::new().eval_ast(false);
+            let result = self.engine.call_fn_with_options::<Dynamic>(
+                options,
+                &mut self.scope,
+                &self.ast,
+                "on_notification",
+                (bulb.to_string(), props),
+            );
+            if let Err(e) = result {
+                self.take_commands();
+                return Err(e.into());
+            }
+        }
+        Ok(self.take_commands())
+    }
+
+    /// When the next `every` handler is due, `None` if there are none.
+    pub fn next_timer(&self) -> Option<Instant> {
+        self.timers.iter().map(|t| t.next).min()
+    }
+
+    /// Run the `every` handlers that are due and return the queued commands.
+    pub fn run_timers(&mut self) -> Result<Vec<ScriptCommand>, ScriptError> {
+        let now = Instant::now();
+        for timer in self.timers.iter_mut().filter(|t| t.next <= now) {
+            timer.next = now + timer.period;
+            if let Err(e) = timer.handler.call::<Dynamic>(&self.engine, &self.ast, ()) {
+                lock(&self.queue).clear();
+                return Err(e.into());
+            }
+        }
+        Ok(self.take_commands())
+    }
+
+    /// Run the script handlers over the group.
+    ///
+    /// This only returns when the notification channels of all the members are closed. The
+    /// notifications are taken with [BulbGroup::notifications]. Script errors are logged and
+    /// do not stop the host.
+    pub async fn run(mut self, group: &mut BulbGroup) {
+        let mut notifications = group.notifications().await;
+
+        let commands = self.take_commands();
+        log_results(group.execute(commands).await);
+
+        loop {
+            let next = self.next_timer();
+            let timer = async move {
+                match next {
+                    Some(next) => tokio::time::sleep_until(next.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            let commands = tokio::select! {
+                notification = notifications.recv() => match notification {
+                    Some((bulb, notification)) => self.on_notification(&bulb, &notification),
+                    None => return,
+                },
+                _ = timer => self.run_timers(),
+            };
+
+            match commands {
+                Ok(commands) => log_results(group.execute(commands).await),
+                Err(e) => log::error!("{}", e),
+            }
+        }
+    }
+}
+
+impl BulbGroup {
+    /// Send the commands queued by a script to the members of the group.
+    ///
+    /// Commands for bulbs that are not in the group are logged and skipped.
+    pub async fn execute(&mut self, commands: Vec<ScriptCommand>) -> GroupResults {
+        let mut results = Vec::with_capacity(commands.len());
+        for ScriptCommand { bulb: name, action } in commands {
+            let bulb = match self.get_mut(&name) {
+                Some(bulb) => bulb,
+                None => {
+                    log::warn!("Script used unknown bulb: {}", name);
+                    continue;
+                }
+            };
+            let label = crate::group::label(&name, bulb);
+            let result = match action {
+                ScriptAction::Power(power) => {
+                    bulb.set_power(power, Effect::Sudden, Duration::ZERO, Mode::Normal)
+                        .await
+                }
+                ScriptAction::Bright(bright) => {
+                    bulb.set_bright(bright, Effect::Sudden, Duration::ZERO)
+                        .await
+                }
+                ScriptAction::Scene(scene) => bulb.set_scene(scene).await,
+            };
+            results.push((name, result.map_err(|e| e.context(label))));
+        }
+        results
+    }
+}
+
+fn register_commands(engine: &mut Engine, queue: &Queue) {
+    let push = |queue: &Queue| {
+        let queue = queue.clone();
+        move |bulb: &str, action: ScriptAction| {
+            lock(&queue).push(ScriptCommand {
+                bulb: bulb.to_string(),
+                action,
+            })
+        }
+    };
+    let bright = |b: i64| b.clamp(1, 100) as u8;
+
+    let q = push(queue);
+    engine.register_fn("set_power", move |bulb: &str, on: bool| {
+        q(
+            bulb,
+            ScriptAction::Power(if on { Power::On } else { Power::Off }),
+        )
+    });
+    let q = push(queue);
+    engine.register_fn("set_bright", move |bulb: &str, b: i64| {
+        q(bulb, ScriptAction::Bright(bright(b)))
+    });
+    let q = push(queue);
+    engine.register_fn("set_ct", move |bulb: &str, ct: i64, b: i64| {
+        let ct = ct.clamp(1700, 6500) as u16;
+        q(
+            bulb,
+            ScriptAction::Scene(Scene::Ct {
+                ct,
+                bright: bright(b),
+            }),
+        )
+    });
+    let q = push(queue);
+    engine.register_fn("set_rgb", move |bulb: &str, rgb: i64, b: i64| {
+        let rgb = rgb.clamp(0, 0xFF_FF_FF) as u32;
+        q(
+            bulb,
+            ScriptAction::Scene(Scene::Color {
+                rgb,
+                bright: bright(b),
+            }),
+        )
+    });
+    let q = push(queue);
+    engine.register_fn("set_hsv", move |bulb: &str, hue: i64, sat: i64, b: i64| {
+        let (hue, sat) = (hue.clamp(0, 359) as u16, sat.clamp(0, 100) as u8);
+        let scene = Scene::Hsv {
+            hue,
+            sat,
+            bright: bright(b),
+        };
+        q(bulb, ScriptAction::Scene(scene))
+    });
+}
+
+fn log_results(results: GroupResults) {
+    for (name, result) in results {
+        if let Err(e) = result {
+            log::error!("Script command for {} failed: {}", name, e);
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
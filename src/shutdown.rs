@@ -0,0 +1,303 @@
+//! Stopping all the background tasks of the crate at once.
+//!
+//! Connections, effects, pollers and watchers run in tasks that are detached from the values
+//! that control them. A [Coordinator] tracks the tasks spawned by the crate while running a
+//! future with [Coordinator::scope] (and the ones spawned by those tasks), so a service can
+//! stop them all when it exits.
+//!
+//! [Coordinator::shutdown] stops the tasks in two stages: first the effects, pollers and
+//! watchers (effects leave the bulbs as they would when [stopped](crate::effects::EffectHandle::stop)),
+//! then the connections. The tasks still running when the grace period ends are aborted.
+//!
+//! The connections of the [broker](crate::broker) to its clients and bulbs are closed with
+//! the others, but its accept loop stops when its `serve` future is dropped. The tasks of the
+//! [simulator](crate::simulator) are not tracked.
+//!
+//! # Example
+//! ```
+//! # async fn test() {
+//! use std::time::Duration;
+//! use yeelight::shutdown::Coordinator;
+//! use yeelight::{effects, Bulb};
+//!
+//! let coordinator = Coordinator::new();
+//! coordinator
+//!     .scope(async {
+//!         let bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+//!         let effect = effects::hue_rotate(bulb, Duration::from_secs(10), 100, 80, Default::default());
+//!         tokio::signal::ctrl_c().await.unwrap();
+//!     })
+//!     .await;
+//! let report = coordinator.shutdown(Duration::from_secs(5)).await;
+//! println!("{} tasks stopped, {} aborted", report.stopped, report.aborted);
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{oneshot, watch};
+use tokio::task::{AbortHandle, JoinHandle};
+
+use crate::BulbError;
+
+tokio::task_local! {
+    static CURRENT: Coordinator;
+}
+
+/// Stage of the shutdown at which a task is stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Stage {
+    /// Effects, pollers, watchers and the tasks spawned with [Coordinator::spawn].
+    Services = 1,
+    /// Reading and writing tasks of the connections, stopped once the services are done
+    /// with them.
+    Connections = 2,
+}
+
+/// Tracks the tasks spawned by the crate to stop them all, see the
+/// [module documentation](self).
+///
+/// Clones track the same tasks.
+#[derive(Debug, Clone, Default)]
+pub struct Coordinator(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    next_id: AtomicU64,
+    tasks: Mutex<HashMap<u64, (Stage, AbortHandle)>>,
+    /// Running tasks of each stage.
+    running: watch::Sender<[usize; 2]>,
+    /// Stage being stopped (0 while running).
+    stopping: watch::Sender<u8>,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            tasks: Mutex::default(),
+            running: watch::Sender::new([0; 2]),
+            stopping: watch::Sender::new(0),
+        }
+    }
+}
+
+/// Result of [Coordinator::shutdown].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShutdownReport {
+    /// Tasks that stopped by themselves within the grace period.
+    pub stopped: usize,
+    /// Tasks aborted when the grace period ended.
+    pub aborted: usize,
+}
+
+/// Removes the task from the [Coordinator] when it ends (or is aborted).
+struct Registration {
+    coordinator: Coordinator,
+    id: u64,
+    stage: Stage,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let inner = &self.coordinator.0;
+        inner
+            .tasks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+        inner
+            .running
+            .send_modify(|running| running[self.stage as usize - 1] -= 1);
+    }
+}
+
+impl Coordinator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Coordinator tracking the current task, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// Run `future`, tracking the tasks spawned by the crate while it runs.
+    pub async fn scope<F: Future>(&self, future: F) -> F::Output {
+        CURRENT.scope(self.clone(), future).await
+    }
+
+    /// Spawn a task of the application that is tracked as the ones of the crate. It should
+    /// stop when [Coordinator::shutting_down] resolves, or it is aborted once the grace
+    /// period ends.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_stage(Stage::Services, future)
+    }
+
+    fn spawn_stage<F>(&self, stage: Stage, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let id = self.0.next_id.fetch_add(1, Ordering::Relaxed);
+        self.0
+            .running
+            .send_modify(|running| running[stage as usize - 1] += 1);
+        let registration = Registration {
+            coordinator: self.clone(),
+            id,
+            stage,
+        };
+        // The task waits until it is registered, so it is never removed before being added
+        let (registered, wait) = oneshot::channel();
+        let task = tokio::spawn(CURRENT.scope(self.clone(), async move {
+            let _registration = registration;
+            let _ = wait.await;
+            future.await
+        }));
+        self.lock_tasks().insert(id, (stage, task.abort_handle()));
+        let _ = registered.send(());
+        task
+    }
+
+    /// Tasks running.
+    pub fn tasks(&self) -> usize {
+        self.0.running.borrow().iter().sum()
+    }
+
+    /// Whether [Coordinator::shutdown] has been called.
+    pub fn is_shutting_down(&self) -> bool {
+        *self.0.stopping.borrow() > 0
+    }
+
+    /// Wait until [Coordinator::shutdown] is called.
+    pub async fn shutting_down(&self) {
+        self.stage_stopped(Stage::Services).await
+    }
+
+    async fn stage_stopped(&self, stage: Stage) {
+        let mut stopping = self.0.stopping.subscribe();
+        let _ = stopping.wait_for(|stopping| *stopping >= stage as u8).await;
+    }
+
+    /// Stop all the tasks, waiting up to `grace` for them to stop by themselves before
+    /// aborting the rest.
+    ///
+    /// Effects, pollers and watchers are stopped first, and then the connections, each stage
+    /// being given `grace`. Tasks spawned during the shutdown are stopped as well.
+    pub async fn shutdown(&self, grace: Duration) -> ShutdownReport {
+        let started = self.tasks();
+        let mut aborted = 0;
+        for stage in [Stage::Services, Stage::Connections] {
+            self.0.stopping.send_replace(stage as u8);
+            let mut running = self.0.running.subscribe();
+            let index = stage as usize - 1;
+            let done = running.wait_for(|running| running[..=index].iter().sum::<usize>() == 0);
+            if tokio::time::timeout(grace, done).await.is_ok() {
+                continue;
+            }
+
+            self.lock_tasks().retain(|_, (task_stage, task)| {
+                let abort = *task_stage <= stage;
+                if abort {
+                    task.abort();
+                    aborted += 1;
+                }
+                !abort
+            });
+            // Aborted tasks are dropped the next time they would run
+            let _ = running
+                .wait_for(|running| running[..=index].iter().sum::<usize>() == 0)
+                .await;
+        }
+        if aborted > 0 {
+            log::warn!("Aborted {} tasks still running after {:?}", aborted, grace);
+        }
+        ShutdownReport {
+            stopped: started.saturating_sub(aborted),
+            aborted,
+        }
+    }
+
+    fn lock_tasks(&self) -> std::sync::MutexGuard<'_, HashMap<u64, (Stage, AbortHandle)>> {
+        self.0.tasks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Spawn an effect, poller or watcher, tracked by the current [Coordinator] if any.
+pub(crate) fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match Coordinator::current() {
+        Some(coordinator) => coordinator.spawn_stage(Stage::Services, future),
+        None => tokio::spawn(future),
+    }
+}
+
+/// Spawn a watcher that is dropped when the services of the current [Coordinator] have to
+/// stop (or that runs until it ends without one).
+pub(crate) fn spawn_watcher<F>(future: F) -> JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    spawn(async move {
+        tokio::select! {
+            _ = future => {}
+            _ = stopping() => {}
+        }
+    })
+}
+
+/// Spawn a task of a connection, tracked by the current [Coordinator] if any.
+pub(crate) fn spawn_connection<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match Coordinator::current() {
+        Some(coordinator) => coordinator.spawn_stage(Stage::Connections, future),
+        None => tokio::spawn(future),
+    }
+}
+
+/// Wait for a task spawned with [spawn], failing with [BulbError::Disconnected] if it was
+/// aborted (by [Coordinator::shutdown]). Panics of the task are resumed.
+pub(crate) async fn join<T>(task: JoinHandle<T>) -> Result<T, BulbError> {
+    match task.await {
+        Ok(output) => Ok(output),
+        Err(e) if e.is_cancelled() => Err(BulbError::Disconnected),
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Resolves when the effects, pollers and watchers of the current [Coordinator] have to stop
+/// (never without one).
+pub(crate) fn stopping() -> impl Future<Output = ()> + Send + 'static {
+    stage_stopped(Stage::Services)
+}
+
+/// Resolves when the connections of the current [Coordinator] have to close (never without
+/// one).
+pub(crate) fn closing() -> impl Future<Output = ()> + Send + 'static {
+    stage_stopped(Stage::Connections)
+}
+
+fn stage_stopped(stage: Stage) -> impl Future<Output = ()> + Send + 'static {
+    let coordinator = Coordinator::current();
+    async move {
+        match coordinator {
+            Some(coordinator) => coordinator.stage_stopped(stage).await,
+            None => std::future::pending().await,
+        }
+    }
+}
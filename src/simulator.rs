@@ -0,0 +1,480 @@
+//! Simulated bulb for development and testing without hardware (requires the `simulator`
+//! feature).
+//!
+//! The [`Simulator`] answers the TCP protocol (including music mode) and discovery requests
+//! like a color bulb would, keeping its state in memory. The `yeelight-simulator` binary runs
+//! one and prints its state to the terminal.
+//!
+//! Supported methods: `get_prop`, `set_power`, `toggle`, `dev_toggle`, `set_bright`,
+//! `set_ct_abx`, `set_rgb`, `set_hsv`, `set_scene`, `start_cf`, `stop_cf`, `set_default`,
+//! `set_adjust`, `adjust_bright`, `adjust_ct`, `adjust_color`, `set_name`, `cron_add`,
+//! `cron_del` and `set_music` (with their `bg_` variants). Other methods get an error
+//! response.
+//!
+//! # Example
+//! ```
+//! # async fn test() -> Result<(), Box<dyn std::error::Error>> {
+//! use yeelight::{simulator::Simulator, Bulb};
+//!
+//! let simulator = Simulator::new("color");
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+//! let addr = listener.local_addr()?;
+//! tokio::spawn(simulator.clone().serve(listener));
+//!
+//! let mut bulb = Bulb::connect(&addr.ip().to_string(), addr.port()).await?;
+//! bulb.toggle().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{broadcast, mpsc};
+
+/// Port and multicast group used for discovery.
+const SSDP_PORT: u16 = 1982;
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
+const SUPPORT: &str = "get_prop set_default set_power toggle set_bright start_cf stop_cf \
+    set_scene cron_add cron_del set_adjust set_music set_name set_rgb set_hsv set_ct_abx \
+    adjust_bright adjust_color adjust_ct bg_set_rgb bg_set_hsv bg_set_ct_abx bg_start_cf \
+    bg_stop_cf bg_set_scene bg_set_default bg_set_power bg_set_bright bg_set_adjust \
+    bg_adjust_bright bg_adjust_color bg_adjust_ct bg_toggle dev_toggle";
+
+/// Properties of a light: (power, bright, color mode, ct, rgb, hue, sat, flowing).
+const MAIN: [&str; 8] = [
+    "power",
+    "bright",
+    "color_mode",
+    "ct",
+    "rgb",
+    "hue",
+    "sat",
+    "flowing",
+];
+const BG: [&str; 8] = [
+    "bg_power",
+    "bg_bright",
+    "bg_lmode",
+    "bg_ct",
+    "bg_rgb",
+    "bg_hue",
+    "bg_sat",
+    "bg_flowing",
+];
+
+/// Simulated bulb, cloning it gives another handle to the same bulb.
+#[derive(Clone)]
+pub struct Simulator {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    id: u64,
+    model: String,
+    state: Mutex<BTreeMap<String, String>>,
+    changes: broadcast::Sender<Map<String, Value>>,
+    /// Music mode connections to be served by [`Simulator::serve`].
+    music: mpsc::UnboundedSender<TcpStream>,
+    music_connections: Mutex<Option<mpsc::UnboundedReceiver<TcpStream>>>,
+}
+
+/// Error returned to the client.
+struct MethodError(&'static str);
+
+impl Simulator {
+    /// New bulb of the given model, turned on at full brightness.
+    pub fn new(model: &str) -> Self {
+        let background = crate::model::lookup(model).is_some_and(|m| m.background);
+
+        let mut state = BTreeMap::new();
+        let lights: &[[&str; 8]] = if background { &[MAIN, BG] } else { &[MAIN] };
+        for light in lights {
+            for (key, value) in light
+                .iter()
+                .zip(["on", "100", "2", "4000", "16777215", "0", "0", "0"])
+            {
+                state.insert(key.to_string(), value.to_string());
+            }
+        }
+        for (key, value) in [
+            ("name", ""),
+            ("delayoff", "0"),
+            ("music_on", "0"),
+            ("nl_br", "0"),
+            ("active_mode", "0"),
+        ] {
+            state.insert(key.to_string(), value.to_string());
+        }
+
+        let id = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(1, |d| d.as_nanos() as u64 & 0xFFFF_FFFF);
+
+        let (music, music_connections) = mpsc::unbounded_channel();
+        Self {
+            inner: Arc::new(Inner {
+                id,
+                model: model.to_string(),
+                state: Mutex::new(state),
+                changes: broadcast::channel(16).0,
+                music,
+                music_connections: Mutex::new(Some(music_connections)),
+            }),
+        }
+    }
+
+    /// Current properties of the bulb.
+    pub fn state(&self) -> BTreeMap<String, String> {
+        self.lock().clone()
+    }
+
+    /// Receive the properties changed by each command (the `props` notifications).
+    pub fn subscribe(&self) -> broadcast::Receiver<Map<String, Value>> {
+        self.inner.changes.subscribe()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, String>> {
+        self.inner.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Accept connections and serve them.
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        let music = self
+            .inner
+            .music_connections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(mut music) = music {
+            let simulator = self.clone();
+            tokio::spawn(async move {
+                while let Some(stream) = music.recv().await {
+                    tokio::spawn(simulator.clone().connection(stream, false));
+                }
+            });
+        }
+
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            log::info!("Connection from {}", addr);
+            tokio::spawn(self.clone().connection(stream, true));
+        }
+    }
+
+    /// Answer discovery requests with the given address as location of the bulb.
+    pub async fn serve_discovery(self, location: SocketAddr) -> io::Result<()> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, SSDP_PORT)).await?;
+        socket.join_multicast_v4(SSDP_GROUP, Ipv4Addr::UNSPECIFIED)?;
+
+        let mut buf = [0; 2048];
+        loop {
+            let (len, addr) = socket.recv_from(&mut buf).await?;
+            let request = String::from_utf8_lossy(&buf[..len]);
+            if request.starts_with("M-SEARCH") && request.contains("wifi_bulb") {
+                let response = self.discovery_response(location);
+                socket.send_to(response.as_bytes(), addr).await?;
+            }
+        }
+    }
+
+    fn discovery_response(&self, location: SocketAddr) -> String {
+        let state = self.lock();
+        let get = |key: &str| state.get(key).map(String::as_str).unwrap_or_default();
+
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nCache-Control: max-age=3600\r\nDate: \r\nExt: \r\n\
+             Location: yeelight://{}\r\nServer: POSIX UPnP/1.0 YGLC/1\r\nid: 0x{:016x}\r\n\
+             model: {}\r\nfw_ver: 18\r\nsupport: {}\r\n",
+            location, self.inner.id, self.inner.model, SUPPORT
+        );
+        for key in [
+            "power",
+            "bright",
+            "color_mode",
+            "ct",
+            "rgb",
+            "hue",
+            "sat",
+            "name",
+        ] {
+            response.push_str(&format!("{}: {}\r\n", key, get(key)));
+        }
+        response
+    }
+
+    /// Serve a connection, `respond` is false for music mode connections.
+    async fn connection(self, stream: TcpStream, respond: bool) {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let mut changes = self.subscribe();
+
+        loop {
+            tokio::select! {
+                line = lines.next_line() => {
+                    let line = match line {
+                        Ok(Some(line)) => line,
+                        _ => return,
+                    };
+                    let response = self.handle(&line).await;
+                    if respond && write.write_all(response.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+                change = changes.recv(), if respond => {
+                    let Ok(params) = change else { continue };
+                    let notification = json!({"method": "props", "params": params});
+                    if write.write_all(format!("{}\r\n", notification).as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a request line and return the response line.
+    async fn handle(&self, line: &str) -> String {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(_) => return error_line(Value::Null, "invalid command"),
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = request
+            .get("method")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let params = match request.get("params") {
+            Some(Value::Array(params)) => params.as_slice(),
+            _ => &[],
+        };
+
+        if method == "set_music" {
+            return match self.set_music(params).await {
+                Ok(()) => result_line(id, vec!["ok".to_string()]),
+                Err(MethodError(message)) => error_line(id, message),
+            };
+        }
+
+        let mut state = self.lock();
+        let before = state.clone();
+        let result = execute(&mut state, method, params);
+
+        let changed: Map<String, Value> = state
+            .iter()
+            .filter(|(key, value)| before.get(*key) != Some(value))
+            .map(|(key, value)| (key.clone(), notification_value(value)))
+            .collect();
+        drop(state);
+
+        if !changed.is_empty() {
+            let _ = self.inner.changes.send(changed);
+        }
+
+        match result {
+            Ok(result) => result_line(id, result),
+            Err(MethodError(message)) => error_line(id, message),
+        }
+    }
+
+    async fn set_music(&self, params: &[Value]) -> Result<(), MethodError> {
+        match int(params, 0)? {
+            0 => {
+                self.lock().insert("music_on".to_string(), "0".to_string());
+                Ok(())
+            }
+            _ => {
+                let host = str(params, 1)?;
+                let port = int(params, 2)? as u16;
+                let stream = TcpStream::connect((host, port))
+                    .await
+                    .map_err(|_| MethodError("could not connect to music host"))?;
+                self.lock().insert("music_on".to_string(), "1".to_string());
+                let _ = self.inner.music.send(stream);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Apply the method to the state and return the result.
+fn execute(
+    state: &mut BTreeMap<String, String>,
+    method: &str,
+    params: &[Value],
+) -> Result<Vec<String>, MethodError> {
+    let ok = Ok(vec!["ok".to_string()]);
+
+    if method == "get_prop" {
+        return Ok(params
+            .iter()
+            .map(|p| {
+                let key = p.as_str().unwrap_or_default();
+                state.get(key).cloned().unwrap_or_default()
+            })
+            .collect());
+    }
+
+    let (light, method) = match method.strip_prefix("bg_") {
+        Some(method) if state.contains_key("bg_power") => (BG, method),
+        Some(_) => return Err(MethodError("method not supported")),
+        None => (MAIN, method),
+    };
+    let [power, bright, mode, ct, rgb, hue, sat, flowing] = light.map(str::to_string);
+
+    match method {
+        "set_power" => {
+            let on = str(params, 0)? == "on";
+            put(state, &power, if on { "on" } else { "off" });
+            if on {
+                match params.get(3).and_then(Value::as_i64) {
+                    Some(1) => put(state, &mode, "2"),
+                    Some(2) => put(state, &mode, "1"),
+                    Some(3) => put(state, &mode, "3"),
+                    _ => {}
+                }
+            }
+        }
+        "toggle" => toggle(state, &power),
+        "dev_toggle" if light == MAIN => {
+            toggle(state, "power");
+            if state.contains_key("bg_power") {
+                toggle(state, "bg_power");
+            }
+        }
+        "set_bright" => put(state, &bright, int(params, 0)?.clamp(1, 100)),
+        "set_ct_abx" => {
+            put(state, &ct, int(params, 0)?.clamp(1700, 6500));
+            put(state, &mode, "2");
+        }
+        "set_rgb" => {
+            put(state, &rgb, int(params, 0)?.clamp(0, 0xFF_FF_FF));
+            put(state, &mode, "1");
+        }
+        "set_hsv" => {
+            put(state, &hue, int(params, 0)?.clamp(0, 359));
+            put(state, &sat, int(params, 1)?.clamp(0, 100));
+            put(state, &mode, "3");
+        }
+        "set_scene" => {
+            match str(params, 0)? {
+                "color" => {
+                    put(state, &rgb, int(params, 1)?.clamp(0, 0xFF_FF_FF));
+                    put(state, &bright, int(params, 2)?.clamp(1, 100));
+                    put(state, &mode, "1");
+                }
+                "hsv" => {
+                    put(state, &hue, int(params, 1)?.clamp(0, 359));
+                    put(state, &sat, int(params, 2)?.clamp(0, 100));
+                    put(state, &bright, int(params, 3)?.clamp(1, 100));
+                    put(state, &mode, "3");
+                }
+                "ct" => {
+                    put(state, &ct, int(params, 1)?.clamp(1700, 6500));
+                    put(state, &bright, int(params, 2)?.clamp(1, 100));
+                    put(state, &mode, "2");
+                }
+                "cf" => put(state, &flowing, "1"),
+                "auto_delay_off" => {
+                    put(state, &bright, int(params, 1)?.clamp(1, 100));
+                    put(state, "delayoff", int(params, 2)?);
+                }
+                _ => return Err(MethodError("invalid params")),
+            }
+            put(state, &power, "on");
+        }
+        "start_cf" => put(state, &flowing, "1"),
+        "stop_cf" => put(state, &flowing, "0"),
+        "set_default" => {}
+        "set_adjust" => {
+            let step = match str(params, 0)? {
+                "increase" => 10,
+                "decrease" => -10,
+                _ => 0,
+            };
+            match str(params, 1)? {
+                "bright" => adjust(state, &bright, step, 1, 100),
+                "ct" => adjust(state, &ct, step * 48, 1700, 6500),
+                _ => {
+                    let next = (number(state, &hue) + 30) % 360;
+                    put(state, &hue, next);
+                    put(state, &mode, 3);
+                }
+            }
+        }
+        "adjust_bright" => adjust(state, &bright, int(params, 0)?, 1, 100),
+        "adjust_ct" => adjust(state, &ct, int(params, 0)? * 48, 1700, 6500),
+        "adjust_color" => {
+            let next = (number(state, &hue) + int(params, 0)? * 36 / 10).rem_euclid(360);
+            put(state, &hue, next);
+            put(state, &mode, 3);
+        }
+        "set_name" if light == MAIN => put(state, "name", str(params, 0)?),
+        "cron_add" if light == MAIN => put(state, "delayoff", int(params, 1)?),
+        "cron_del" if light == MAIN => put(state, "delayoff", "0"),
+        _ => return Err(MethodError("method not supported")),
+    }
+    ok
+}
+
+fn put(state: &mut BTreeMap<String, String>, key: &str, value: impl ToString) {
+    state.insert(key.to_string(), value.to_string());
+}
+
+fn toggle(state: &mut BTreeMap<String, String>, power: &str) {
+    let next = if state.get(power).is_some_and(|p| p == "on") {
+        "off"
+    } else {
+        "on"
+    };
+    state.insert(power.to_string(), next.to_string());
+}
+
+fn number(state: &BTreeMap<String, String>, key: &str) -> i64 {
+    state
+        .get(key)
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+}
+
+fn adjust(state: &mut BTreeMap<String, String>, key: &str, step: i64, min: i64, max: i64) {
+    let value = (number(state, key) + step).clamp(min, max);
+    state.insert(key.to_string(), value.to_string());
+}
+
+fn int(params: &[Value], i: usize) -> Result<i64, MethodError> {
+    params
+        .get(i)
+        .and_then(|v| v.as_i64().or_else(|| v.as_str()?.parse().ok()))
+        .ok_or(MethodError("invalid params"))
+}
+
+fn str(params: &[Value], i: usize) -> Result<&str, MethodError> {
+    params
+        .get(i)
+        .and_then(Value::as_str)
+        .ok_or(MethodError("invalid params"))
+}
+
+/// Numeric properties are sent as numbers in notifications.
+fn notification_value(value: &str) -> Value {
+    value
+        .parse::<u64>()
+        .map_or_else(|_| Value::from(value), Value::from)
+}
+
+fn result_line(id: Value, result: Vec<String>) -> String {
+    format!("{}\r\n", json!({"id": id, "result": result}))
+}
+
+fn error_line(id: Value, message: &str) -> String {
+    format!(
+        "{}\r\n",
+        json!({"id": id, "error": {"code": -1, "message": message}})
+    )
+}
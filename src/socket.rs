@@ -0,0 +1,82 @@
+use std::io;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+
+/// Options of the TCP sockets used to talk to the bulbs.
+///
+/// Set them with [ConnectOptions::socket](crate::ConnectOptions::socket) or
+/// [Bulb::start_music_with](crate::Bulb::start_music_with), or [apply](SocketOptions::apply)
+/// them to a stream before [attaching](crate::Bulb::attach_tokio) it. The defaults leave the
+/// socket as opened by the OS.
+///
+/// # Example
+/// ```
+/// # async fn test() {
+/// # use yeelight::*;
+/// use std::time::Duration;
+///
+/// let socket = SocketOptions::default()
+///     .nodelay(true)
+///     .keepalive(Duration::from_secs(30));
+/// let options = ConnectOptions::default().socket(socket);
+/// let mut bulb = Bulb::connect_with("192.168.1.204", 0, &options).await.unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm (`TCP_NODELAY`), so each command is sent right away instead
+    /// of being delayed to be grouped with the next ones. Useful in music mode and for fast
+    /// flows.
+    pub nodelay: bool,
+    /// Enable `SO_KEEPALIVE`, probing the connection after it is idle for this long, so
+    /// connections to bulbs that disappeared are detected.
+    pub keepalive: Option<Duration>,
+    /// Size of the receive buffer (`SO_RCVBUF`), in bytes.
+    pub recv_buffer_size: Option<usize>,
+    /// Size of the send buffer (`SO_SNDBUF`), in bytes.
+    pub send_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Set these options on the given stream.
+    ///
+    /// The OS may adjust the buffer sizes (Linux doubles them, for instance).
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        let socket = SockRef::from(stream);
+        if let Some(idle) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
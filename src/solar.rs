@@ -0,0 +1,177 @@
+//! Sunrise, sunset and twilight times for a location.
+//!
+//! The times are computed with the [sunrise equation] and are accurate to a couple of
+//! minutes, which is enough to schedule lights.
+//!
+//! [sunrise equation]: https://en.wikipedia.org/wiki/Sunrise_equation
+//!
+//! # Example
+//! ```
+//! use yeelight::solar::{Date, Location};
+//!
+//! let barcelona = Location {
+//!     latitude: 41.39,
+//!     longitude: 2.17,
+//!     utc_offset: 120, // CEST
+//! };
+//! let sun = barcelona.sun_times(Date::new(2024, 6, 21).unwrap());
+//! println!("Sunset at {}", sun.sunset.unwrap());
+//! ```
+
+use std::f64::consts::PI;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::TimeOfDay;
+
+/// Julian day of 2000-01-01 12:00 UTC.
+const J2000: f64 = 2_451_545.0;
+/// Julian day of 1970-01-01 12:00 UTC.
+const UNIX_EPOCH_NOON: f64 = 2_440_588.0;
+
+/// Sun altitude at sunrise and sunset, accounting for refraction and the solar disc.
+const SUNRISE_ALTITUDE: f64 = -0.833;
+/// Sun altitude at the start and end of civil twilight.
+const CIVIL_TWILIGHT_ALTITUDE: f64 = -6.0;
+
+/// Coordinates and time zone where the bulbs are.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    /// Degrees north (negative for south).
+    pub latitude: f64,
+    /// Degrees east (negative for west).
+    pub longitude: f64,
+    /// Offset from UTC in minutes of the returned times.
+    #[serde(default)]
+    pub utc_offset: i32,
+}
+
+/// Calendar date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+}
+
+/// Sun events of a day in the local time of the [`Location`].
+///
+/// The events that do not happen (polar day or night) are `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SunTimes {
+    /// Start of civil twilight.
+    pub dawn: Option<TimeOfDay>,
+    pub sunrise: Option<TimeOfDay>,
+    pub solar_noon: TimeOfDay,
+    pub sunset: Option<TimeOfDay>,
+    /// End of civil twilight.
+    pub dusk: Option<TimeOfDay>,
+}
+
+impl Date {
+    pub fn new(year: i32, month: u8, day: u8) -> Option<Self> {
+        let date = Self { year, month, day };
+        let days_in_month = match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+            2 => 28,
+            _ => return None,
+        };
+        (1..=days_in_month).contains(&day).then_some(date)
+    }
+
+    /// Current date at the given offset from UTC (in minutes).
+    pub fn today(utc_offset: i32) -> Self {
+        Self::from_days(local_minutes(utc_offset).div_euclid(1440))
+    }
+
+    /// Days since 1970-01-01.
+    fn days(&self) -> i64 {
+        // https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+        let (month, day) = (self.month as i64, self.day as i64);
+        let year = self.year as i64 - (month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146_097 + doe - 719_468
+    }
+
+    fn from_days(days: i64) -> Self {
+        // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let days = days + 719_468;
+        let era = days.div_euclid(146_097);
+        let doe = days - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = (yoe + era * 400 + (month <= 2) as i64) as i32;
+        Self { year, month, day }
+    }
+}
+
+/// Minutes since 1970-01-01 00:00 in the local time at the given offset from UTC (in
+/// minutes), the clock of [Date::today] and [TimeOfDay::now].
+pub(crate) fn local_minutes(utc_offset: i32) -> i64 {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    seconds.div_euclid(60) + utc_offset as i64
+}
+
+impl Location {
+    /// Sun events for the given date.
+    pub fn sun_times(&self, date: Date) -> SunTimes {
+        let day = (date.days() as f64 + UNIX_EPOCH_NOON - J2000).round();
+        let mean_solar_time = day - self.longitude / 360.0;
+
+        let anomaly = (357.5291 + 0.985_600_28 * mean_solar_time).rem_euclid(360.0);
+        let m = anomaly.to_radians();
+        let center = 1.9148 * m.sin() + 0.02 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+        let longitude = (anomaly + center + 180.0 + 102.9372)
+            .rem_euclid(360.0)
+            .to_radians();
+
+        let transit = J2000 + mean_solar_time + 0.0053 * m.sin() - 0.0069 * (2.0 * longitude).sin();
+        let declination = (longitude.sin() * 23.4397_f64.to_radians().sin()).asin();
+        let latitude = self.latitude.to_radians();
+
+        // Julian days of the moments the sun is at the given altitude
+        let at_altitude = |altitude: f64| {
+            let cos_hour_angle = (altitude.to_radians().sin() - latitude.sin() * declination.sin())
+                / (latitude.cos() * declination.cos());
+            (-1.0..=1.0).contains(&cos_hour_angle).then(|| {
+                let offset = cos_hour_angle.acos() / (2.0 * PI);
+                (transit - offset, transit + offset)
+            })
+        };
+
+        let local = |julian_day: f64| {
+            let minutes = ((julian_day - 0.5).rem_euclid(1.0) * 1440.0).round() as i64;
+            let minutes = (minutes + self.utc_offset as i64).rem_euclid(1440);
+            TimeOfDay {
+                hour: (minutes / 60) as u8,
+                minute: (minutes % 60) as u8,
+            }
+        };
+
+        let sun = at_altitude(SUNRISE_ALTITUDE);
+        let twilight = at_altitude(CIVIL_TWILIGHT_ALTITUDE);
+        SunTimes {
+            dawn: twilight.map(|(rise, _)| local(rise)),
+            sunrise: sun.map(|(rise, _)| local(rise)),
+            solar_noon: local(transit),
+            sunset: sun.map(|(_, set)| local(set)),
+            dusk: twilight.map(|(_, set)| local(set)),
+        }
+    }
+
+    /// Sun events for the current date.
+    pub fn sun_times_today(&self) -> SunTimes {
+        self.sun_times(Date::today(self.utc_offset))
+    }
+}
@@ -0,0 +1,534 @@
+//! Snapshots of the bulb state that can be restored later.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Bulb, BulbError, ColorMode, Effect, Mode, Power, Properties, Property, Scene, ToParam, ToParams,
+};
+
+/// Properties of the main and background lights (power, bright, mode, ct, rgb, hue, sat).
+const MAIN_LIGHT: [Property; 7] = [
+    Property::Power,
+    Property::Bright,
+    Property::ColorMode,
+    Property::Ct,
+    Property::Rgb,
+    Property::Hue,
+    Property::Sat,
+];
+const BG_LIGHT: [Property; 7] = [
+    Property::BgPower,
+    Property::BgBright,
+    Property::BgColorMode,
+    Property::BgCt,
+    Property::BgRgb,
+    Property::BgHue,
+    Property::BgSat,
+];
+
+/// Properties saved in a [`StateSnapshot`]
+const SNAPSHOT_PROPERTIES: [Property; 16] = [
+    Property::Power,
+    Property::Bright,
+    Property::ColorMode,
+    Property::Ct,
+    Property::Rgb,
+    Property::Hue,
+    Property::Sat,
+    Property::ActiveMode,
+    Property::NightLightBright,
+    Property::BgPower,
+    Property::BgBright,
+    Property::BgColorMode,
+    Property::BgCt,
+    Property::BgRgb,
+    Property::BgHue,
+    Property::BgSat,
+];
+
+/// Raw property values of a bulb at a point in time.
+///
+/// Properties not supported by the bulb (empty values) are not stored. The snapshot serializes
+/// as a map from property name to value:
+///
+/// ```json
+/// {"bright":"50","color_mode":"2","ct":"2700","power":"on"}
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct StateSnapshot(pub BTreeMap<String, String>);
+
+impl StateSnapshot {
+    /// Value of the given property, `None` if it was not captured.
+    pub fn get(&self, property: Property) -> Option<&str> {
+        self.0.get(&property_name(property)).map(String::as_str)
+    }
+
+    /// Power state of the main light, `None` if it was not captured.
+    pub fn power(&self) -> Option<Power> {
+        self.get(Property::Power).and_then(Power::from_property_str)
+    }
+
+    /// Power state of the background light, `None` if it was not captured.
+    pub fn bg_power(&self) -> Option<Power> {
+        self.get(Property::BgPower)
+            .and_then(Power::from_property_str)
+    }
+
+    /// Properties with different values in this snapshot and `other`, sorted by name.
+    ///
+    /// All the captured properties are compared (see [StateSnapshot::drift] to compare only
+    /// the ones that are visible).
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() -> Result<(), yeelight::BulbError> {
+    /// # use yeelight::*;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// let before = bulb.snapshot().await?;
+    /// bulb.set_scene(Scene::Ct { ct: 2700, bright: 40 }).await?;
+    /// for change in before.diff(&bulb.snapshot().await?) {
+    ///     println!("{}", change); // e.g. bright: 100 -> 40
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn diff(&self, other: &StateSnapshot) -> Vec<PropertyChange> {
+        let names: BTreeSet<&String> = self.0.keys().chain(other.0.keys()).collect();
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let before = self.0.get(name);
+                let after = other.0.get(name);
+                (before != after).then(|| PropertyChange {
+                    property: name.clone(),
+                    before: before.cloned(),
+                    after: after.cloned(),
+                })
+            })
+            .collect()
+    }
+
+    /// Messages (method and params) needed to bring the bulb back to this state.
+    pub(crate) fn restore_commands(&self) -> Vec<(&'static str, Vec<serde_json::Value>)> {
+        let mut commands = Vec::new();
+
+        let main = match self.night_light() {
+            true => self.night_light_commands(),
+            false => self
+                .light_commands(MAIN_LIGHT, ("set_power", "set_scene"))
+                .into_iter()
+                .collect(),
+        };
+        let bg = self.light_commands(BG_LIGHT, ("bg_set_power", "bg_set_scene"));
+
+        commands.extend(main);
+        commands.extend(bg);
+        commands
+    }
+
+    /// The main light is on in night light mode (`active_mode` is 1).
+    fn night_light(&self) -> bool {
+        self.get(Property::ActiveMode) == Some("1") && self.get(Property::Power) == Some("on")
+    }
+
+    /// Turn the main light on in night light mode, with its brightness.
+    fn night_light_commands(&self) -> Vec<(&'static str, Vec<serde_json::Value>)> {
+        let params = vec![
+            Power::On.to_param(),
+            Effect::Sudden.to_param(),
+            Duration::ZERO.to_param(),
+            Mode::NightLight.to_param(),
+        ];
+        let bright = self.get(Property::NightLightBright);
+        let bright = bright.and_then(|v| v.parse::<u8>().ok()).map(|bright| {
+            let params = vec![
+                bright.to_param(),
+                Effect::Sudden.to_param(),
+                Duration::ZERO.to_param(),
+            ];
+            ("set_bright", params)
+        });
+        std::iter::once(("set_power", params))
+            .chain(bright)
+            .collect()
+    }
+
+    /// Properties that define the visible state: the ones [restore](Bulb::restore) would set.
+    ///
+    /// For instance, the color temperature does not matter if the light is off or in RGB mode.
+    pub(crate) fn relevant_properties(&self) -> Vec<Property> {
+        let mut properties = Vec::new();
+        if self.night_light() {
+            properties.extend([
+                Property::Power,
+                Property::ActiveMode,
+                Property::NightLightBright,
+            ]);
+        }
+        for [power, bright, mode, ct, rgb, hue, sat] in [MAIN_LIGHT, BG_LIGHT] {
+            if power == Property::Power && self.night_light() {
+                continue;
+            }
+            match self.get(power) {
+                None => continue,
+                Some("off") => properties.push(power),
+                Some(_) => {
+                    properties.extend([power, bright, mode]);
+                    match self.get(mode) {
+                        Some("1") => properties.push(rgb),
+                        Some("3") => properties.extend([hue, sat]),
+                        _ => properties.push(ct),
+                    }
+                }
+            }
+        }
+        properties
+    }
+
+    fn light_commands(
+        &self,
+        [power, bright, mode, ct, rgb, hue, sat]: [Property; 7],
+        (set_power, set_scene): (&'static str, &'static str),
+    ) -> Option<(&'static str, Vec<serde_json::Value>)> {
+        let number = |p| self.get(p).and_then(|v| v.parse::<u32>().ok());
+
+        match self.get(power)? {
+            "off" => {
+                let params = vec![
+                    Power::Off.to_param(),
+                    Effect::Sudden.to_param(),
+                    Duration::ZERO.to_param(),
+                    Mode::Normal.to_param(),
+                ];
+                Some((set_power, params))
+            }
+            _ => {
+                let bright = number(bright)? as u8;
+                let scene = match self.get(mode)? {
+                    "1" => Scene::Color {
+                        rgb: number(rgb)?,
+                        bright,
+                    },
+                    "3" => Scene::Hsv {
+                        hue: number(hue)? as u16,
+                        sat: number(sat)? as u8,
+                        bright,
+                    },
+                    _ => Scene::Ct {
+                        ct: number(ct)? as u16,
+                        bright,
+                    },
+                };
+                Some((set_scene, scene.to_params()))
+            }
+        }
+    }
+}
+
+/// Properties read by [`Bulb::state`].
+const STATE_PROPERTIES: [Property; 20] = [
+    Property::Power,
+    Property::Bright,
+    Property::ColorMode,
+    Property::Ct,
+    Property::Rgb,
+    Property::Hue,
+    Property::Sat,
+    Property::ActiveMode,
+    Property::NightLightBright,
+    Property::Flowing,
+    Property::DelayOff,
+    Property::MusicOn,
+    Property::Name,
+    Property::BgPower,
+    Property::BgBright,
+    Property::BgColorMode,
+    Property::BgCt,
+    Property::BgRgb,
+    Property::BgHue,
+    Property::BgSat,
+];
+
+/// Typed state of a bulb, returned by [`Bulb::state`].
+///
+/// The color values of the main light are `None` if the bulb does not support them (such as
+/// the color of white bulbs).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BulbState {
+    pub power: Power,
+    /// Brightness (`1` to `100`).
+    pub bright: u8,
+    pub color_mode: Option<ColorMode>,
+    /// Color temperature (K).
+    pub ct: Option<u16>,
+    /// Color in RGB format (`0x00_00_00` to `0xff_ff_ff`).
+    pub rgb: Option<u32>,
+    /// Hue (`0` to `359`).
+    pub hue: Option<u16>,
+    /// Saturation (`0` to `100`).
+    pub sat: Option<u8>,
+    /// The main light is in night light (moonlight) mode, `None` if the bulb has no night
+    /// light.
+    pub night_light: Option<bool>,
+    /// Brightness of the night light (`1` to `100`).
+    pub nl_bright: Option<u8>,
+    /// A color flow is running.
+    pub flowing: bool,
+    /// Minutes left until the light turns off (`0` if no timer is set).
+    pub delay_off: u16,
+    /// Music mode is on.
+    pub music_on: bool,
+    pub name: String,
+    /// State of the background light, `None` if the bulb has none.
+    pub bg: Option<LightState>,
+}
+
+/// Typed state of the background light of a bulb, see [BulbState].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LightState {
+    pub power: Power,
+    pub bright: u8,
+    pub color_mode: Option<ColorMode>,
+    pub ct: Option<u16>,
+    pub rgb: Option<u32>,
+    pub hue: Option<u16>,
+    pub sat: Option<u8>,
+}
+
+impl BulbState {
+    /// Parse the properties of a snapshot (such as the [cached state](Bulb::cached_state)).
+    ///
+    /// `None` if the power or the brightness of the main light are missing.
+    pub fn from_snapshot(snapshot: &StateSnapshot) -> Option<Self> {
+        let flag = |property| snapshot.get(property) == Some("1");
+        let light = LightState::from_snapshot(snapshot, MAIN_LIGHT)?;
+        Some(Self {
+            power: light.power,
+            bright: light.bright,
+            color_mode: light.color_mode,
+            ct: light.ct,
+            rgb: light.rgb,
+            hue: light.hue,
+            sat: light.sat,
+            night_light: snapshot.get(Property::ActiveMode).map(|mode| mode == "1"),
+            nl_bright: number(snapshot, Property::NightLightBright),
+            flowing: flag(Property::Flowing),
+            delay_off: number(snapshot, Property::DelayOff).unwrap_or_default(),
+            music_on: flag(Property::MusicOn),
+            name: snapshot.get(Property::Name).unwrap_or_default().to_string(),
+            bg: LightState::from_snapshot(snapshot, BG_LIGHT),
+        })
+    }
+}
+
+impl LightState {
+    fn from_snapshot(
+        snapshot: &StateSnapshot,
+        [power, bright, mode, ct, rgb, hue, sat]: [Property; 7],
+    ) -> Option<Self> {
+        Some(Self {
+            power: Power::from_property_str(snapshot.get(power)?)?,
+            bright: number(snapshot, bright)?,
+            color_mode: snapshot.get(mode).and_then(ColorMode::from_property_str),
+            ct: number(snapshot, ct),
+            rgb: number(snapshot, rgb),
+            hue: number(snapshot, hue),
+            sat: number(snapshot, sat),
+        })
+    }
+}
+
+/// Parsed value of `property`, `None` if it is missing or invalid.
+fn number<T: std::str::FromStr>(snapshot: &StateSnapshot, property: Property) -> Option<T> {
+    snapshot.get(property)?.parse().ok()
+}
+
+/// Property with a different value in two snapshots, see [StateSnapshot::diff].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PropertyChange {
+    /// Name of the property (as used by the protocol, such as `bg_power`).
+    pub property: String,
+    /// Value in the first snapshot, `None` if it was not captured.
+    pub before: Option<String>,
+    /// Value in the second snapshot, `None` if it was not captured.
+    pub after: Option<String>,
+}
+
+impl fmt::Display for PropertyChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.property,
+            self.before.as_deref().unwrap_or("unknown"),
+            self.after.as_deref().unwrap_or("unknown")
+        )
+    }
+}
+
+/// Name of the property as used by the protocol.
+pub(crate) fn property_name(property: Property) -> String {
+    property.to_string().trim_matches('"').to_string()
+}
+
+/// Guard returned by [`Bulb::scoped_state`].
+///
+/// Gives access to the [`Bulb`] and restores the saved state when dropped (or when
+/// [`StateGuard::restore`] is called).
+pub struct StateGuard<'a> {
+    bulb: &'a mut Bulb,
+    snapshot: StateSnapshot,
+    restored: bool,
+}
+
+impl StateGuard<'_> {
+    /// State that will be restored.
+    pub fn snapshot(&self) -> &StateSnapshot {
+        &self.snapshot
+    }
+
+    /// Restore the saved state waiting for the responses of the bulb.
+    pub async fn restore(mut self) -> Result<(), BulbError> {
+        self.restored = true;
+        self.bulb.restore(&self.snapshot).await
+    }
+
+    /// Drop the guard without restoring the state.
+    pub fn forget(mut self) {
+        self.restored = true;
+    }
+}
+
+impl Deref for StateGuard<'_> {
+    type Target = Bulb;
+
+    fn deref(&self) -> &Bulb {
+        self.bulb
+    }
+}
+
+impl DerefMut for StateGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Bulb {
+        self.bulb
+    }
+}
+
+impl Drop for StateGuard<'_> {
+    fn drop(&mut self) {
+        if self.restored {
+            return;
+        }
+
+        // We can not await in drop, so the commands are written without waiting for the
+        // responses.
+        for (method, params) in self.snapshot.restore_commands() {
+            if let Err(e) = self.bulb.writer.send_detached(method, &params) {
+                log::error!("Could not restore state on drop: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+impl Bulb {
+    /// Read the current state of the bulb.
+    ///
+    /// If the connection is in [no_response](Bulb::no_response) mode the state can not be read
+    /// and the snapshot will be empty.
+    pub async fn snapshot(&mut self) -> Result<StateSnapshot, BulbError> {
+        let values = self
+            .read_prop(&Properties(SNAPSHOT_PROPERTIES.to_vec()))
+            .await?
+            .unwrap_or_default();
+
+        Ok(StateSnapshot(
+            SNAPSHOT_PROPERTIES
+                .iter()
+                .zip(values)
+                .filter(|(_, value)| !value.is_empty())
+                .map(|(property, value)| (property_name(*property), value))
+                .collect(),
+        ))
+    }
+
+    /// Read the state of the main and background lights with typed values.
+    ///
+    /// Fails if the state can not be read (for example, in [no_response](Bulb::no_response)
+    /// mode).
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() -> Result<(), yeelight::BulbError> {
+    /// # use yeelight::*;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// let state = bulb.state().await?;
+    /// if state.power == Power::On && state.color_mode == Some(ColorMode::Ct) {
+    ///     println!("{}K at {}%", state.ct.unwrap_or_default(), state.bright);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn state(&mut self) -> Result<BulbState, BulbError> {
+        let values = self
+            .read_prop(&Properties(STATE_PROPERTIES.to_vec()))
+            .await?
+            .unwrap_or_default();
+        let snapshot = StateSnapshot(
+            STATE_PROPERTIES
+                .iter()
+                .zip(values)
+                .filter(|(_, value)| !value.is_empty())
+                .map(|(property, value)| (property_name(*property), value))
+                .collect(),
+        );
+        BulbState::from_snapshot(&snapshot).ok_or_else(|| {
+            let message = "The bulb did not report its power and brightness";
+            std::io::Error::new(std::io::ErrorKind::InvalidData, message).into()
+        })
+    }
+
+    /// Bring the bulb back to the state saved in `snapshot`.
+    pub async fn restore(&mut self, snapshot: &StateSnapshot) -> Result<(), BulbError> {
+        for (method, params) in snapshot.restore_commands() {
+            self.writer.send(method, &params).await?;
+        }
+        Ok(())
+    }
+
+    /// Save the current state and return a guard that restores it when dropped.
+    ///
+    /// The guard can be used as the [`Bulb`] itself. This is useful for temporary effects such
+    /// as notifications, the original state is restored even on early returns.
+    ///
+    /// When the guard is dropped the commands are sent without waiting for a response, use
+    /// [`StateGuard::restore`] to restore and check for errors.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() -> Result<(), yeelight::BulbError> {
+    /// # use yeelight::*;
+    /// # use std::time::Duration;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// {
+    ///     let mut guard = bulb.scoped_state().await?;
+    ///     guard.set_rgb(0xff_00_00, Effect::Sudden, Duration::ZERO).await?;
+    ///     tokio::time::sleep(Duration::from_secs(1)).await;
+    /// } // previous state restored here
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn scoped_state(&mut self) -> Result<StateGuard<'_>, BulbError> {
+        let snapshot = self.snapshot().await?;
+        Ok(StateGuard {
+            bulb: self,
+            snapshot,
+            restored: false,
+        })
+    }
+}
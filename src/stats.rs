@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Number of latency samples kept to compute the percentiles.
+const LATENCY_WINDOW: usize = 100;
+
+/// Window of [Stats::commands_last_minute] and [Stats::notifications_last_minute].
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Commands allowed per minute on each connection by the bulbs.
+pub const COMMAND_QUOTA: u32 = 60;
+
+/// Connection statistics since the [`Bulb`](crate::Bulb) was connected.
+///
+/// Obtained with [`Bulb::stats`](crate::Bulb::stats).
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// Time since the connection was established.
+    pub uptime: Duration,
+    /// Time since the connection was last established (equal to `uptime` if it never
+    /// [reconnected](crate::ConnectOptions::reconnect)).
+    pub connected_for: Duration,
+    /// Number of times the connection was lost and opened again.
+    pub reconnects: u64,
+    /// Number of commands sent.
+    pub commands: u64,
+    /// Number of commands that returned an error (bulb error response, connection error or
+    /// timeout).
+    pub errors: u64,
+    /// Number of notifications received.
+    pub notifications: u64,
+    /// Number of notifications discarded because nobody was subscribed (see
+    /// [Bulb::get_notify](crate::Bulb::get_notify)) or the receiver was dropped.
+    ///
+    /// They are still used to update the [cached state](crate::Bulb::watch).
+    pub dropped_notifications: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Commands sent in the last minute, to estimate the usage of the [COMMAND_QUOTA].
+    pub commands_last_minute: u32,
+    /// Notifications received in the last minute.
+    pub notifications_last_minute: u32,
+    /// Latency percentiles (50, 90 and 99) of the last 100 responses.
+    ///
+    /// `None` if no response was received yet (or the connection is in
+    /// [no_response](crate::Bulb::no_response) mode).
+    pub latency: Option<Latency>,
+}
+
+/// Response latency percentiles.
+#[derive(Debug, Clone, Copy)]
+pub struct Latency {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Latency {
+    /// Percentiles of the given samples, `None` if there are none.
+    pub(crate) fn from_samples(samples: &mut [Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort();
+        let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+        Some(Latency {
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: percentile(100),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    since: Instant,
+    connected_since: Instant,
+    reconnects: u64,
+    commands: u64,
+    errors: u64,
+    notifications: u64,
+    dropped_notifications: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    latencies: VecDeque<Duration>,
+    /// Times of the commands and notifications in the last [RATE_WINDOW].
+    recent_commands: VecDeque<Instant>,
+    recent_notifications: VecDeque<Instant>,
+}
+
+/// Shared statistics updated by the reader and the writer.
+#[derive(Debug, Clone)]
+pub struct StatsCollector(Arc<Mutex<Inner>>);
+
+impl StatsCollector {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self(Arc::new(Mutex::new(Inner {
+            since: now,
+            connected_since: now,
+            reconnects: 0,
+            commands: 0,
+            errors: 0,
+            notifications: 0,
+            dropped_notifications: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            latencies: VecDeque::with_capacity(LATENCY_WINDOW),
+            recent_commands: VecDeque::new(),
+            recent_notifications: VecDeque::new(),
+        })))
+    }
+
+    fn inner(&self) -> ::std::sync::MutexGuard<'_, Inner> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        let mut inner = self.inner();
+        inner.commands += 1;
+        inner.bytes_sent += bytes as u64;
+        record(&mut inner.recent_commands);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.inner().bytes_received += bytes as u64;
+    }
+
+    pub fn notification(&self) {
+        let mut inner = self.inner();
+        inner.notifications += 1;
+        record(&mut inner.recent_notifications);
+    }
+
+    pub fn reconnected(&self) {
+        let mut inner = self.inner();
+        inner.reconnects += 1;
+        inner.connected_since = Instant::now();
+    }
+
+    pub fn dropped_notification(&self) {
+        self.inner().dropped_notifications += 1;
+    }
+
+    pub fn error(&self) {
+        self.inner().errors += 1;
+    }
+
+    pub fn latency(&self, latency: Duration) {
+        let mut inner = self.inner();
+        if inner.latencies.len() == LATENCY_WINDOW {
+            inner.latencies.pop_front();
+        }
+        inner.latencies.push_back(latency);
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        let mut inner = self.inner();
+        expire(&mut inner.recent_commands);
+        expire(&mut inner.recent_notifications);
+
+        let mut latencies: Vec<Duration> = inner.latencies.iter().copied().collect();
+        let latency = Latency::from_samples(&mut latencies);
+
+        Stats {
+            uptime: inner.since.elapsed(),
+            connected_for: inner.connected_since.elapsed(),
+            reconnects: inner.reconnects,
+            commands: inner.commands,
+            errors: inner.errors,
+            notifications: inner.notifications,
+            dropped_notifications: inner.dropped_notifications,
+            bytes_sent: inner.bytes_sent,
+            bytes_received: inner.bytes_received,
+            commands_last_minute: inner.recent_commands.len() as u32,
+            notifications_last_minute: inner.recent_notifications.len() as u32,
+            latency,
+        }
+    }
+}
+
+/// Add an event happening now to `times`, forgetting the ones out of the [RATE_WINDOW].
+fn record(times: &mut VecDeque<Instant>) {
+    expire(times);
+    times.push_back(Instant::now());
+}
+
+fn expire(times: &mut VecDeque<Instant>) {
+    while times
+        .front()
+        .is_some_and(|time| time.elapsed() > RATE_WINDOW)
+    {
+        times.pop_front();
+    }
+}
+
+/// Human-readable report to troubleshoot the connection.
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = |duration: Duration| Duration::from_secs(duration.as_secs());
+        writeln!(f, "Connected for:   {:?}", seconds(self.connected_for))?;
+        writeln!(
+            f,
+            "Reconnects:      {} (first connected {:?} ago)",
+            self.reconnects,
+            seconds(self.uptime)
+        )?;
+        writeln!(
+            f,
+            "Commands:        {} sent, {} failed",
+            self.commands, self.errors
+        )?;
+        writeln!(
+            f,
+            "Quota usage:     {}/{} commands in the last minute{}",
+            self.commands_last_minute,
+            COMMAND_QUOTA,
+            match self.commands_last_minute >= COMMAND_QUOTA {
+                true => " (commands may be rejected)",
+                false => "",
+            }
+        )?;
+        writeln!(
+            f,
+            "Notifications:   {} received ({} in the last minute), {} dropped",
+            self.notifications, self.notifications_last_minute, self.dropped_notifications
+        )?;
+        writeln!(
+            f,
+            "Traffic:         {} bytes sent, {} bytes received",
+            self.bytes_sent, self.bytes_received
+        )?;
+        match &self.latency {
+            Some(l) => write!(
+                f,
+                "Latency:         p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+                l.p50, l.p90, l.p99, l.max
+            ),
+            None => write!(f, "Latency:         no responses yet"),
+        }
+    }
+}
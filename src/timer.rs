@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::{Bulb, BulbError, Power, Properties, Property};
+
+/// Power and sleep timer of a bulb, see [`Bulb::time_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeStatus {
+    /// The light is on.
+    pub on: bool,
+    /// Time left until the light turns off, as reported by the bulb (`None` if there is no
+    /// timer).
+    pub delay_off: Option<Duration>,
+    /// When the status was read.
+    pub read_at: Instant,
+}
+
+impl TimeStatus {
+    /// Estimated time left until the timer turns off the light, accounting for the time
+    /// elapsed since the status was read.
+    ///
+    /// Bulbs report the timer in whole minutes, so this can be up to a minute early.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.delay_off
+            .map(|delay| delay.saturating_sub(self.read_at.elapsed()))
+    }
+
+    /// Estimated moment the light turns off.
+    pub fn turns_off_at(&self) -> Option<Instant> {
+        self.delay_off.map(|delay| self.read_at + delay)
+    }
+}
+
+impl Bulb {
+    /// Time left on the sleep timer (set with [Bulb::cron_add] or
+    /// [Scene::AutoDelayOff](crate::Scene::AutoDelayOff)), `None` if there is no timer.
+    ///
+    /// Also `None` in [no_response](Bulb::no_response) mode.
+    pub async fn delay_off(&mut self) -> Result<Option<Duration>, BulbError> {
+        Ok(self
+            .time_status()
+            .await?
+            .and_then(|status| status.delay_off))
+    }
+
+    /// Read the power and sleep timer of the bulb in a single request, so UIs can show
+    /// "turns off in 12 min".
+    ///
+    /// Returns `None` in [no_response](Bulb::no_response) mode.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn test() {
+    /// # use yeelight::*;
+    /// let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+    /// if let Some(status) = bulb.time_status().await.unwrap() {
+    ///     if let Some(remaining) = status.remaining() {
+    ///         println!("Turns off in {} min", remaining.as_secs().div_ceil(60));
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub async fn time_status(&mut self) -> Result<Option<TimeStatus>, BulbError> {
+        let props = Properties(vec![Property::Power, Property::DelayOff]);
+        let Some(values) = self.get_prop(&props).await? else {
+            return Ok(None);
+        };
+        let value = |i: usize| values.get(i).map(String::as_str).unwrap_or_default();
+
+        let minutes = value(1).parse::<u64>().unwrap_or_default();
+        Ok(Some(TimeStatus {
+            on: Power::from_property_str(value(0)) == Some(Power::On),
+            delay_off: (minutes > 0).then(|| Duration::from_secs(minutes * 60)),
+            read_at: Instant::now(),
+        }))
+    }
+}
@@ -0,0 +1,173 @@
+//! Transitions longer than the bulbs accept in a single command.
+//!
+//! # Example
+//! ```
+//! # async fn test() {
+//! # use yeelight::Bulb;
+//! # use std::time::Duration;
+//! use yeelight::transition::{long_fade, FadeTarget};
+//!
+//! let mut bulb = Bulb::connect("192.168.1.204", 0).await.unwrap();
+//! // 30 minute sunrise
+//! let target = FadeTarget::Ct { ct: 4000, bright: 100 };
+//! long_fade(&mut bulb, target, Duration::from_secs(30 * 60)).await.unwrap();
+//! # }
+//! ```
+
+use std::io;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::helpers::{hsv_to_rgb, MIN_DURATION};
+use crate::model::MAX_FLOW_TUPLES;
+use crate::{
+    Bulb, BulbError, CfAction, FlowExpresion, FlowTuple, Properties, Property, Response, Scene,
+};
+
+/// Longest smooth change sent in a single flow tuple.
+///
+/// Firmwares clamp long durations, so longer transitions are split in segments of at most
+/// this duration.
+pub const MAX_SEGMENT: Duration = Duration::from_secs(60);
+
+/// Maximum number of segments of a transition (leaving room for the initial tuple).
+const MAX_SEGMENTS: u32 = MAX_FLOW_TUPLES as u32 - 1;
+
+/// State at the end of a [`long_fade`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FadeTarget {
+    /// Brightness (`1` to `100`), keeping the current color.
+    Bright(u8),
+    /// Color temperature (`1700` to `6500`) and brightness (`1` to `100`).
+    Ct { ct: u16, bright: u8 },
+    /// Color in RGB format (`0x00_00_00` to `0xff_ff_ff`) and brightness (`1` to `100`).
+    Rgb { rgb: u32, bright: u8 },
+}
+
+/// Color of the light during the transition.
+#[derive(Debug, Clone, Copy)]
+enum Color {
+    Ct(u16),
+    Rgb(u32),
+}
+
+/// Gradually change the light to `target` over `duration`.
+///
+/// The change is split in segments of at most [MAX_SEGMENT], interpolating the brightness
+/// and color linearly, and sent as a single color flow, so it only uses one command of the
+/// quota. The light is turned on if it was off, starting from 1% brightness (useful for
+/// sunrise alarms). The flow can be interrupted with [Bulb::stop_cf] or any other command.
+///
+/// The current state is read with [Bulb::get_prop]. In [no_response](Bulb::no_response)
+/// mode it can not be read, so the fade starts from 1% brightness with the target color
+/// ([FadeTarget::Bright] targets fail in that case).
+///
+/// Transitions are limited to 254 segments (more than 4 hours).
+pub async fn long_fade(
+    bulb: &mut Bulb,
+    target: FadeTarget,
+    duration: Duration,
+) -> Result<Option<Response>, BulbError> {
+    let segments = duration
+        .as_millis()
+        .div_ceil(MAX_SEGMENT.as_millis())
+        .max(1) as u32;
+    if segments > MAX_SEGMENTS {
+        return Err(invalid_input("Transition too long"));
+    }
+
+    let props = Properties(vec![
+        Property::Power,
+        Property::Bright,
+        Property::ColorMode,
+        Property::Ct,
+        Property::Rgb,
+        Property::Hue,
+        Property::Sat,
+    ]);
+    let values = bulb.get_prop(&props).await?;
+    let value = |i: usize| {
+        values
+            .as_ref()
+            .and_then(|v| v.get(i))
+            .map(String::as_str)
+            .unwrap_or_default()
+    };
+    let number = |i: usize| value(i).parse::<u32>().unwrap_or_default();
+
+    let on = value(0) == "on";
+    let start_bright = match on {
+        true => number(1).clamp(1, 100) as u8,
+        false => 1,
+    };
+    let start_color = match value(2) {
+        "1" => Color::Rgb(number(4)),
+        "2" => Color::Ct(number(3) as u16),
+        "3" => Color::Rgb(hsv_to_rgb(number(5), number(6))),
+        _ => Color::Ct(number(3) as u16),
+    };
+
+    let (end_bright, end_color) = match target {
+        FadeTarget::Bright(bright) if values.is_some() => (bright, start_color),
+        FadeTarget::Bright(_) => {
+            return Err(invalid_input("The current color is needed to fade"));
+        }
+        FadeTarget::Ct { ct, bright } => (bright, Color::Ct(ct)),
+        FadeTarget::Rgb { rgb, bright } => (bright, Color::Rgb(rgb)),
+    };
+    // Without a known start color (or changing between ct and rgb) the color is not faded
+    let start_color = match (values.is_some(), start_color, end_color) {
+        (true, Color::Ct(ct), Color::Ct(_)) if ct > 0 => Color::Ct(ct),
+        (true, Color::Rgb(rgb), Color::Rgb(_)) => Color::Rgb(rgb),
+        _ => end_color,
+    };
+
+    let mut tuples = Vec::with_capacity(segments as usize + 1);
+    if !on || values.is_none() {
+        tuples.push(tuple(MIN_DURATION, start_color, start_bright));
+    }
+    let segment = duration / segments;
+    for i in 1..=segments {
+        let fraction = i as f64 / segments as f64;
+        let bright = lerp(start_bright as f64, end_bright as f64, fraction) as u8;
+        let color = match (start_color, end_color) {
+            (Color::Ct(from), Color::Ct(to)) => {
+                Color::Ct(lerp(from as f64, to as f64, fraction) as u16)
+            }
+            (Color::Rgb(from), Color::Rgb(to)) => Color::Rgb(lerp_rgb(from, to, fraction)),
+            (_, to) => to,
+        };
+        tuples.push(tuple(segment.max(MIN_DURATION), color, bright));
+    }
+
+    let scene = Scene::Cf {
+        count: tuples.len() as u8,
+        action: CfAction::Stay,
+        flow: FlowExpresion(tuples),
+    };
+    bulb.set_scene(scene).await
+}
+
+fn tuple(duration: Duration, color: Color, bright: u8) -> FlowTuple {
+    let bright = bright.clamp(1, 100) as i8;
+    match color {
+        Color::Ct(ct) => FlowTuple::ct(duration, ct as u32, bright),
+        Color::Rgb(rgb) => FlowTuple::rgb(duration, rgb, bright),
+    }
+}
+
+fn lerp(from: f64, to: f64, fraction: f64) -> f64 {
+    (from + (to - from) * fraction).round()
+}
+
+fn lerp_rgb(from: u32, to: u32, fraction: f64) -> u32 {
+    [16, 8, 0].iter().fold(0, |rgb, shift| {
+        let channel = |c: u32| ((c >> shift) & 0xff) as f64;
+        rgb | (lerp(channel(from), channel(to), fraction) as u32) << shift
+    })
+}
+
+fn invalid_input(message: &str) -> BulbError {
+    BulbError::Io(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
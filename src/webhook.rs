@@ -0,0 +1,145 @@
+//! Forward state changes to webhooks (requires the `webhooks` feature).
+//!
+//! Each [`Webhook`] watches a property and POSTs a JSON payload to its URL when a notification
+//! changes it (optionally only when it changes to a given value). The configuration can be
+//! loaded from YAML:
+//!
+//! ```yaml
+//! - url: https://ntfy.sh/my-lights
+//!   property: power
+//!   value: "on"
+//!   template: '{"message": "{{bulb}} turned {{value}}"}'
+//! - url: https://example.com/hook
+//!   property: bright
+//! ```
+//!
+//! Templates can use the following placeholders, which are replaced with JSON escaped strings
+//! (without the quotes):
+//!
+//! - `{{bulb}}`: name of the bulb in the [`BulbGroup`].
+//! - `{{property}}`: name of the property that changed.
+//! - `{{value}}`: new value of the property.
+//! - `{{props}}`: all the properties of the notification as a JSON object (this one is not
+//!   escaped, so it should not be quoted).
+
+use std::error::Error;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{BulbGroup, Notification};
+
+/// Template used when none is configured.
+pub const DEFAULT_TEMPLATE: &str =
+    r#"{"bulb":"{{bulb}}","property":"{{property}}","value":"{{value}}"}"#;
+
+/// Timeout of the webhook requests.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Webhook triggered by the change of a property.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    /// Name of the property, as sent by the bulb (e.g. `power` or `bg_bright`).
+    pub property: String,
+    /// Only trigger when the property changes to this value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// Payload template, see the [module documentation](self).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+}
+
+impl Webhook {
+    /// Payload to send for the notification, `None` if it does not trigger this webhook.
+    pub fn render(&self, bulb: &str, notification: &Notification) -> Option<String> {
+        let props = notification.props()?;
+        let value = match props.get(&self.property)? {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        if self.value.as_ref().is_some_and(|v| *v != value) {
+            return None;
+        }
+
+        let props = serde_json::Value::Object(props.clone()).to_string();
+        let payload = self
+            .template
+            .as_deref()
+            .unwrap_or(DEFAULT_TEMPLATE)
+            .replace("{{bulb}}", &escape(bulb))
+            .replace("{{property}}", &escape(&self.property))
+            .replace("{{value}}", &escape(&value))
+            .replace("{{props}}", &props);
+        Some(payload)
+    }
+}
+
+/// Escape the string to be used inside a JSON string.
+fn escape(s: &str) -> String {
+    let quoted = serde_json::Value::from(s).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Set of webhooks that forward the notifications of a group.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Webhooks(pub Vec<Webhook>);
+
+impl Webhooks {
+    /// Parse a list of webhooks from YAML.
+    pub fn from_yaml(yaml: &str) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+
+    /// URL and payload of each webhook triggered by the notification.
+    pub fn triggered(&self, bulb: &str, notification: &Notification) -> Vec<(String, String)> {
+        self.0
+            .iter()
+            .filter_map(|hook| Some((hook.url.clone(), hook.render(bulb, notification)?)))
+            .collect()
+    }
+
+    /// POST the payload of each webhook triggered by the notification.
+    ///
+    /// All the requests are sent even if some fail, the errors are returned with their URL.
+    pub async fn forward(
+        &self,
+        bulb: &str,
+        notification: &Notification,
+    ) -> Vec<(String, Box<dyn Error + Send + Sync>)> {
+        let mut errors = Vec::new();
+        for (url, payload) in self.triggered(bulb, notification) {
+            if let Err(e) = post(url.clone(), payload).await {
+                errors.push((url, e));
+            }
+        }
+        errors
+    }
+
+    /// Forward the notifications of all the members of the group.
+    ///
+    /// This only returns when the notification channels of all the members are closed. The
+    /// notifications are taken with [BulbGroup::notifications]. Failed requests are logged.
+    pub async fn run(&self, group: &mut BulbGroup) {
+        let mut notifications = group.notifications().await;
+        while let Some((bulb, notification)) = notifications.recv().await {
+            for (url, e) in self.forward(&bulb, &notification).await {
+                log::error!("Webhook {} failed: {}", url, e);
+            }
+        }
+    }
+}
+
+async fn post(url: String, payload: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+    log::info!("webhook -> {} {}", url, payload);
+    tokio::task::spawn_blocking(move || {
+        ureq::post(&url)
+            .timeout(TIMEOUT)
+            .set("Content-Type", "application/json")
+            .send_string(&payload)
+            .map(drop)
+            .map_err(Into::into)
+    })
+    .await?
+}
@@ -1,73 +1,836 @@
-use crate::reader::{BulbError, RespChan, Response};
+use crate::cache::SharedCache;
+use crate::journal::{Journal, Replay};
+use crate::music::FrameCap;
+use crate::protocol::{self, RawResponse, Request, Response};
+use crate::rate::{self, QuotaLimiter, RateLimit, SharedQuota};
+use crate::reader::{BulbError, RespChan, Responder, SlotRef};
+use crate::shutdown;
+use crate::stats::StatsCollector;
 
-use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::sync::oneshot::channel;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use serde_json::Value;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot::{self, channel};
+use tokio::sync::{mpsc, watch};
+
+/// Write coalescing settings, see [`Bulb::coalesce_writes`](crate::Bulb::coalesce_writes).
+///
+/// Messages are buffered and written together when the buffer reaches `max_bytes` or when
+/// `max_delay` has elapsed since the first buffered message.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteCoalescing {
+    pub max_delay: Duration,
+    pub max_bytes: usize,
+}
+
+impl Default for WriteCoalescing {
+    fn default() -> Self {
+        Self {
+            max_delay: Duration::from_millis(5),
+            max_bytes: 1024,
+        }
+    }
+}
+
+/// Priority of a message, see [`Bulb::priority`](crate::Bulb::priority).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    #[default]
+    Normal,
+    /// Written before any message waiting to be written, which are discarded.
+    High,
+}
+
+impl Priority {
+    /// Default priority of a message: turning lights off and stopping flows is
+    /// [High](Priority::High).
+    fn of(method: &str, params: &[Value]) -> Self {
+        match method {
+            "stop_cf" | "bg_stop_cf" => Priority::High,
+            "set_power" | "bg_set_power"
+                if params.first().and_then(Value::as_str) == Some("off") =>
+            {
+                Priority::High
+            }
+            _ => Priority::Normal,
+        }
+    }
+}
+
+/// Write half of the connection.
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Handle sending messages to the writing task of the connection.
+///
+/// Clones share the connection, the message ids and the write coalescing settings, while
+/// the other settings (such as waiting for responses) are set for each handle.
+#[derive(Clone)]
 pub struct Writer {
-    writer: OwnedWriteHalf,
-    counter: u64,
+    frames: mpsc::UnboundedSender<Frame>,
+    urgent: mpsc::UnboundedSender<Message>,
+    /// Write coalescing is enabled (messages are only preempted when coalescing).
+    coalescing: Arc<AtomicBool>,
+    /// Id of the last message.
+    counter: Arc<AtomicU64>,
     resp_chan: RespChan,
     get_response: bool,
+    stats: StatsCollector,
+    /// Priority of the next message.
+    priority: Priority,
+    /// Incremented after each high priority message. Frames of previous epochs still waiting
+    /// to be written are discarded.
+    epoch: Arc<AtomicU64>,
+    /// Messages sent to the writing task and not written yet.
+    queued: Arc<AtomicUsize>,
+    /// Journal recording the commands sent, with the name of the bulb.
+    journal: Option<(Journal, String)>,
+    /// Journal replayed when reconnecting.
+    replay: Replay,
+    /// State of the bulb, whose values changed by the commands sent are marked stale.
+    cache: SharedCache,
+    /// Budget waited for before sending each command.
+    rate_limit: Option<RateLimit>,
+    /// Frames per second of a music mode connection.
+    frame_cap: Option<FrameCap>,
+    /// Command quota of the connection.
+    quota: SharedQuota,
+    /// Maximum time to wait for each response.
+    timeout: Option<Duration>,
+    /// Number of reconnections, if the connection is opened again when lost.
+    reconnected: Option<watch::Receiver<u64>>,
+}
+
+enum Frame {
+    Data(Message),
+    Flush(oneshot::Sender<io::Result<()>>),
+    /// Write the buffered messages, shut down the write half and stop.
+    Shutdown(oneshot::Sender<io::Result<()>>),
+    Config(WriteCoalescing),
 }
 
-struct Message(u64, String);
+/// Message waiting to be written by the writing task.
+struct Message {
+    epoch: u64,
+    data: Vec<u8>,
+    reply: Reply,
+}
+
+/// What to do once a message has been written.
+enum Reply {
+    /// Nothing, nobody is waiting for the message.
+    None,
+    /// Send the result of the write.
+    Written(oneshot::Sender<Result<(), BulbError>>),
+    /// Register the sender of the response to the request with the given id (or send the
+    /// error if the write failed).
+    Response(u64, Responder),
+}
+
+impl Reply {
+    /// Answer a message that is not written with `error`.
+    fn fail(self, error: impl Fn() -> BulbError) {
+        match self {
+            Reply::None => {}
+            Reply::Written(sender) => {
+                let _ = sender.send(Err(error()));
+            }
+            Reply::Response(_, Responder::Channel(sender)) => {
+                let _ = sender.send(Err(error()));
+            }
+            Reply::Response(_, Responder::Slot(slot)) => slot.fail(error()),
+        }
+    }
+}
+
+/// Receiving end of a [Reply].
+enum Waiting {
+    None,
+    Written(oneshot::Receiver<Result<(), BulbError>>),
+    Response(oneshot::Receiver<Result<RawResponse, BulbError>>),
+    Slot(SlotRef),
+}
 
 impl Writer {
-    pub fn new(writer: OwnedWriteHalf, resp_chan: RespChan) -> Self {
-        Self {
+    /// Writer of the messages to `writer`.
+    ///
+    /// Messages are written by a separate task, so a message is never partially written
+    /// when the future sending it is cancelled. Nothing is written until `reader_ready`
+    /// resolves (or is dropped), so the responses are always read by a running reader.
+    ///
+    /// When the connection is opened again, the new write half is received from `halves`
+    /// and `reconnected` is incremented, so the requests that failed are sent again.
+    pub fn new(
+        writer: WriteHalf,
+        resp_chan: RespChan,
+        stats: StatsCollector,
+        cache: SharedCache,
+        reader_ready: oneshot::Receiver<()>,
+        halves: mpsc::UnboundedReceiver<WriteHalf>,
+        reconnected: Option<watch::Receiver<u64>>,
+    ) -> Self {
+        let (frames, receiver) = mpsc::unbounded_channel();
+        let (urgent, urgent_receiver) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let buffer = Buffer::new(queued.clone());
+        let counter = Arc::new(AtomicU64::new(0));
+        shutdown::spawn_connection(write_loop(
             writer,
-            counter: 0,
+            reader_ready,
+            halves,
+            receiver,
+            urgent_receiver,
+            buffer,
+            resp_chan.clone(),
+        ));
+
+        Self {
+            frames,
+            urgent,
+            coalescing: Arc::new(AtomicBool::new(false)),
+            replay: Replay::new(counter.clone()),
+            counter,
             resp_chan,
             get_response: true,
+            stats,
+            priority: Priority::Normal,
+            epoch: Arc::new(AtomicU64::new(0)),
+            queued,
+            journal: None,
+            cache,
+            rate_limit: None,
+            frame_cap: None,
+            quota: SharedQuota::default(),
+            timeout: None,
+            reconnected,
         }
     }
 
+    /// Requests sent and waiting for a response.
+    pub async fn pending(&self) -> usize {
+        self.resp_chan.len().await
+    }
+
+    /// Messages sent to the writing task and not written yet.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> &StatsCollector {
+        &self.stats
+    }
+
+    fn coalescing(&self) -> bool {
+        self.coalescing.load(Ordering::Relaxed)
+    }
+
     fn get_message_id(&mut self) -> u64 {
-        self.counter += 1;
-        self.counter
+        self.counter.fetch_add(1, Ordering::Relaxed) + 1
     }
 
     pub fn set_get_response(&mut self, get_response: bool) {
         self.get_response = get_response;
     }
 
+    /// Set the priority of the next message (it is raised for
+    /// [High](Priority::High) priority methods).
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    pub fn set_response_slots(&self, capacity: usize) {
+        self.resp_chan.set_slots(capacity);
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_journal(&mut self, journal: Journal, name: &str) {
+        self.replay.set(journal.clone(), name);
+        self.journal = Some((journal, name.to_string()));
+    }
+
+    pub fn journal(&self) -> Option<(Journal, String)> {
+        self.journal.clone()
+    }
+
+    /// Journal replayed on the connections opened again, shared by the clones.
+    pub(crate) fn replay(&self) -> Replay {
+        self.replay.clone()
+    }
+
+    pub fn set_rate_limit(&mut self, limit: RateLimit) {
+        self.rate_limit = Some(limit);
+    }
+
+    pub fn set_frame_cap(&mut self, cap: Option<FrameCap>) {
+        self.frame_cap = cap;
+    }
+
+    pub fn set_quota(&self, limiter: QuotaLimiter) {
+        *self.quota.lock().unwrap_or_else(|e| e.into_inner()) = Some(limiter);
+    }
+
+    pub fn remaining_quota(&self) -> Option<u32> {
+        let mut quota = self.quota.lock().unwrap_or_else(|e| e.into_inner());
+        quota.as_mut().map(QuotaLimiter::remaining)
+    }
+
+    /// Buffer writes according to `config` (only effective when not waiting for responses).
+    pub fn set_coalescing(&mut self, config: WriteCoalescing) {
+        self.coalescing.store(true, Ordering::Relaxed);
+        let _ = self.frames.send(Frame::Config(config));
+    }
+
+    /// Send the request and wait for the response, with its values as strings (see
+    /// [protocol::to_strings]).
     pub async fn send(
         &mut self,
         method: &str,
-        params: &str,
+        params: &[Value],
     ) -> Result<Option<Response>, BulbError> {
-        let Message(id, content) = self.craft_message(method, params);
+        let response = self.send_raw(method, params).await?;
+        Ok(response.map(|values| protocol::to_strings(&values)))
+    }
+
+    /// Send the request and wait for the response, with its values as received.
+    pub async fn send_raw(
+        &mut self,
+        method: &str,
+        params: &[Value],
+    ) -> Result<Option<RawResponse>, BulbError> {
+        let result = self.send_request(method, params).await;
+        if result.is_err() {
+            self.stats.error();
+        }
+        result.map_err(|e| e.method_context(method))
+    }
+
+    async fn send_request(
+        &mut self,
+        method: &str,
+        params: &[Value],
+    ) -> Result<Option<RawResponse>, BulbError> {
+        let generation = self.generation();
+        let start = Instant::now();
+        match self.send_once(method, params).await {
+            Err(BulbError::Disconnected) => self.retry(method, params, generation, start).await,
+            result => result,
+        }
+    }
+
+    async fn send_once(
+        &mut self,
+        method: &str,
+        params: &[Value],
+    ) -> Result<Option<RawResponse>, BulbError> {
+        let priority = std::mem::take(&mut self.priority).max(Priority::of(method, params));
+        self.pace().await;
+        rate::acquire_quota(&self.quota).await?;
+        let request = self.craft_message(method, params);
+
+        let (reply, waiting) = self.reply(request.id);
+        let start = Instant::now();
+        self.queue(request.to_string().into_bytes(), priority, reply)?;
+        if self.get_response && self.coalescing() {
+            self.flush().await?;
+        }
+        self.wait(request.id, waiting, start).await
+    }
+
+    /// Send several messages at once and wait for all the responses.
+    ///
+    /// Messages are written together, so the bulb processes them without waiting for a round
+    /// trip between them.
+    pub async fn send_all(
+        &mut self,
+        messages: &[(&str, Vec<Value>)],
+    ) -> Vec<Result<Option<Response>, BulbError>> {
+        let priority = std::mem::take(&mut self.priority);
+        let generation = self.generation();
+        let start = Instant::now();
+        let mut pending = Vec::with_capacity(messages.len());
+        for (method, params) in messages {
+            let priority = priority.max(Priority::of(method, params));
+            self.pace().await;
+            if let Err(e) = rate::acquire_quota(&self.quota).await {
+                pending.push(Err(e));
+                continue;
+            }
+            let request = self.craft_message(method, params);
+            let (reply, waiting) = self.reply(request.id);
+            let result = self.queue(request.to_string().into_bytes(), priority, reply);
+            pending.push(
+                result
+                    .map(|()| (request.id, waiting))
+                    .map_err(BulbError::from),
+            );
+        }
+
+        let flushed = match self.get_response && self.coalescing() {
+            true => self.flush().await.map_err(|e| e.kind()),
+            false => Ok(()),
+        };
 
+        let mut results = Vec::with_capacity(pending.len());
+        for ((method, params), waiting) in messages.iter().zip(pending) {
+            let result = match (waiting, flushed) {
+                (Err(e), _) => Err(e),
+                (Ok(_), Err(kind)) => Err(io::Error::from(kind).into()),
+                (Ok((id, waiting)), Ok(())) => match self.wait(id, waiting, start).await {
+                    Err(BulbError::Disconnected) => {
+                        self.retry(method, params, generation, start).await
+                    }
+                    result => result,
+                },
+            };
+            if result.is_err() {
+                self.stats.error();
+            }
+            let result =
+                result.map(|response| response.map(|values| protocol::to_strings(&values)));
+            results.push(result.map_err(|e| e.method_context(method)));
+        }
+        results
+    }
+
+    /// Wait for the rate limit and the frame cap before sending a command.
+    async fn pace(&self) {
+        if let Some(limit) = &self.rate_limit {
+            limit.acquire().await;
+        }
+        if let Some(cap) = &self.frame_cap {
+            cap.acquire().await;
+        }
+    }
+
+    /// Send the message without waiting for it to be written nor for a response.
+    ///
+    /// Used when we can not await (such as in `Drop` implementations).
+    pub fn send_detached(&mut self, method: &str, params: &[Value]) -> Result<(), io::Error> {
+        let priority = std::mem::take(&mut self.priority).max(Priority::of(method, params));
+        rate::record_quota(&self.quota);
+        let content = self.craft_message(method, params).to_string();
+        self.queue(content.into_bytes(), priority, Reply::None)
+    }
+
+    /// Write all the buffered messages.
+    pub async fn flush(&mut self) -> Result<(), io::Error> {
+        let (result, receiver) = oneshot::channel();
+        self.frames
+            .send(Frame::Flush(result))
+            .map_err(|_| writer_stopped())?;
+        receiver.await.map_err(|_| writer_stopped())?
+    }
+
+    /// Write all the buffered messages and shut down the write half of the connection.
+    ///
+    /// Messages sent afterwards (from any clone) fail. Does nothing if the writing task
+    /// already stopped.
+    pub async fn shutdown(&mut self) -> Result<(), io::Error> {
+        let (result, receiver) = oneshot::channel();
+        if self.frames.send(Frame::Shutdown(result)).is_err() {
+            return Ok(());
+        }
+        receiver.await.unwrap_or(Ok(()))
+    }
+
+    fn craft_message(&mut self, method: &str, params: &[Value]) -> Request {
+        if let Some((journal, name)) = &self.journal {
+            journal.record(name, method, params);
+        }
+        self.cache.sent(method);
+        let id = self.get_message_id();
+        let request = Request::new(id, method, params.to_vec());
+
+        log::info!("sent -> {}", request);
+
+        request
+    }
+
+    /// How to wait for the message with the given id: the response, the write or nothing
+    /// (coalesced messages without response).
+    fn reply(&self, id: u64) -> (Reply, Waiting) {
         if self.get_response {
+            if let Some(slot) = self.resp_chan.slot(id) {
+                return (
+                    Reply::Response(id, Responder::Slot(slot.clone())),
+                    Waiting::Slot(slot),
+                );
+            }
+            let (sender, receiver) = channel();
+            let responder = Responder::Channel(sender);
+            (Reply::Response(id, responder), Waiting::Response(receiver))
+        } else if !self.coalescing() {
             let (sender, receiver) = channel();
+            (Reply::Written(sender), Waiting::Written(receiver))
+        } else {
+            (Reply::None, Waiting::None)
+        }
+    }
 
-            self.resp_chan.lock().await.insert(id, sender);
-            self.send_content(&content).await?;
+    fn generation(&self) -> u64 {
+        self.reconnected.as_ref().map_or(0, |r| *r.borrow())
+    }
 
-            Ok(Some(receiver.await??))
-        } else {
-            self.send_content(&content).await?;
-            Ok(None)
+    /// Send the request again once reconnected, if the connection is opened again after
+    /// `generation` before the timeout.
+    async fn retry(
+        &mut self,
+        method: &str,
+        params: &[Value],
+        generation: u64,
+        start: Instant,
+    ) -> Result<Option<RawResponse>, BulbError> {
+        let Some(mut reconnected) = self.reconnected.clone() else {
+            return Err(BulbError::Disconnected);
+        };
+        let reconnect = async move {
+            let reconnected = reconnected.wait_for(|&current| current > generation);
+            reconnected.await.map(|_| ())
+        };
+        let reconnected = match self.timeout {
+            Some(timeout) => {
+                let deadline = tokio::time::Instant::from_std(start + timeout);
+                tokio::time::timeout_at(deadline, reconnect).await.ok()
+            }
+            None => Some(reconnect.await),
+        };
+        match reconnected {
+            Some(Ok(())) => self.send_once(method, params).await,
+            // Closed, or the writing task stopped
+            Some(Err(_)) => Err(BulbError::Disconnected),
+            None => Err(BulbError::Timeout),
         }
     }
 
-    fn craft_message(&mut self, method: &str, params: &str) -> Message {
-        let id = self.get_message_id();
-        let message = Message(
+    /// Wait for the response to the request `id` (or for the message to be written), up to
+    /// the timeout.
+    async fn wait(
+        &self,
+        id: u64,
+        waiting: Waiting,
+        start: Instant,
+    ) -> Result<Option<RawResponse>, BulbError> {
+        // The response is not waited for if the caller stops waiting
+        let _forget = Forget {
+            resp_chan: &self.resp_chan,
             id,
-            format!(
-                "{{\"id\":{},\"method\":\"{}\",\"params\":[{}]}}\r\n",
-                id, method, params
-            ),
-        );
+        };
+        let Some(timeout) = self.timeout else {
+            return self.wait_response(waiting, start).await;
+        };
+        // The time already spent writing the message counts
+        let deadline = tokio::time::Instant::from_std(start + timeout);
+        match tokio::time::timeout_at(deadline, self.wait_response(waiting, start)).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.resp_chan.cancel(id).await;
+                Err(BulbError::Timeout)
+            }
+        }
+    }
+
+    async fn wait_response(
+        &self,
+        waiting: Waiting,
+        start: Instant,
+    ) -> Result<Option<RawResponse>, BulbError> {
+        match waiting {
+            Waiting::None => Ok(None),
+            Waiting::Written(receiver) => {
+                receiver.await.map_err(|_| writer_stopped())??;
+                Ok(None)
+            }
+            Waiting::Response(receiver) => {
+                // Disconnections are sent, the sender is only dropped if the task stopped
+                let response = receiver.await.map_err(|_| writer_stopped())?;
+                self.stats.latency(start.elapsed());
+                Ok(Some(response?))
+            }
+            Waiting::Slot(slot) => {
+                let response = slot.recv().await;
+                self.stats.latency(start.elapsed());
+                Ok(Some(response?))
+            }
+        }
+    }
+
+    /// Send the message to the writing task.
+    fn queue(&mut self, data: Vec<u8>, priority: Priority, reply: Reply) -> Result<(), io::Error> {
+        let len = data.len();
+        // Messages are only preempted by urgent ones when they are buffered
+        let sent = match (priority, self.coalescing()) {
+            (Priority::High, true) => {
+                let message = Message {
+                    epoch: self.epoch.fetch_add(1, Ordering::Relaxed),
+                    data,
+                    reply,
+                };
+                self.urgent.send(message).is_ok()
+            }
+            _ => {
+                let message = Message {
+                    epoch: self.epoch.load(Ordering::Relaxed),
+                    data,
+                    reply,
+                };
+                self.queued.fetch_add(1, Ordering::Relaxed);
+                self.frames.send(Frame::Data(message)).is_ok()
+            }
+        };
+        if !sent {
+            return Err(writer_stopped());
+        }
+        self.stats.sent(len);
+        Ok(())
+    }
+}
 
-        log::info!("sent -> {}", message.1);
+/// Forgets the request when the future waiting for its response is dropped (or done).
+struct Forget<'a> {
+    resp_chan: &'a RespChan,
+    id: u64,
+}
 
-        message
+impl Drop for Forget<'_> {
+    fn drop(&mut self) {
+        self.resp_chan.forget(self.id);
     }
+}
 
-    async fn send_content(&mut self, content: &str) -> Result<(), ::std::io::Error> {
-        self.writer.write_all(content.as_bytes()).await
+fn writer_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Writer task stopped")
+}
+
+/// Write the messages received to the socket.
+///
+/// Each message is written whole from an owned buffer, independently of the futures that sent
+/// it, so cancelling them never leaves a message partially written. The senders of the
+/// responses are registered here too, so they are only left waiting for requests that have
+/// been written.
+///
+/// With write coalescing, messages are buffered and written in batches. Urgent messages are
+/// written immediately, discarding the buffered messages and the ones still in the channel
+/// that were sent before them.
+async fn write_loop(
+    mut writer: WriteHalf,
+    reader_ready: oneshot::Receiver<()>,
+    mut halves: mpsc::UnboundedReceiver<WriteHalf>,
+    mut receiver: mpsc::UnboundedReceiver<Frame>,
+    mut urgent: mpsc::UnboundedReceiver<Message>,
+    mut buffer: Buffer,
+    resp_chan: RespChan,
+) {
+    let mut config = None::<WriteCoalescing>;
+    let mut deadline = tokio::time::Instant::now();
+    // Frames of previous epochs are discarded
+    let mut epoch = 0;
+
+    let closing = shutdown::closing();
+    tokio::pin!(closing);
+
+    // Messages sent in the meantime wait in the channels
+    let _ = reader_ready.await;
+
+    loop {
+        let buffered = !buffer.is_empty();
+        let sleep = async move {
+            match buffered {
+                true => tokio::time::sleep_until(deadline).await,
+                false => std::future::pending().await,
+            }
+        };
+
+        let frame = tokio::select! {
+            biased;
+            // Taken before the messages sent once reconnected
+            Some(half) = halves.recv() => {
+                writer = half;
+                continue;
+            }
+            Some(message) = urgent.recv() => {
+                epoch = epoch.max(message.epoch + 1);
+                let discarded = buffer.discard_before(epoch);
+                if !discarded.is_empty() {
+                    log::debug!("Discarded {} messages preempted by an urgent one", discarded.len());
+                }
+                for reply in discarded {
+                    reply.fail(|| BulbError::Preempted);
+                }
+                let mut single = Buffer::new(Arc::default());
+                single.push(message);
+                if let Err(e) = write_buffer(&mut writer, &mut single, &resp_chan).await {
+                    log::error!("Could not write urgent message: {}", e);
+                }
+                continue;
+            }
+            frame = receiver.recv() => frame,
+            _ = sleep => {
+                if let Err(e) = write_buffer(&mut writer, &mut buffer, &resp_chan).await {
+                    log::error!("Could not write coalesced messages: {}", e);
+                }
+                continue;
+            }
+            _ = &mut closing => {
+                if let Err(e) = write_buffer(&mut writer, &mut buffer, &resp_chan).await {
+                    log::error!("Could not write messages: {}", e);
+                }
+                let _ = writer.shutdown().await;
+                return;
+            }
+        };
+
+        match frame {
+            Some(Frame::Data(message)) if message.epoch < epoch => {
+                buffer.queued.fetch_sub(1, Ordering::Relaxed);
+                log::debug!("Discarded message preempted by an urgent one");
+                message.reply.fail(|| BulbError::Preempted);
+            }
+            Some(Frame::Data(message)) => {
+                if buffer.is_empty() {
+                    deadline = tokio::time::Instant::now()
+                        + config.map_or(Duration::ZERO, |c| c.max_delay);
+                }
+                buffer.push(message);
+                if buffer.len() >= config.map_or(0, |c| c.max_bytes) {
+                    if let Err(e) = write_buffer(&mut writer, &mut buffer, &resp_chan).await {
+                        log::error!("Could not write messages: {}", e);
+                    }
+                }
+            }
+            Some(Frame::Flush(result)) => {
+                let _ = result.send(write_buffer(&mut writer, &mut buffer, &resp_chan).await);
+            }
+            Some(Frame::Shutdown(result)) => {
+                let written = write_buffer(&mut writer, &mut buffer, &resp_chan).await;
+                let shutdown = writer.shutdown().await;
+                let _ = result.send(written.and(shutdown));
+                return;
+            }
+            Some(Frame::Config(new_config)) => config = Some(new_config),
+            None => {
+                if let Err(e) = write_buffer(&mut writer, &mut buffer, &resp_chan).await {
+                    log::error!("Could not write messages: {}", e);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Messages waiting to be written, with the epoch they were sent in.
+struct Buffer {
+    data: Vec<u8>,
+    /// Epoch, end offset in `data` and reply of each message.
+    messages: Vec<(u64, usize, Reply)>,
+    /// Counter of messages not written, shared with the [Writer].
+    queued: Arc<AtomicUsize>,
+}
+
+impl Buffer {
+    fn new(queued: Arc<AtomicUsize>) -> Self {
+        Self {
+            data: Vec::new(),
+            messages: Vec::new(),
+            queued,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn push(&mut self, message: Message) {
+        self.data.extend(message.data);
+        self.messages
+            .push((message.epoch, self.data.len(), message.reply));
+    }
+
+    /// Remove the messages sent before `epoch`, returning how many were removed.
+    fn discard_before(&mut self, epoch: u64) -> Vec<Reply> {
+        // Epochs only increase, so the discarded messages are at the start
+        let count = self.messages.partition_point(|(e, _, _)| *e < epoch);
+        if count == 0 {
+            return Vec::new();
+        }
+        let end = self.messages[count - 1].1;
+        self.data.drain(..end);
+        let replies = self
+            .messages
+            .drain(..count)
+            .map(|(_, _, reply)| reply)
+            .collect();
+        for (_, offset, _) in &mut self.messages {
+            *offset -= end;
+        }
+        self.queued.fetch_sub(count, Ordering::Relaxed);
+        replies
+    }
+
+    /// Remove all the messages (and their data), returning their replies.
+    fn clear(&mut self) -> Vec<Reply> {
+        self.queued
+            .fetch_sub(self.messages.len(), Ordering::Relaxed);
+        self.data.clear();
+        self.messages.drain(..).map(|(_, _, reply)| reply).collect()
+    }
+}
+
+async fn write_buffer(
+    writer: &mut WriteHalf,
+    buffer: &mut Buffer,
+    resp_chan: &RespChan,
+) -> io::Result<()> {
+    let data = std::mem::take(&mut buffer.data);
+    let replies = buffer.clear();
+
+    // The response can arrive before the write returns, so the senders are registered
+    // before writing (by this task, they are removed again if the write fails)
+    let mut requests = Vec::new();
+    let mut written = Vec::new();
+    for reply in replies {
+        match reply {
+            Reply::None => {}
+            Reply::Written(sender) => written.push(sender),
+            Reply::Response(id, responder) => {
+                resp_chan.register(id, responder).await;
+                requests.push(id);
+            }
+        }
+    }
+
+    let mut result = match data.is_empty() {
+        true => Ok(()),
+        false => writer.write_all(&data).await,
+    };
+    // Buffered transports may hold the messages until flushed
+    if result.is_ok() {
+        result = writer.flush().await;
+    }
+
+    if let Err(e) = &result {
+        for id in requests {
+            let error = io::Error::from(e.kind()).into();
+            resp_chan.respond(id, Err(error)).await;
+        }
+    }
+    for sender in written {
+        let _ = sender.send(
+            result
+                .as_ref()
+                .map(|_| ())
+                .map_err(|e| io::Error::from(e.kind()).into()),
+        );
     }
+    result
 }